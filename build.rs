@@ -2,5 +2,14 @@ use std::io::Result;
 
 fn main() -> Result<()> {
     prost_build::compile_protos(&["proto/profile.proto"], &["proto/"])?;
+    #[cfg(feature = "bpf")]
+    {
+        let out = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+        libbpf_cargo::SkeletonBuilder::new()
+            .source("src/bpf/profile.bpf.c")
+            .build_and_generate(out.join("profile.skel.rs"))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))?;
+        println!("cargo:rerun-if-changed=src/bpf/profile.bpf.c");
+    }
     Ok(())
 }
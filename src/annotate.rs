@@ -0,0 +1,152 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fmt::Write;
+use std::io;
+
+lazy_static! {
+    // Percent |	Source code & Disassembly of foo for cpu-clock:u (123 samples, percent: local period)
+    static ref FUNCTION_RE: Regex =
+        Regex::new(r"Disassembly of (.+) for \S+ \((\d+) samples").unwrap();
+    //   12.34 :	  1134:       cmp    %rsi,%rdx
+    static ref INSN_RE: Regex =
+        Regex::new(r"^\s*(\d+\.\d+)?\s*:\s+([0-9a-f]+):\s+(.*)$").unwrap();
+    //  jae    1160 <foo+0x30>
+    static ref JUMP_RE: Regex = Regex::new(r"^j[a-z]+\s+([0-9a-f]+)\b").unwrap();
+    static ref PANIC_RE: Regex = Regex::new(
+        r"call.*(core::panicking::|panic_bounds_check|slice_(start_|end_)?index_(len_|order_)?fail)"
+    )
+    .unwrap();
+}
+
+// instructions to look at from a jump target for the call to the panic
+const PANIC_BLOCK_LEN: usize = 8;
+
+struct Insn {
+    addr: u64,
+    percent: f64,
+    text: String,
+}
+
+/// A conditional jump to a panicking bounds check.
+#[derive(Debug)]
+pub struct Check {
+    pub function: String,
+    pub addr: u64,
+    /// samples of the compare and the jump
+    pub samples: f64,
+    /// the panic called if the check fails
+    pub panic: String,
+}
+
+fn panic_of(insns: &[Insn], target: u64) -> Option<String> {
+    let start = insns.iter().position(|i| i.addr == target)?;
+    for i in insns[start..].iter().take(PANIC_BLOCK_LEN) {
+        if let Some(m) = PANIC_RE.captures(&i.text) {
+            return Some(m[1].trim_end_matches("::").to_string());
+        }
+        if i.text.starts_with("jmp") || i.text.starts_with("ret") {
+            break;
+        }
+    }
+    None
+}
+
+fn checks_of(function: &str, samples: u64, insns: &[Insn], checks: &mut Vec<Check>) {
+    for (n, i) in insns.iter().enumerate() {
+        let target = match JUMP_RE.captures(&i.text) {
+            Some(caps) if !i.text.starts_with("jmp") => {
+                u64::from_str_radix(&caps[1], 16).unwrap_or(0)
+            }
+            _ => continue,
+        };
+        if let Some(panic) = panic_of(insns, target) {
+            // samples skid to the instruction after the compare
+            let percent = i.percent + if n > 0 { insns[n - 1].percent } else { 0.0 };
+            checks.push(Check {
+                function: function.to_string(),
+                addr: i.addr,
+                samples: percent * samples as f64 / 100.0,
+                panic,
+            });
+        }
+    }
+}
+
+/// Finds the bounds checks in `perf annotate --stdio` output, hottest first.
+pub fn bounds_checks<R>(mut reader: R) -> io::Result<Vec<Check>>
+where
+    R: io::BufRead,
+{
+    let mut checks = Vec::new();
+    let mut function: Option<(String, u64)> = None;
+    let mut insns = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        let eof = reader.read_until(b'\n', &mut buf)? == 0;
+        let line = String::from_utf8_lossy(&buf);
+        let next = FUNCTION_RE.captures(&line);
+        if eof || next.is_some() {
+            if let Some((name, samples)) = function.take() {
+                checks_of(&name, samples, &insns, &mut checks);
+            }
+            insns.clear();
+            if eof {
+                break;
+            }
+        }
+        if let Some(caps) = next {
+            function = Some((caps[1].to_string(), caps[2].parse().unwrap_or(0)));
+            continue;
+        }
+        if let Some(caps) = INSN_RE.captures(line.trim_end()) {
+            if let Ok(addr) = u64::from_str_radix(&caps[2], 16) {
+                insns.push(Insn {
+                    addr,
+                    percent: caps
+                        .get(1)
+                        .and_then(|p| p.as_str().parse().ok())
+                        .unwrap_or(0.0),
+                    text: caps[3].trim().to_string(),
+                });
+            }
+        }
+    }
+    checks.sort_by(|a, b| {
+        b.samples
+            .partial_cmp(&a.samples)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(checks)
+}
+
+/// renders the hottest `n` bounds checks as a text table
+pub fn render(checks: &[Check], n: usize) -> String {
+    let mut s = format!(
+        "{:>8} {:>10}  {:<24}  {}\n",
+        "Samples", "Address", "Panic", "Function"
+    );
+    for c in checks.iter().take(n) {
+        let _ = writeln!(
+            s,
+            "{:>8.1} {:>10x}  {:<24}  {}",
+            c.samples, c.addr, c.panic, c.function
+        );
+    }
+    s
+}
@@ -15,12 +15,17 @@
 
 use cargo_metadata::Message;
 use clap::Parser;
-use inferno::collapse::Collapse;
 use std::io;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
 
-use perf_tools::pprof;
+use perf_tools::{
+    annotate, budget, calibrate, callgraph, calltree, diff, dso, exemplar, ffi, flamegraph, flat,
+    fleet, grouping, html, inline, lang, marker, merge, parallel, pprof, primitives, query,
+    selftest, sidecar, snapshot, subtract, table, tenant, timeline, top, trend, unit,
+};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -38,35 +43,606 @@ enum Commands {
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    #[clap(subcommand)]
+    action: Option<Action>,
+
     /// command to run
     #[clap(short, long)]
     bin: Option<String>,
 
+    /// integration test to run, generating one pprof per test case
+    #[clap(long, conflicts_with_all = &["bin", "bench"])]
+    test: Option<String>,
+
+    /// benchmark to run, generating one pprof per bench case
+    #[clap(long, conflicts_with = "bin")]
+    bench: Option<String>,
+
+    /// binary to run without building it
+    #[clap(long, conflicts_with_all = &["bin", "test", "bench"])]
+    binary: Option<String>,
+
+    /// profile a binary built without optimizations
+    #[clap(long)]
+    allow_debug: bool,
+
     /// output file name
     #[clap(short, long)]
     output: Option<String>,
 
+    /// merge the profile into the file instead of writing the output, creating it if
+    /// missing, to keep a rolling aggregate
+    #[clap(long, conflicts_with_all = &["flamegraph", "output", "bpf"])]
+    append_to: Option<String>,
+
     /// sampling frequency
     #[clap(long)]
     frequency: Option<u32>,
 
+    /// compression of the profiles written: `gzip`, `zstd` or `none`, with the level like
+    /// `gzip:1` to trade size for speed; pprof reads gzip and uncompressed profiles only; also
+    /// after the subcommands writing profiles
+    #[clap(long, default_value = "gzip", global = true)]
+    compression: pprof::Compression,
+
     /// generate flamegraph instead of pprof
     #[clap(long)]
     flamegraph: bool,
+
+    /// write a self-contained report instead of pprof: the metadata, the top functions and
+    /// flamegraphs of all the threads and of each thread, in a single file
+    #[clap(long, possible_values = &["html"], conflicts_with_all = &["flamegraph", "append-to"])]
+    report: Option<String>,
+
+    /// link the frames of the flamegraph to the focus view of a pprof web UI at the URL, e.g.
+    /// http://localhost:8080/ui/
+    #[clap(long, requires = "flamegraph")]
+    flamegraph_link: Option<String>,
+
+    /// TOML file mapping pids and cgroups to tenants, to label the samples with `tenant`
+    #[clap(long)]
+    tenants: Option<String>,
+
+    /// sample type pprof shows by default, `samples` for the counts or `cpu` for the time
+    #[clap(long)]
+    default_sample_type: Option<String>,
+
+    /// reattach the stacks that dwarf unwinding cut short to their most likely callers, seen in
+    /// the complete stacks, labeled `repaired`
+    #[clap(long)]
+    repair_stacks: bool,
+
+    /// count panics and C++ exceptions per call stack instead of cpu time
+    #[clap(long)]
+    panics: bool,
+
+    /// count failed syscalls per call stack instead of cpu time
+    #[clap(long, conflicts_with = "panics")]
+    syscall_errors: bool,
+
+    /// pause/resume sampling and put markers from the keyboard while recording
+    #[clap(long)]
+    interactive: bool,
+
+    /// label samples with the phases marked by `perf_tools::marker()` in the binary
+    #[clap(long)]
+    markers: bool,
+
+    /// compress perf.data with zstd (`perf record -z`)
+    #[clap(short = 'z', long)]
+    compress: bool,
+
+    /// size of the ring buffer in pages or with a unit like 2M (`perf record --mmap-pages`)
+    #[clap(long)]
+    mmap_pages: Option<String>,
+
+    /// write perf.data asynchronously with the number of control blocks (`perf record --aio`)
+    #[clap(long)]
+    aio: Option<u32>,
+
+    /// expected run time in seconds, to refuse recording when perf.data would fill the disk
+    #[clap(long)]
+    expected_duration: Option<u64>,
+
+    /// skip checking free disk space before recording
+    #[clap(long)]
+    no_space_check: bool,
+
+    /// directory to look for binaries in, like a container image (`perf script --symfs`)
+    #[clap(long)]
+    symfs: Option<String>,
+
+    /// collapse the plumbing frames of a runtime into one frame
+    #[clap(long, possible_values = &grouping::PRESETS)]
+    preset: Vec<String>,
+
+    /// TOML file of rules to collapse, hide or highlight frames
+    #[clap(long)]
+    grouping: Option<String>,
+
+    /// pass the seed in PERF_TOOLS_SEED and record a hash of the binary's stdout,
+    /// so that `cargo perf diff` can tell if two runs did different work
+    #[clap(long, conflicts_with_all = &["test", "bench"])]
+    seed: Option<u64>,
+
+    /// save /proc/kallsyms and /proc/modules next to perf.data for later conversion
+    #[clap(long)]
+    kallsyms: bool,
+
+    /// convert while recording without perf.data, keeping only the distinct stacks
+    #[clap(long, conflicts_with_all = &["panics", "syscall-errors", "interactive", "markers", "flamegraph", "report", "test", "bench", "seed", "bpf"])]
+    stream: bool,
+
+    /// sample with a BPF program instead of perf, without perf.data; needs the bpf feature
+    /// and frame pointers
+    #[clap(long, conflicts_with_all = &["panics", "syscall-errors", "interactive", "markers", "flamegraph", "report", "test", "bench"])]
+    bpf: bool,
+
+    /// sample with ETW through xperf instead of perf; the default on Windows
+    #[clap(long, conflicts_with_all = &["panics", "syscall-errors", "interactive", "markers", "test", "bench", "bpf", "stream"])]
+    etw: bool,
+
+    /// sample with the Time Profiler of Instruments through xctrace instead of perf; the
+    /// default on macOS
+    #[clap(long, conflicts_with_all = &["panics", "syscall-errors", "interactive", "markers", "test", "bench", "bpf", "stream", "etw"])]
+    xctrace: bool,
+
+    /// record on the Android device of the serial with simpleperf of the NDK
+    /// (`ANDROID_NDK_HOME`), building for `--android-target`
+    #[clap(long, conflicts_with_all = &["panics", "syscall-errors", "interactive", "markers", "test", "bench", "bpf", "stream", "etw", "xctrace"])]
+    android: Option<String>,
+
+    /// the target triple of the device
+    #[clap(long, default_value = "aarch64-linux-android", requires = "android")]
+    android_target: String,
 }
 
-fn build_binary(args: &Args) -> std::io::Result<Vec<cargo_metadata::Artifact>> {
-    let mut cmd = Command::new("cargo");
-    cmd.args([
-        "build",
-        "--release",
-        "--message-format=json-render-diagnostics",
-    ]);
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+    /// show the hottest functions continuously without writing any files
+    Top(TopArgs),
+    /// compare two profiles function by function
+    Diff(DiffArgs),
+    /// check a profile against the cpu budgets
+    Check(CheckArgs),
+    /// show how the share of the hottest functions changes over profiles
+    Trend(TrendArgs),
+    /// show how evenly the samples in perf.data spread over threads
+    Parallel(ParallelArgs),
+    /// export the busy time of threads in perf.data as a Chrome trace
+    Timeline(TimelineArgs),
+    /// show the time spent per shared object (the binary, libraries, the kernel) in perf.data
+    Dso(DsoArgs),
+    /// print the hottest functions of a profile as a table, like `pprof -top`, or the call tree
+    Report(TopReportArgs),
+    /// show the time spent in foreign code per Rust call site
+    Ffi(ReportArgs),
+    /// show the time spent in memcpy, hashing and the allocator with their callers
+    Primitives(ReportArgs),
+    /// show the hottest call paths into the allocator
+    Allocs(AllocsArgs),
+    /// show the hottest bounds checks in perf.data, from `perf annotate`
+    Bounds(BoundsArgs),
+    /// suggest #[inline] and #[inline(never)] from call edges and function sizes
+    Inline(InlineArgs),
+    /// export the call graph of a profile, pruned of cold functions and edges
+    Callgraph(CallgraphArgs),
+    /// export the samples or the functions of a profile as a table
+    Export(ExportArgs),
+    /// run a SQL query over the stacks or the functions of a profile
+    Query(QueryArgs),
+    /// subtract an idle recording from a profile, leaving the work caused by the load
+    Subtract(SubtractArgs),
+    /// keep the samples of a profile through some functions, or drop them, like `pprof -focus`
+    Filter(FilterArgs),
+    /// print the OpenMetrics exemplar linking a CPU usage series to a profile
+    Exemplar(ExemplarArgs),
+    /// merge the profiles of many services and hosts into a fleet profile
+    Fleet(FleetArgs),
+    /// print the stacks of all the threads of a running process, like jstack
+    Snapshot(SnapshotArgs),
+    /// profile a built-in workload with known hotspots to validate perf and the conversion
+    Selftest(SelftestArgs),
+    /// record the binary at several frequencies and recommend one from the overhead measured
+    Calibrate(CalibrateArgs),
+}
+
+#[derive(Parser, Debug)]
+struct CalibrateArgs {
+    /// sampling frequencies to try
+    #[clap(
+        long,
+        use_delimiter = true,
+        default_value = "99,499,999,1999,3999,7999"
+    )]
+    frequencies: Vec<u32>,
+
+    /// highest acceptable slowdown of the binary in percent
+    #[clap(long, default_value = "5")]
+    max_overhead: f64,
+
+    /// highest acceptable share of lost samples in percent
+    #[clap(long, default_value = "1")]
+    max_lost: f64,
+}
+
+#[derive(Parser, Debug)]
+struct SelftestArgs {
+    /// seconds to run the workload
+    #[clap(long, default_value = "3")]
+    duration: u64,
+
+    /// allowed difference from the expected share in percent
+    #[clap(long, default_value = "5")]
+    tolerance: f64,
+
+    /// run the workload, under `perf record`
+    #[clap(long, hide = true)]
+    workload: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ReportArgs {
+    /// profile to analyze
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// number of lines to show
+    #[clap(long, default_value = "20")]
+    lines: usize,
+}
+
+#[derive(Parser, Debug)]
+struct TopReportArgs {
+    /// profile to report
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// number of functions to show
+    #[clap(long, default_value = "20")]
+    top: usize,
+
+    /// sort by the time including the callees instead of the time in the function itself
+    #[clap(long)]
+    cum: bool,
+
+    /// show the values as a percentage of the total, sample counts, or times
+    #[clap(long, default_value = "percent", possible_values = &["percent", "samples", "ns", "us", "ms"])]
+    unit: unit::Unit,
+
+    /// print the call tree from the roots of the threads instead, like `pprof -tree`
+    #[clap(long, conflicts_with = "cum")]
+    tree: bool,
+
+    /// levels of the call tree to show
+    #[clap(long, default_value = "30")]
+    depth: usize,
+
+    /// leave out the calls below this percentage of the total from the call tree
+    #[clap(long, default_value = "1")]
+    min_percent: f64,
+
+    /// show the functions by type and method names as `diff --cross-language`
+    /// matches them, for a Go or Python profile
+    #[clap(long)]
+    cross_language: bool,
+}
+
+#[derive(Parser, Debug)]
+struct QueryArgs {
+    /// profile to query
+    profile: String,
+
+    /// e.g. "select leaf, sum(samples) as s from stacks group by leaf order by s desc limit 10"
+    query: String,
+}
+
+#[derive(Parser, Debug)]
+struct FilterArgs {
+    /// profile to filter
+    profile: String,
+
+    /// regex of the functions to focus on; only the samples with a frame matching it are kept
+    #[clap(long, required_unless_present = "ignore")]
+    focus: Option<String>,
+
+    /// regex of the functions to ignore; the samples with a frame matching it are dropped
+    #[clap(long)]
+    ignore: Option<String>,
+
+    /// output file name
+    #[clap(short, long, default_value = "filtered.pprof")]
+    output: String,
+}
+
+#[derive(Parser, Debug)]
+struct SubtractArgs {
+    /// profile under load
+    profile: String,
+
+    /// idle recording to subtract, scaled by the ratio of the durations
+    baseline: String,
+
+    /// output file name
+    #[clap(short, long, default_value = "marginal.pprof")]
+    output: String,
+}
+
+#[derive(Parser, Debug)]
+struct FleetArgs {
+    /// profiles, `service=path` or a path whose file stem names the service
+    #[clap(required = true)]
+    profiles: Vec<String>,
+
+    /// output file name
+    #[clap(short, long, default_value = "fleet.pprof")]
+    output: String,
+}
+
+#[derive(Parser, Debug)]
+struct ExemplarArgs {
+    /// profile, as uploaded
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// counter the exemplar is attached to, exposed as `<metric>_total`
+    #[clap(long, default_value = "profile_cpu_seconds")]
+    metric: String,
+
+    /// label of the series, `key=value`
+    #[clap(long)]
+    label: Vec<String>,
+
+    /// `json` prints the profile id and the time range for pipelines to attach themselves
+    #[clap(long, default_value = "openmetrics", possible_values = &["openmetrics", "json"])]
+    format: String,
+}
+
+#[derive(Parser, Debug)]
+struct SnapshotArgs {
+    /// process to capture
+    pid: u32,
+
+    /// `pprof` writes a profile of a sample per thread instead
+    #[clap(long, default_value = "text", possible_values = &["text", "pprof"])]
+    format: String,
+
+    /// output file name of the pprof format
+    #[clap(short, long, default_value = "snapshot.pprof")]
+    output: String,
+
+    /// take the snapshots and report the threads whose stacks never changed, likely stuck or
+    /// deadlocked; the pprof format has the time they were stuck
+    #[clap(long, default_value = "1")]
+    count: u32,
+
+    /// milliseconds between the snapshots
+    #[clap(long, default_value = "1000")]
+    interval_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+struct ExportArgs {
+    /// profile to export
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// one row per stack or per function
+    #[clap(long, default_value = "stack", possible_values = &["stack", "function"])]
+    by: String,
+
+    /// output format; parquet needs the `parquet` feature
+    #[clap(long, default_value = "csv", possible_values = &["csv", "parquet"])]
+    format: String,
+
+    /// output file name; CSV goes to stdout without it
+    #[clap(short, long)]
+    output: Option<String>,
+
+    /// unit of the times, which are in nanoseconds by default
+    #[clap(long, default_value = "ns", possible_values = &["ns", "us", "ms"])]
+    unit: unit::Unit,
+}
+
+#[derive(Parser, Debug)]
+struct CallgraphArgs {
+    /// profile to export
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// output format; `callgrind` keeps all the functions and edges, for KCachegrind
+    #[clap(long, default_value = "csv", possible_values = &["csv", "dot", "mermaid", "callgrind"])]
+    format: String,
+
+    /// drop functions below this fraction of the total
+    #[clap(long, default_value = "0.005")]
+    node_fraction: f64,
+
+    /// drop edges below this fraction of the total
+    #[clap(long, default_value = "0.001")]
+    edge_fraction: f64,
+}
+
+#[derive(Parser, Debug)]
+struct InlineArgs {
+    /// binary the profile was taken from
+    binary: String,
+
+    /// profile to analyze
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// number of suggestions of each kind to show
+    #[clap(long, default_value = "20")]
+    lines: usize,
+}
+
+#[derive(Parser, Debug)]
+struct BoundsArgs {
+    /// perf data file
+    #[clap(default_value = "perf.data")]
+    input: String,
+
+    /// number of checks to show
+    #[clap(long, default_value = "20")]
+    lines: usize,
+}
+
+#[derive(Parser, Debug)]
+struct AllocsArgs {
+    /// profile to analyze
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// number of paths to show
+    #[clap(long, default_value = "10")]
+    lines: usize,
+
+    /// number of callers to tell paths apart
+    #[clap(long, default_value = "5")]
+    depth: usize,
+}
+
+#[derive(Parser, Debug)]
+struct DsoArgs {
+    /// perf data file
+    #[clap(default_value = "perf.data")]
+    input: String,
+
+    /// print CSV instead of a table
+    #[clap(long)]
+    csv: bool,
+}
+
+#[derive(Parser, Debug)]
+struct TimelineArgs {
+    /// perf data file
+    #[clap(default_value = "perf.data")]
+    input: String,
+
+    /// width of a bucket in milliseconds
+    #[clap(long, default_value = "10")]
+    interval: u64,
+
+    /// output file name
+    #[clap(short, long, default_value = "timeline.json")]
+    output: String,
+}
+
+#[derive(Parser, Debug)]
+struct ParallelArgs {
+    /// perf data file
+    #[clap(default_value = "perf.data")]
+    input: String,
+
+    /// function running on one thread at a time, like the setup of a pool
+    #[clap(long)]
+    serial: Vec<String>,
+
+    /// number of stacks to show
+    #[clap(long, default_value = "20")]
+    lines: usize,
+}
 
+#[derive(Parser, Debug)]
+struct TrendArgs {
+    /// directory of the profiles
+    dir: String,
+
+    /// output format
+    #[clap(long, default_value = "csv", possible_values = &["csv", "json"])]
+    format: String,
+
+    /// number of functions to track
+    #[clap(long, default_value = "8")]
+    lines: usize,
+
+    /// write a line chart in SVG
+    #[clap(long)]
+    chart: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct CheckArgs {
+    /// profile to check
+    #[clap(default_value = "cpu.pprof")]
+    profile: String,
+
+    /// budgets file
+    #[clap(long, default_value = "perf-budgets.toml")]
+    budgets: String,
+
+    /// write a standalone HTML report with the budgets and the flamegraph
+    #[clap(long)]
+    html: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct DiffArgs {
+    /// profile to compare against
+    base: String,
+
+    /// profile to compare
+    new: String,
+
+    /// match functions by exact symbol names across builds
+    #[clap(long)]
+    exact: bool,
+
+    /// match functions across languages by type and method names, to compare
+    /// against a Go or Python profile
+    #[clap(long, conflicts_with = "exact")]
+    cross_language: bool,
+
+    /// number of functions to show
+    #[clap(long, default_value = "20")]
+    lines: usize,
+
+    /// write a standalone HTML report with the differential flamegraph
+    #[clap(long)]
+    html: Option<String>,
+
+    /// write the new profile minus the base per stack as a profile, negative where it got faster
+    #[clap(long)]
+    delta: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct TopArgs {
+    /// refresh interval in seconds
+    #[clap(long, default_value = "1")]
+    interval: u64,
+
+    /// number of functions to show
+    #[clap(long, default_value = "20")]
+    lines: usize,
+}
+
+fn cargo_build_args(args: &Args) -> Vec<String> {
+    let mut v = vec!["build".to_string(), "--release".to_string()];
     if let Some(bin) = &args.bin {
-        cmd.arg("--bin");
-        cmd.arg(bin);
+        v.push("--bin".to_string());
+        v.push(bin.clone());
+    }
+    if let Some(test) = &args.test {
+        v.push("--test".to_string());
+        v.push(test.clone());
+    }
+    if let Some(bench) = &args.bench {
+        v.push("--bench".to_string());
+        v.push(bench.clone());
+    }
+    if args.android.is_some() {
+        v.push("--target".to_string());
+        v.push(args.android_target.clone());
     }
+    v
+}
+
+fn build_binary(args: &Args) -> std::io::Result<Vec<cargo_metadata::Artifact>> {
+    let mut cmd = Command::new("cargo");
+    cmd.args(cargo_build_args(args));
+    cmd.arg("--message-format=json-render-diagnostics");
 
     let mut command = cmd
         .stdout(Stdio::piped())
@@ -89,7 +665,10 @@ fn build_binary(args: &Args) -> std::io::Result<Vec<cargo_metadata::Artifact>> {
         .collect::<Vec<cargo_metadata::Artifact>>())
 }
 
-fn find_binary(args: &Args, artifact: &[cargo_metadata::Artifact]) -> std::io::Result<String> {
+fn find_binary<'a>(
+    args: &Args,
+    artifact: &'a [cargo_metadata::Artifact],
+) -> std::io::Result<&'a cargo_metadata::Artifact> {
     if artifact.is_empty() {
         return Err(io::Error::new(
             io::ErrorKind::Other,
@@ -97,10 +676,19 @@ fn find_binary(args: &Args, artifact: &[cargo_metadata::Artifact]) -> std::io::R
         ));
     }
 
-    if let Some(name) = args.bin.as_ref() {
+    if let Some(name) = args.test.as_ref().or(args.bench.as_ref()) {
+        for a in artifact {
+            if a.profile.test && &a.target.name == name {
+                return Ok(a);
+            }
+        }
+        Err(io::Error::other(
+            "can't find test binary name to be specified",
+        ))
+    } else if let Some(name) = args.bin.as_ref() {
         for a in artifact {
             if a.executable.as_ref().unwrap().ends_with(name) {
-                return Ok(a.executable.as_ref().unwrap().to_string());
+                return Ok(a);
             }
         }
         Err(io::Error::new(
@@ -109,7 +697,7 @@ fn find_binary(args: &Args, artifact: &[cargo_metadata::Artifact]) -> std::io::R
         ))
     } else {
         if artifact.len() == 1 {
-            return Ok(artifact[0].executable.as_ref().unwrap().to_string());
+            return Ok(&artifact[0]);
         }
         Err(io::Error::new(
             io::ErrorKind::Other,
@@ -119,76 +707,1447 @@ fn find_binary(args: &Args, artifact: &[cargo_metadata::Artifact]) -> std::io::R
 }
 
 const PERF_DATA_FILE: &str = "perf.data";
+const ETW_TRACE_FILE: &str = "perf.etl";
+const XCTRACE_TRACE_FILE: &str = "perf.trace";
+const ANDROID_DIR: &str = "/data/local/tmp";
+const ANDROID_SYMFS: &str = "perf.symfs";
 const DEFAULT_PPROF_OUTPUT: &str = "cpu.pprof";
+const DEFAULT_PANIC_OUTPUT: &str = "panic.pprof";
+const DEFAULT_SYSCALL_OUTPUT: &str = "syscall.pprof";
 const DEFAULT_FLAMEGRAPH_OUTPUT: &str = "flamegraph.svg";
+const DEFAULT_REPORT_OUTPUT: &str = "report.html";
 const DEFAULT_RECORD_FREQ: u32 = 99;
+// enough samples to tell 10% apart in a few seconds
+const SELFTEST_RECORD_FREQ: u32 = 999;
 
-fn main() {
-    let Commands::Perf(args) = Cli::parse().command;
+const PROBE_GROUP: &str = "perf_tools";
+const PANIC_SYMBOLS: [&str; 2] = ["rust_begin_unwind", "__cxa_throw"];
 
-    let artifact = build_binary(&args).unwrap();
-    let binary_path = find_binary(&args, &artifact).unwrap();
+fn add_panic_probes(binary_path: &str) -> std::io::Result<Vec<String>> {
+    let mut events = Vec::new();
+    for sym in PANIC_SYMBOLS {
+        let event = format!("{}:{}", PROBE_GROUP, sym);
+        let output = Command::new("perf")
+            .args(["probe", "-q", "-x", binary_path, "-a"])
+            .arg(format!("{}={}", event, sym))
+            .output()?;
+        // __cxa_throw exists only when the binary links libstdc++ statically
+        if output.status.success() {
+            events.push(event);
+        } else {
+            eprintln!("skipped probing {}", sym);
+        }
+    }
+    if events.is_empty() {
+        return Err(io::Error::other("can't add any probe for panics"));
+    }
+    Ok(events)
+}
 
-    let mut cmd = Command::new("perf");
-    cmd.args([
-        "record",
-        "--call-graph",
-        "dwarf",
-        "-g",
-        "-F",
-        &format!("{}", args.frequency.unwrap_or(DEFAULT_RECORD_FREQ)),
-        "-o",
-        PERF_DATA_FILE,
-    ]);
-    cmd.arg(binary_path);
-    cmd.spawn()
-        .unwrap_or_else(|e| panic!("failed to run {:?}", e))
-        .wait_with_output()
-        .map(|output| {
-            if output.status.success() {
-                println!("{}", String::from_utf8(output.stdout).unwrap());
-            } else {
-                panic!("{}", String::from_utf8(output.stderr).unwrap());
-            }
-        })
-        .expect("failed to wait for `perf record`");
+fn delete_probes() {
+    let _ = Command::new("perf")
+        .args(["probe", "-q", "-d"])
+        .arg(format!("{}:*", PROBE_GROUP))
+        .output();
+}
+
+fn top(binary_path: &str, freq: u32, symfs: Option<&str>, top_args: &TopArgs) {
+    let mut child = Command::new(binary_path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to run {:?}", e));
+    let pid = child.id().to_string();
+
+    while let Ok(None) = child.try_wait() {
+        // perf.data in pipe mode never touches the disk
+        let record = Command::new("perf")
+            .args(["record", "--call-graph", "dwarf", "-F"])
+            .arg(freq.to_string())
+            .args(["-p", &pid, "-o", "-", "--", "sleep"])
+            .arg(top_args.interval.to_string())
+            .stderr(Stdio::null())
+            .output()
+            .expect("failed to run `perf record`");
+
+        let mut script = Command::new("perf");
+        script.args(["script", "-i", "-"]);
+        if let Some(symfs) = symfs {
+            script.arg("--symfs").arg(symfs);
+        }
+        let mut script = script
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to run `perf script`");
+        let mut stdin = script.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&record.stdout));
+
+        let mut t = top::Top::default();
+        t.update(BufReader::new(script.stdout.take().unwrap()))
+            .expect("failed to read `perf script`");
+        let _ = writer.join();
+        let _ = script.wait();
+
+        print!(
+            "\x1b[2J\x1b[H{} samples\n\n{}",
+            t.total(),
+            t.render(top_args.lines)
+        );
+        let _ = io::stdout().flush();
+    }
+}
+
+fn run_selftest(args: &Args, selftest_args: &SelftestArgs) {
+    let duration = selftest_args.duration.to_string();
+    let perf_data = std::env::temp_dir().join("perf-tools-selftest.data");
+    let exe = std::env::current_exe().expect("failed to find cargo-perf");
+    let status = Command::new("perf")
+        .args(["record", "--call-graph", "dwarf", "-F"])
+        .arg(args.frequency.unwrap_or(SELFTEST_RECORD_FREQ).to_string())
+        .arg("-o")
+        .arg(&perf_data)
+        .arg("--")
+        .arg(exe)
+        .args(["perf", "selftest", "--workload", "--duration", &duration])
+        .stdout(Stdio::null())
+        .status()
+        .expect("failed to run `perf record`");
+    if !status.success() {
+        panic!("`perf record` failed");
+    }
 
     let script_output = Command::new("perf")
-        .arg("script")
-        .arg("--header")
+        .args(["script", "--header", "-i"])
+        .arg(&perf_data)
         .output()
         .expect("failed to execute perf");
+    let _ = std::fs::remove_file(&perf_data);
     if !script_output.status.success() {
         panic!("{}", String::from_utf8(script_output.stderr).unwrap());
     }
+    let mut encoded = Vec::new();
+    pprof::PprofConverterBuilder::default()
+        .build()
+        .from_reader(BufReader::new(&*script_output.stdout), &mut encoded)
+        .unwrap();
+    let profile = pprof::decode(&*encoded).unwrap();
 
-    let output = args.output.unwrap_or_else(|| {
-        if args.flamegraph {
-            DEFAULT_FLAMEGRAPH_OUTPUT.to_string()
-        } else {
-            DEFAULT_PPROF_OUTPUT.to_string()
-        }
-    });
-    let writer = std::fs::File::create(output).expect("failed to create output file");
-    let perf_reader = BufReader::new(&*script_output.stdout);
-    if args.flamegraph {
-        let mut collapsed = vec![];
-        inferno::collapse::perf::Folder::default()
-            .collapse(perf_reader, BufWriter::new(&mut collapsed))
-            .unwrap();
+    let tolerance = selftest_args.tolerance / 100.0;
+    let hotspots = selftest::verify(&profile);
+    print!("{}", selftest::render(&hotspots, tolerance));
+    if !hotspots.iter().all(|h| h.passed(tolerance)) {
+        std::process::exit(1);
+    }
+}
 
-        inferno::flamegraph::from_reader(
-            &mut inferno::flamegraph::Options::default(),
-            BufReader::new(&*collapsed),
-            &writer,
-        )
-        .unwrap();
-    } else {
-        let mut encoder = libflate::gzip::Encoder::new(writer).unwrap();
+fn calibrate(binary_path: &str, calibrate_args: &CalibrateArgs) {
+    let start = std::time::Instant::now();
+    let status = Command::new(binary_path)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap_or_else(|e| panic!("failed to run {}: {}", binary_path, e));
+    if !status.success() {
+        panic!("{} failed", binary_path);
+    }
+    let baseline = start.elapsed();
+
+    let perf_data = std::env::temp_dir().join("perf-tools-calibrate.data");
+    let mut trials = Vec::new();
+    for freq in &calibrate_args.frequencies {
+        eprintln!("recording at {}Hz", freq);
+        let start = std::time::Instant::now();
+        let status = Command::new("perf")
+            .args([
+                "record",
+                "--call-graph",
+                "dwarf",
+                "-F",
+                &freq.to_string(),
+                "-o",
+            ])
+            .arg(&perf_data)
+            .arg("--")
+            .arg(binary_path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to run `perf record`");
+        let elapsed = start.elapsed();
+        if !status.success() {
+            panic!("`perf record` failed at {}Hz", freq);
+        }
+        let bytes = std::fs::metadata(&perf_data).map_or(0, |m| m.len());
+        let script_output = Command::new("perf")
+            .args(["script", "--header", "--show-lost-events", "-i"])
+            .arg(&perf_data)
+            .output()
+            .expect("failed to execute perf");
+        if !script_output.status.success() {
+            panic!("{}", String::from_utf8_lossy(&script_output.stderr));
+        }
+        let mut encoded = Vec::new();
         pprof::PprofConverterBuilder::default()
             .build()
-            .from_reader(perf_reader, &mut encoder)
+            .from_reader(BufReader::new(&*script_output.stdout), &mut encoded)
             .unwrap();
-        encoder.finish().into_result().unwrap();
+        let profile = pprof::decode(&*encoded).unwrap();
+        trials.push(calibrate::Trial::new(*freq, elapsed, &profile, bytes));
+    }
+    let _ = std::fs::remove_file(&perf_data);
+    print!(
+        "{}",
+        calibrate::render(
+            baseline,
+            &trials,
+            calibrate_args.max_overhead / 100.0,
+            calibrate_args.max_lost / 100.0
+        )
+    );
+}
+
+// a dwarf sample carries 8KB of the user stack, registers and the header
+const DWARF_SAMPLE_SIZE: u64 = 8 * 1024 + 512;
+// warn if the disk is filled up faster than this
+const MIN_RECORD_SECS: u64 = 60;
+
+// available bytes of the filesystem
+fn free_space(dir: &str) -> io::Result<u64> {
+    let output = Command::new("df").args(["-Pk", dir]).output()?;
+    // Filesystem 1024-blocks Used Available Capacity Mounted on
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|l| l.split_whitespace().nth(3))
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| io::Error::other("can't parse `df` output"))
+}
+
+fn check_space(args: &Args, freq: u32) {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
+    // upper bound: every cpu runs the binary all the time
+    let rate = freq as u64 * DWARF_SAMPLE_SIZE * cpus;
+    let free = match free_space(".") {
+        Ok(free) => free,
+        Err(e) => {
+            eprintln!("skipped checking free space: {}", e);
+            return;
+        }
+    };
+    const MB: u64 = 1024 * 1024;
+    println!(
+        "perf.data grows up to {} MB/s, {} MB free",
+        rate / MB,
+        free / MB
+    );
+
+    if let Some(secs) = args.expected_duration {
+        let needed = rate * secs;
+        if needed > free && !args.compress {
+            eprintln!(
+                "perf.data would need up to {} MB for {} seconds but only {} MB free; \
+                lower `--frequency`, use `-z`, or pass `--no-space-check`",
+                needed / MB,
+                secs,
+                free / MB
+            );
+            std::process::exit(1);
+        }
+    } else if free / rate.max(1) < MIN_RECORD_SECS {
+        eprintln!(
+            "WARNING: the disk might be full in {} seconds",
+            free / rate.max(1)
+        );
+    }
+}
+
+const CONTROL_FIFO: &str = "perf.ctl";
+const MARKERS_FILE: &str = "perf.markers";
+
+type Markers = Arc<Mutex<Vec<(u64, String)>>>;
+
+// reads commands from stdin and forwards them to `perf record --control`
+fn interact() -> Markers {
+    let markers: Markers = Arc::new(Mutex::new(Vec::new()));
+    let m = markers.clone();
+    std::thread::spawn(move || {
+        // blocks until perf opens the fifo
+        let mut ctl = match std::fs::OpenOptions::new().write(true).open(CONTROL_FIFO) {
+            Ok(ctl) => ctl,
+            Err(e) => {
+                eprintln!("failed to open {}: {}", CONTROL_FIFO, e);
+                return;
+            }
+        };
+        eprintln!("commands: p (pause), r (resume), m <name> (marker)");
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            let line = line.trim();
+            let r = match line.split_once(' ').unwrap_or((line, "")) {
+                ("p", _) => writeln!(ctl, "disable"),
+                ("r", _) => writeln!(ctl, "enable"),
+                ("m", name) if !name.trim().is_empty() => {
+                    m.lock()
+                        .unwrap()
+                        .push((marker::now_usec(), name.trim().to_string()));
+                    Ok(())
+                }
+                _ => {
+                    eprintln!("unknown command: {}", line);
+                    Ok(())
+                }
+            };
+            if r.is_err() {
+                break;
+            }
+        }
+    });
+    markers
+}
+
+// libtest prints "test name ... " when a test starts, and the result when it ends
+fn watch_tests<R: Read>(mut reader: R) -> Vec<(u64, String)> {
+    let mut markers = Vec::new();
+    let mut line = Vec::new();
+    let mut buf = [0; 4096];
+    let mut stdout = io::stdout();
+    while let Ok(n) = reader.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        let _ = stdout.write_all(&buf[..n]);
+        let _ = stdout.flush();
+        for b in &buf[..n] {
+            if *b == b'\n' {
+                line.clear();
+                continue;
+            }
+            line.push(*b);
+            if line.ends_with(b" ... ") {
+                if let Some(name) = String::from_utf8_lossy(&line)
+                    .strip_prefix("test ")
+                    .and_then(|l| l.strip_suffix(" ... "))
+                {
+                    markers.push((marker::now_usec(), name.to_string()));
+                }
+            }
+        }
+    }
+    markers
+}
+
+// copies the binary's stdout through, returning its hash
+fn hash_stdout<R: Read>(mut reader: R) -> u64 {
+    let mut hash = sidecar::HASH_INIT;
+    let mut buf = [0; 4096];
+    let mut stdout = io::stdout();
+    while let Ok(n) = reader.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        let _ = stdout.write_all(&buf[..n]);
+        hash = sidecar::hash_bytes(hash, &buf[..n]);
+    }
+    let _ = stdout.flush();
+    hash
+}
+
+fn write_pprof<R: BufRead>(
+    args: &Args,
+    script: R,
+    meta: &sidecar::Meta,
+    phase: Option<&str>,
+    output: &str,
+) {
+    let profile = convert_script(args, script, meta, phase);
+    match args.append_to.as_ref() {
+        Some(path) => merge::append_to(path, &profile, args.compression)
+            .unwrap_or_else(|e| panic!("failed to append to {}: {}", path, e)),
+        None => write_profile(args, &profile, output),
+    }
+}
+
+// converts perf script output to a profile, warning about lost samples
+fn convert_script<R: BufRead>(
+    args: &Args,
+    script: R,
+    meta: &sidecar::Meta,
+    phase: Option<&str>,
+) -> pprof::pb::Profile {
+    let mut builder = pprof::PprofConverterBuilder::default();
+    builder
+        .captured_time(chrono::Local::now())
+        .freq(args.frequency.unwrap_or(DEFAULT_RECORD_FREQ) as u64);
+    if args.panics {
+        builder.event(PROBE_GROUP);
+    }
+    builder.syscall_errors(args.syscall_errors);
+    builder.repair(args.repair_stacks);
+    if let Some(name) = args.default_sample_type.as_ref() {
+        builder.default_sample_type(name);
+    }
+    if let Some(path) = args.tenants.as_ref() {
+        builder.tenants(
+            tenant::Tenants::load(path)
+                .unwrap_or_else(|e| panic!("failed to read {}: {}", path, e)),
+        );
+    }
+    builder.metadata(true);
+    for (time, name) in &meta.markers {
+        builder.marker(*time, name);
+    }
+    if let Some(phase) = phase {
+        builder.phase(phase);
+    }
+    for preset in &args.preset {
+        builder.grouping(&grouping::preset(preset).unwrap());
+    }
+    if let Some(path) = args.grouping.as_ref() {
+        let rules =
+            grouping::load(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+        builder.grouping(&rules);
+    }
+    for comment in meta.comments() {
+        builder.comment(&comment);
+    }
+    let mut content = Vec::new();
+    builder.build().from_reader(script, &mut content).unwrap();
+    let profile = pprof::decode(&*content).unwrap();
+    warn_lost(&profile);
+    profile
+}
+
+fn write_flamegraph(args: &Args, profile: &pprof::pb::Profile, output: &str) {
+    let options = flamegraph::Options {
+        title: "Flame Graph".to_string(),
+        index: 0,
+        link: args.flamegraph_link.clone(),
+    };
+    let writer = std::fs::File::create(output).expect("failed to create output file");
+    flamegraph::render(profile, &options, BufWriter::new(writer)).unwrap();
+}
+
+fn write_report(profile: &pprof::pb::Profile, output: &str) {
+    let report = html::report("cargo perf", profile, 30).unwrap();
+    std::fs::write(output, report).expect("failed to write the report");
+}
+
+fn warn_lost(profile: &pprof::pb::Profile) {
+    if let Some(lost) = pprof::lost_samples(profile) {
+        eprintln!(
+            "WARNING: {}; record with a lower `--frequency` or a larger `--mmap-pages`",
+            lost
+        );
+    }
+}
+
+// the opt-level of the cargo profile which built a binary in the target
+// directory, from the profiles in the workspace manifest; None for a binary
+// built elsewhere
+fn opt_level(path: &str) -> Option<String> {
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .ok()?;
+    let path = std::fs::canonicalize(path).ok()?;
+    let target = std::fs::canonicalize(&metadata.target_directory).ok()?;
+    let manifest: toml::Value = std::fs::read_to_string(metadata.workspace_root.join("Cargo.toml"))
+        .ok()?
+        .parse()
+        .ok()?;
+    let profiles = manifest.get("profile");
+    let profile = |name: &str| profiles.and_then(|p| p.get(name));
+    // target/<profile>/ or target/<triple>/<profile>/, where the dev profile's is debug
+    let dir = path
+        .strip_prefix(target)
+        .ok()?
+        .components()
+        .take(2)
+        .filter_map(|c| c.as_os_str().to_str())
+        .find(|d| matches!(*d, "debug" | "release") || profile(d).is_some())?;
+    let mut name = if dir == "debug" { "dev" } else { dir };
+    // bounded, in case the profiles inherit in a cycle
+    for _ in 0..8 {
+        if let Some(level) = profile(name).and_then(|p| p.get("opt-level")) {
+            return Some(match level {
+                toml::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            });
+        }
+        name = match profile(name).and_then(|p| p.get("inherits")?.as_str()) {
+            Some(parent) => parent,
+            None => match name {
+                "test" => "dev",
+                "bench" => "release",
+                "dev" => return Some("0".to_string()),
+                _ => return Some("3".to_string()),
+            },
+        };
+    }
+    None
+}
+
+fn refuse_debug_build(path: &str) -> ! {
+    eprintln!(
+        "WARNING: {} is built without optimizations; the profile would be misleading.",
+        path
+    );
+    eprintln!("Build it with optimizations, or pass `--allow-debug` to profile it anyway.");
+    std::process::exit(1);
+}
+
+fn write_profile(args: &Args, profile: &pprof::pb::Profile, path: &str) {
+    let mut content = Vec::new();
+    prost::Message::encode(profile, &mut content).unwrap();
+    let compressed = pprof::compress(&content, args.compression).expect("compression failed");
+    std::fs::write(path, compressed).unwrap_or_else(|e| panic!("failed to write {}: {}", path, e));
+}
+
+fn load_profile(path: &str) -> pprof::pb::Profile {
+    let f = std::fs::File::open(path).unwrap_or_else(|e| panic!("failed to open {}: {}", path, e));
+    pprof::decode(BufReader::new(f)).unwrap()
+}
+
+// perf.data in pipe mode is converted as it's recorded, so that the memory
+// grows with the distinct stacks rather than with the duration
+fn record_stream(args: &Args, binary_path: &str) {
+    // perf starts the binary, so that no sample is missed before attaching;
+    // it sends the binary's stdout to stderr, away from the data
+    let mut record = Command::new("perf")
+        .args(["record", "--call-graph", "dwarf", "-F"])
+        .arg(args.frequency.unwrap_or(DEFAULT_RECORD_FREQ).to_string())
+        .args(["-o", "-", "--", binary_path])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run `perf record`");
+    let mut script = Command::new("perf");
+    script.args(["script", "--header", "--show-lost-events", "-i", "-"]);
+    if args.tenants.is_some() {
+        script.args(["-F", "+pid"]);
+    }
+    if let Some(symfs) = args.symfs.as_ref() {
+        script.arg("--symfs").arg(symfs);
+    }
+    let mut script = script
+        .stdin(record.stdout.take().unwrap())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run `perf script`");
+
+    let meta = sidecar::Meta::collect(if args.binary.is_some() {
+        Vec::new()
+    } else {
+        cargo_build_args(args)
+    });
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PPROF_OUTPUT.to_string());
+    write_pprof(
+        args,
+        BufReader::new(script.stdout.take().unwrap()),
+        &meta,
+        None,
+        &output,
+    );
+    let _ = record.wait();
+    let _ = script.wait();
+}
+
+#[cfg(feature = "bpf")]
+fn record_bpf(args: &Args, binary_path: &str) {
+    let mut child = Command::new(binary_path)
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to run {}: {}", binary_path, e));
+    let freq = args.frequency.unwrap_or(DEFAULT_RECORD_FREQ) as u64;
+    let mut sampler = perf_tools::bpf::Sampler::start(child.id(), freq).unwrap_or_else(|e| {
+        let _ = child.kill();
+        panic!(
+            "failed to load the BPF program, which needs CAP_BPF and CAP_PERFMON: {}",
+            e
+        )
+    });
+    loop {
+        if child
+            .try_wait()
+            .expect("failed to wait for the binary")
+            .is_some()
+        {
+            break;
+        }
+        // the libraries loaded later
+        sampler.read_maps();
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
+    let profile = sampler.profile().expect("failed to read the samples");
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PPROF_OUTPUT.to_string());
+    write_profile(args, &profile, &output);
+}
+
+#[cfg(not(feature = "bpf"))]
+fn record_bpf(_args: &Args, _binary_path: &str) {
+    eprintln!("built without the bpf feature; `cargo install perf-tools --features bpf`");
+    std::process::exit(1);
+}
+
+// the arguments of the libtest harness: the tests run one at a time so
+// that the markers of each are apart, and the benches run as benches
+fn harness_args(args: &Args) -> &'static [&'static str] {
+    if args.test.is_some() {
+        &["--test-threads=1"]
+    } else if args.bench.is_some() {
+        &["--bench"]
+    } else {
+        &[]
+    }
+}
+
+// xperf of the Windows Performance Toolkit records the kernel's sampled
+// profile events with stacks, and dumps them with the symbols resolved
+fn record_etw(args: &Args, binary_path: &str) {
+    let freq = args.frequency.unwrap_or(DEFAULT_RECORD_FREQ) as u64;
+    // in units of 100 nanoseconds, at least 1221 as xperf requires
+    let interval = (10_000_000 / freq.max(1)).max(1221);
+    let status = Command::new("xperf")
+        .args(["-on", "PROC_THREAD+LOADER+PROFILE", "-stackwalk", "Profile"])
+        .args(["-setprofint", &interval.to_string(), "cached"])
+        .status()
+        .expect("failed to run `xperf`; install the Windows Performance Toolkit");
+    if !status.success() {
+        eprintln!("failed to start the ETW session; run as administrator");
+        std::process::exit(2);
+    }
+    let pid = Command::new(binary_path)
+        .args(harness_args(args))
+        .spawn()
+        .and_then(|mut child| {
+            let pid = child.id();
+            child.wait().map(|_| pid)
+        });
+    let status = Command::new("xperf")
+        .args(["-d", ETW_TRACE_FILE])
+        .status()
+        .expect("failed to run `xperf -d`");
+    let pid = pid.unwrap_or_else(|e| panic!("failed to run {}: {}", binary_path, e));
+    if !status.success() {
+        eprintln!("failed to stop the ETW session");
+        std::process::exit(2);
+    }
+    let dump = Command::new("xperf")
+        .args([
+            "-i",
+            ETW_TRACE_FILE,
+            "-symbols",
+            "-a",
+            "dumper",
+            "-stacktrace",
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .expect("failed to run `xperf -i`");
+    let profile = perf_tools::etw::decode_xperf(&*dump.stdout, Some(pid))
+        .expect("failed to read the ETW trace");
+    write_recorded(args, &profile);
+}
+
+// Instruments' Time Profiler records the launched binary, and the samples
+// are exported as XML
+fn record_xctrace(args: &Args, binary_path: &str) {
+    let _ = std::fs::remove_dir_all(XCTRACE_TRACE_FILE);
+    let status = Command::new("xcrun")
+        .args(["xctrace", "record", "--template", "Time Profiler"])
+        .args([
+            "--output",
+            XCTRACE_TRACE_FILE,
+            "--launch",
+            "--",
+            binary_path,
+        ])
+        .status()
+        .expect("failed to run `xctrace`; install Xcode");
+    if !status.success() {
+        eprintln!("failed to record with xctrace");
+        std::process::exit(2);
+    }
+    let export = Command::new("xcrun")
+        .args([
+            "xctrace",
+            "export",
+            "--input",
+            XCTRACE_TRACE_FILE,
+            "--xpath",
+        ])
+        .arg(r#"/trace-toc/run[@number="1"]/data/table[@schema="time-profile"]"#)
+        .output()
+        .expect("failed to run `xctrace export`");
+    if !export.status.success() {
+        panic!("{}", String::from_utf8_lossy(&export.stderr));
+    }
+    let profile = perf_tools::xctrace::decode_time_profile(&*export.stdout)
+        .expect("failed to read the time profile");
+    write_recorded(args, &profile);
+}
+
+// the directory of the NDK's simpleperf binaries for the target triple
+fn android_abi(target: &str) -> &'static str {
+    match target.split('-').next() {
+        Some("aarch64") => "arm64",
+        Some("x86_64") => "x86_64",
+        Some("i686") => "x86",
+        _ => "arm",
+    }
+}
+
+// simpleperf of the NDK records on the device, and the host one reads the
+// samples back with the symbols of the binary before it's stripped
+fn record_android(args: &Args, serial: &str, binary_path: &str) {
+    let ndk = std::env::var("ANDROID_NDK_HOME").unwrap_or_else(|_| {
+        eprintln!("set ANDROID_NDK_HOME to the NDK, which has simpleperf");
+        std::process::exit(2);
+    });
+    let simpleperf = Path::new(&ndk).join("simpleperf/bin");
+    let adb = |adb_args: &[&str]| {
+        let status = Command::new("adb")
+            .args(["-s", serial])
+            .args(adb_args)
+            .status()
+            .expect("failed to run `adb`");
+        if !status.success() {
+            eprintln!("`adb {}` failed", adb_args.join(" "));
+            std::process::exit(2);
+        }
+    };
+    let name = Path::new(binary_path)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    let device_binary = format!("{}/{}", ANDROID_DIR, name);
+    let device_simpleperf = format!("{}/simpleperf", ANDROID_DIR);
+    let device_data = format!("{}/{}", ANDROID_DIR, PERF_DATA_FILE);
+    let android = simpleperf
+        .join("android")
+        .join(android_abi(&args.android_target));
+    adb(&[
+        "push",
+        &android.join("simpleperf").to_string_lossy(),
+        &device_simpleperf,
+    ]);
+    adb(&["push", binary_path, &device_binary]);
+    adb(&["shell", "chmod", "755", &device_simpleperf, &device_binary]);
+    let freq = args.frequency.unwrap_or(DEFAULT_RECORD_FREQ).to_string();
+    adb(&[
+        "shell",
+        &device_simpleperf,
+        "record",
+        "-g",
+        "-f",
+        &freq,
+        "-o",
+        &device_data,
+        &device_binary,
+    ]);
+    adb(&["pull", &device_data, PERF_DATA_FILE]);
+
+    // the binary at the path on the device
+    let symfs = Path::new(ANDROID_SYMFS);
+    let dir = symfs.join(ANDROID_DIR.trim_start_matches('/'));
+    std::fs::create_dir_all(&dir).expect("failed to create the symfs directory");
+    std::fs::copy(binary_path, dir.join(&name)).expect("failed to copy the binary");
+
+    let host = if cfg!(target_os = "macos") {
+        "darwin"
+    } else if cfg!(windows) {
+        "windows"
+    } else {
+        "linux"
+    };
+    let report = Command::new(simpleperf.join(host).join("x86_64").join("simpleperf"))
+        .args(["report-sample", "--show-callchain", "-i", PERF_DATA_FILE])
+        .arg("--symfs")
+        .arg(symfs)
+        .output()
+        .expect("failed to run the host simpleperf");
+    if !report.status.success() {
+        panic!("{}", String::from_utf8_lossy(&report.stderr));
+    }
+    let profile = perf_tools::simpleperf::decode_report_sample(&*report.stdout)
+        .expect("failed to read the samples");
+    write_recorded(args, &profile);
+}
+
+// writes the profile recorded without perf as pprof, a flamegraph or a report
+fn write_recorded(args: &Args, profile: &pprof::pb::Profile) {
+    if let Some(path) = args.append_to.as_ref() {
+        merge::append_to(path, profile, args.compression)
+            .unwrap_or_else(|e| panic!("failed to append to {}: {}", path, e));
+        return;
+    }
+    if args.flamegraph {
+        let output = args
+            .output
+            .clone()
+            .unwrap_or_else(|| DEFAULT_FLAMEGRAPH_OUTPUT.to_string());
+        write_flamegraph(args, profile, &output);
+        return;
+    }
+    if args.report.is_some() {
+        let output = args
+            .output
+            .clone()
+            .unwrap_or_else(|| DEFAULT_REPORT_OUTPUT.to_string());
+        write_report(profile, &output);
+        return;
+    }
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PPROF_OUTPUT.to_string());
+    write_profile(args, profile, &output);
+}
+
+fn main() {
+    let Commands::Perf(args) = Cli::parse().command;
+
+    if let Some(Action::Selftest(selftest_args)) = &args.action {
+        if selftest_args.workload {
+            selftest::workload(std::time::Duration::from_secs(selftest_args.duration));
+        } else {
+            run_selftest(&args, selftest_args);
+        }
+        return;
+    }
+
+    if let Some(Action::Report(report_args)) = &args.action {
+        let mut profile = load_profile(&report_args.profile);
+        if report_args.cross_language {
+            profile = lang::normalize(&profile);
+        }
+        if report_args.tree {
+            print!(
+                "{}",
+                calltree::CallTree::new(&profile, report_args.unit.index(&profile)).render(
+                    report_args.depth,
+                    report_args.min_percent / 100.0,
+                    report_args.unit
+                )
+            );
+            return;
+        }
+        print!(
+            "{}",
+            flat::FlatProfile::new(&profile, report_args.unit.index(&profile)).render_top(
+                &flat::modules(&profile),
+                report_args.top,
+                report_args.cum,
+                report_args.unit
+            )
+        );
+        return;
+    }
+
+    if let Some(Action::Ffi(report_args)) = &args.action {
+        let profile = load_profile(&report_args.profile);
+        print!("{}", ffi::Ffi::new(&profile, 0).render(report_args.lines));
+        return;
+    }
+
+    if let Some(Action::Primitives(report_args)) = &args.action {
+        let profile = load_profile(&report_args.profile);
+        print!(
+            "{}",
+            primitives::Primitives::new(&profile, 0).render(report_args.lines)
+        );
+        return;
+    }
+
+    if let Some(Action::Query(query_args)) = &args.action {
+        let query = query::parse(&query_args.query).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+        match query.run(&load_profile(&query_args.profile)) {
+            Ok((header, rows)) => print!("{}", query::render(&header, &rows)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
+    if let Some(Action::Filter(filter_args)) = &args.action {
+        let regex = |pattern: &String| {
+            regex::Regex::new(pattern).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            })
+        };
+        let profile = pprof::filter(
+            &load_profile(&filter_args.profile),
+            filter_args.focus.as_ref().map(regex).as_ref(),
+            filter_args.ignore.as_ref().map(regex).as_ref(),
+        );
+        write_profile(&args, &profile, &filter_args.output);
+        return;
+    }
+
+    if let Some(Action::Subtract(subtract_args)) = &args.action {
+        let profile = subtract::subtract(
+            &load_profile(&subtract_args.profile),
+            &load_profile(&subtract_args.baseline),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+        write_profile(&args, &profile, &subtract_args.output);
+        return;
+    }
+
+    if let Some(Action::Fleet(fleet_args)) = &args.action {
+        let profiles: Vec<(String, pprof::pb::Profile)> = fleet_args
+            .profiles
+            .iter()
+            .map(|p| match p.split_once('=') {
+                Some((service, path)) => (service.to_string(), load_profile(path)),
+                None => {
+                    let stem = Path::new(p).file_stem().unwrap_or_default();
+                    (stem.to_string_lossy().to_string(), load_profile(p))
+                }
+            })
+            .collect();
+        let profiles: Vec<(&str, &pprof::pb::Profile)> =
+            profiles.iter().map(|(s, p)| (s.as_str(), p)).collect();
+        let profile = fleet::merge(&profiles).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+        write_profile(&args, &profile, &fleet_args.output);
+        return;
+    }
+
+    if let Some(Action::Snapshot(snapshot_args)) = &args.action {
+        let capture = || {
+            snapshot::capture(snapshot_args.pid).unwrap_or_else(|e| {
+                eprintln!("failed to read the process {}: {}", snapshot_args.pid, e);
+                std::process::exit(2);
+            })
+        };
+        if snapshot_args.count > 1 {
+            let interval = std::time::Duration::from_millis(snapshot_args.interval_ms);
+            let mut snapshots = vec![capture()];
+            for _ in 1..snapshot_args.count {
+                std::thread::sleep(interval);
+                snapshots.push(capture());
+            }
+            let window = interval * (snapshot_args.count - 1);
+            let stuck = snapshot::unchanged(&snapshots);
+            if snapshot_args.format == "pprof" {
+                write_profile(
+                    &args,
+                    &snapshot::stuck_profile(&stuck, window),
+                    &snapshot_args.output,
+                );
+            } else {
+                println!(
+                    "{} of {} threads didn't move for {:.1}s\n",
+                    stuck.len(),
+                    snapshots.last().unwrap().len(),
+                    window.as_secs_f64()
+                );
+                print!("{}", snapshot::to_text(stuck));
+            }
+            return;
+        }
+        let threads = capture();
+        if snapshot_args.format == "pprof" {
+            write_profile(
+                &args,
+                &snapshot::to_profile(&threads),
+                &snapshot_args.output,
+            );
+        } else {
+            print!("{}", snapshot::to_text(&threads));
+        }
+        return;
+    }
+
+    if let Some(Action::Exemplar(exemplar_args)) = &args.action {
+        let content = std::fs::read(&exemplar_args.profile)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", exemplar_args.profile, e));
+        let profile = pprof::decode(&*content).unwrap();
+        let exemplar = exemplar::exemplar(&profile, &content);
+        if exemplar_args.format == "json" {
+            println!("{}", serde_json::to_string(&exemplar).unwrap());
+        } else {
+            let labels: Vec<(String, String)> = exemplar_args
+                .label
+                .iter()
+                .map(|l| match l.split_once('=') {
+                    Some((k, v)) => (k.to_string(), v.to_string()),
+                    None => {
+                        eprintln!("label must be key=value: {}", l);
+                        std::process::exit(2);
+                    }
+                })
+                .collect();
+            println!(
+                "{}",
+                exemplar.to_openmetrics(&exemplar_args.metric, &labels)
+            );
+        }
+        return;
+    }
+
+    if let Some(Action::Export(export_args)) = &args.action {
+        let profile = load_profile(&export_args.profile);
+        let mut t = if export_args.by == "function" {
+            table::Table::functions(&profile)
+        } else {
+            table::Table::stacks(&profile)
+        };
+        if export_args.unit != unit::Unit::Nanoseconds {
+            t.time_unit(&profile, export_args.unit);
+        }
+        match (export_args.format.as_str(), export_args.output.as_ref()) {
+            ("parquet", Some(output)) => {
+                let f = std::fs::File::create(output).expect("failed to create output file");
+                t.write_parquet(f).unwrap();
+            }
+            ("parquet", None) => panic!("parquet needs --output"),
+            (_, Some(output)) => {
+                std::fs::write(output, t.to_csv()).expect("failed to write the table")
+            }
+            (_, None) => print!("{}", t.to_csv()),
+        }
+        return;
+    }
+
+    if let Some(Action::Callgraph(callgraph_args)) = &args.action {
+        let profile = load_profile(&callgraph_args.profile);
+        let graph = callgraph::CallGraph::new(&profile, 0);
+        if callgraph_args.format == "callgrind" {
+            let event = profile
+                .sample_type
+                .first()
+                .and_then(|t| profile.string_table.get(t.r#type as usize))
+                .map_or("samples", |s| s.as_str());
+            print!("{}", graph.to_callgrind(event));
+            return;
+        }
+        let graph = graph.prune(callgraph_args.node_fraction, callgraph_args.edge_fraction);
+        match callgraph_args.format.as_str() {
+            "dot" => print!("{}", graph.to_dot()),
+            "mermaid" => print!("{}", graph.to_mermaid()),
+            _ => print!("{}", graph.to_csv()),
+        }
+        return;
+    }
+
+    if let Some(Action::Inline(inline_args)) = &args.action {
+        let sizes = inline::function_sizes(&inline_args.binary)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", inline_args.binary, e));
+        let suggestions = inline::suggest(
+            &load_profile(&inline_args.profile),
+            &sizes,
+            &inline::Thresholds::default(),
+        );
+        print!("{}", inline::render(&suggestions, inline_args.lines));
+        return;
+    }
+
+    if let Some(Action::Bounds(bounds_args)) = &args.action {
+        let mut cmd = Command::new("perf");
+        cmd.args([
+            "annotate",
+            "--stdio",
+            "--no-source",
+            "-i",
+            &bounds_args.input,
+        ]);
+        if let Some(symfs) = args.symfs.as_ref() {
+            cmd.arg("--symfs").arg(symfs);
+        }
+        let output = cmd.output().expect("failed to execute perf");
+        if !output.status.success() {
+            panic!("{}", String::from_utf8(output.stderr).unwrap());
+        }
+        let checks = annotate::bounds_checks(BufReader::new(&*output.stdout)).unwrap();
+        print!("{}", annotate::render(&checks, bounds_args.lines));
+        return;
+    }
+
+    if let Some(Action::Allocs(allocs_args)) = &args.action {
+        let profile = load_profile(&allocs_args.profile);
+        let (total, paths) = primitives::alloc_paths(&profile, 0, allocs_args.depth);
+        print!(
+            "{}",
+            primitives::render_alloc_paths(total, &paths, allocs_args.lines, &allocs_args.profile)
+        );
+        return;
+    }
+
+    if let Some(Action::Dso(dso_args)) = &args.action {
+        let mut cmd = Command::new("perf");
+        cmd.args(["script", "-i", &dso_args.input]);
+        if let Some(symfs) = args.symfs.as_ref() {
+            cmd.arg("--symfs").arg(symfs);
+        }
+        let script_output = cmd.output().expect("failed to execute perf");
+        if !script_output.status.success() {
+            panic!("{}", String::from_utf8(script_output.stderr).unwrap());
+        }
+        let mut d = dso::Dsos::default();
+        d.update(BufReader::new(&*script_output.stdout)).unwrap();
+        if dso_args.csv {
+            print!("{}", d.to_csv());
+        } else {
+            print!("{} samples\n\n{}", d.total(), d.render());
+        }
+        return;
+    }
+
+    if let Some(Action::Timeline(timeline_args)) = &args.action {
+        let script_output = Command::new("perf")
+            .args(["script", "--header", "-i", &timeline_args.input])
+            .output()
+            .expect("failed to execute perf");
+        if !script_output.status.success() {
+            panic!("{}", String::from_utf8(script_output.stderr).unwrap());
+        }
+        let mut t = timeline::Timeline::new(timeline_args.interval * 1000);
+        t.update(BufReader::new(&*script_output.stdout)).unwrap();
+        std::fs::write(&timeline_args.output, t.chrome_trace().unwrap())
+            .expect("failed to write the timeline");
+        return;
+    }
+
+    if let Some(Action::Parallel(parallel_args)) = &args.action {
+        let mut cmd = Command::new("perf");
+        cmd.args(["script", "-i", &parallel_args.input]);
+        if let Some(symfs) = args.symfs.as_ref() {
+            cmd.arg("--symfs").arg(symfs);
+        }
+        let script_output = cmd.output().expect("failed to execute perf");
+        if !script_output.status.success() {
+            panic!("{}", String::from_utf8(script_output.stderr).unwrap());
+        }
+        let mut p = parallel::Parallel::default();
+        p.update(BufReader::new(&*script_output.stdout)).unwrap();
+        print!("{}", p.render(&parallel_args.serial, parallel_args.lines));
+        return;
+    }
+
+    if let Some(Action::Trend(trend_args)) = &args.action {
+        let t = trend::Trend::load(&trend_args.dir, trend_args.lines).unwrap();
+        if trend_args.format == "json" {
+            println!("{}", t.to_json().unwrap());
+        } else {
+            print!("{}", t.to_csv());
+        }
+        if let Some(chart) = trend_args.chart.as_ref() {
+            std::fs::write(chart, t.to_svg()).expect("failed to write the chart");
+        }
+        return;
+    }
+
+    if let Some(Action::Check(check_args)) = &args.action {
+        let budgets = std::fs::read_to_string(&check_args.budgets)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", check_args.budgets, e));
+        let budgets = budget::Budgets::parse(&budgets).unwrap();
+        let profile = load_profile(&check_args.profile);
+        let verdicts = budgets.check(&profile, 0);
+        print!("{}", budget::render(&verdicts));
+        if let Some(path) = check_args.html.as_ref() {
+            let title = format!("{} against {}", check_args.profile, check_args.budgets);
+            let report = html::check_report(&title, &profile, &verdicts).unwrap();
+            std::fs::write(path, report).expect("failed to write the report");
+        }
+        if !verdicts.iter().all(|v| v.passed()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Action::Diff(diff_args)) = &args.action {
+        let mut base = load_profile(&diff_args.base);
+        let mut new = load_profile(&diff_args.new);
+        if diff_args.cross_language {
+            base = lang::normalize(&base);
+            new = lang::normalize(&new);
+        }
+        let changes = diff::diff(
+            &flat::FlatProfile::new(&base, 0),
+            &flat::FlatProfile::new(&new, 0),
+            !diff_args.exact,
+        );
+        if let (Some(b), Some(n)) = (sidecar::stdout_hash(&base), sidecar::stdout_hash(&new)) {
+            if b != n {
+                eprintln!(
+                    "warning: the runs produced different outputs; the profiles might not be of the same work"
+                );
+            }
+        }
+        print!("{}", diff::render(&changes, diff_args.lines));
+        if let Some(warning) = diff::noise_warning(&changes, diff_args.lines) {
+            eprintln!("warning: {}", warning);
+        }
+        if let Some(path) = diff_args.html.as_ref() {
+            let title = format!("{} vs {}", diff_args.new, diff_args.base);
+            let report = html::diff_report(&title, &base, &new, &changes, diff_args.lines).unwrap();
+            std::fs::write(path, report).expect("failed to write the report");
+        }
+        if let Some(path) = diff_args.delta.as_ref() {
+            let delta = diff::delta(&base, &new).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            });
+            write_profile(&args, &delta, path);
+        }
+        return;
+    }
+
+    let binary_path = match args.binary.as_ref() {
+        Some(path) => {
+            if !args.allow_debug && opt_level(path).as_deref() == Some("0") {
+                refuse_debug_build(path);
+            }
+            path.clone()
+        }
+        None => {
+            let artifact = build_binary(&args).unwrap();
+            let a = find_binary(&args, &artifact).unwrap();
+            let path = a.executable.as_ref().unwrap().to_string();
+            if !args.allow_debug && a.profile.opt_level == "0" {
+                refuse_debug_build(&path);
+            }
+            path
+        }
+    };
+
+    if let Some(Action::Top(top_args)) = &args.action {
+        top(
+            &binary_path,
+            args.frequency.unwrap_or(DEFAULT_RECORD_FREQ),
+            args.symfs.as_deref(),
+            top_args,
+        );
+        return;
+    }
+
+    if let Some(Action::Calibrate(calibrate_args)) = &args.action {
+        calibrate(&binary_path, calibrate_args);
+        return;
+    }
+
+    if args.bpf {
+        record_bpf(&args, &binary_path);
+        return;
+    }
+
+    if let Some(serial) = args.android.as_ref() {
+        record_android(&args, serial, &binary_path);
+        return;
+    }
+
+    if args.xctrace || cfg!(target_os = "macos") {
+        record_xctrace(&args, &binary_path);
+        return;
+    }
+
+    if args.etw || cfg!(windows) {
+        record_etw(&args, &binary_path);
+        return;
+    }
+
+    if args.stream {
+        record_stream(&args, &binary_path);
+        return;
+    }
+
+    // panics and syscall errors are recorded only when they happen
+    if !args.no_space_check && !args.panics && !args.syscall_errors {
+        check_space(&args, args.frequency.unwrap_or(DEFAULT_RECORD_FREQ));
+    }
+
+    if args.kallsyms {
+        sidecar::snapshot_kallsyms(PERF_DATA_FILE).expect("failed to save kallsyms");
+    }
+
+    let mut cmd = Command::new("perf");
+    cmd.args(["record", "--call-graph", "dwarf", "-g"]);
+    if args.panics {
+        for event in add_panic_probes(&binary_path).unwrap() {
+            cmd.arg("-e");
+            cmd.arg(event);
+        }
+    } else if args.syscall_errors {
+        cmd.args(["-e", pprof::SYSCALL_EXIT_EVENT, "--filter", "ret < 0"]);
+    } else {
+        cmd.arg("-F");
+        cmd.arg(format!("{}", args.frequency.unwrap_or(DEFAULT_RECORD_FREQ)));
+    }
+    let per_test = args.test.is_some() || args.bench.is_some();
+    if args.interactive || args.markers || per_test {
+        // markers are taken with the wall clock
+        cmd.args(["-k", "realtime"]);
+    }
+    if args.markers {
+        let _ = std::fs::remove_file(MARKERS_FILE);
+        cmd.env(marker::MARKERS_ENV, MARKERS_FILE);
+    }
+    if args.interactive {
+        cmd.arg("--control");
+        cmd.arg(format!("fifo:{}", CONTROL_FIFO));
+        cmd.stdin(Stdio::null());
+        let _ = std::fs::remove_file(CONTROL_FIFO);
+        let status = Command::new("mkfifo")
+            .arg(CONTROL_FIFO)
+            .status()
+            .expect("failed to run `mkfifo`");
+        if !status.success() {
+            panic!("failed to create {}", CONTROL_FIFO);
+        }
+    }
+    if args.compress {
+        cmd.arg("-z");
+    }
+    if let Some(pages) = args.mmap_pages.as_ref() {
+        cmd.arg("--mmap-pages");
+        cmd.arg(pages);
+    }
+    if let Some(aio) = args.aio {
+        cmd.arg(format!("--aio={}", aio));
+    }
+    cmd.args(["-o", PERF_DATA_FILE]);
+    cmd.arg(binary_path);
+    cmd.args(harness_args(&args));
+    if let Some(seed) = args.seed {
+        cmd.env(sidecar::SEED_ENV, seed.to_string());
+    }
+    if per_test || args.seed.is_some() {
+        cmd.stdout(Stdio::piped());
+    }
+    let mut child = cmd
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to run {:?}", e));
+    let markers = if args.interactive {
+        interact()
+    } else {
+        Markers::default()
+    };
+    if per_test {
+        let tests = watch_tests(child.stdout.take().unwrap());
+        markers.lock().unwrap().extend(tests);
+    }
+    let stdout_hash = if args.seed.is_some() {
+        Some(hash_stdout(child.stdout.take().unwrap()))
+    } else {
+        None
+    };
+    let output = child.wait_with_output();
+    // the uprobes stay installed until deleted, even if perf failed
+    if args.panics {
+        delete_probes();
+    }
+    let output = output.expect("failed to wait for `perf record`");
+    if output.status.success() {
+        println!("{}", String::from_utf8(output.stdout).unwrap());
+    } else {
+        panic!("{}", String::from_utf8(output.stderr).unwrap());
+    }
+    if args.interactive {
+        let _ = std::fs::remove_file(CONTROL_FIFO);
+    }
+    if args.markers {
+        // the binary might not call marker() at all
+        if let Ok(f) = std::fs::File::open(MARKERS_FILE) {
+            markers
+                .lock()
+                .unwrap()
+                .extend(marker::read_markers(BufReader::new(f)).unwrap());
+        }
+    }
+
+    let mut meta = sidecar::Meta::collect(if args.binary.is_some() {
+        Vec::new()
+    } else {
+        cargo_build_args(&args)
+    });
+    meta.markers = markers.lock().unwrap().clone();
+    meta.seed = args.seed;
+    meta.stdout_hash = stdout_hash.map(|h| format!("{:016x}", h));
+    meta.write(PERF_DATA_FILE)
+        .expect("failed to write the metadata of the recording");
+
+    let mut cmd = Command::new("perf");
+    cmd.arg("script").arg("--header").arg("--show-lost-events");
+    if args.tenants.is_some() {
+        // the tenants are mapped by the pid, not the tid printed by default
+        cmd.args(["-F", "+pid"]);
+    }
+    if let Some(symfs) = args.symfs.as_ref() {
+        cmd.arg("--symfs").arg(symfs);
+    }
+    if args.kallsyms {
+        cmd.arg("--kallsyms")
+            .arg(sidecar::kallsyms_path(PERF_DATA_FILE));
+    }
+    let script_output = cmd.output().expect("failed to execute perf");
+    if !script_output.status.success() {
+        panic!("{}", String::from_utf8(script_output.stderr).unwrap());
+    }
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        if args.flamegraph {
+            DEFAULT_FLAMEGRAPH_OUTPUT.to_string()
+        } else if args.report.is_some() {
+            DEFAULT_REPORT_OUTPUT.to_string()
+        } else if args.panics {
+            DEFAULT_PANIC_OUTPUT.to_string()
+        } else if args.syscall_errors {
+            DEFAULT_SYSCALL_OUTPUT.to_string()
+        } else {
+            DEFAULT_PPROF_OUTPUT.to_string()
+        }
+    });
+    if args.flamegraph {
+        let profile = convert_script(&args, BufReader::new(&*script_output.stdout), &meta, None);
+        write_flamegraph(&args, &profile, &output);
+    } else if args.report.is_some() {
+        let profile = convert_script(&args, BufReader::new(&*script_output.stdout), &meta, None);
+        write_report(&profile, &output);
+    } else {
+        write_pprof(
+            &args,
+            BufReader::new(&*script_output.stdout),
+            &meta,
+            None,
+            &output,
+        );
+        // the per-test profiles would count the samples twice in the aggregate
+        if per_test && args.append_to.is_none() {
+            let stem = output.strip_suffix(".pprof").unwrap_or(&output);
+            let mut done = std::collections::HashSet::new();
+            for (_, name) in meta.markers.iter() {
+                if done.insert(name) {
+                    let file = name.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+                    let output = format!("{}-{}.pprof", stem, file);
+                    write_pprof(
+                        &args,
+                        BufReader::new(&*script_output.stdout),
+                        &meta,
+                        Some(name),
+                        &output,
+                    );
+                    println!("{}: {}", name, output);
+                }
+            }
+        }
     }
 }
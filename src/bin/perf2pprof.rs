@@ -14,50 +14,751 @@
 // limitations under the License.
 
 use clap::Parser;
-use std::process::Command;
+use prost::Message;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
 
-use perf_tools::pprof;
+use perf_tools::{
+    buildid, date, firefox, grouping, inspect, itm, jitdump, jsonl, lang, latency, marker, merge,
+    pcdump, perfdata, pprof, python, sidecar, speedscope, tenant, trace, wasm,
+};
 
 /// convert perf to pprof format
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// input file name
+    #[clap(subcommand)]
+    action: Option<Action>,
+
+    /// input file name; async-profiler's collapsed (`.collapsed`, `.folded`) and JFR (`.jfr`),
+    /// py-spy's raw (`.folded`) and speedscope (`.speedscope.json`), and austin's (`.austin`)
+    /// outputs, ITM PC samples of Cortex-M (`.itm`, `.swo`), PC dumps from gdbstub or
+    /// JTAG (`.pcs`), and wasmtime's guest profiles (other `.json`) are converted too
     #[clap(short, long, default_value = "perf.data")]
     input: String,
 
-    /// output file name
+    /// print the pprof profile given with `-i` as JSON, with the strings resolved, instead of
+    /// converting
+    #[clap(long)]
+    inspect: bool,
+
+    /// output file name, `-` for stdout
     #[clap(short, long, default_value = "cpu.pprof")]
     output: String,
+
+    /// `jsonl` streams one JSON object per sample instead, `folded` writes the collapsed
+    /// stacks for flamegraph tools, weighted by the last sample type, and `trace` writes a
+    /// Chrome trace of the samples over time for chrome://tracing or ui.perfetto.dev, and
+    /// `firefox` the processed profile of the Firefox Profiler for profiler.firefox.com, and
+    /// `speedscope` a flame chart per thread in time order for www.speedscope.app
+    #[clap(long, default_value = "pprof", possible_values = &["pprof", "jsonl", "folded", "trace", "firefox", "speedscope"])]
+    format: String,
+
+    /// `gzip`, `zstd` or `none`, with the level like `zstd:19`; zstd is smaller and faster to
+    /// archive, but pprof reads gzip and uncompressed profiles only; also after `merge`
+    #[clap(long, default_value = "gzip", global = true)]
+    compression: pprof::Compression,
+
+    /// merge into the profile file instead of writing the output, creating it if missing,
+    /// to keep a rolling aggregate
+    #[clap(long)]
+    append_to: Option<String>,
+
+    /// markers file written by `perf_tools::marker()`
+    #[clap(long)]
+    markers: Option<String>,
+
+    /// request log, `<start> <end> <latency>` per line in microseconds since the epoch and
+    /// milliseconds, to write the profiles during slow and fast requests too, like
+    /// cpu.slow.pprof and cpu.fast.pprof; record with `-k realtime`
+    #[clap(long)]
+    requests: Option<String>,
+
+    /// latency in milliseconds from which requests are slow; defaults to the 99th percentile
+    #[clap(long, requires = "requests")]
+    slow_ms: Option<f64>,
+
+    /// read perf.data without perf, the default if perf isn't installed; the stacks
+    /// need to be recorded with frame pointers (`perf record -g`)
+    #[clap(long)]
+    native: bool,
+
+    /// convert even if binaries on disk differ from the recorded ones
+    #[clap(long)]
+    ignore_build_id: bool,
+
+    /// directory to look for binaries in, like a container image or the root filesystem of
+    /// the recording host (`perf script --symfs`)
+    #[clap(long, alias = "sysroot")]
+    symfs: Option<String>,
+
+    /// kallsyms file for kernel symbols; defaults to the snapshot next to the input if any
+    #[clap(long)]
+    kallsyms: Option<String>,
+
+    /// resolve the file names and the line numbers from the DWARF info of the binaries
+    #[clap(long)]
+    lines: bool,
+
+    /// keep the hostname, the perf version, the command line and the events of the recording
+    /// as comments of the profile
+    #[clap(long)]
+    metadata: bool,
+
+    /// demangle the Rust and C++ symbols left mangled, from libraries perf can't demangle
+    #[clap(long)]
+    demangle: bool,
+
+    /// collapse the plumbing frames of a runtime into one frame
+    #[clap(long, possible_values = &grouping::PRESETS)]
+    preset: Vec<String>,
+
+    /// TOML file of rules to collapse, hide or highlight frames
+    #[clap(long)]
+    grouping: Option<String>,
+
+    /// regex of the frames pprof drops with the frames they call, like the allocator's
+    /// `malloc|__rust_alloc` to charge their time to the callers; for root frames like
+    /// `__libc_start_main`, which would take whole stacks with them, use `--prune`
+    #[clap(long)]
+    drop_frames: Option<String>,
+
+    /// regex of the frames pprof keeps even if they match `--drop-frames`
+    #[clap(long)]
+    keep_frames: Option<String>,
+
+    /// remove the frames matching `--drop-frames` while converting instead, leaving the
+    /// frames they call
+    #[clap(long, requires = "drop-frames")]
+    prune: bool,
+
+    /// regex of the functions to focus on; only the samples with a frame matching it are kept
+    #[clap(long)]
+    focus: Option<String>,
+
+    /// regex of the functions to ignore; the samples with a frame matching it are dropped
+    #[clap(long)]
+    ignore: Option<String>,
+
+    /// TOML file mapping pids and cgroups to tenants, to label the samples with `tenant`
+    #[clap(long)]
+    tenants: Option<String>,
+
+    /// sample type pprof shows by default, `samples` for the counts or `cpu` for the time
+    #[clap(long)]
+    default_sample_type: Option<String>,
+
+    /// reattach the stacks that dwarf unwinding cut short to their most likely callers, seen in
+    /// the complete stacks, labeled `repaired`
+    #[clap(long)]
+    repair_stacks: bool,
+
+    /// `drop` the samples of idle cpus in system-wide recordings, or `collapse` them into a
+    /// single `[idle]` frame; interrupts served while idle are kept either way
+    #[clap(long, default_value = "keep", possible_values = &["keep", "drop", "collapse"])]
+    idle: pprof::Idle,
+
+    /// jitdump file of a JIT compiler, `jit-<pid>.dump`, to name the generated code without
+    /// `perf inject --jit`; those next to the input are read by default
+    #[clap(long)]
+    jitdump: Vec<String>,
+
+    /// write a profile per event recorded, like cpu.cycles.pprof and
+    /// cpu.cache-misses.pprof, instead of a profile with a value per event
+    #[clap(long, conflicts_with = "append-to")]
+    split_events: bool,
+
+    /// the ELF of the firmware of an ITM capture, or of the kernel of a PC dump
+    #[clap(long)]
+    elf: Option<String>,
+
+    /// time of the recording in ISO 8601, like `2022-03-10T10:45:19+09:00`, in place of the
+    /// `captured on` of the header
+    #[clap(long)]
+    captured_on: Option<String>,
+
+    /// fail on a line of perf script that can't be parsed, naming it, instead of skipping it
+    #[clap(long)]
+    strict: bool,
+
+    /// convert a long recording in this many slices of its time, one `perf script` at a time,
+    /// saving each to `--checkpoint-dir`, so that an interrupted conversion resumes from the
+    /// slices done when run again; perf reads the whole perf.data for each slice, so N chunks
+    /// take N passes over it
+    #[clap(long, default_value = "1")]
+    chunks: u32,
+
+    /// directory of the slices converted with `--chunks`, `<output>.chunks` by default; it's
+    /// removed once the output is written
+    #[clap(long)]
+    checkpoint_dir: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+    /// sum up profiles of repeated runs or of many hosts into one
+    Merge(MergeArgs),
+}
+
+#[derive(Parser, Debug)]
+struct MergeArgs {
+    /// pprof profiles with the same sample types, gzipped or not
+    #[clap(required = true, min_values = 2)]
+    profiles: Vec<String>,
+
+    /// output file name
+    #[clap(short, long, default_value = "merged.pprof")]
+    output: String,
+
+    /// match functions across languages by type and method names, to merge
+    /// Go or Python profiles with Rust ones
+    #[clap(long)]
+    cross_language: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+fn recorded_arch(input: &str) -> Option<String> {
+    let output = Command::new("perf")
+        .args(["script", "--header-only", "-i", input])
+        .output()
+        .ok()?;
+    // # arch : aarch64
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|l| l.strip_prefix("# arch :").map(|a| a.trim().to_string()))
+}
+
+// `uname -m` to Rust's names
+fn normalize_arch(arch: &str) -> &str {
+    match arch {
+        "ppc64le" | "ppc64" => "powerpc64",
+        "i386" | "i686" => "x86",
+        a if a.starts_with("armv") => "arm",
+        a => a,
+    }
+}
 
+fn verify_build_ids(input: &str, root: Option<&Path>) {
     let output = Command::new("perf")
-        .arg("script")
-        .arg("--header")
+        .arg("buildid-list")
         .arg("-i")
-        .arg(&args.input)
+        .arg(input)
         .output()
         .expect("failed to execute perf");
-
+    // recorded with --no-buildid
     if !output.status.success() {
-        panic!("{}", String::from_utf8(output.stderr).unwrap());
+        return;
+    }
+
+    let list = buildid::parse_list(&*output.stdout).unwrap();
+    let mismatches = buildid::mismatches(&list, root);
+    if mismatches.is_empty() {
+        return;
+    }
+    for m in mismatches {
+        eprintln!(
+            "{}: recorded build-id {}, but found {}",
+            m.path,
+            m.recorded,
+            m.found.as_deref().unwrap_or("none")
+        );
+    }
+    eprintln!(
+        "The binaries were rebuilt after recording so the symbols would be wrong. \
+        Restore them, add them to the build-id cache with `perf buildid-cache`, \
+        or pass `--ignore-build-id`."
+    );
+    std::process::exit(1);
+}
+
+const FOREIGN_INPUTS: [&str; 9] = [
+    ".collapsed",
+    ".folded",
+    ".jfr",
+    ".speedscope.json",
+    ".austin",
+    ".itm",
+    ".swo",
+    ".pcs",
+    ".json",
+];
+
+// converts the output of other profilers, for JVM and Python services
+fn convert_foreign(args: &Args) {
+    let read = || {
+        std::fs::read(&args.input)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", args.input, e))
+    };
+    let profile = if args.input.ends_with(".speedscope.json") {
+        python::decode_speedscope(&*read()).unwrap()
+    } else if args.input.ends_with(".json") {
+        wasm::decode_guest_profile(&*read()).unwrap()
+    } else if args.input.ends_with(".austin") {
+        python::decode_austin(&*read()).unwrap()
+    } else if [".itm", ".swo", ".pcs"]
+        .iter()
+        .any(|ext| args.input.ends_with(ext))
+    {
+        let elf = args.elf.as_ref().unwrap_or_else(|| {
+            eprintln!("pass the ELF of the firmware or the kernel with `--elf` for the symbols");
+            std::process::exit(2);
+        });
+        if args.input.ends_with(".pcs") {
+            pcdump::decode_pc_dump(&*read(), elf).unwrap()
+        } else {
+            itm::decode_itm(&read(), elf).unwrap()
+        }
+    } else if args.input.ends_with(".jfr") {
+        let path =
+            std::env::temp_dir().join(format!("perf2pprof-{}.collapsed", std::process::id()));
+        let status = Command::new("jfrconv")
+            .args(["-o", "collapsed", &args.input])
+            .arg(&path)
+            .status()
+            .expect("failed to execute jfrconv of async-profiler");
+        if !status.success() {
+            std::process::exit(1);
+        }
+        let collapsed = std::fs::read(&path).expect("failed to read jfrconv output");
+        let _ = std::fs::remove_file(&path);
+        pprof::decode_folded(&*collapsed).unwrap()
+    } else {
+        pprof::decode_folded(&*read()).unwrap()
+    };
+    let profile = if args.focus.is_some() || args.ignore.is_some() {
+        let focus = args.focus.as_deref().map(compile_regex);
+        let ignore = args.ignore.as_deref().map(compile_regex);
+        pprof::filter(&profile, focus.as_ref(), ignore.as_ref())
+    } else {
+        profile
+    };
+
+    if args.format == "folded" {
+        write_folded(args, &profile);
+        return;
+    }
+    let mut content = Vec::new();
+    profile.encode(&mut content).unwrap();
+    write_compressed(args, &args.output, &content);
+}
+
+// a regex of the command line, exiting on errors
+fn compile_regex(pattern: &str) -> regex::Regex {
+    regex::Regex::new(pattern).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    })
+}
+
+fn write_folded(args: &Args, profile: &pprof::pb::Profile) {
+    let writer: Box<dyn Write> = if args.output == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        Box::new(std::fs::File::create(&args.output).unwrap())
+    };
+    let index = profile.sample_type.len().saturating_sub(1);
+    pprof::write_folded(profile, index, std::io::BufWriter::new(writer)).unwrap();
+}
+
+fn write_compressed(args: &Args, path: &str, content: &[u8]) {
+    let compressed = pprof::compress(content, args.compression).expect("compression failed");
+    std::fs::write(path, compressed).unwrap_or_else(|e| panic!("failed to write {}: {}", path, e));
+}
+
+fn perf_script(args: &Args) -> Command {
+    let mut cmd = Command::new("perf");
+    cmd.arg("script")
+        .arg("--header")
+        .arg("--show-lost-events")
+        .arg("-i")
+        .arg(&args.input);
+    if let Some(symfs) = args.symfs.as_ref() {
+        // perf unwinds foreign stacks if it's built with libunwind for the architecture
+        cmd.arg("--symfs").arg(symfs);
+    }
+    if args.lines {
+        cmd.arg("--show-mmap-events");
+    }
+    if args.tenants.is_some() {
+        // the tenants are mapped by the pid, not the tid printed by default
+        cmd.args(["-F", "+pid"]);
     }
+    if let Some(kallsyms) = args
+        .kallsyms
+        .clone()
+        .or_else(|| sidecar::find_kallsyms(&args.input))
+    {
+        cmd.arg("--kallsyms").arg(kallsyms);
+    }
+    cmd
+}
+
+fn checkpoint_dir(args: &Args) -> String {
+    args.checkpoint_dir
+        .clone()
+        .unwrap_or_else(|| format!("{}.chunks", args.output))
+}
 
-    let mut encoder =
-        libflate::gzip::Encoder::new(std::fs::File::create(args.output).unwrap()).unwrap();
-    pprof::PprofConverterBuilder::default()
-        .build()
-        .from_reader(
-            std::io::BufReader::with_capacity(4096, &*output.stdout),
-            &mut encoder,
-        )
+// converts the slices of the recording time not converted yet into the
+// checkpoint directory, and merges them all
+fn convert_chunks(args: &Args, builder: &mut pprof::PprofConverterBuilder) -> Vec<u8> {
+    let dir = Path::new(&checkpoint_dir(args)).to_path_buf();
+    // the slices of another recording or of another number of chunks are stale
+    let meta = std::fs::metadata(&args.input)
+        .unwrap_or_else(|e| panic!("failed to open {}: {}", args.input, e));
+    let source = format!(
+        "{} {} {:?} {}\n",
+        args.input,
+        meta.len(),
+        meta.modified().ok(),
+        args.chunks
+    );
+    let source_path = dir.join("source");
+    if std::fs::read_to_string(&source_path).ok().as_deref() != Some(source.as_str()) {
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .unwrap_or_else(|e| panic!("failed to remove {}: {}", dir.display(), e));
+        }
+        std::fs::create_dir_all(&dir)
+            .unwrap_or_else(|e| panic!("failed to create {}: {}", dir.display(), e));
+        std::fs::write(&source_path, &source).unwrap();
+    }
+
+    let slice = 100.0 / args.chunks as f64;
+    let mut profiles = Vec::new();
+    for i in 1..=args.chunks {
+        let path = dir.join(format!("{}.pprof", i));
+        if !path.exists() {
+            // `--time 25%/2` is the second quarter of the recording
+            let mut child = perf_script(args)
+                .arg("--time")
+                .arg(format!("{}%/{}", slice, i))
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("failed to execute perf");
+            let reader = std::io::BufReader::with_capacity(4096, child.stdout.take().unwrap());
+            let mut content = Vec::new();
+            let result = builder.build().from_reader(reader, &mut content);
+            if !child.wait().expect("failed to wait for perf").success() {
+                std::process::exit(1);
+            }
+            match result {
+                Ok(_) => {}
+                // the workload idled through the slice; an empty file stands
+                // for a slice without samples
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => content.clear(),
+                Err(e) => panic!("failed to convert chunk {}: {}", i, e),
+            }
+            // renamed so that a slice cut short isn't taken for done
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, &content)
+                .and_then(|_| std::fs::rename(&tmp, &path))
+                .unwrap_or_else(|e| panic!("failed to write {}: {}", path.display(), e));
+            eprintln!("converted {} of {} chunks", i, args.chunks);
+        }
+        let f = std::fs::File::open(&path)
+            .unwrap_or_else(|e| panic!("failed to open {}: {}", path.display(), e));
+        let profile = pprof::decode(std::io::BufReader::new(f))
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+        if !profile.sample.is_empty() {
+            profiles.push(profile);
+        }
+    }
+    let merged = merge::merge(&profiles.iter().collect::<Vec<_>>()).unwrap_or_else(|e| {
+        eprintln!("failed to merge the chunks in {}: {}", dir.display(), e);
+        std::process::exit(1);
+    });
+    let mut content = Vec::new();
+    merged.encode(&mut content).unwrap();
+    content
+}
+
+// what perf script would print, without perf
+fn native_script(args: &Args) -> Vec<u8> {
+    let perf = perfdata::PerfData::open(&args.input).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", args.input, e);
+        std::process::exit(1);
+    });
+    let kallsyms = args
+        .kallsyms
+        .clone()
+        .or_else(|| sidecar::find_kallsyms(&args.input))
+        .unwrap_or_else(|| "/proc/kallsyms".to_string());
+    let mut script = Vec::new();
+    perf.script(args.symfs.as_deref(), &kallsyms, args.lines, &mut script)
         .unwrap();
+    script
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Some(Action::Merge(merge_args)) = &args.action {
+        let profiles: Vec<pprof::pb::Profile> = merge_args
+            .profiles
+            .iter()
+            .map(|path| {
+                let f = std::fs::File::open(path)
+                    .unwrap_or_else(|e| panic!("failed to open {}: {}", path, e));
+                let profile = pprof::decode(std::io::BufReader::new(f))
+                    .unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+                if merge_args.cross_language {
+                    lang::normalize(&profile)
+                } else {
+                    profile
+                }
+            })
+            .collect();
+        let merged = merge::merge(&profiles.iter().collect::<Vec<_>>()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+        let mut content = Vec::new();
+        merged.encode(&mut content).unwrap();
+        write_compressed(&args, &merge_args.output, &content);
+        return;
+    }
+
+    if args.inspect {
+        let f = std::fs::File::open(&args.input)
+            .unwrap_or_else(|e| panic!("failed to open {}: {}", args.input, e));
+        let profile = pprof::Profile::parse(std::io::BufReader::new(f))
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", args.input, e));
+        inspect::to_json(&profile, std::io::stdout().lock()).unwrap();
+        return;
+    }
+
+    if FOREIGN_INPUTS.iter().any(|ext| args.input.ends_with(ext)) {
+        convert_foreign(&args);
+        return;
+    }
 
-    encoder
-        .finish()
-        .into_result()
-        .expect("gzip encoding failed");
+    // minimal containers lack perf
+    let native = args.native || Command::new("perf").arg("--version").output().is_err();
+    if !native {
+        if let Some(arch) = recorded_arch(&args.input) {
+            if normalize_arch(&arch) != std::env::consts::ARCH && args.symfs.is_none() {
+                eprintln!(
+                    "{} was recorded on {}; pass the root filesystem of the recording host with `--symfs`",
+                    args.input, arch
+                );
+                std::process::exit(1);
+            }
+        }
+
+        if !args.ignore_build_id {
+            verify_build_ids(&args.input, args.symfs.as_deref().map(Path::new));
+        }
+    }
+
+    let mut builder = pprof::PprofConverterBuilder::default();
+    if let Some(meta) = sidecar::Meta::read(&args.input).expect("failed to read the metadata") {
+        for (time, name) in &meta.markers {
+            builder.marker(*time, name);
+        }
+        for comment in meta.comments() {
+            builder.comment(&comment);
+        }
+    }
+    builder.metadata(args.metadata);
+    builder.lines(args.lines);
+    builder.demangle(args.demangle);
+    builder.strict(args.strict);
+    let mut jitdumps = args.jitdump.clone();
+    if jitdumps.is_empty() {
+        let dir = Path::new(&args.input)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        jitdumps = jitdump::find(dir);
+    }
+    for path in &jitdumps {
+        match jitdump::JitDump::open(path) {
+            Ok(dump) => {
+                builder.jitdump(dump);
+            }
+            Err(e) => eprintln!("WARNING: failed to read {}: {}", path, e),
+        }
+    }
+    builder.strict_header(args.strict);
+    if let Some(captured_on) = args.captured_on.as_ref() {
+        builder.captured_on(date::parse_iso8601(captured_on).unwrap_or_else(|| {
+            eprintln!("`--captured-on` takes a time in ISO 8601: {}", captured_on);
+            std::process::exit(2);
+        }));
+    }
+    if let Some(symfs) = args.symfs.as_ref() {
+        builder.symfs(symfs);
+    }
+    for preset in &args.preset {
+        builder.grouping(&grouping::preset(preset).unwrap());
+    }
+    if let Some(path) = args.grouping.as_ref() {
+        let rules =
+            grouping::load(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+        builder.grouping(&rules);
+    }
+    if let Some(pattern) = args.drop_frames.as_ref() {
+        builder.drop_frames(&compile_regex(pattern));
+    }
+    if let Some(pattern) = args.keep_frames.as_ref() {
+        builder.keep_frames(&compile_regex(pattern));
+    }
+    builder.prune(args.prune);
+    if let Some(pattern) = args.focus.as_ref() {
+        builder.focus(&compile_regex(pattern));
+    }
+    if let Some(pattern) = args.ignore.as_ref() {
+        builder.ignore(&compile_regex(pattern));
+    }
+    builder.idle(args.idle);
+    builder.repair(args.repair_stacks);
+    if let Some(name) = args.default_sample_type.as_ref() {
+        builder.default_sample_type(name);
+    }
+    if let Some(path) = args.tenants.as_ref() {
+        builder.tenants(
+            tenant::Tenants::load(path)
+                .unwrap_or_else(|e| panic!("failed to read {}: {}", path, e)),
+        );
+    }
+    if let Some(markers) = args.markers.as_ref() {
+        let f = std::fs::File::open(markers).expect("failed to open markers file");
+        for (time, name) in marker::read_markers(std::io::BufReader::new(f)).unwrap() {
+            builder.marker(time, &name);
+        }
+    }
+
+    if let Some(path) = args.requests.as_ref() {
+        let f =
+            std::fs::File::open(path).unwrap_or_else(|e| panic!("failed to open {}: {}", path, e));
+        let requests = latency::read_requests(std::io::BufReader::new(f)).unwrap();
+        let slow = args
+            .slow_ms
+            .unwrap_or_else(|| latency::percentile(&requests, 99.0));
+        for r in &requests {
+            builder.request(r.start, r.end, r.latency >= slow);
+        }
+    }
+
+    // the samples in order, read like the profile's
+    if matches!(
+        args.format.as_str(),
+        "jsonl" | "trace" | "firefox" | "speedscope"
+    ) {
+        let writer: Box<dyn Write> = if args.output == "-" {
+            Box::new(std::io::stdout().lock())
+        } else {
+            Box::new(std::fs::File::create(&args.output).unwrap())
+        };
+        let convert = match args.format.as_str() {
+            "trace" => trace::convert::<&mut dyn std::io::BufRead, _>,
+            "firefox" => firefox::convert::<&mut dyn std::io::BufRead, _>,
+            "speedscope" => speedscope::convert::<&mut dyn std::io::BufRead, _>,
+            _ => jsonl::convert::<&mut dyn std::io::BufRead, _>,
+        };
+        if native {
+            convert(
+                &builder,
+                &mut &*native_script(&args),
+                std::io::BufWriter::new(writer),
+            )
+            .unwrap();
+            return;
+        }
+        let mut child = perf_script(&args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to execute perf");
+        let mut reader = std::io::BufReader::new(child.stdout.take().unwrap());
+        convert(&builder, &mut reader, std::io::BufWriter::new(writer)).unwrap();
+        if !child.wait().expect("failed to wait for perf").success() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut content = Vec::new();
+    if args.chunks > 1 {
+        if native {
+            eprintln!("`--chunks` needs perf to slice the recording");
+            std::process::exit(2);
+        }
+        content = convert_chunks(&args, &mut builder);
+    } else if native {
+        builder
+            .build()
+            .from_reader(
+                std::io::BufReader::with_capacity(4096, &*native_script(&args)),
+                &mut content,
+            )
+            .unwrap();
+    } else {
+        // the output is aggregated as it's read, so that the memory grows
+        // with the distinct stacks rather than with the samples
+        let mut child = perf_script(&args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to execute perf");
+        let reader = std::io::BufReader::with_capacity(4096, child.stdout.take().unwrap());
+        let result = builder.build().from_reader(reader, &mut content);
+        if !child.wait().expect("failed to wait for perf").success() {
+            std::process::exit(1);
+        }
+        result.unwrap();
+    }
+    let profile = pprof::decode(&*content).unwrap();
+    if let Some(lost) = pprof::lost_samples(&profile) {
+        eprintln!(
+            "WARNING: {}; record with a lower frequency or a larger buffer (`perf record -m`)",
+            lost
+        );
+    }
+    if args.format == "folded" {
+        write_folded(&args, &profile);
+        remove_checkpoints(&args);
+        return;
+    }
+    let split = if args.split_events {
+        pprof::split_events(&profile)
+    } else {
+        Vec::new()
+    };
+    match args.append_to.as_ref() {
+        Some(path) => merge::append_to(path, &profile, args.compression)
+            .unwrap_or_else(|e| panic!("failed to append to {}: {}", path, e)),
+        None if split.is_empty() => write_compressed(&args, &args.output, &content),
+        None => {
+            let stem = args.output.trim_end_matches(".pprof");
+            for (event, p) in split {
+                let event = event.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+                let mut content = Vec::new();
+                p.encode(&mut content).unwrap();
+                write_compressed(&args, &format!("{}.{}.pprof", stem, event), &content);
+            }
+        }
+    }
+
+    if args.requests.is_some() {
+        let stem = args.output.trim_end_matches(".pprof");
+        for l in ["slow", "fast"] {
+            let mut content = Vec::new();
+            latency::only(&profile, l).encode(&mut content).unwrap();
+            write_compressed(&args, &format!("{}.{}.pprof", stem, l), &content);
+        }
+    }
+    remove_checkpoints(&args);
+}
+
+fn remove_checkpoints(args: &Args) {
+    if args.chunks > 1 {
+        let dir = checkpoint_dir(args);
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            eprintln!("WARNING: failed to remove {}: {}", dir, e);
+        }
+    }
 }
@@ -0,0 +1,219 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A sampler without the perf binary: a BPF program attached to cpu-clock
+//! events counts the samples per stack in a map, and the counts are read
+//! when the recording ends. The stacks are unwound with frame pointers, so
+//! build with `-C force-frame-pointers=yes`.
+
+use libbpf_rs::skel::{OpenSkel, SkelBuilder};
+use libbpf_rs::{Link, MapFlags};
+use std::collections::HashMap;
+use std::io;
+
+use crate::pprof::{self, pb, SampleBuilder};
+use crate::symbolize::{self, Symbolizer};
+
+mod skel {
+    include!(concat!(env!("OUT_DIR"), "/profile.skel.rs"));
+}
+
+use skel::*;
+
+// the layout of `struct key` in profile.bpf.c
+const KEY_SIZE: usize = 32;
+const TASK_COMM_LEN: usize = 16;
+
+// linux/perf_event.h
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_ATTR_FLAG_FREQ: u64 = 1 << 10;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+/// struct perf_event_attr, PERF_ATTR_SIZE_VER5
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    r#type: u32,
+    size: u32,
+    config: u64,
+    sample_freq: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+fn other(e: libbpf_rs::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{}", e))
+}
+
+// a cpu-clock event of the cpu at `freq` Hz
+fn perf_event_open(cpu: i32, freq: u64) -> io::Result<i32> {
+    let attr = PerfEventAttr {
+        r#type: PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config: PERF_COUNT_SW_CPU_CLOCK,
+        sample_freq: freq,
+        flags: PERF_ATTR_FLAG_FREQ,
+        ..Default::default()
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            -1,
+            cpu,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd as i32)
+}
+
+fn online_cpus() -> i32 {
+    unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as i32 }
+}
+
+/// Samples a process with a BPF program until it's dropped.
+pub struct Sampler {
+    skel: ProfileSkel<'static>,
+    _links: Vec<Link>,
+    pid: u32,
+    symbolizer: Symbolizer,
+}
+
+impl Sampler {
+    /// starts sampling the process `pid` at `freq` Hz on every cpu
+    pub fn start(pid: u32, freq: u64) -> io::Result<Sampler> {
+        let mut open = ProfileSkelBuilder::default().open().map_err(other)?;
+        open.rodata().target_pid = pid;
+        let mut skel = open.load().map_err(other)?;
+        let mut links = Vec::new();
+        for cpu in 0..online_cpus() {
+            let fd = perf_event_open(cpu, freq)?;
+            links.push(
+                skel.progs_mut()
+                    .profile()
+                    .attach_perf_event(fd)
+                    .map_err(other)?,
+            );
+        }
+        let mut sampler = Sampler {
+            skel,
+            _links: links,
+            pid,
+            symbolizer: Symbolizer::new(None),
+        };
+        sampler.read_maps();
+        Ok(sampler)
+    }
+
+    /// reads the mappings of the process; call again before it exits to catch
+    /// the libraries loaded later
+    pub fn read_maps(&mut self) {
+        if let Ok(maps) = std::fs::read_to_string(format!("/proc/{}/maps", self.pid)) {
+            for (file, mmap) in maps.lines().filter_map(symbolize::parse_proc_maps) {
+                self.symbolizer.add_mmap(file, mmap);
+            }
+        }
+    }
+
+    fn stack(&self, id: i32) -> Vec<u64> {
+        if id < 0 {
+            return Vec::new();
+        }
+        let value = self
+            .skel
+            .maps()
+            .stacks()
+            .lookup(&(id as u32).to_ne_bytes(), MapFlags::ANY)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        value
+            .chunks_exact(8)
+            .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
+            .take_while(|addr| *addr != 0)
+            .collect()
+    }
+
+    /// the samples so far with the `pid`, `tid` and `thread_name` labels
+    pub fn profile(&mut self) -> io::Result<pb::Profile> {
+        let kernel = symbolize::kallsyms("/proc/kallsyms");
+        let mut counts = Vec::new();
+        let maps = self.skel.maps();
+        for key in maps.counts().keys() {
+            if key.len() != KEY_SIZE {
+                continue;
+            }
+            if let Some(value) = maps.counts().lookup(&key, MapFlags::ANY).map_err(other)? {
+                counts.push((key, u64::from_ne_bytes(value[..8].try_into().unwrap())));
+            }
+        }
+
+        let mut names: HashMap<u64, String> = HashMap::new();
+        let mut builder = SampleBuilder::default();
+        for (key, count) in counts {
+            let field = |i: usize| i32::from_ne_bytes(key[i * 4..i * 4 + 4].try_into().unwrap());
+            let (pid, tid) = (field(0).to_string(), field(1).to_string());
+            let comm = &key[16..16 + TASK_COMM_LEN];
+            let comm = String::from_utf8_lossy(
+                &comm[..comm.iter().position(|b| *b == 0).unwrap_or(TASK_COMM_LEN)],
+            )
+            .to_string();
+
+            // leaf first, the kernel frames on top of the user ones
+            let mut frames = Vec::new();
+            for addr in self.stack(field(3)) {
+                frames.push(match symbolize::kernel_function(&kernel, addr) {
+                    Some(name) => name.to_string(),
+                    None => format!("{:x}", addr),
+                });
+            }
+            for addr in self.stack(field(2)) {
+                let symbolizer = &mut self.symbolizer;
+                let name = names
+                    .entry(addr)
+                    .or_insert_with(|| match symbolizer.function(addr) {
+                        Some((name, _)) => pprof::demangle(&name),
+                        None => format!("{:x}", addr),
+                    });
+                frames.push(name.clone());
+            }
+            let frames: Vec<&str> = frames.iter().map(|f| f.as_str()).collect();
+            builder.add(
+                &frames,
+                &[("pid", &pid), ("tid", &tid), ("thread_name", &comm)],
+                count as i64,
+            );
+        }
+        Ok(builder.build())
+    }
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Counts the samples per stack in kernel space, so that nothing but the
+// counts has to be copied to user space.
+
+#include <linux/bpf.h>
+#include <linux/bpf_perf_event.h>
+#include <bpf/bpf_helpers.h>
+
+#define MAX_STACKS 16384
+#define MAX_DEPTH 127
+#define TASK_COMM_LEN 16
+
+struct key {
+	__u32 pid;
+	__u32 tid;
+	__s32 user_stack;
+	__s32 kernel_stack;
+	char comm[TASK_COMM_LEN];
+};
+
+struct {
+	__uint(type, BPF_MAP_TYPE_STACK_TRACE);
+	__uint(max_entries, MAX_STACKS);
+	__uint(key_size, sizeof(__u32));
+	__uint(value_size, MAX_DEPTH * sizeof(__u64));
+} stacks SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, MAX_STACKS);
+	__type(key, struct key);
+	__type(value, __u64);
+} counts SEC(".maps");
+
+// the process to sample, set before loading
+const volatile __u32 target_pid = 0;
+
+SEC("perf_event")
+int profile(struct bpf_perf_event_data *ctx)
+{
+	__u64 id = bpf_get_current_pid_tgid();
+	struct key key = {};
+	__u64 one = 1;
+	__u64 *count;
+
+	key.pid = id >> 32;
+	if (target_pid && key.pid != target_pid)
+		return 0;
+	key.tid = (__u32)id;
+	key.user_stack = bpf_get_stackid(ctx, &stacks, BPF_F_USER_STACK);
+	key.kernel_stack = bpf_get_stackid(ctx, &stacks, 0);
+	bpf_get_current_comm(&key.comm, sizeof(key.comm));
+
+	count = bpf_map_lookup_elem(&counts, &key);
+	if (count)
+		__sync_fetch_and_add(count, 1);
+	else
+		bpf_map_update_elem(&counts, &key, &one, BPF_NOEXIST);
+	return 0;
+}
+
+char LICENSE[] SEC("license") = "GPL";
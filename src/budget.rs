@@ -0,0 +1,141 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Deserialize;
+use std::fmt::Write;
+use std::io;
+
+use crate::pprof::{self, pb};
+
+/// Budgets file, `perf-budgets.toml`:
+///
+/// ```toml
+/// [[budget]]
+/// function = "serde_json::de"
+/// max = 8.0
+///
+/// [[budget]]
+/// crate = "regex"
+/// max = 5.0
+/// self = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Budgets {
+    #[serde(default, rename = "budget")]
+    pub budgets: Vec<Budget>,
+}
+
+/// The maximum cpu share of functions.
+#[derive(Debug, Deserialize)]
+pub struct Budget {
+    /// functions whose names start with this
+    pub function: Option<String>,
+    /// functions of the crate
+    #[serde(rename = "crate")]
+    pub krate: Option<String>,
+    /// in percent
+    pub max: f64,
+    /// excludes the time spent in callees
+    #[serde(default, rename = "self")]
+    pub self_only: bool,
+}
+
+/// The result of a budget.
+#[derive(Debug)]
+pub struct Verdict<'a> {
+    pub budget: &'a Budget,
+    /// in percent
+    pub actual: f64,
+}
+
+impl Verdict<'_> {
+    pub fn passed(&self) -> bool {
+        self.actual <= self.budget.max
+    }
+}
+
+impl Budget {
+    pub fn name(&self) -> String {
+        match (self.function.as_ref(), self.krate.as_ref()) {
+            (Some(f), _) => f.clone(),
+            (None, Some(c)) => format!("crate {}", c),
+            (None, None) => "(none)".to_string(),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        // <serde_json::de::Deserializer as ...>::deserialize
+        let name = name.strip_prefix('<').unwrap_or(name);
+        if let Some(f) = self.function.as_ref() {
+            return name.starts_with(f.as_str());
+        }
+        if let Some(c) = self.krate.as_ref() {
+            return name.starts_with(&format!("{}::", c));
+        }
+        false
+    }
+}
+
+impl Budgets {
+    pub fn parse(s: &str) -> io::Result<Self> {
+        toml::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)))
+    }
+
+    /// evaluates the `index`-th sample value of the profile against the budgets
+    pub fn check<'a>(&'a self, profile: &pb::Profile, index: usize) -> Vec<Verdict<'a>> {
+        let stacks = pprof::stacks(profile);
+        let total: i64 = stacks
+            .iter()
+            .map(|(_, s)| s.value.get(index).copied().unwrap_or(0))
+            .sum();
+        self.budgets
+            .iter()
+            .map(|b| {
+                let used: i64 = stacks
+                    .iter()
+                    .filter(|(names, _)| {
+                        if b.self_only {
+                            matches!(names.first(), Some(n) if b.matches(n))
+                        } else {
+                            names.iter().any(|n| b.matches(n))
+                        }
+                    })
+                    .map(|(_, s)| s.value.get(index).copied().unwrap_or(0))
+                    .sum();
+                Verdict {
+                    budget: b,
+                    actual: used as f64 * 100.0 / total.max(1) as f64,
+                }
+            })
+            .collect()
+    }
+}
+
+/// renders the verdicts as a text table
+pub fn render(verdicts: &[Verdict]) -> String {
+    let mut s = format!("{:>6} {:>8} {:>8}  {}\n", "", "Actual%", "Max%", "Budget");
+    for v in verdicts {
+        let _ = writeln!(
+            s,
+            "{:>6} {:>7.2}% {:>7.2}%  {}{}",
+            if v.passed() { "ok" } else { "FAILED" },
+            v.actual,
+            v.budget.max,
+            v.budget.name(),
+            if v.budget.self_only { " (self)" } else { "" }
+        );
+    }
+    s
+}
@@ -0,0 +1,330 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const SHT_NOTE: u32 = 7;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// A binary and its build-id recorded in perf.data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildId {
+    pub id: String,
+    pub path: String,
+}
+
+/// A binary on disk which isn't the one recorded.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub path: String,
+    pub recorded: String,
+    pub found: Option<String>,
+}
+
+/// parses `perf buildid-list` output
+pub fn parse_list<R>(reader: R) -> io::Result<Vec<BuildId>>
+where
+    R: io::BufRead,
+{
+    let mut list = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        // 2f0c8a0b6c1e... /usr/lib/x86_64-linux-gnu/libc.so.6
+        if let Some((id, path)) = line.trim().split_once(' ') {
+            list.push(BuildId {
+                id: id.to_string(),
+                path: path.trim().to_string(),
+            });
+        }
+    }
+    Ok(list)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+struct Elf {
+    file: File,
+    // the file size, which no offset or size of a header may go beyond
+    len: u64,
+    is_64: bool,
+    is_le: bool,
+}
+
+impl Elf {
+    fn read(&self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        if !matches!(offset.checked_add(len), Some(end) if end <= self.len) {
+            return Err(invalid("ELF header points beyond the file"));
+        }
+        let mut buf = vec![0; len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u16(&self, b: &[u8]) -> u64 {
+        let b = [b[0], b[1]];
+        (if self.is_le {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        }) as u64
+    }
+
+    fn u32(&self, b: &[u8]) -> u64 {
+        let b = [b[0], b[1], b[2], b[3]];
+        (if self.is_le {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }) as u64
+    }
+
+    fn u64(&self, b: &[u8]) -> u64 {
+        let mut a = [0; 8];
+        a.copy_from_slice(&b[..8]);
+        if self.is_le {
+            u64::from_le_bytes(a)
+        } else {
+            u64::from_be_bytes(a)
+        }
+    }
+
+    // an address or an offset
+    fn word(&self, b: &[u8]) -> u64 {
+        if self.is_64 {
+            self.u64(b)
+        } else {
+            self.u32(b)
+        }
+    }
+
+    fn build_id(&self) -> io::Result<Option<String>> {
+        let header = self.read(0, 64)?;
+        let (shoff, shentsize, shnum) = if self.is_64 {
+            (
+                self.u64(&header[0x28..]),
+                self.u16(&header[0x3a..]),
+                self.u16(&header[0x3c..]),
+            )
+        } else {
+            (
+                self.u32(&header[0x20..]),
+                self.u16(&header[0x2e..]),
+                self.u16(&header[0x30..]),
+            )
+        };
+        let (offset_pos, size_pos) = if self.is_64 {
+            (0x18, 0x20)
+        } else {
+            (0x10, 0x14)
+        };
+        // the section headers end with sh_entsize, a word after the size
+        let min_shentsize = if self.is_64 { 0x40 } else { 0x28 };
+        if shnum > 0 && shentsize < min_shentsize {
+            return Err(invalid("broken ELF section header size"));
+        }
+
+        for i in 0..shnum {
+            let offset = shoff
+                .checked_add(i * shentsize)
+                .ok_or_else(|| invalid("broken ELF section header offset"))?;
+            let sh = self.read(offset, shentsize)?;
+            if self.u32(&sh[4..]) != SHT_NOTE as u64 {
+                continue;
+            }
+            let notes = self.read(self.word(&sh[offset_pos..]), self.word(&sh[size_pos..]))?;
+            if let Some(id) = self.gnu_build_id(&notes) {
+                return Ok(Some(id));
+            }
+        }
+        Ok(None)
+    }
+
+    // the GNU build-id in the notes of a section; a broken note ends them
+    fn gnu_build_id(&self, notes: &[u8]) -> Option<String> {
+        let align = |n: usize| n.checked_add(3).map(|n| n & !3);
+        let mut pos = 0;
+        while pos + 12 <= notes.len() {
+            let namesz = self.u32(&notes[pos..]) as usize;
+            let descsz = self.u32(&notes[pos + 4..]) as usize;
+            let kind = self.u32(&notes[pos + 8..]);
+            let name = pos + 12;
+            let desc = name.checked_add(align(namesz)?)?;
+            let name = notes.get(name..name + namesz)?;
+            let id = notes.get(desc..desc.checked_add(descsz)?)?;
+            if kind == NT_GNU_BUILD_ID as u64 && name == b"GNU\0" {
+                return Some(hex::encode(id));
+            }
+            pos = desc.checked_add(align(descsz)?)?;
+        }
+        None
+    }
+}
+
+/// reads the GNU build-id note of an ELF file
+pub fn read_build_id<P: AsRef<Path>>(path: P) -> io::Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut ident = [0; 16];
+    file.read_exact(&mut ident)?;
+    if &ident[..4] != b"\x7fELF" {
+        return Ok(None);
+    }
+    Elf {
+        file,
+        len,
+        is_64: ident[4] == 2,
+        is_le: ident[5] == 1,
+    }
+    .build_id()
+}
+
+/// the path of the binary in perf's build-id cache (~/.debug)
+pub fn cache_path(id: &str) -> Option<PathBuf> {
+    if id.len() < 3 {
+        return None;
+    }
+    let home = std::env::var_os("HOME")?;
+    Some(
+        Path::new(&home)
+            .join(".debug/.build-id")
+            .join(&id[..2])
+            .join(&id[2..]),
+    )
+}
+
+/// finds the binaries on disk which differ from the recorded ones and aren't in the build-id cache;
+/// the binaries are looked up under `root` if given
+pub fn mismatches(list: &[BuildId], root: Option<&Path>) -> Vec<Mismatch> {
+    list.iter()
+        .filter(|b| b.path.starts_with('/'))
+        .filter_map(|b| {
+            let path = match root {
+                Some(root) => root.join(b.path.trim_start_matches('/')),
+                None => PathBuf::from(&b.path),
+            };
+            let found = match read_build_id(path) {
+                Ok(found) => found,
+                // perf can't use it either
+                Err(_) => return None,
+            };
+            if found.as_deref() == Some(b.id.as_str())
+                || matches!(cache_path(&b.id), Some(p) if p.exists())
+            {
+                return None;
+            }
+            Some(Mismatch {
+                path: b.path.clone(),
+                recorded: b.id.clone(),
+                found,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 64-bit little-endian ELF file with one note section
+    fn elf(shentsize: u16, notes: &[u8], notes_size: u64) -> Vec<u8> {
+        let mut b = vec![0; 128];
+        b[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        b[0x28..0x30].copy_from_slice(&64u64.to_le_bytes());
+        b[0x3a..0x3c].copy_from_slice(&shentsize.to_le_bytes());
+        b[0x3c..0x3e].copy_from_slice(&1u16.to_le_bytes());
+        b[64 + 4..64 + 8].copy_from_slice(&SHT_NOTE.to_le_bytes());
+        b[64 + 0x18..64 + 0x20].copy_from_slice(&128u64.to_le_bytes());
+        b[64 + 0x20..64 + 0x28].copy_from_slice(&notes_size.to_le_bytes());
+        b.extend_from_slice(notes);
+        b
+    }
+
+    fn note(kind: u32, name: &[u8], desc: &[u8]) -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        b.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        b.extend_from_slice(&kind.to_le_bytes());
+        for s in [name, desc] {
+            b.extend_from_slice(s);
+            b.resize((b.len() + 3) & !3, 0);
+        }
+        b
+    }
+
+    fn build_id(name: &str, data: &[u8]) -> io::Result<Option<String>> {
+        let path = std::env::temp_dir().join(format!(
+            "perf-tools-buildid-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, data).unwrap();
+        let id = read_build_id(&path);
+        std::fs::remove_file(&path).unwrap();
+        id
+    }
+
+    #[test]
+    fn gnu_note() {
+        let mut notes = note(1, b"Go\0", b"abc");
+        notes.extend(note(NT_GNU_BUILD_ID, b"GNU\0", &[0xde, 0xad, 0xbe, 0xef]));
+        let data = elf(0x40, &notes, notes.len() as u64);
+        assert_eq!(build_id("gnu", &data).unwrap().as_deref(), Some("deadbeef"));
+
+        let notes = note(NT_GNU_BUILD_ID, b"Go\0", &[1, 2]);
+        let data = elf(0x40, &notes, notes.len() as u64);
+        assert_eq!(build_id("other", &data).unwrap(), None);
+        assert_eq!(build_id("not-elf", b"#!/bin/sh\nexit 0\n").unwrap(), None);
+    }
+
+    #[test]
+    fn broken_headers() {
+        let notes = note(NT_GNU_BUILD_ID, b"GNU\0", &[1, 2, 3, 4]);
+        let short = build_id("short", &elf(4, &notes, notes.len() as u64));
+        assert_eq!(short.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let huge = build_id("huge", &elf(0x40, &notes, u64::MAX / 2));
+        assert_eq!(huge.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a note claiming more than its section holds
+        let mut notes = notes;
+        notes[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        let data = elf(0x40, &notes, notes.len() as u64);
+        assert_eq!(build_id("note", &data).unwrap(), None);
+    }
+
+    #[test]
+    fn buildid_list() {
+        let list =
+            parse_list(&b"2f0c8a /usr/lib/libc.so.6\n\nab12 [kernel.kallsyms]\n"[..]).unwrap();
+        assert_eq!(
+            list,
+            vec![
+                BuildId {
+                    id: "2f0c8a".to_string(),
+                    path: "/usr/lib/libc.so.6".to_string(),
+                },
+                BuildId {
+                    id: "ab12".to_string(),
+                    path: "[kernel.kallsyms]".to_string(),
+                },
+            ]
+        );
+    }
+}
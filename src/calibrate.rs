@@ -0,0 +1,143 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measures what sampling costs on this machine and workload, to pick the
+//! sampling frequency from the overhead and the lost samples instead of
+//! folklore like 99Hz.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::pprof::{self, pb};
+
+/// The frequencies tried by default.
+pub const FREQUENCIES: [u32; 6] = [99, 499, 999, 1999, 3999, 7999];
+
+/// A recording at a sampling frequency.
+#[derive(Debug, Clone)]
+pub struct Trial {
+    pub freq: u32,
+    /// wall time of the workload under perf
+    pub elapsed: Duration,
+    pub samples: u64,
+    pub lost: u64,
+    /// size of perf.data
+    pub bytes: u64,
+}
+
+impl Trial {
+    /// reads the samples and the samples lost from the profile of the
+    /// recording
+    pub fn new(freq: u32, elapsed: Duration, profile: &pb::Profile, bytes: u64) -> Self {
+        let samples = profile
+            .sample
+            .iter()
+            .map(|s| s.value.first().copied().unwrap_or(0).max(0) as u64)
+            .sum();
+        // "perf lost 123 samples (4.5%)"
+        let lost = pprof::lost_samples(profile)
+            .and_then(|c| c.split_whitespace().nth(2)?.parse().ok())
+            .unwrap_or(0);
+        Trial {
+            freq,
+            elapsed,
+            samples,
+            lost,
+            bytes,
+        }
+    }
+
+    /// the slowdown of the workload over the run without perf, as a fraction
+    pub fn overhead(&self, baseline: Duration) -> f64 {
+        self.elapsed.as_secs_f64() / baseline.as_secs_f64().max(f64::EPSILON) - 1.0
+    }
+
+    /// the fraction of the samples lost
+    pub fn lost_rate(&self) -> f64 {
+        self.lost as f64 / (self.samples + self.lost).max(1) as f64
+    }
+
+    /// perf.data written per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn acceptable(&self, baseline: Duration, max_overhead: f64, max_lost: f64) -> bool {
+        self.overhead(baseline) <= max_overhead && self.lost_rate() <= max_lost
+    }
+}
+
+/// the highest frequency whose overhead and lost samples stay within the
+/// fractions, none if even the lowest doesn't
+pub fn recommend(
+    baseline: Duration,
+    trials: &[Trial],
+    max_overhead: f64,
+    max_lost: f64,
+) -> Option<u32> {
+    trials
+        .iter()
+        .filter(|t| t.acceptable(baseline, max_overhead, max_lost))
+        .map(|t| t.freq)
+        .max()
+}
+
+/// renders the trials as a table, with the recommendation
+pub fn render(baseline: Duration, trials: &[Trial], max_overhead: f64, max_lost: f64) -> String {
+    let mut s = format!("without perf: {:.2}s\n\n", baseline.as_secs_f64());
+    let _ = writeln!(
+        s,
+        "{:>7} {:>9} {:>9} {:>9} {:>7} {:>9}",
+        "Hz", "Elapsed", "Overhead", "Samples", "Lost%", "MB/s"
+    );
+    for t in trials {
+        let _ = writeln!(
+            s,
+            "{:>7} {:>8.2}s {:>8.1}% {:>9} {:>6.2}% {:>9.1}{}",
+            t.freq,
+            t.elapsed.as_secs_f64(),
+            t.overhead(baseline) * 100.0,
+            t.samples,
+            t.lost_rate() * 100.0,
+            t.bytes_per_sec() / 1_000_000.0,
+            if t.acceptable(baseline, max_overhead, max_lost) {
+                ""
+            } else {
+                "  (too costly)"
+            }
+        );
+    }
+    s.push('\n');
+    match recommend(baseline, trials, max_overhead, max_lost) {
+        Some(freq) => {
+            let _ = writeln!(
+                s,
+                "recommended: --frequency {} (overhead within {:.1}%, lost samples within {:.1}%)",
+                freq,
+                max_overhead * 100.0,
+                max_lost * 100.0
+            );
+        }
+        None => {
+            let _ = writeln!(
+                s,
+                "no frequency tried keeps the overhead within {:.1}% and the lost samples within {:.1}%; try lower ones",
+                max_overhead * 100.0,
+                max_lost * 100.0
+            );
+        }
+    }
+    s
+}
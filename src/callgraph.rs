@@ -0,0 +1,219 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::flat::FlatProfile;
+use crate::pprof::{self, pb};
+
+/// Functions and caller to callee edges weighted by a sample value.
+#[derive(Default)]
+pub struct CallGraph {
+    pub total: i64,
+    /// function name, (self, total)
+    pub nodes: HashMap<String, (i64, i64)>,
+    /// (caller, callee), value
+    pub edges: HashMap<(String, String), i64>,
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl CallGraph {
+    /// aggregates the `index`-th sample value of the profile per function and per call edge
+    pub fn new(profile: &pb::Profile, index: usize) -> Self {
+        let flat = FlatProfile::new(profile, index);
+        let mut edges = HashMap::new();
+        let mut seen = HashSet::new();
+        for (names, s) in pprof::stacks(profile) {
+            let v = s.value.get(index).copied().unwrap_or(0);
+            seen.clear();
+            // leaf first
+            for w in names.windows(2) {
+                // count recursive calls once per stack
+                if w[0] != w[1] && seen.insert((w[1], w[0])) {
+                    *edges
+                        .entry((w[1].to_string(), w[0].to_string()))
+                        .or_insert(0) += v;
+                }
+            }
+        }
+        CallGraph {
+            total: flat.total,
+            nodes: flat.functions,
+            edges,
+        }
+    }
+
+    /// Drops the functions below `node_fraction` of the total and the edges
+    /// below `edge_fraction`, like pprof does.
+    pub fn prune(&self, node_fraction: f64, edge_fraction: f64) -> CallGraph {
+        let total = self.total as f64;
+        let nodes: HashMap<String, (i64, i64)> = self
+            .nodes
+            .iter()
+            .filter(|(_, (_, t))| *t as f64 >= total * node_fraction)
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        let edges = self
+            .edges
+            .iter()
+            .filter(|((caller, callee), v)| {
+                **v as f64 >= total * edge_fraction
+                    && nodes.contains_key(caller)
+                    && nodes.contains_key(callee)
+            })
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        CallGraph {
+            total: self.total,
+            nodes,
+            edges,
+        }
+    }
+
+    fn sorted_nodes(&self) -> Vec<(&str, i64, i64)> {
+        let mut v: Vec<(&str, i64, i64)> = self
+            .nodes
+            .iter()
+            .map(|(name, (s, t))| (name.as_str(), *s, *t))
+            .collect();
+        v.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)));
+        v
+    }
+
+    fn node_label(&self, name: &str, self_value: i64, total_value: i64, newline: &str) -> String {
+        let total = self.total.max(1) as f64;
+        format!(
+            "{}{}{:.2}% of {:.2}%",
+            name,
+            newline,
+            self_value as f64 * 100.0 / total,
+            total_value as f64 * 100.0 / total
+        )
+    }
+
+    /// edges sorted by value
+    pub fn sorted_edges(&self) -> Vec<(&str, &str, i64)> {
+        let mut v: Vec<(&str, &str, i64)> = self
+            .edges
+            .iter()
+            .map(|((caller, callee), v)| (caller.as_str(), callee.as_str(), *v))
+            .collect();
+        v.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)).then(a.1.cmp(b.1)));
+        v
+    }
+
+    /// one `caller,callee,value` row per edge
+    pub fn to_csv(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+        let mut s = String::from("caller,callee,value\n");
+        for (caller, callee, v) in self.sorted_edges() {
+            let _ = writeln!(s, "{},{},{}", quote(caller), quote(callee), v);
+        }
+        s
+    }
+
+    /// renders the graph in Graphviz DOT
+    pub fn to_dot(&self) -> String {
+        let total = self.total.max(1) as f64;
+        let mut s = String::from("digraph profile {\n  node [shape=box];\n");
+        for (name, self_value, total_value) in self.sorted_nodes() {
+            let _ = writeln!(
+                s,
+                "  {} [label={}];",
+                quote(name),
+                // an escape sequence of DOT, not a newline
+                quote(&self.node_label(name, self_value, total_value, "\n")).replace('\n', "\\n")
+            );
+        }
+        for (caller, callee, v) in self.sorted_edges() {
+            let share = v as f64 / total;
+            let _ = writeln!(
+                s,
+                "  {} -> {} [label=\"{:.2}%\", penwidth={:.1}];",
+                quote(caller),
+                quote(callee),
+                share * 100.0,
+                1.0 + share * 4.0
+            );
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// Renders the graph in the callgrind format of Valgrind, to open in
+    /// KCachegrind or QCachegrind, with the values as the `event` cost. The
+    /// call counts are the values too, since samples don't count calls.
+    pub fn to_callgrind(&self, event: &str) -> String {
+        let mut s = format!(
+            "# callgrind format\nversion: 1\ncreator: perf-tools\nevents: {}\nsummary: {}\n",
+            event.replace(char::is_whitespace, "_"),
+            self.total
+        );
+        let mut ids = HashMap::new();
+        // the name at its first use, the id later, to keep the file small
+        let name = |ids: &mut HashMap<String, usize>, f: &str| match ids.get(f) {
+            Some(id) => format!("({})", id),
+            None => {
+                let id = ids.len() + 1;
+                ids.insert(f.to_string(), id);
+                format!("({}) {}", id, f)
+            }
+        };
+        let mut callees: HashMap<&str, Vec<(&str, i64)>> = HashMap::new();
+        for (caller, callee, v) in self.sorted_edges() {
+            callees.entry(caller).or_default().push((callee, v));
+        }
+        for (f, self_value, _) in self.sorted_nodes() {
+            let _ = writeln!(s, "\nfn={}\n0 {}", name(&mut ids, f), self_value);
+            for (callee, v) in callees.get(f).into_iter().flatten() {
+                let _ = writeln!(s, "cfn={}\ncalls={} 0\n0 {}", name(&mut ids, callee), v, v);
+            }
+        }
+        s
+    }
+
+    /// renders the graph as a Mermaid flowchart, to embed in Markdown
+    pub fn to_mermaid(&self) -> String {
+        let total = self.total.max(1) as f64;
+        let mut s = String::from("graph TD\n");
+        let mut ids = HashMap::new();
+        for (i, (name, self_value, total_value)) in self.sorted_nodes().into_iter().enumerate() {
+            ids.insert(name, i);
+            // Mermaid's entity codes, as the labels are HTML; `Vec<T>` would be a tag
+            let escaped = name
+                .replace('#', "#35;")
+                .replace('&', "#amp;")
+                .replace('<', "#lt;")
+                .replace('>', "#gt;")
+                .replace('"', "#quot;");
+            let label = self.node_label(&escaped, self_value, total_value, "<br/>");
+            let _ = writeln!(s, "  n{}[\"{}\"]", i, label);
+        }
+        for (caller, callee, v) in self.sorted_edges() {
+            let _ = writeln!(
+                s,
+                "  n{} -->|{:.2}%| n{}",
+                ids[caller],
+                v as f64 * 100.0 / total,
+                ids[callee]
+            );
+        }
+        s
+    }
+}
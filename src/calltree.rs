@@ -0,0 +1,107 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The call tree of a profile as text, from the roots of the threads down,
+//! like `pprof -tree` or `perf report --stdio --children`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::pprof::{self, pb};
+use crate::unit::Unit;
+
+#[derive(Default)]
+struct Node {
+    /// including the callees
+    total: i64,
+    self_value: i64,
+    children: HashMap<String, Node>,
+}
+
+/// The stacks of a profile merged from the roots.
+#[derive(Default)]
+pub struct CallTree {
+    total: i64,
+    roots: HashMap<String, Node>,
+}
+
+impl CallTree {
+    /// merges the stacks of the profile with the `index`-th sample value
+    pub fn new(profile: &pb::Profile, index: usize) -> Self {
+        let mut tree = CallTree::default();
+        for (names, s) in pprof::stacks(profile) {
+            let v = s.value.get(index).copied().unwrap_or(0);
+            if names.is_empty() || v == 0 {
+                continue;
+            }
+            tree.total += v;
+            let mut children = &mut tree.roots;
+            // leaf first
+            for (i, name) in names.iter().rev().enumerate() {
+                let node = children.entry(name.to_string()).or_default();
+                node.total += v;
+                if i == names.len() - 1 {
+                    node.self_value += v;
+                }
+                children = &mut node.children;
+            }
+        }
+        tree
+    }
+
+    /// Renders the tree down to `depth` callees, leaving out the calls below
+    /// `min_fraction` of the total. Each line has the share of the time with
+    /// the callees, then in the function itself, in `unit`.
+    pub fn render(&self, depth: usize, min_fraction: f64, unit: Unit) -> String {
+        let mut s = String::new();
+        self.render_children(&mut s, &self.roots, "", depth, min_fraction, unit);
+        s
+    }
+
+    fn render_children(
+        &self,
+        s: &mut String,
+        children: &HashMap<String, Node>,
+        prefix: &str,
+        depth: usize,
+        min_fraction: f64,
+        unit: Unit,
+    ) {
+        if depth == 0 {
+            return;
+        }
+        let total = self.total.max(1) as f64;
+        let mut shown: Vec<(&String, &Node)> = children
+            .iter()
+            .filter(|(_, n)| n.total as f64 >= total * min_fraction)
+            .collect();
+        shown.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        for (i, (name, node)) in shown.iter().enumerate() {
+            let last = i == shown.len() - 1;
+            let _ = writeln!(
+                s,
+                "{:>w$} {:>w$}  {}{}{}",
+                unit.format(node.total, self.total),
+                unit.format(node.self_value, self.total),
+                prefix,
+                if last { "└─ " } else { "├─ " },
+                name,
+                w = if unit == Unit::Percent { 7 } else { 12 }
+            );
+            let prefix = format!("{}{}", prefix, if last { "   " } else { "│  " });
+            self.render_children(s, &node.children, &prefix, depth - 1, min_fraction, unit);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The time of the recording in the header of `perf script --header`.
+//! `captured on` is the modification time of perf.data as `%c` prints it,
+//! which depends on the perf version and LC_TIME of the machine that ran
+//! perf script, so it's read from the numbers and the month names rather
+//! than one format. The `reference time` that perf records with `-k` has
+//! the exact time in seconds since the epoch and takes precedence.
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // # reference time: 2022-03-10 10:45:19.123456 = 1646905519.123456 (TOD) = 12345.678901234 (CLOCK_MONOTONIC)
+    static ref REFERENCE_RE: Regex = Regex::new(r"=\s*(\d+)\.(\d+)\s*\(TOD\)").unwrap();
+    // 10:45:19, 10時45分19秒, 10时45分19秒, 10시 45분 19초
+    static ref TIME_RE: Regex =
+        Regex::new(r"(\d{1,2})(?::|時|时|시\s*)(\d{1,2})(?::|分|분\s*)(\d{1,2})(?:秒|초)?").unwrap();
+    // 2022-03-10, 2022年03月10日, 2022년 03월 10일, 2022/03/10
+    static ref YMD_RE: Regex =
+        Regex::new(r"(\d{4})(?:-|/|\.|年|년\s*)(\d{1,2})(?:-|/|\.|月|월\s*)(\d{1,2})").unwrap();
+    // 10.03.2022, 10/03/2022, 03/10/2022
+    static ref DMY_RE: Regex = Regex::new(r"(\d{1,2})[./](\d{1,2})[./](\d{4})").unwrap();
+    static ref NUMBER_RE: Regex = Regex::new(r"\d+").unwrap();
+    static ref WORD_RE: Regex = Regex::new(r"[^\W\d_]+").unwrap();
+}
+
+// the abbreviations of the months that `%c` prints in English, German,
+// French, Spanish, Italian, Portuguese, Dutch, Swedish, Polish and
+// Russian, matched against the beginning of a word
+const MONTHS: [&[&str]; 12] = [
+    &["jan", "ene", "gen", "sty", "янв"],
+    &["feb", "fév", "fev", "lut", "фев"],
+    &["mar", "mär", "mrt", "мар"],
+    &["apr", "avr", "abr", "kwi", "апр"],
+    &["may", "mai", "mag", "mei", "maj", "мая", "май"],
+    &["jun", "juin", "giu", "cze", "июн"],
+    &["jul", "juil", "lug", "lip", "июл"],
+    &["aug", "aoû", "ago", "sie", "авг"],
+    &["sep", "set", "wrz", "сен"],
+    &["oct", "okt", "ott", "out", "paź", "окт"],
+    &["nov", "lis", "ноя"],
+    &["dec", "dez", "déc", "dic", "gru", "дек"],
+];
+
+// the morning and the afternoon of the locales with a 12-hour clock
+const AM: [&str; 3] = ["am", "午前", "오전"];
+const PM: [&str; 3] = ["pm", "午後", "오후"];
+
+/// the time in seconds since the epoch of the `reference time` line
+pub fn parse_reference_time(line: &str) -> Option<DateTime<Local>> {
+    let caps = REFERENCE_RE.captures(line)?;
+    let secs = caps[1].parse().ok()?;
+    // microseconds
+    let frac = &caps[2];
+    let nanos = format!("{:0<9}", &frac[..frac.len().min(9)]).parse().ok()?;
+    Local.timestamp_opt(secs, nanos).single()
+}
+
+/// the time of `captured on`, like `Thu Mar 10 10:45:19 2022`, `Thu 10 Mar
+/// 2022 10:45:19 AM JST`, `Do 10 Mär 2022 10:45:19 CET` or `2022年03月10日
+/// 10時45分19秒`, in the local time zone
+pub fn parse_captured_on(s: &str) -> Option<DateTime<Local>> {
+    if let Some(t) = parse_iso8601(s) {
+        return Some(t);
+    }
+    let s = s.trim();
+    let time = TIME_RE.captures(s)?;
+    let mut hour: u32 = time[1].parse().ok()?;
+    let min = time[2].parse().ok()?;
+    let sec = time[3].parse().ok()?;
+    let words: Vec<String> = s
+        .split_whitespace()
+        .map(|w| w.replace('.', "").to_lowercase())
+        .collect();
+    // the CJK ones might be written together with the time, `午後02時`
+    let marked = |marks: &[&str]| {
+        words.iter().any(|w| {
+            marks
+                .iter()
+                .any(|m| w == m || (!m.is_ascii() && w.contains(m)))
+        })
+    };
+    if marked(&PM) && hour < 12 {
+        hour += 12;
+    } else if marked(&AM) && hour == 12 {
+        hour = 0;
+    }
+    // the rest holds the date
+    let rest = format!(
+        "{} {}",
+        &s[..time.get(0)?.start()],
+        &s[time.get(0)?.end()..]
+    );
+
+    let date = if let Some(caps) = YMD_RE.captures(&rest) {
+        NaiveDate::from_ymd_opt(
+            caps[1].parse().ok()?,
+            caps[2].parse().ok()?,
+            caps[3].parse().ok()?,
+        )
+    } else if let Some(month) = month(&rest) {
+        let mut year = None;
+        let mut day = None;
+        for m in NUMBER_RE.find_iter(&rest) {
+            match m.as_str().len() {
+                4 => year = year.or_else(|| m.as_str().parse().ok()),
+                1 | 2 => day = day.or_else(|| m.as_str().parse().ok()),
+                _ => {}
+            }
+        }
+        NaiveDate::from_ymd_opt(year?, month, day?)
+    } else if let Some(caps) = DMY_RE.captures(&rest) {
+        let (a, b): (u32, u32) = (caps[1].parse().ok()?, caps[2].parse().ok()?);
+        // day first unless it can't be
+        let (day, month) = if b > 12 { (b, a) } else { (a, b) };
+        NaiveDate::from_ymd_opt(caps[3].parse().ok()?, month, day)
+    } else {
+        None
+    }?;
+    local(date.and_hms_opt(hour, min, sec)?)
+}
+
+/// the time in ISO 8601, `2022-03-10T10:45:19+09:00`, or without the offset
+/// in the local time zone, `2022-03-10 10:45:19`
+pub fn parse_iso8601(s: &str) -> Option<DateTime<Local>> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Local));
+    }
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return local(t);
+        }
+    }
+    None
+}
+
+// the last word naming a month since the name of the weekday comes first
+// and some, like Tuesday in French and Spanish, look like one
+fn month(s: &str) -> Option<u32> {
+    WORD_RE
+        .find_iter(s)
+        .filter_map(|w| {
+            let w = w.as_str().to_lowercase();
+            if w.chars().count() < 3 {
+                return None;
+            }
+            MONTHS
+                .iter()
+                .position(|names| names.iter().any(|n| w.starts_with(n)))
+                .map(|i| i as u32 + 1)
+        })
+        .last()
+}
+
+// the earlier of the two times when the clock went back
+fn local(t: NaiveDateTime) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&t).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> DateTime<Local> {
+        local(
+            NaiveDate::from_ymd_opt(y, m, d)
+                .unwrap()
+                .and_hms_opt(h, min, s)
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn captured_on() {
+        let cases = [
+            // ctime, the C locale
+            ("Thu Mar 10 10:45:19 2022", at(2022, 3, 10, 10, 45, 19)),
+            ("Thu Mar  3 09:05:07 2022", at(2022, 3, 3, 9, 5, 7)),
+            // en_US, 12-hour
+            (
+                "Thu 10 Mar 2022 10:45:19 AM JST",
+                at(2022, 3, 10, 10, 45, 19),
+            ),
+            (
+                "Thu 10 Mar 2022 02:45:19 PM JST",
+                at(2022, 3, 10, 14, 45, 19),
+            ),
+            ("Thu 10 Mar 2022 12:05:00 AM JST", at(2022, 3, 10, 0, 5, 0)),
+            ("Thu 10 Mar 2022 12:05:00 PM JST", at(2022, 3, 10, 12, 5, 0)),
+            (
+                "Thu 10 Mar 2022 02:45:19 p.m. JST",
+                at(2022, 3, 10, 14, 45, 19),
+            ),
+            // de_DE, fr_FR, es_ES, it_IT, pt_BR, nl_NL, sv_SE, pl_PL, ru_RU
+            ("Do 10 Mär 2022 10:45:19 CET", at(2022, 3, 10, 10, 45, 19)),
+            ("Mo 02 Mai 2022 10:45:19 CEST", at(2022, 5, 2, 10, 45, 19)),
+            (
+                "jeu. 10 mars 2022 10:45:19 CET",
+                at(2022, 3, 10, 10, 45, 19),
+            ),
+            (
+                "ven. 10 juin 2022 10:45:19 CEST",
+                at(2022, 6, 10, 10, 45, 19),
+            ),
+            (
+                "dim. 10 juil. 2022 10:45:19 CEST",
+                at(2022, 7, 10, 10, 45, 19),
+            ),
+            (
+                "sam. 10 déc. 2022 10:45:19 CET",
+                at(2022, 12, 10, 10, 45, 19),
+            ),
+            ("jue 10 ene 2022 10:45:19 CET", at(2022, 1, 10, 10, 45, 19)),
+            ("gio 10 giu 2022, 10:45:19", at(2022, 6, 10, 10, 45, 19)),
+            ("lun 10 ott 2022, 10:45:19", at(2022, 10, 10, 10, 45, 19)),
+            ("qui 10 fev 2022 10:45:19", at(2022, 2, 10, 10, 45, 19)),
+            ("seg 10 out 2022 10:45:19", at(2022, 10, 10, 10, 45, 19)),
+            ("do 10 mrt 2022 10:45:19 CET", at(2022, 3, 10, 10, 45, 19)),
+            ("tor 10 maj 2022 10:45:19", at(2022, 5, 10, 10, 45, 19)),
+            ("czw, 10 lis 2022, 10:45:19", at(2022, 11, 10, 10, 45, 19)),
+            ("pią, 10 wrz 2022, 10:45:19", at(2022, 9, 10, 10, 45, 19)),
+            ("Чт 10 мар 2022 10:45:19", at(2022, 3, 10, 10, 45, 19)),
+            ("Пн 10 окт 2022 10:45:19", at(2022, 10, 10, 10, 45, 19)),
+            // Tuesday in French and Spanish looks like March
+            (
+                "mar. 12 avril 2022 10:45:19 CEST",
+                at(2022, 4, 12, 10, 45, 19),
+            ),
+            ("mar 12 abr 2022 10:45:19 CEST", at(2022, 4, 12, 10, 45, 19)),
+            // ja_JP, zh_CN, ko_KR
+            ("2022年03月10日 10時45分19秒", at(2022, 3, 10, 10, 45, 19)),
+            (
+                "2022年03月10日 星期四 10时45分19秒",
+                at(2022, 3, 10, 10, 45, 19),
+            ),
+            (
+                "2022년 03월 10일 (목) 오후 02시 45분 19초",
+                at(2022, 3, 10, 14, 45, 19),
+            ),
+            (
+                "2022년 03월 10일 (목) 오전 12시 45분 19초",
+                at(2022, 3, 10, 0, 45, 19),
+            ),
+            (
+                "2022年03月10日 午後02時45分19秒",
+                at(2022, 3, 10, 14, 45, 19),
+            ),
+            // numeric dates, day first unless the day can't be the month
+            ("10.03.2022 10:45:19", at(2022, 3, 10, 10, 45, 19)),
+            ("10/03/2022 10:45:19", at(2022, 3, 10, 10, 45, 19)),
+            ("03/25/2022 10:45:19", at(2022, 3, 25, 10, 45, 19)),
+            ("2022/03/10 10:45:19", at(2022, 3, 10, 10, 45, 19)),
+            // ISO 8601
+            ("2022-03-10 10:45:19", at(2022, 3, 10, 10, 45, 19)),
+        ];
+        for (s, t) in cases {
+            assert_eq!(parse_captured_on(s), Some(t), "{}", s);
+        }
+        for s in ["", "unknown", "Thu Mar 10 2022", "Thu Foo 10 10:45:19 2022"] {
+            assert_eq!(parse_captured_on(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn reference_time() {
+        let t = parse_reference_time(
+            "# reference time: 2022-03-10 10:45:19.123456 = 1646905519.123456 (TOD) = 12345.678901234 (CLOCK_MONOTONIC)",
+        )
+        .unwrap();
+        assert_eq!(t.timestamp(), 1646905519);
+        assert_eq!(t.timestamp_subsec_nanos(), 123456000);
+        assert_eq!(parse_reference_time("# clockid: 1"), None);
+    }
+
+    #[test]
+    fn iso8601() {
+        let t = parse_iso8601("2022-03-10T10:45:19+09:00").unwrap();
+        assert_eq!(t.timestamp(), 1646876719);
+        let t = parse_iso8601("2022-03-10T01:45:19.5Z").unwrap();
+        assert_eq!(t.timestamp(), 1646876719);
+        assert_eq!(
+            parse_iso8601("2022-03-10T10:45:19"),
+            Some(at(2022, 3, 10, 10, 45, 19))
+        );
+        assert_eq!(
+            parse_iso8601(" 2022-03-10 10:45 "),
+            Some(at(2022, 3, 10, 10, 45, 0))
+        );
+        assert_eq!(parse_iso8601("Thu Mar 10 10:45:19 2022"), None);
+        assert_eq!(parse_iso8601("2022-13-10T10:45:19"), None);
+    }
+}
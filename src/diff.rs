@@ -0,0 +1,315 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+
+use crate::flat::FlatProfile;
+use crate::merge;
+use crate::pprof::pb;
+
+/// How much a function changed between two profiles.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub name: String,
+    /// (self, total) share in the base profile
+    pub base: (f64, f64),
+    /// (self, total) share in the new profile
+    pub new: (f64, f64),
+    /// the number of samples in the base profile
+    pub base_samples: i64,
+    /// the number of samples in the new profile
+    pub new_samples: i64,
+}
+
+// two-sided 95% confidence
+const Z: f64 = 1.96;
+
+/// the binomial standard error of a share estimated from `n` samples
+pub fn std_error(p: f64, n: i64) -> f64 {
+    (p * (1.0 - p) / n.max(1) as f64).sqrt()
+}
+
+impl Change {
+    fn new(name: &str) -> Self {
+        Change {
+            name: name.to_string(),
+            base: (0.0, 0.0),
+            new: (0.0, 0.0),
+            base_samples: 0,
+            new_samples: 0,
+        }
+    }
+
+    /// the difference of the self share
+    pub fn delta(&self) -> f64 {
+        self.new.0 - self.base.0
+    }
+
+    /// the 95% confidence interval of `delta()` is `delta() ± error()`
+    pub fn error(&self) -> f64 {
+        let b = std_error(self.base.0, self.base_samples);
+        let n = std_error(self.new.0, self.new_samples);
+        Z * (b * b + n * n).sqrt()
+    }
+
+    /// whether the change is beyond the sampling noise
+    pub fn significant(&self) -> bool {
+        self.delta().abs() > self.error()
+    }
+
+    /// the number of samples per profile that would make the change significant
+    pub fn samples_needed(&self) -> Option<i64> {
+        let d = self.delta();
+        if d == 0.0 {
+            return None;
+        }
+        let v = self.base.0 * (1.0 - self.base.0) + self.new.0 * (1.0 - self.new.0);
+        Some((Z * Z * v / (d * d)).ceil() as i64)
+    }
+}
+
+fn add(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+/// strips the hash of legacy mangled Rust symbols, `foo::bar::h0123456789abcdef`
+pub fn strip_hash(name: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"::h[0-9a-f]{16}").unwrap();
+    }
+    RE.replace_all(name, "").to_string()
+}
+
+/// strips generic arguments, `Vec<u8>::push` to `Vec<_>::push`; qualified paths
+/// like `<Foo as Bar>::baz` are kept
+pub fn strip_generics(name: &str) -> String {
+    let mut s = String::new();
+    // whether each open bracket is stripped
+    let mut stack = Vec::new();
+    let mut prev = ' ';
+    for c in name.chars() {
+        let stripping = stack.contains(&true);
+        match c {
+            '<' => {
+                let strip = prev.is_alphanumeric() || prev == '_' || prev == ':';
+                if !stripping {
+                    s.push(c);
+                    if strip {
+                        s.push('_');
+                    }
+                }
+                stack.push(strip);
+            }
+            // not `->`
+            '>' if prev != '-' && !stack.is_empty() => {
+                stack.pop();
+                if !stack.contains(&true) {
+                    s.push(c);
+                }
+            }
+            _ if !stripping => s.push(c),
+            _ => {}
+        }
+        prev = c;
+    }
+    s
+}
+
+/// Compares the functions of two profiles, sorted by the self share difference.
+///
+/// With `fuzzy`, symbol hashes are ignored, and then functions found only in
+/// one of the profiles are matched ignoring generic arguments, so that the
+/// functions are matched across builds.
+pub fn diff(base: &FlatProfile, new: &FlatProfile, fuzzy: bool) -> Vec<Change> {
+    let key = |name: &str| {
+        if fuzzy {
+            strip_hash(name)
+        } else {
+            name.to_string()
+        }
+    };
+
+    let mut changes: BTreeMap<String, Change> = BTreeMap::new();
+    let mut in_base = HashSet::new();
+    let mut in_new = HashSet::new();
+    for name in base.functions.keys() {
+        let k = key(name);
+        let c = changes.entry(k.clone()).or_insert_with(|| Change::new(&k));
+        c.base = add(c.base, base.share(name));
+        in_base.insert(k);
+    }
+    for name in new.functions.keys() {
+        let k = key(name);
+        let c = changes.entry(k.clone()).or_insert_with(|| Change::new(&k));
+        c.new = add(c.new, new.share(name));
+        in_new.insert(k);
+    }
+
+    if fuzzy {
+        // generic name, (only in base, only in new)
+        let mut groups: HashMap<String, (Vec<String>, Vec<String>)> = HashMap::new();
+        for k in in_base.difference(&in_new) {
+            groups
+                .entry(strip_generics(k))
+                .or_default()
+                .0
+                .push(k.clone());
+        }
+        for k in in_new.difference(&in_base) {
+            groups
+                .entry(strip_generics(k))
+                .or_default()
+                .1
+                .push(k.clone());
+        }
+        for (g, (b, n)) in groups {
+            if b.is_empty() || n.is_empty() {
+                continue;
+            }
+            let removed: Vec<Change> = b
+                .iter()
+                .chain(n.iter())
+                .filter_map(|k| changes.remove(k))
+                .collect();
+            let merged = changes.entry(g.clone()).or_insert_with(|| Change::new(&g));
+            for c in removed {
+                merged.base = add(merged.base, c.base);
+                merged.new = add(merged.new, c.new);
+            }
+        }
+    }
+
+    let mut v: Vec<Change> = changes
+        .into_values()
+        .map(|mut c| {
+            c.base_samples = base.total;
+            c.new_samples = new.total;
+            c
+        })
+        .collect();
+    v.sort_by(|a, b| {
+        b.delta()
+            .abs()
+            .partial_cmp(&a.delta().abs())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    v
+}
+
+/// renders the biggest `n` changes as a text table, marking the ones within
+/// the sampling noise with `~`
+pub fn render(changes: &[Change], n: usize) -> String {
+    let mut s = format!(
+        "{:>8} {:>8} {:>8} {:>7} {:>15}  {}\n",
+        "Base%", "New%", "Delta", "±95%", "Samples", "Function"
+    );
+    for c in changes.iter().take(n) {
+        let _ = writeln!(
+            s,
+            "{:>7.2}% {:>7.2}% {:>+7.2}% {:>6.2}% {:>15} {}{}",
+            c.base.0 * 100.0,
+            c.new.0 * 100.0,
+            c.delta() * 100.0,
+            c.error() * 100.0,
+            format!(
+                "{}/{}",
+                (c.base.0 * c.base_samples as f64).round(),
+                (c.new.0 * c.new_samples as f64).round()
+            ),
+            if c.significant() { " " } else { "~" },
+            c.name
+        );
+    }
+    s
+}
+
+/// Warns about the biggest regression among the first `n` changes if it is
+/// within the sampling noise, with the number of samples needed to tell.
+pub fn noise_warning(changes: &[Change], n: usize) -> Option<String> {
+    let c = changes
+        .iter()
+        .take(n)
+        .filter(|c| c.delta() > 0.0)
+        .max_by(|a, b| {
+            a.delta()
+                .partial_cmp(&b.delta())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+    if c.significant() {
+        return None;
+    }
+    let mut s = format!(
+        "{:+.2}% of {} is within the noise of {} samples",
+        c.delta() * 100.0,
+        c.name,
+        c.new_samples
+    );
+    if let Some(needed) = c.samples_needed() {
+        let _ = write!(
+            s,
+            "; about {} samples ({:.1}x the frequency or duration) are needed to tell",
+            needed,
+            needed as f64 / c.new_samples.max(1) as f64
+        );
+    }
+    Some(s)
+}
+
+/// Subtracts the base profile from the new one per stack, matched by the
+/// functions, without their symbol hashes, and lines rather than the
+/// addresses and build-ids, leaving the growth as positive values and the savings as
+/// negative ones, which pprof shows like `-diff_base`. The labels are dropped
+/// so that the stacks of different threads and runs meet.
+///
+/// Unlike `subtract::subtract`, the base isn't scaled by the durations: both
+/// profiles do the same work, so a run taking longer is the regression itself.
+pub fn delta(base: &pb::Profile, new: &pb::Profile) -> io::Result<pb::Profile> {
+    let unlabeled = |p: &pb::Profile, sign: i64| {
+        let mut p = p.clone();
+        for i in 0..p.function.len() {
+            let name = p
+                .string_table
+                .get(p.function[i].name as usize)
+                .map(|n| strip_hash(n))
+                .unwrap_or_default();
+            // the mangled names carry the hashes too
+            p.function[i].name = p.string_table.len() as i64;
+            p.function[i].system_name = p.function[i].name;
+            p.string_table.push(name);
+        }
+        // the binaries of two builds differ
+        for m in p.mapping.iter_mut() {
+            m.build_id = 0;
+        }
+        for s in p.sample.iter_mut() {
+            s.label.clear();
+            for v in s.value.iter_mut() {
+                *v *= sign;
+            }
+        }
+        p
+    };
+    let mut delta = merge::merge(&[&unlabeled(new, 1), &unlabeled(base, -1)])?;
+    delta.sample.retain(|s| s.value.iter().any(|v| *v != 0));
+    delta.comment.push(delta.string_table.len() as i64);
+    delta
+        .string_table
+        .push("delta of the new profile from the base".to_string());
+    Ok(delta)
+}
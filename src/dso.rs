@@ -0,0 +1,96 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+
+use crate::pprof::PprofConverterBuilder;
+
+/// Samples per shared object (the binary, libc, the kernel, ...) in
+/// `perf script` output.
+#[derive(Default)]
+pub struct Dsos {
+    total: u64,
+    self_count: HashMap<String, u64>,
+    total_count: HashMap<String, u64>,
+}
+
+impl Dsos {
+    /// adds the samples in `perf script` output
+    pub fn update<R>(&mut self, reader: R) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        PprofConverterBuilder::default().read_samples(reader, |sample| {
+            self.total += 1;
+            if let Some(dso) = sample.modules.first() {
+                *self.self_count.entry(dso.clone()).or_insert(0) += 1;
+            }
+            let seen: HashSet<&String> = sample.modules.iter().collect();
+            for dso in seen {
+                *self.total_count.entry(dso.clone()).or_insert(0) += 1;
+            }
+            Ok(())
+        })
+    }
+
+    /// the number of samples
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// shared objects sorted by self samples with their self and total samples
+    pub fn hottest(&self) -> Vec<(&str, u64, u64)> {
+        let mut v: Vec<(&str, u64, u64)> = self
+            .total_count
+            .iter()
+            .map(|(k, v)| (k.as_str(), self.self_count.get(k).copied().unwrap_or(0), *v))
+            .collect();
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+        v
+    }
+
+    /// renders the shared objects as a text table
+    pub fn render(&self) -> String {
+        let mut s = format!("{:>8} {:>8}  {}\n", "Self%", "Total%", "Object");
+        let total = self.total.max(1) as f64;
+        for (dso, self_count, total_count) in self.hottest() {
+            let _ = writeln!(
+                s,
+                "{:>7.2}% {:>7.2}%  {}",
+                self_count as f64 * 100.0 / total,
+                total_count as f64 * 100.0 / total,
+                dso
+            );
+        }
+        s
+    }
+
+    /// renders the shared objects as CSV
+    pub fn to_csv(&self) -> String {
+        let mut s = String::from("object,self_samples,total_samples\n");
+        for (dso, self_count, total_count) in self.hottest() {
+            let _ = writeln!(
+                s,
+                "\"{}\",{},{}",
+                dso.replace('"', "\"\""),
+                self_count,
+                total_count
+            );
+        }
+        s
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the CPU samples of an ETW trace, as `xperf -i trace.etl -symbols -a
+//! dumper -stacktrace` prints them, so that Windows recordings go through
+//! the same pipeline as perf's.
+
+use std::io;
+
+use crate::pprof::{pb, SampleBuilder};
+
+// SampledProfile, TimeStamp, Process Name ( PID), ThreadID, PrgrmCtr, CPU, ThreadStartImage!Function, Image!Function, Count, SubCount, SampledProfile type
+// SampledProfile,      12345,        foo.exe (1234),       5678, 0x00007ff612341234,   0, ntdll.dll!RtlUserThreadStart, foo.exe!main, 1, 0, Unbatched
+// Stack, TimeStamp, ThreadID, No., Address, Image!Function
+// Stack,      12345,       5678,    1, 0x00007ff612341234, foo.exe!main
+struct Current {
+    timestamp: String,
+    pid: String,
+    tid: String,
+    image: String,
+    frames: Vec<String>,
+}
+
+// "foo.exe (1234)" to ("foo.exe", "1234")
+fn process(field: &str) -> Option<(&str, &str)> {
+    let (image, pid) = field.rsplit_once('(')?;
+    Some((image.trim(), pid.trim_end_matches(')').trim()))
+}
+
+// "foo.exe!main" to "main"; the image stays when the function is unknown
+fn frame_name(field: &str) -> String {
+    match field.split_once('!') {
+        Some((_, f)) if !f.is_empty() && f != "?" && f != "Unknown" => f.to_string(),
+        _ => field.to_string(),
+    }
+}
+
+fn flush(builder: &mut SampleBuilder, current: Option<Current>) {
+    if let Some(mut c) = current {
+        if c.frames.is_empty() {
+            c.frames.push(frame_name(&c.image));
+        }
+        let frames: Vec<&str> = c.frames.iter().map(|f| f.as_str()).collect();
+        builder.add(&frames, &[("pid", &c.pid), ("tid", &c.tid)], 1);
+    }
+}
+
+/// Reads the samples of the xperf dumper output, of the process `pid` if any,
+/// with the `pid` and `tid` labels. The stacks follow their samples, leaf first.
+pub fn decode_xperf<R>(mut reader: R, pid: Option<u32>) -> io::Result<pb::Profile>
+where
+    R: io::BufRead,
+{
+    let pid = pid.map(|p| p.to_string());
+    let mut builder = SampleBuilder::default();
+    let mut current: Option<Current> = None;
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            break;
+        }
+        let fields: Vec<&str> = buf.trim().splitn(11, ',').map(|f| f.trim()).collect();
+        match fields.as_slice() {
+            ["SampledProfile", timestamp, process_field, tid, _, _, _, image, ..] => {
+                flush(&mut builder, current.take());
+                let p = match process(process_field) {
+                    Some((_, p)) => p,
+                    None => continue,
+                };
+                // the idle process
+                if p == "0" || matches!(pid.as_deref(), Some(pid) if pid != p) {
+                    continue;
+                }
+                current = Some(Current {
+                    timestamp: timestamp.to_string(),
+                    pid: p.to_string(),
+                    tid: tid.to_string(),
+                    image: image.to_string(),
+                    frames: Vec::new(),
+                });
+            }
+            ["Stack", timestamp, tid, _, _, rest @ ..] => {
+                if let Some(c) = current.as_mut() {
+                    if c.timestamp == *timestamp && c.tid == *tid {
+                        c.frames.push(frame_name(&rest.join(",")));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    flush(&mut builder, current.take());
+    Ok(builder.build())
+}
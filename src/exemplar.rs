@@ -0,0 +1,91 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! References from metrics to profiles: an OpenMetrics exemplar on a CPU
+//! usage series carries the id of the profile of that window and its time
+//! range, so that a dashboard can link a spike to the profile.
+
+use serde::Serialize;
+
+use crate::pprof::pb;
+use crate::sidecar;
+
+/// The reference to a profile attached to a metric.
+#[derive(Debug, Serialize)]
+pub struct Exemplar {
+    /// the hash of the profile file, stable across uploads of the same file
+    pub profile_id: String,
+    /// seconds since the epoch
+    pub start: f64,
+    pub end: f64,
+    /// the cpu time in the profile, if it's sampled in time
+    pub cpu_seconds: Option<f64>,
+}
+
+/// the exemplar of a profile, `content` being the file as stored
+pub fn exemplar(profile: &pb::Profile, content: &[u8]) -> Exemplar {
+    let cpu = profile.sample_type.iter().position(|t| {
+        profile
+            .string_table
+            .get(t.unit as usize)
+            .map(|s| s.as_str())
+            == Some("nanoseconds")
+    });
+    let start = profile.time_nanos as f64 / 1e9;
+    Exemplar {
+        profile_id: format!("{:016x}", sidecar::hash_bytes(sidecar::HASH_INIT, content)),
+        start,
+        end: start + profile.duration_nanos as f64 / 1e9,
+        cpu_seconds: cpu.map(|i| {
+            profile
+                .sample
+                .iter()
+                .map(|s| s.value.get(i).copied().unwrap_or(0))
+                .sum::<i64>() as f64
+                / 1e9
+        }),
+    }
+}
+
+impl Exemplar {
+    /// an exposition of the counter family `metric` with a `_total` sample
+    /// carrying the exemplar, in the OpenMetrics text format, which allows
+    /// exemplars on counters and histogram buckets only; the labels stay under
+    /// the 128 characters allowed
+    pub fn to_openmetrics(&self, metric: &str, labels: &[(String, String)]) -> String {
+        let family = metric.strip_suffix("_total").unwrap_or(metric);
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        let value = self.cpu_seconds.unwrap_or(0.0);
+        format!(
+            "# TYPE {} counter\n{}_total{} {} # {{profile_id=\"{}\",start=\"{:.3}\",end=\"{:.3}\"}} {} {:.3}\n# EOF",
+            family,
+            family,
+            if labels.is_empty() {
+                String::new()
+            } else {
+                format!("{{{}}}", labels.join(","))
+            },
+            value,
+            self.profile_id,
+            self.start,
+            self.end,
+            value,
+            self.end
+        )
+    }
+}
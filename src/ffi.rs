@@ -0,0 +1,86 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::pprof::{self, pb};
+
+/// Whether the function looks like Rust from its demangled name; C++ names
+/// also have paths but end with the parameter list.
+pub fn is_rust(name: &str) -> bool {
+    name.starts_with("__rust")
+        || name.starts_with("rust_")
+        || (name.contains("::") && !name.ends_with(')') && !name.ends_with(" const"))
+}
+
+/// Time spent in foreign code, per Rust call site.
+#[derive(Default)]
+pub struct Ffi {
+    pub total: i64,
+    /// (Rust caller, foreign callee), value
+    pub calls: HashMap<(String, String), i64>,
+}
+
+impl Ffi {
+    /// Attributes the `index`-th sample value of the samples whose leaf is
+    /// foreign code to the innermost call from Rust into foreign code.
+    pub fn new(profile: &pb::Profile, index: usize) -> Self {
+        let mut ffi = Ffi::default();
+        for (names, s) in pprof::stacks(profile) {
+            let v = s.value.get(index).copied().unwrap_or(0);
+            ffi.total += v;
+            match names.first() {
+                Some(leaf) if !is_rust(leaf) => {}
+                _ => continue,
+            }
+            // leaf first
+            if let Some(i) = names.iter().position(|n| is_rust(n)) {
+                *ffi.calls
+                    .entry((names[i].to_string(), names[i - 1].to_string()))
+                    .or_insert(0) += v;
+            }
+        }
+        ffi
+    }
+
+    /// the share of the time spent beyond the FFI boundaries
+    pub fn share(&self) -> f64 {
+        self.calls.values().sum::<i64>() as f64 / self.total.max(1) as f64
+    }
+
+    /// renders the hottest `n` call sites as a text table
+    pub fn render(&self, n: usize) -> String {
+        let mut v: Vec<(&(String, String), &i64)> = self.calls.iter().collect();
+        v.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let total = self.total.max(1) as f64;
+        let mut s = format!(
+            "{:.2}% of the time is spent beyond FFI boundaries\n\n{:>8}  {}\n",
+            self.share() * 100.0,
+            "Total%",
+            "Rust caller -> foreign function"
+        );
+        for ((caller, callee), value) in v.into_iter().take(n) {
+            let _ = writeln!(
+                s,
+                "{:>7.2}%  {} -> {}",
+                *value as f64 * 100.0 / total,
+                caller,
+                callee
+            );
+        }
+        s
+    }
+}
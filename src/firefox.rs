@@ -0,0 +1,316 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The processed profile format of the Firefox Profiler, which
+//! profiler.firefox.com opens and shares by links. Unlike pprof, it keeps
+//! each sample with its time and thread, and the tables intern the stacks
+//! as prefix trees of frames.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::jsonl::{self, Event};
+use crate::pprof::PprofConverterBuilder;
+
+/// the version of the processed format written, which the profiler
+/// upgrades from
+const PROCESSED_VERSION: u32 = 47;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Category {
+    name: &'static str,
+    color: &'static str,
+    subcategories: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Meta {
+    /// milliseconds
+    interval: f64,
+    /// milliseconds since the epoch
+    start_time: f64,
+    process_type: u32,
+    product: &'static str,
+    stackwalk: u32,
+    version: u32,
+    preprocessed_profile_version: u32,
+    categories: Vec<Category>,
+    marker_schema: Vec<()>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Samples {
+    length: usize,
+    stack: Vec<Option<usize>>,
+    /// milliseconds
+    time: Vec<f64>,
+    weight: Vec<u64>,
+    weight_type: &'static str,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Markers {
+    length: usize,
+    data: Vec<()>,
+    name: Vec<usize>,
+    start_time: Vec<f64>,
+    end_time: Vec<f64>,
+    phase: Vec<u32>,
+    category: Vec<u32>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct StackTable {
+    length: usize,
+    frame: Vec<usize>,
+    prefix: Vec<Option<usize>>,
+    category: Vec<u32>,
+    subcategory: Vec<u32>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct FrameTable {
+    length: usize,
+    address: Vec<i64>,
+    inline_depth: Vec<u32>,
+    category: Vec<Option<u32>>,
+    subcategory: Vec<Option<u32>>,
+    func: Vec<usize>,
+    native_symbol: Vec<Option<usize>>,
+    #[serde(rename = "innerWindowID")]
+    inner_window_id: Vec<Option<u32>>,
+    implementation: Vec<Option<usize>>,
+    line: Vec<Option<u32>>,
+    column: Vec<Option<u32>>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct FuncTable {
+    length: usize,
+    name: Vec<usize>,
+    #[serde(rename = "isJS")]
+    is_js: Vec<bool>,
+    #[serde(rename = "relevantForJS")]
+    relevant_for_js: Vec<bool>,
+    resource: Vec<i32>,
+    file_name: Vec<Option<usize>>,
+    line_number: Vec<Option<u32>>,
+    column_number: Vec<Option<u32>>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ResourceTable {
+    length: usize,
+    lib: Vec<usize>,
+    name: Vec<usize>,
+    host: Vec<Option<usize>>,
+    r#type: Vec<u32>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct NativeSymbols {
+    length: usize,
+    lib_index: Vec<usize>,
+    address: Vec<u64>,
+    name: Vec<usize>,
+    function_size: Vec<Option<u32>>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Thread {
+    process_type: &'static str,
+    process_startup_time: f64,
+    process_shutdown_time: Option<f64>,
+    register_time: f64,
+    unregister_time: Option<f64>,
+    paused_ranges: Vec<()>,
+    name: String,
+    process_name: String,
+    is_main_thread: bool,
+    pid: String,
+    tid: u32,
+    samples: Samples,
+    markers: Markers,
+    stack_table: StackTable,
+    frame_table: FrameTable,
+    func_table: FuncTable,
+    resource_table: ResourceTable,
+    native_symbols: NativeSymbols,
+    string_array: Vec<String>,
+    /// interned (prefix, frame) to stack, not written
+    #[serde(skip)]
+    stacks: HashMap<(Option<usize>, usize), usize>,
+    /// interned function name to frame, not written
+    #[serde(skip)]
+    frames: HashMap<String, usize>,
+}
+
+impl Thread {
+    fn new(pid: u32, tid: u32, comm: &str) -> Self {
+        Thread {
+            process_type: "default",
+            name: comm.to_string(),
+            process_name: comm.to_string(),
+            is_main_thread: pid == tid,
+            pid: pid.to_string(),
+            tid,
+            samples: Samples {
+                weight_type: "samples",
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // a frame per function, and the function name in the strings
+    fn frame(&mut self, name: &str) -> usize {
+        if let Some(frame) = self.frames.get(name) {
+            return *frame;
+        }
+        self.string_array.push(name.to_string());
+        let func = self.func_table.length;
+        let t = &mut self.func_table;
+        t.name.push(self.string_array.len() - 1);
+        t.is_js.push(false);
+        t.relevant_for_js.push(false);
+        t.resource.push(-1);
+        t.file_name.push(None);
+        t.line_number.push(None);
+        t.column_number.push(None);
+        t.length += 1;
+
+        let frame = self.frame_table.length;
+        let t = &mut self.frame_table;
+        t.address.push(-1);
+        t.inline_depth.push(0);
+        t.category.push(Some(0));
+        t.subcategory.push(Some(0));
+        t.func.push(func);
+        t.native_symbol.push(None);
+        t.inner_window_id.push(None);
+        t.implementation.push(None);
+        t.line.push(None);
+        t.column.push(None);
+        t.length += 1;
+
+        self.frames.insert(name.to_string(), frame);
+        frame
+    }
+
+    // the stack of the frames, leaf first
+    fn stack(&mut self, frames: &[String]) -> Option<usize> {
+        let mut prefix = None;
+        for name in frames.iter().rev() {
+            let frame = self.frame(name);
+            let next = self.stack_table.length;
+            let stack = *self.stacks.entry((prefix, frame)).or_insert(next);
+            if stack == next {
+                let t = &mut self.stack_table;
+                t.frame.push(frame);
+                t.prefix.push(prefix);
+                t.category.push(0);
+                t.subcategory.push(0);
+                t.length += 1;
+            }
+            prefix = Some(stack);
+        }
+        prefix
+    }
+
+    fn sample(&mut self, e: &Event, start: f64) {
+        let stack = self.stack(&e.stack);
+        let s = &mut self.samples;
+        s.stack.push(stack);
+        s.time.push((e.timestamp - start) * 1000.0);
+        s.weight.push(e.weight);
+        s.length += 1;
+    }
+}
+
+#[derive(Serialize)]
+struct Profile {
+    meta: Meta,
+    libs: Vec<()>,
+    threads: Vec<Thread>,
+}
+
+/// Converts `perf script` output into the processed profile format of the
+/// Firefox Profiler, a thread per perf thread with its samples in time.
+pub fn convert<R, W>(builder: &PprofConverterBuilder, reader: R, mut writer: W) -> io::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut events = Vec::new();
+    let mut start = f64::INFINITY;
+    jsonl::read_events(builder, reader, |e| {
+        start = start.min(e.timestamp);
+        events.push(e);
+        Ok(())
+    })?;
+    if !start.is_finite() {
+        start = 0.0;
+    }
+
+    // the shortest gap between the samples of a thread, the interval
+    let mut last: HashMap<u32, f64> = HashMap::new();
+    let mut interval = f64::INFINITY;
+    let mut threads: BTreeMap<(u32, u32), Thread> = BTreeMap::new();
+    for e in &events {
+        if let Some(t) = last.insert(e.tid, e.timestamp) {
+            if e.timestamp > t {
+                interval = interval.min((e.timestamp - t) * 1000.0);
+            }
+        }
+        threads
+            .entry((e.pid, e.tid))
+            .or_insert_with(|| Thread::new(e.pid, e.tid, &e.comm))
+            .sample(e, start);
+    }
+
+    let profile = Profile {
+        meta: Meta {
+            interval: if interval.is_finite() { interval } else { 1.0 },
+            // perf times are since boot, not the epoch
+            start_time: start * 1000.0,
+            process_type: 0,
+            product: "perf",
+            stackwalk: 1,
+            version: 24,
+            preprocessed_profile_version: PROCESSED_VERSION,
+            categories: vec![Category {
+                name: "Other",
+                color: "grey",
+                subcategories: vec!["Other"],
+            }],
+            marker_schema: Vec::new(),
+        },
+        libs: Vec::new(),
+        threads: threads.into_values().collect(),
+    };
+    serde_json::to_writer(&mut writer, &profile)?;
+    writer.flush()
+}
@@ -0,0 +1,337 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Flamegraph SVG rendered straight from a profile, with the click to zoom
+//! and the search of flamegraph.pl, without folding the stacks to text
+//! first. A frame can link to the focus view of the function in a pprof web
+//! UI.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+
+use crate::html::escape;
+use crate::pprof::{self, pb};
+
+const WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const PAD_TOP: f64 = 40.0;
+const PAD_BOTTOM: f64 = 24.0;
+const PAD_SIDE: f64 = 10.0;
+const FONT_SIZE: f64 = 12.0;
+/// the average width of a character to fit the labels
+const FONT_WIDTH: f64 = 0.59;
+/// frames narrower than this are left out
+const MIN_WIDTH: f64 = 0.1;
+
+const SCRIPT: &str = r#"
+var frames = document.getElementById("frames");
+var details = document.getElementById("details");
+var matched = document.getElementById("matched");
+var width = WIDTH - 2 * SIDE;
+function fit(g) {
+  var r = g.querySelector("rect"), t = g.querySelector("text");
+  var name = g.getAttribute("data-name");
+  var w = parseFloat(r.getAttribute("width"));
+  var n = Math.floor(w / (FONT * FONT_WIDTH));
+  t.setAttribute("x", parseFloat(r.getAttribute("x")) + 3);
+  t.textContent = n < 3 ? "" : name.length <= n ? name : name.substring(0, n - 2) + "..";
+}
+function zoom(target) {
+  var x = parseFloat(target.getAttribute("data-x"));
+  var w = parseFloat(target.getAttribute("data-w"));
+  var depth = parseInt(target.getAttribute("data-depth"));
+  var scale = width / w;
+  for (var g of frames.children) {
+    var gx = parseFloat(g.getAttribute("data-x"));
+    var gw = parseFloat(g.getAttribute("data-w"));
+    var r = g.querySelector("rect");
+    if (gx + gw <= x || gx >= x + w) {
+      g.style.display = "none";
+      continue;
+    }
+    g.style.display = "";
+    if (parseInt(g.getAttribute("data-depth")) < depth) {
+      r.setAttribute("x", SIDE);
+      r.setAttribute("width", width);
+      g.style.opacity = 0.5;
+    } else {
+      r.setAttribute("x", SIDE + (gx - x) * scale);
+      r.setAttribute("width", gw * scale);
+      g.style.opacity = "";
+    }
+    fit(g);
+  }
+}
+function reset() {
+  for (var g of frames.children) {
+    var r = g.querySelector("rect");
+    r.setAttribute("x", g.getAttribute("data-x"));
+    r.setAttribute("width", g.getAttribute("data-w"));
+    g.style.display = "";
+    g.style.opacity = "";
+    fit(g);
+  }
+}
+function search() {
+  var term = prompt("Search (regular expression)", "");
+  var re = term ? new RegExp(term) : null;
+  var spans = [];
+  for (var g of frames.children) {
+    var r = g.querySelector("rect");
+    var hit = re && re.test(g.getAttribute("data-name"));
+    r.setAttribute("fill", hit ? "rgb(230,0,230)" : r.getAttribute("data-fill"));
+    if (hit) {
+      spans.push([parseFloat(g.getAttribute("data-x")), parseFloat(g.getAttribute("data-w"))]);
+    }
+  }
+  // nested matches count once
+  spans.sort(function (a, b) { return a[0] - b[0]; });
+  var sum = 0, end = SIDE;
+  for (var s of spans) {
+    if (s[0] + s[1] > end) {
+      sum += s[0] + s[1] - Math.max(s[0], end);
+      end = s[0] + s[1];
+    }
+  }
+  matched.textContent = re ? "Matched: " + (100 * sum / width).toFixed(1) + "%" : "";
+}
+frames.addEventListener("click", function (e) {
+  var g = e.target.closest("g");
+  if (g && !e.target.closest("a")) zoom(g);
+});
+frames.addEventListener("mouseover", function (e) {
+  var g = e.target.closest("g");
+  if (g) details.textContent = g.querySelector("title").textContent;
+});
+frames.addEventListener("mouseout", function () { details.textContent = ""; });
+document.getElementById("reset").addEventListener("click", reset);
+document.getElementById("search").addEventListener("click", search);
+window.addEventListener("keydown", function (e) {
+  if ((e.ctrlKey || e.metaKey) && e.key == "f") { e.preventDefault(); search(); }
+  if (e.key == "Escape") reset();
+});
+"#;
+
+/// Options of the flamegraph.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub title: String,
+    /// the sample type to weigh the frames by
+    pub index: usize,
+    /// the base URL of a pprof web UI, e.g. `http://localhost:8080/ui/`, to
+    /// link the frames to the focus view of their function
+    pub link: Option<String>,
+}
+
+#[derive(Default)]
+struct Node<'a> {
+    value: i64,
+    children: BTreeMap<&'a str, Node<'a>>,
+}
+
+// the color of flamegraph.pl's hot palette, stable per name
+fn color(name: &str) -> (u8, u8, u8) {
+    let mut h: u32 = 2166136261;
+    for b in name.bytes() {
+        h = (h ^ b as u32).wrapping_mul(16777619);
+    }
+    let v1 = (h & 0xff) as f64 / 255.0;
+    let v2 = ((h >> 8) & 0xff) as f64 / 255.0;
+    let v3 = ((h >> 16) & 0xff) as f64 / 255.0;
+    (
+        (205.0 + 50.0 * v3) as u8,
+        (230.0 * v1) as u8,
+        (55.0 * v2) as u8,
+    )
+}
+
+// percent-encodes everything but unreserved characters
+fn url_encode(s: &str) -> String {
+    let mut e = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            e.push(b as char);
+        } else {
+            let _ = write!(e, "%{:02X}", b);
+        }
+    }
+    e
+}
+
+// escapes the metacharacters of a regular expression
+fn regex_quote(s: &str) -> String {
+    let mut q = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            q.push('\\');
+        }
+        q.push(c);
+    }
+    q
+}
+
+struct Renderer<'a> {
+    options: &'a Options,
+    total: i64,
+    unit: &'a str,
+    height: f64,
+    frames: String,
+}
+
+impl Renderer<'_> {
+    fn frame(&mut self, name: &str, node: &Node, x: f64, depth: usize) {
+        let w = node.value as f64 / self.total as f64 * (WIDTH - 2.0 * PAD_SIDE);
+        if w < MIN_WIDTH {
+            return;
+        }
+        let y = self.height - PAD_BOTTOM - (depth + 1) as f64 * FRAME_HEIGHT;
+        let (r, g, b) = color(name);
+        let fill = format!("rgb({},{},{})", r, g, b);
+        let _ = write!(
+            self.frames,
+            "<g data-name=\"{}\" data-x=\"{:.2}\" data-w=\"{:.2}\" data-depth=\"{}\"><title>{} ({} {}, {:.2}%)</title>",
+            escape(name),
+            x,
+            w,
+            depth,
+            escape(name),
+            node.value,
+            self.unit,
+            node.value as f64 * 100.0 / self.total as f64
+        );
+        let _ = write!(
+            self.frames,
+            "<rect x=\"{:.2}\" y=\"{:.1}\" width=\"{:.2}\" height=\"{}\" fill=\"{}\" data-fill=\"{}\" rx=\"2\"/>",
+            x,
+            y,
+            w,
+            FRAME_HEIGHT - 1.0,
+            fill,
+            fill
+        );
+        let chars = (w / (FONT_SIZE * FONT_WIDTH)) as usize;
+        let label = if chars < 3 {
+            String::new()
+        } else if name.chars().count() <= chars {
+            escape(name)
+        } else {
+            format!(
+                "{}..",
+                escape(&name.chars().take(chars - 2).collect::<String>())
+            )
+        };
+        let text = format!(
+            "<text x=\"{:.2}\" y=\"{:.1}\">{}</text>",
+            x + 3.0,
+            y + FRAME_HEIGHT - 4.0,
+            label
+        );
+        match self.options.link.as_ref() {
+            // the root frame is all the samples
+            Some(link) if depth > 0 => {
+                let _ = write!(
+                    self.frames,
+                    "<a href=\"{}flamegraph?f={}\" target=\"_blank\">{}</a>",
+                    escape(link),
+                    url_encode(&format!("^{}$", regex_quote(name))),
+                    text
+                );
+            }
+            _ => self.frames.push_str(&text),
+        }
+        self.frames.push_str("</g>\n");
+
+        let mut x = x;
+        for (name, child) in &node.children {
+            self.frame(name, child, x, depth + 1);
+            x += child.value as f64 / self.total as f64 * (WIDTH - 2.0 * PAD_SIDE);
+        }
+    }
+}
+
+/// renders the flamegraph of the profile as SVG, root at the bottom
+pub fn render<W: io::Write>(
+    profile: &pb::Profile,
+    options: &Options,
+    mut writer: W,
+) -> io::Result<()> {
+    let mut root = Node::default();
+    let mut depth = 0;
+    for (names, sample) in pprof::stacks(profile) {
+        let v = sample.value.get(options.index).copied().unwrap_or(0);
+        if names.is_empty() || v <= 0 {
+            continue;
+        }
+        depth = depth.max(names.len());
+        root.value += v;
+        let mut node = &mut root;
+        for name in names.iter().rev() {
+            node = node.children.entry(name).or_default();
+            node.value += v;
+        }
+    }
+    let unit = profile
+        .sample_type
+        .get(options.index)
+        .and_then(|t| profile.string_table.get(t.unit as usize))
+        .map_or("samples", |s| s.as_str());
+
+    let height = PAD_TOP + PAD_BOTTOM + (depth + 1) as f64 * FRAME_HEIGHT;
+    let mut renderer = Renderer {
+        options,
+        total: root.value.max(1),
+        unit,
+        height,
+        frames: String::new(),
+    };
+    if root.value > 0 {
+        renderer.frame("all", &root, PAD_SIDE, 0);
+    }
+
+    let script = SCRIPT
+        .replace("FONT_WIDTH", &FONT_WIDTH.to_string())
+        .replace("FONT", &FONT_SIZE.to_string())
+        .replace("WIDTH", &WIDTH.to_string())
+        .replace("SIDE", &PAD_SIDE.to_string());
+    write!(
+        writer,
+        r#"<?xml version="1.0" standalone="no"?>
+<svg version="1.1" width="{width}" height="{height}" viewBox="0 0 {width} {height}" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
+<style>text {{ font-family: Verdana, sans-serif; font-size: {font}px; fill: black; }} #frames rect:hover {{ stroke: black; stroke-width: 0.5; }} #frames {{ cursor: pointer; }} #reset, #search {{ cursor: pointer; }}</style>
+<rect width="100%" height="100%" fill="rgb(248,248,248)"/>
+<text x="{center}" y="24" text-anchor="middle" style="font-size: 17px">{title}</text>
+<text id="reset" x="{side}" y="24">Reset Zoom</text>
+<text id="search" x="{search}" y="24" text-anchor="end">Search</text>
+<text id="details" x="{side}" y="{bottom}"> </text>
+<text id="matched" x="{search}" y="{bottom}" text-anchor="end"></text>
+<g id="frames">
+{frames}</g>
+<script type="text/ecmascript"><![CDATA[{script}]]></script>
+</svg>
+"#,
+        width = WIDTH,
+        height = height,
+        font = FONT_SIZE,
+        center = WIDTH / 2.0,
+        title = escape(&options.title),
+        side = PAD_SIDE,
+        search = WIDTH - PAD_SIDE,
+        bottom = height - 8.0,
+        frames = renderer.frames,
+        script = script,
+    )?;
+    writer.flush()
+}
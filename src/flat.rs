@@ -0,0 +1,143 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::pprof::{self, pb};
+use crate::unit::Unit;
+
+/// Self and total values per function.
+#[derive(Default)]
+pub struct FlatProfile {
+    pub total: i64,
+    /// function name, (self, total)
+    pub functions: HashMap<String, (i64, i64)>,
+}
+
+impl FlatProfile {
+    /// aggregates the `index`-th sample value of the profile per function
+    pub fn new(profile: &pb::Profile, index: usize) -> Self {
+        let mut flat = FlatProfile::default();
+        let mut seen = HashSet::new();
+        for (names, s) in pprof::stacks(profile) {
+            let v = s.value.get(index).copied().unwrap_or(0);
+            flat.total += v;
+            seen.clear();
+            if let Some(leaf) = names.first() {
+                flat.functions.entry(leaf.to_string()).or_insert((0, 0)).0 += v;
+            }
+            for name in names {
+                // count recursive functions once per stack
+                if seen.insert(name) {
+                    flat.functions.entry(name.to_string()).or_insert((0, 0)).1 += v;
+                }
+            }
+        }
+        flat
+    }
+
+    /// self and total values as fractions of the whole profile
+    pub fn share(&self, name: &str) -> (f64, f64) {
+        let total = self.total.max(1) as f64;
+        self.functions
+            .get(name)
+            .map_or((0.0, 0.0), |(s, t)| (*s as f64 / total, *t as f64 / total))
+    }
+
+    /// Renders the top `n` functions by self value, or by total value with
+    /// `cumulative`, as a table of the self value, the running sum of it, the
+    /// total value, the function and its module in `unit`, like `pprof -top`.
+    pub fn render_top(
+        &self,
+        modules: &HashMap<String, String>,
+        n: usize,
+        cumulative: bool,
+        unit: Unit,
+    ) -> String {
+        let mut functions: Vec<(&String, &(i64, i64))> = self.functions.iter().collect();
+        if cumulative {
+            functions.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+        } else {
+            functions.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+        }
+        let (width, headers) = if unit == Unit::Percent {
+            (7, ["Self%", "Sum%", "Total%"])
+        } else {
+            (12, ["Self", "Sum", "Total"])
+        };
+        let mut s = format!(
+            "{:>w$} {:>w$} {:>w$}  {:<60} {}\n",
+            headers[0],
+            headers[1],
+            headers[2],
+            "Function",
+            "Module",
+            w = width
+        );
+        let mut sum = 0;
+        for (name, (self_value, total_value)) in functions.into_iter().take(n) {
+            sum += self_value;
+            let _ = writeln!(
+                s,
+                "{:>w$} {:>w$} {:>w$}  {:<60} {}",
+                unit.format(*self_value, self.total),
+                unit.format(sum, self.total),
+                unit.format(*total_value, self.total),
+                name,
+                modules.get(name).map_or("", |m| m.as_str()),
+                w = width
+            );
+        }
+        s
+    }
+}
+
+/// the file name of the binary or the library of each function, from the
+/// mappings of its locations
+pub fn modules(profile: &pb::Profile) -> HashMap<String, String> {
+    let string = |i: i64| {
+        profile
+            .string_table
+            .get(i as usize)
+            .map_or("", |s| s.as_str())
+    };
+    let mapping: HashMap<u64, &str> = profile
+        .mapping
+        .iter()
+        .map(|m| {
+            let path = string(m.filename);
+            (m.id, path.rsplit('/').next().unwrap_or(path))
+        })
+        .collect();
+    let function: HashMap<u64, &str> = profile
+        .function
+        .iter()
+        .map(|f| (f.id, string(f.name)))
+        .collect();
+    let mut modules = HashMap::new();
+    for l in &profile.location {
+        if let Some(m) = mapping.get(&l.mapping_id).filter(|m| !m.is_empty()) {
+            for line in &l.line {
+                if let Some(f) = function.get(&line.function_id) {
+                    modules
+                        .entry(f.to_string())
+                        .or_insert_with(|| m.to_string());
+                }
+            }
+        }
+    }
+    modules
+}
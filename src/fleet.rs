@@ -0,0 +1,48 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+
+use crate::merge;
+use crate::pprof::pb;
+
+/// the host the profile was recorded on, from the comments of `metadata()`
+pub fn hostname(profile: &pb::Profile) -> Option<&str> {
+    profile
+        .comment
+        .iter()
+        .filter_map(|c| profile.string_table.get(*c as usize))
+        .find_map(|c| c.strip_prefix("hostname: "))
+}
+
+/// Merges the profiles of services into a fleet profile; the samples of each
+/// are labeled with `service` and `host`, from the hostname in the comments.
+pub fn merge(profiles: &[(&str, &pb::Profile)]) -> io::Result<pb::Profile> {
+    let labels: Vec<[(&str, &str); 2]> = profiles
+        .iter()
+        .map(|(service, profile)| {
+            [
+                ("service", *service),
+                ("host", hostname(profile).unwrap_or("unknown")),
+            ]
+        })
+        .collect();
+    let profiles: Vec<(&pb::Profile, &[(&str, &str)])> = profiles
+        .iter()
+        .zip(&labels)
+        .map(|((_, profile), labels)| (*profile, &labels[..]))
+        .collect();
+    merge::merge_labeled(&profiles)
+}
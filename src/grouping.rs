@@ -0,0 +1,147 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use regex::Regex;
+use serde::Deserialize;
+use std::io;
+
+/// What to do with the frames matching a rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// runs of the frames become one frame named after the group
+    #[default]
+    Collapse,
+    /// the frames are dropped
+    Hide,
+    /// the frames are kept and the samples are labeled `group=<group>`
+    Highlight,
+}
+
+/// Frames matching the pattern belong to `group`.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub pattern: Regex,
+    pub group: String,
+    pub action: Action,
+}
+
+impl Rule {
+    /// a rule collapsing the frames matching the pattern; fails on an invalid regex
+    pub fn new(pattern: &str, group: &str) -> io::Result<Self> {
+        Ok(Rule {
+            pattern: Regex::new(pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)))?,
+            group: group.to_string(),
+            action: Action::Collapse,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    #[serde(rename = "match")]
+    pattern: String,
+    group: String,
+    #[serde(default)]
+    action: Action,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleConfig>,
+}
+
+/// Parses grouping rules; the first matching rule wins:
+///
+/// ```toml
+/// [[rule]]
+/// match = "^<?(std|core|alloc)::"
+/// group = "std"
+///
+/// [[rule]]
+/// match = "^<?my_app::"
+/// group = "ours"
+/// action = "highlight"
+/// ```
+pub fn parse(s: &str) -> io::Result<Vec<Rule>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let config: Config = toml::from_str(s).map_err(|e| invalid(format!("{}", e)))?;
+    config
+        .rules
+        .into_iter()
+        .map(|r| {
+            Ok(Rule {
+                pattern: Regex::new(&r.pattern).map_err(|e| invalid(format!("{}", e)))?,
+                group: r.group,
+                action: r.action,
+            })
+        })
+        .collect()
+}
+
+/// reads grouping rules from a file
+pub fn load(path: &str) -> io::Result<Vec<Rule>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+/// the names of the built-in presets
+pub const PRESETS: [&str; 4] = ["tokio", "rayon", "async-std", "crossbeam"];
+
+// glue between the runtimes and the closures they run
+const STD_GLUE: [&str; 5] = [
+    r"^<?std::(rt|thread|sys|sys_common|panic|panicking)::",
+    r"^<?core::ops::function::",
+    r"^<?core::panic::unwind_safe::",
+    r"^<alloc::boxed::Box<.*> as core::ops::function::",
+    r"__rust_begin_short_backtrace",
+];
+
+/// the rules of a built-in preset, collapsing the plumbing frames of the runtime
+pub fn preset(name: &str) -> Option<Vec<Rule>> {
+    let (group, patterns): (&str, &[&str]) = match name {
+        "tokio" => ("[tokio]", &[r"^<?tokio::", r"^<?mio::"]),
+        "rayon" => (
+            "[rayon]",
+            &[r"^<?rayon::", r"^<?rayon_core::", r"^<?crossbeam_\w+::"],
+        ),
+        "async-std" => (
+            "[async-std]",
+            &[
+                r"^<?async_std::",
+                r"^<?async_executor::",
+                r"^<?async_global_executor::",
+                r"^<?async_task::",
+                r"^<?async_io::",
+                r"^<?futures_lite::",
+            ],
+        ),
+        "crossbeam" => ("[crossbeam]", &[r"^<?crossbeam(_\w+)?::"]),
+        _ => return None,
+    };
+    Some(
+        patterns
+            .iter()
+            .chain(STD_GLUE.iter())
+            .map(|p| Rule::new(p, group).expect("invalid preset pattern"))
+            .collect(),
+    )
+}
+
+/// the first rule matching the function, if any
+pub fn rule_of<'a>(rules: &'a [Rule], func: &str) -> Option<&'a Rule> {
+    rules.iter().find(|r| r.pattern.is_match(func))
+}
@@ -0,0 +1,326 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::TimeZone;
+use std::fmt::Write;
+use std::io;
+
+use crate::budget::Verdict;
+use crate::diff::Change;
+use crate::flamegraph;
+use crate::flat::FlatProfile;
+use crate::pprof::{self, pb};
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { padding: 2px 8px; text-align: right; }
+td:last-child, th:last-child { text-align: left; font-family: monospace; }
+tr:nth-child(even) { background: #f4f4f4; }
+.regression { color: #c00; }
+.improvement { color: #080; }
+.noise { color: #888; }
+.tabs button { padding: 4px 12px; border: 1px solid #ccc; background: #f4f4f4; cursor: pointer; }
+.tabs button.active { background: #fff; border-bottom-color: #fff; }
+.tab { display: none; }
+.tab.active { display: block; }
+iframe { width: 100%; border: none; }";
+
+const TABS_SCRIPT: &str = "function show(i) {
+  document.querySelectorAll('.tabs button').forEach(function (b, j) { b.classList.toggle('active', i == j); });
+  document.querySelectorAll('.tab').forEach(function (t, j) { t.classList.toggle('active', i == j); });
+}
+document.querySelectorAll('iframe').forEach(function (f) {
+  f.addEventListener('load', function () { f.style.height = f.contentDocument.documentElement.scrollHeight + 'px'; });
+});";
+
+/// escapes text for HTML
+pub fn escape(s: &str) -> String {
+    let mut e = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => e.push_str("&amp;"),
+            '<' => e.push_str("&lt;"),
+            '>' => e.push_str("&gt;"),
+            '"' => e.push_str("&quot;"),
+            _ => e.push(c),
+        }
+    }
+    e
+}
+
+fn table<'a, I>(s: &mut String, title: &str, class: &str, changes: I)
+where
+    I: Iterator<Item = &'a Change>,
+{
+    let _ = writeln!(s, "<h2>{}</h2>", title);
+    s.push_str("<table>\n<tr><th>Base%</th><th>New%</th><th>Delta</th><th>&plusmn;95%</th><th>Function</th></tr>\n");
+    for c in changes {
+        let _ = writeln!(
+            s,
+            "<tr class=\"{}\"><td>{:.2}%</td><td>{:.2}%</td><td>{:+.2}%</td><td>{:.2}%</td><td>{}</td></tr>",
+            if c.significant() { class } else { "noise" },
+            c.base.0 * 100.0,
+            c.new.0 * 100.0,
+            c.delta() * 100.0,
+            c.error() * 100.0,
+            escape(&c.name)
+        );
+    }
+    s.push_str("</table>\n");
+}
+
+/// renders the differential flamegraph of two profiles as SVG; growth is red
+pub fn differential_flamegraph(base: &pb::Profile, new: &pb::Profile) -> io::Result<String> {
+    let mut folded = Vec::new();
+    inferno::differential::from_readers(
+        inferno::differential::Options::default(),
+        pprof::folded(base, 0).as_bytes(),
+        pprof::folded(new, 0).as_bytes(),
+        &mut folded,
+    )?;
+    let mut svg = Vec::new();
+    inferno::flamegraph::from_reader(
+        &mut inferno::flamegraph::Options::default(),
+        &*folded,
+        &mut svg,
+    )
+    .map_err(|e| io::Error::other(format!("{}", e)))?;
+    Ok(String::from_utf8_lossy(&svg).to_string())
+}
+
+/// Renders a standalone HTML report of the biggest `n` regressions and
+/// improvements with the differential flamegraph.
+pub fn diff_report(
+    title: &str,
+    base: &pb::Profile,
+    new: &pb::Profile,
+    changes: &[Change],
+    n: usize,
+) -> io::Result<String> {
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>",
+        escape(title),
+        STYLE
+    );
+    let _ = writeln!(s, "<h1>{}</h1>", escape(title));
+    s.push_str(&body(base, new, changes, n)?);
+    s.push_str("</body>\n</html>\n");
+    Ok(s)
+}
+
+fn body(base: &pb::Profile, new: &pb::Profile, changes: &[Change], n: usize) -> io::Result<String> {
+    let mut regressions: Vec<&Change> = changes.iter().filter(|c| c.delta() > 0.0).collect();
+    regressions.sort_by(|a, b| b.delta().partial_cmp(&a.delta()).unwrap());
+    let mut improvements: Vec<&Change> = changes.iter().filter(|c| c.delta() < 0.0).collect();
+    improvements.sort_by(|a, b| a.delta().partial_cmp(&b.delta()).unwrap());
+
+    let mut s = String::new();
+    table(
+        &mut s,
+        "Regressions",
+        "regression",
+        regressions.into_iter().take(n),
+    );
+    table(
+        &mut s,
+        "Improvements",
+        "improvement",
+        improvements.into_iter().take(n),
+    );
+    s.push_str("<h2>Differential flamegraph</h2>\n");
+    s.push_str(&differential_flamegraph(base, new)?);
+    s.push('\n');
+    Ok(s)
+}
+
+/// Renders a standalone HTML report of the budgets checked against a profile,
+/// the exceeded ones first, with the flamegraph of the profile.
+pub fn check_report(
+    title: &str,
+    profile: &pb::Profile,
+    verdicts: &[Verdict],
+) -> io::Result<String> {
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>",
+        escape(title),
+        STYLE
+    );
+    let _ = writeln!(s, "<h1>{}</h1>", escape(title));
+    let mut verdicts: Vec<&Verdict> = verdicts.iter().collect();
+    verdicts.sort_by_key(|v| v.passed());
+    s.push_str("<h2>Budgets</h2>\n<table>\n<tr><th></th><th>Actual%</th><th>Max%</th><th>Budget</th></tr>\n");
+    for v in verdicts {
+        let _ = writeln!(
+            s,
+            "<tr class=\"{}\"><td>{}</td><td>{:.2}%</td><td>{:.2}%</td><td>{}{}</td></tr>",
+            if v.passed() { "noise" } else { "regression" },
+            if v.passed() { "ok" } else { "FAILED" },
+            v.actual,
+            v.budget.max,
+            escape(&v.budget.name()),
+            if v.budget.self_only { " (self)" } else { "" }
+        );
+    }
+    s.push_str("</table>\n<h2>Flamegraph</h2>\n");
+    let mut svg = Vec::new();
+    flamegraph::render(profile, &flamegraph::Options::default(), &mut svg)?;
+    s.push_str(&String::from_utf8_lossy(&svg));
+    s.push_str("\n</body>\n</html>\n");
+    Ok(s)
+}
+
+// the samples of each thread name, most busy first
+fn threads(profile: &pb::Profile) -> Vec<(String, pb::Profile)> {
+    let key = profile.string_table.iter().position(|s| s == "thread_name");
+    let mut threads: std::collections::BTreeMap<&str, Vec<pb::Sample>> = Default::default();
+    for sample in &profile.sample {
+        let name = sample
+            .label
+            .iter()
+            .find(|l| Some(l.key as usize) == key)
+            .and_then(|l| profile.string_table.get(l.str as usize));
+        if let Some(name) = name {
+            threads
+                .entry(name.as_str())
+                .or_default()
+                .push(sample.clone());
+        }
+    }
+    let total = |samples: &[pb::Sample]| -> i64 {
+        samples
+            .iter()
+            .map(|s| s.value.first().copied().unwrap_or(0))
+            .sum()
+    };
+    let mut threads: Vec<(String, pb::Profile)> = threads
+        .into_iter()
+        .map(|(name, sample)| {
+            (
+                name.to_string(),
+                pb::Profile {
+                    sample,
+                    ..profile.clone()
+                },
+            )
+        })
+        .collect();
+    threads.sort_by_key(|(_, p)| -total(&p.sample));
+    threads
+}
+
+fn metadata(s: &mut String, profile: &pb::Profile) {
+    let string = |i: i64| {
+        profile
+            .string_table
+            .get(i as usize)
+            .map_or("", |s| s.as_str())
+    };
+    s.push_str("<h2>Metadata</h2>\n<table>\n");
+    let mut row = |k: &str, v: &str| {
+        let _ = writeln!(s, "<tr><th>{}</th><td>{}</td></tr>", escape(k), escape(v));
+    };
+    if profile.time_nanos > 0 {
+        let time = chrono::Local.timestamp_nanos(profile.time_nanos);
+        row("Captured", &time.to_rfc3339());
+    }
+    if profile.duration_nanos > 0 {
+        row(
+            "Duration",
+            &format!("{:.3}s", profile.duration_nanos as f64 / 1e9),
+        );
+    }
+    for (i, t) in profile.sample_type.iter().enumerate() {
+        let total: i64 = profile.sample.iter().filter_map(|s| s.value.get(i)).sum();
+        row(string(t.r#type), &format!("{} {}", total, string(t.unit)));
+    }
+    row("Samples", &profile.sample.len().to_string());
+    for c in &profile.comment {
+        row("Comment", string(*c));
+    }
+    s.push_str("</table>\n");
+}
+
+fn top(s: &mut String, profile: &pb::Profile, n: usize) {
+    let flat = FlatProfile::new(profile, 0);
+    let total = flat.total.max(1) as f64;
+    let mut functions: Vec<(&String, &(i64, i64))> = flat.functions.iter().collect();
+    functions.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+    s.push_str(
+        "<h2>Top functions</h2>\n<table>\n<tr><th>Self</th><th>Total</th><th>Function</th></tr>\n",
+    );
+    for (name, (self_value, total_value)) in functions.into_iter().take(n) {
+        let _ = writeln!(
+            s,
+            "<tr><td>{:.2}%</td><td>{:.2}%</td><td>{}</td></tr>",
+            *self_value as f64 * 100.0 / total,
+            *total_value as f64 * 100.0 / total,
+            escape(name)
+        );
+    }
+    s.push_str("</table>\n");
+}
+
+/// Renders a standalone HTML report of a profile, to mail or attach to a
+/// ticket: the metadata, the top `n` functions and the flamegraphs of all
+/// the threads and of each thread name in tabs, working offline.
+pub fn report(title: &str, profile: &pb::Profile, n: usize) -> io::Result<String> {
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>",
+        escape(title),
+        STYLE
+    );
+    let _ = writeln!(s, "<h1>{}</h1>", escape(title));
+    metadata(&mut s, profile);
+    top(&mut s, profile, n);
+
+    let mut tabs = vec![("All threads".to_string(), profile.clone())];
+    tabs.extend(threads(profile));
+    s.push_str("<h2>Flamegraphs</h2>\n<div class=\"tabs\">");
+    for (i, (name, _)) in tabs.iter().enumerate() {
+        let _ = write!(
+            s,
+            "<button{} onclick=\"show({})\">{}</button>",
+            if i == 0 { " class=\"active\"" } else { "" },
+            i,
+            escape(name)
+        );
+    }
+    s.push_str("</div>\n");
+    for (i, (name, p)) in tabs.iter().enumerate() {
+        let options = flamegraph::Options {
+            title: name.clone(),
+            ..Default::default()
+        };
+        let mut svg = Vec::new();
+        flamegraph::render(p, &options, &mut svg)?;
+        // a document of its own, so that the scripts of the flamegraphs
+        // don't see each other
+        let _ = writeln!(
+            s,
+            "<div class=\"tab{}\"><iframe srcdoc=\"{}\"></iframe></div>",
+            if i == 0 { " active" } else { "" },
+            escape(&String::from_utf8_lossy(&svg))
+        );
+    }
+    let _ = writeln!(s, "<script>\n{}\n</script>", TABS_SCRIPT);
+    s.push_str("</body>\n</html>\n");
+    Ok(s)
+}
@@ -0,0 +1,178 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::process::Command;
+
+use crate::callgraph::CallGraph;
+use crate::diff::strip_hash;
+use crate::flat::FlatProfile;
+use crate::pprof::pb;
+
+/// Reads the sizes of the functions in the binary with `nm`.
+pub fn function_sizes(binary: &str) -> io::Result<HashMap<String, u64>> {
+    let output = Command::new("nm")
+        .args(["--print-size", "--demangle", "--defined-only"])
+        .arg(binary)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    let mut sizes = HashMap::new();
+    // 0000000000012340 0000000000000040 T foo::bar::h0123456789abcdef
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.splitn(4, ' ').collect();
+        if fields.len() != 4 || !matches!(fields[2], "T" | "t" | "W" | "w") {
+            continue;
+        }
+        if let Ok(size) = u64::from_str_radix(fields[1], 16) {
+            sizes.insert(strip_hash(fields[3]), size);
+        }
+    }
+    Ok(sizes)
+}
+
+/// the crate of a function, `<serde_json::de::Deserializer<R> as ...>::foo` to `serde_json`
+pub fn crate_of(name: &str) -> &str {
+    let name = name.trim_start_matches('<');
+    name.split("::").next().unwrap_or(name)
+}
+
+/// An `#[inline]` or `#[inline(never)]` candidate.
+pub struct Suggestion {
+    pub function: String,
+    pub attribute: &'static str,
+    pub size: u64,
+    /// the share of the hot call edge, or of the function if it's cold
+    pub share: f64,
+    pub reason: String,
+}
+
+/// Thresholds of the suggestions.
+pub struct Thresholds {
+    /// bytes of code small enough to inline
+    pub small: u64,
+    /// bytes of code too large to inline
+    pub large: u64,
+    /// share of a call edge to be hot
+    pub hot: f64,
+    /// share of a function to be cold
+    pub cold: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            small: 64,
+            large: 4096,
+            hot: 0.005,
+            cold: 0.001,
+        }
+    }
+}
+
+/// Suggests `#[inline]` for tiny functions called hot across crates, and
+/// `#[inline(never)]` for huge functions that hardly run, ranked by share.
+pub fn suggest(
+    profile: &pb::Profile,
+    sizes: &HashMap<String, u64>,
+    t: &Thresholds,
+) -> Vec<Suggestion> {
+    let flat = FlatProfile::new(profile, 0);
+    let total = flat.total.max(1) as f64;
+    let size_of = |name: &str| sizes.get(&strip_hash(name)).copied();
+
+    let mut v = Vec::new();
+    for ((caller, callee), value) in CallGraph::new(profile, 0).edges {
+        let share = value as f64 / total;
+        if share < t.hot || crate_of(&caller) == crate_of(&callee) {
+            continue;
+        }
+        match size_of(&callee) {
+            Some(size) if size <= t.small => v.push(Suggestion {
+                function: callee.clone(),
+                attribute: "#[inline]",
+                size,
+                share,
+                reason: format!("called from {}", caller),
+            }),
+            _ => {}
+        }
+    }
+    // a function called from many hot edges appears once
+    v.sort_by(|a, b| {
+        b.share
+            .partial_cmp(&a.share)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut seen = std::collections::HashSet::new();
+    v.retain(|s| seen.insert(s.function.clone()));
+
+    // functions never sampled are the coldest
+    let shares: HashMap<String, f64> = flat
+        .functions
+        .keys()
+        .map(|name| (strip_hash(name), flat.share(name).1))
+        .collect();
+    let mut cold: Vec<Suggestion> = sizes
+        .iter()
+        .filter_map(|(name, size)| {
+            let share = shares.get(name).copied().unwrap_or(0.0);
+            if *size < t.large || share >= t.cold {
+                return None;
+            }
+            Some(Suggestion {
+                function: name.clone(),
+                attribute: "#[inline(never)]",
+                size: *size,
+                share,
+                reason: "cold".to_string(),
+            })
+        })
+        .collect();
+    cold.sort_by(|a, b| b.size.cmp(&a.size).then(a.function.cmp(&b.function)));
+    v.extend(cold);
+    v
+}
+
+/// renders the first `n` suggestions of each kind as a text table
+pub fn render(suggestions: &[Suggestion], n: usize) -> String {
+    let mut s = format!(
+        "{:<17} {:>8} {:>8}  {}\n",
+        "Attribute", "Bytes", "Share%", "Function"
+    );
+    for attribute in ["#[inline]", "#[inline(never)]"] {
+        for sg in suggestions
+            .iter()
+            .filter(|sg| sg.attribute == attribute)
+            .take(n)
+        {
+            let _ = writeln!(
+                s,
+                "{:<17} {:>8} {:>7.2}%  {} ({})",
+                sg.attribute,
+                sg.size,
+                sg.share * 100.0,
+                sg.function,
+                sg.reason
+            );
+        }
+    }
+    s
+}
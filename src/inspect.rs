@@ -0,0 +1,209 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dumps a pprof profile as JSON with the references to the string table
+//! resolved, to read profiles or script over them without protoc. The
+//! functions of each sample are listed too, leaf first, so that most
+//! scripts don't need to follow the ids. The ids are the positions in their
+//! lists plus one, as `Profile::parse` renumbers them.
+
+use serde::Serialize;
+use std::io;
+
+use crate::profile::{self, LabelValue};
+
+#[derive(Serialize)]
+struct ValueType<'a> {
+    r#type: &'a str,
+    unit: &'a str,
+}
+
+#[derive(Serialize)]
+struct Label<'a> {
+    key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    str: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num: Option<i64>,
+    num_unit: &'a str,
+}
+
+#[derive(Serialize)]
+struct Sample<'a> {
+    value: &'a [i64],
+    location_id: Vec<u64>,
+    /// leaf first, the inlined functions of a location before their callers
+    functions: Vec<&'a str>,
+    label: Vec<Label<'a>>,
+}
+
+#[derive(Serialize)]
+struct Mapping<'a> {
+    id: u64,
+    memory_start: u64,
+    memory_limit: u64,
+    file_offset: u64,
+    filename: &'a str,
+    build_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    function_id: u64,
+    function: &'a str,
+    line: i64,
+}
+
+#[derive(Serialize)]
+struct Location<'a> {
+    id: u64,
+    mapping_id: u64,
+    address: u64,
+    line: Vec<Line<'a>>,
+}
+
+#[derive(Serialize)]
+struct Function<'a> {
+    id: u64,
+    name: &'a str,
+    system_name: &'a str,
+    filename: &'a str,
+    start_line: i64,
+}
+
+#[derive(Serialize)]
+struct Profile<'a> {
+    sample_type: Vec<ValueType<'a>>,
+    default_sample_type: &'a str,
+    time_nanos: i64,
+    duration_nanos: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    period_type: Option<ValueType<'a>>,
+    period: i64,
+    comment: Vec<&'a str>,
+    drop_frames: &'a str,
+    keep_frames: &'a str,
+    sample: Vec<Sample<'a>>,
+    mapping: Vec<Mapping<'a>>,
+    location: Vec<Location<'a>>,
+    function: Vec<Function<'a>>,
+}
+
+fn value_type(t: &profile::ValueType) -> ValueType<'_> {
+    ValueType {
+        r#type: &t.r#type,
+        unit: &t.unit,
+    }
+}
+
+// the id of the position in a list
+fn id(i: usize) -> u64 {
+    i as u64 + 1
+}
+
+/// writes the profile as JSON, with the strings in place of their indices
+pub fn to_json<W: io::Write>(profile: &profile::Profile, mut writer: W) -> io::Result<()> {
+    let function = |i: usize| profile.functions[i].name.as_str();
+
+    let json = Profile {
+        sample_type: profile.sample_types.iter().map(value_type).collect(),
+        default_sample_type: profile.default_sample_type.as_deref().unwrap_or(""),
+        time_nanos: profile.time_nanos,
+        duration_nanos: profile.duration_nanos,
+        period_type: profile.period_type.as_ref().map(value_type),
+        period: profile.period,
+        comment: profile.comments.iter().map(|c| c.as_str()).collect(),
+        drop_frames: profile.drop_frames.as_deref().unwrap_or(""),
+        keep_frames: profile.keep_frames.as_deref().unwrap_or(""),
+        sample: profile
+            .samples
+            .iter()
+            .map(|s| Sample {
+                value: &s.values,
+                location_id: s.locations.iter().map(|l| id(*l)).collect(),
+                functions: s
+                    .locations
+                    .iter()
+                    .flat_map(|l| profile.locations[*l].lines.iter())
+                    .map(|l| function(l.function))
+                    .collect(),
+                label: s
+                    .labels
+                    .iter()
+                    .map(|l| match &l.value {
+                        LabelValue::Str(v) => Label {
+                            key: &l.key,
+                            str: Some(v),
+                            num: None,
+                            num_unit: "",
+                        },
+                        LabelValue::Num { value, unit } => Label {
+                            key: &l.key,
+                            str: None,
+                            num: Some(*value),
+                            num_unit: unit,
+                        },
+                    })
+                    .collect(),
+            })
+            .collect(),
+        mapping: profile
+            .mappings
+            .iter()
+            .enumerate()
+            .map(|(i, m)| Mapping {
+                id: id(i),
+                memory_start: m.memory_start,
+                memory_limit: m.memory_limit,
+                file_offset: m.file_offset,
+                filename: &m.filename,
+                build_id: &m.build_id,
+            })
+            .collect(),
+        location: profile
+            .locations
+            .iter()
+            .enumerate()
+            .map(|(i, l)| Location {
+                id: id(i),
+                mapping_id: l.mapping.map_or(0, id),
+                address: l.address,
+                line: l
+                    .lines
+                    .iter()
+                    .map(|line| Line {
+                        function_id: id(line.function),
+                        function: function(line.function),
+                        line: line.line,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        function: profile
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, f)| Function {
+                id: id(i),
+                name: &f.name,
+                system_name: &f.system_name,
+                filename: &f.filename,
+                start_line: f.start_line,
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(&mut writer, &json)?;
+    writeln!(writer)
+}
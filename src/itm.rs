@@ -0,0 +1,90 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the PC samples of Cortex-M firmware: the DWT samples the program
+//! counter periodically and sends it over SWO as ITM packets, which probes
+//! like probe-rs or orbuculum capture as a raw byte stream.
+
+use std::io;
+
+use crate::pprof::{self, pb, SampleBuilder};
+use crate::symbolize::Symbolizer;
+
+// the hardware source packet of the DWT for PC samples
+const PC_SAMPLE_ID: u8 = 2;
+
+/// The PC samples in the ITM stream; `None` for those taken while the core
+/// was sleeping.
+pub fn pc_samples(data: &[u8]) -> Vec<Option<u32>> {
+    let mut samples = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let header = data[i];
+        i += 1;
+        match header {
+            // synchronization, `00 00 00 00 00 80`, and overflow; the 0x80
+            // ending a synchronization isn't a timestamp
+            0x00 | 0x70 | 0x80 => {}
+            // timestamps and extensions, continued while bit 7 is set
+            h if h & 0x03 == 0 => {
+                if h & 0x80 != 0 {
+                    while i < data.len() && data[i] & 0x80 != 0 {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            h => {
+                let size = match h & 0x03 {
+                    3 => 4,
+                    n => n as usize,
+                };
+                let payload = match data.get(i..i + size) {
+                    Some(p) => p,
+                    None => break,
+                };
+                i += size;
+                // hardware source
+                if h & 0x04 != 0 && h >> 3 == PC_SAMPLE_ID {
+                    samples.push(match payload {
+                        [a, b, c, d] => Some(u32::from_le_bytes([*a, *b, *c, *d])),
+                        _ => None,
+                    });
+                }
+            }
+        }
+    }
+    samples
+}
+
+/// Converts an ITM capture into a profile of the sample counts, with the
+/// functions of the PCs from the symbols of the ELF the firmware was built
+/// into. There are no stacks but the functions, and the samples taken
+/// while sleeping are `[sleep]`.
+pub fn decode_itm(data: &[u8], elf: &str) -> io::Result<pb::Profile> {
+    let mut symbolizer = Symbolizer::new(None);
+    let mut builder = SampleBuilder::default();
+    for pc in pc_samples(data) {
+        let name = match pc {
+            Some(pc) => match symbolizer.symbol(elf, pc as u64) {
+                Some(name) => pprof::demangle(&name),
+                None => format!("{:#x}", pc),
+            },
+            None => "[sleep]".to_string(),
+        };
+        builder.add(&[&name], &[], 1);
+    }
+    Ok(builder.build())
+}
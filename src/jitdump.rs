@@ -0,0 +1,194 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the jitdump files (`jit-<pid>.dump`) that JIT compilers like the
+//! JVM's, .NET's, LuaJIT and V8 write, so that the samples in the generated
+//! code get their names without `perf inject --jit`. A file is a header and
+//! records of the code loaded and moved.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+
+// "JiTD" in the byte order of the writer
+const MAGIC: u32 = 0x4a69_5444;
+const HEADER_SIZE: usize = 40;
+const RECORD_HEADER_SIZE: usize = 16;
+
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+
+// the timestamps are of the TSC, not of the clock perf records with
+const JITDUMP_FLAGS_ARCH_TIMESTAMP: u64 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> io::Result<u32> {
+    buf.get(pos..pos + 4)
+        .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("truncated jitdump"))
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> io::Result<u64> {
+    buf.get(pos..pos + 8)
+        .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("truncated jitdump"))
+}
+
+// a function generated, from its load until it's moved
+#[derive(Clone, Debug)]
+struct Code {
+    end: u64,
+    name: String,
+    loaded: u64,
+    moved: Option<u64>,
+}
+
+/// The functions a JIT compiler generated in a process.
+#[derive(Clone, Debug)]
+pub struct JitDump {
+    pub pid: u32,
+    pub path: String,
+    // start to the code there in the order loaded
+    code: BTreeMap<u64, Vec<Code>>,
+    // the longest code, to bound the search back from an address
+    max_len: u64,
+}
+
+impl JitDump {
+    pub fn open(path: &str) -> io::Result<JitDump> {
+        let buf = std::fs::read(path)?;
+        match u32_at(&buf, 0)? {
+            MAGIC => {}
+            m if m.swap_bytes() == MAGIC => {
+                return Err(invalid("jitdump of the other byte order isn't supported"))
+            }
+            _ => return Err(invalid("not a jitdump file")),
+        }
+        let header_size = (u32_at(&buf, 8)? as usize).max(HEADER_SIZE);
+        let pid = u32_at(&buf, 20)?;
+        // without the times of perf's clock, the latest code at an address wins
+        let timed = u64_at(&buf, 32)? & JITDUMP_FLAGS_ARCH_TIMESTAMP == 0;
+
+        let mut code: BTreeMap<u64, Vec<Code>> = BTreeMap::new();
+        let mut max_len = 0;
+        // code_index to the name, for the moves
+        let mut names: HashMap<u64, String> = HashMap::new();
+        let mut pos = header_size;
+        while pos + RECORD_HEADER_SIZE <= buf.len() {
+            let id = u32_at(&buf, pos)?;
+            let size = u32_at(&buf, pos + 4)? as usize;
+            if size < RECORD_HEADER_SIZE || pos + size > buf.len() {
+                break;
+            }
+            let time = if timed { u64_at(&buf, pos + 8)? } else { 0 };
+            let body = &buf[pos + RECORD_HEADER_SIZE..pos + size];
+            pos += size;
+            match id {
+                // pid, tid, vma, code_addr, code_size, code_index, name, code
+                JIT_CODE_LOAD => {
+                    let addr = u64_at(body, 16)?;
+                    let len = u64_at(body, 24)?;
+                    let index = u64_at(body, 32)?;
+                    let rest = body.get(40..).unwrap_or_default();
+                    let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+                    let name = String::from_utf8_lossy(&rest[..end]).to_string();
+                    let end = addr
+                        .checked_add(len)
+                        .ok_or_else(|| invalid("broken code range"))?;
+                    names.insert(index, name.clone());
+                    max_len = max_len.max(len);
+                    code.entry(addr).or_default().push(Code {
+                        end,
+                        name,
+                        loaded: time,
+                        moved: None,
+                    });
+                }
+                // pid, tid, vma, old_code_addr, new_code_addr, code_size, code_index
+                JIT_CODE_MOVE => {
+                    let old = u64_at(body, 16)?;
+                    let addr = u64_at(body, 24)?;
+                    let len = u64_at(body, 32)?;
+                    let index = u64_at(body, 40)?;
+                    let end = addr
+                        .checked_add(len)
+                        .ok_or_else(|| invalid("broken code range"))?;
+                    if let Some(c) = code
+                        .get_mut(&old)
+                        .and_then(|v| v.iter_mut().rev().find(|c| c.moved.is_none()))
+                    {
+                        c.moved = Some(time);
+                    }
+                    if let Some(name) = names.get(&index) {
+                        max_len = max_len.max(len);
+                        code.entry(addr).or_default().push(Code {
+                            end,
+                            name: name.clone(),
+                            loaded: time,
+                            moved: None,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(JitDump {
+            pid,
+            path: path.to_string(),
+            code,
+            max_len,
+        })
+    }
+
+    /// the function generated at the address at the time of a sample, in
+    /// nanoseconds of the clock perf records with (`-k mono` as for
+    /// `perf inject --jit`); the code loaded last wins
+    pub fn function(&self, addr: u64, time: u64) -> Option<&str> {
+        for (start, codes) in self.code.range(..=addr).rev() {
+            if start.saturating_add(self.max_len) <= addr {
+                break;
+            }
+            let code = codes.iter().rev().find(|c| {
+                addr < c.end && c.loaded <= time && !matches!(c.moved, Some(m) if m <= time)
+            });
+            if let Some(c) = code {
+                return Some(c.name.as_str());
+            }
+        }
+        None
+    }
+}
+
+/// the jitdump files in the directory, like next to perf.data
+pub fn find(dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| {
+                    matches!(p.file_name().and_then(|n| n.to_str()),
+                        Some(n) if n.starts_with("jit-") && n.ends_with(".dump"))
+                })
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
@@ -0,0 +1,80 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::pprof::{PprofConverterBuilder, ScriptSample};
+
+/// One sample of `perf script` output.
+#[derive(Serialize, Debug, Default)]
+pub struct Event {
+    /// seconds, as perf prints
+    pub timestamp: f64,
+    pub comm: String,
+    /// same as tid unless perf script prints pid/tid
+    pub pid: u32,
+    pub tid: u32,
+    /// leaf first
+    pub stack: Vec<String>,
+    /// the period if perf script prints it, otherwise 1
+    pub weight: u64,
+    /// the other labels of the profile, like `marker` and `group`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl From<ScriptSample> for Event {
+    fn from(sample: ScriptSample) -> Self {
+        let mut labels: BTreeMap<String, String> = sample.labels.into_iter().collect();
+        let mut take = |key: &str| labels.remove(key).unwrap_or_default();
+        let comm = take("thread_name");
+        let tid = take("tid").parse().unwrap_or(0);
+        let pid = take("pid").parse().unwrap_or(tid);
+        Event {
+            timestamp: sample.nsec as f64 / 1e9,
+            comm,
+            pid,
+            tid,
+            stack: sample.functions,
+            weight: sample.period,
+            labels,
+        }
+    }
+}
+
+/// streams `perf script` output as one JSON object per line and sample
+pub fn convert<R, W>(builder: &PprofConverterBuilder, reader: R, mut writer: W) -> io::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    read_events(builder, reader, |e| {
+        serde_json::to_writer(&mut writer, &e)?;
+        writer.write_all(b"\n")
+    })?;
+    writer.flush()
+}
+
+/// calls `f` with the samples of `perf script` output in order, read with the
+/// options of the converter, like `--preset`, `--markers` and `--focus`
+pub fn read_events<R, F>(builder: &PprofConverterBuilder, reader: R, mut f: F) -> io::Result<()>
+where
+    R: io::BufRead,
+    F: FnMut(Event) -> io::Result<()>,
+{
+    builder.read_samples(reader, |s| f(Event::from(s)))
+}
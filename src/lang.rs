@@ -0,0 +1,141 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::diff::{strip_generics, strip_hash};
+use crate::pprof::pb;
+
+lazy_static! {
+    // github.com/foo/bar.(*Server).Handle.func1
+    static ref GO_RE: Regex =
+        Regex::new(r"^(?:[\w.\-]+/)*([\w\-]+)(?:\.v\d+)?\.(?:\(\*?(\w+)\)|(\w+))?\.?(\w+)?").unwrap();
+    // <foo::Server as foo::Handler>::handle
+    static ref QUALIFIED_RE: Regex = Regex::new(r"^<(?:.*::)?(\w+)(?:<_>)? as .*>::(\w+)").unwrap();
+    // handle (app/server.py:42), as py-spy writes
+    static ref PYTHON_RE: Regex = Regex::new(r"^(\w+) \((?:.*/)?(\w+)\.py(?::\d+)?\)$").unwrap();
+}
+
+/// The language of the functions of a profile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    Rust,
+    Go,
+    Python,
+}
+
+/// guesses the language of the profile from the runtime functions
+pub fn detect(profile: &pb::Profile) -> Language {
+    let names = || {
+        profile
+            .function
+            .iter()
+            .filter_map(|f| profile.string_table.get(f.name as usize))
+    };
+    if names().any(|n| n.starts_with("runtime.") || n.starts_with("main.")) {
+        Language::Go
+    } else if names().any(|n| PYTHON_RE.is_match(n)) {
+        Language::Python
+    } else {
+        Language::Rust
+    }
+}
+
+// HandleRequest to handle_request
+fn snake_case(s: &str) -> String {
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in s.chars() {
+        if c.is_uppercase() {
+            if prev_lower {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+            prev_lower = false;
+        } else {
+            out.push(c);
+            prev_lower = c.is_lowercase() || c.is_ascii_digit();
+        }
+    }
+    out
+}
+
+/// Maps a function name to `type.method`, or `module.function` for a free
+/// function, in snake case, dropping the outer packages, crates and modules and
+/// generics, so that a function is matched across the implementations in
+/// different languages while `a::new` and `b::new` stay apart.
+pub fn canonical(name: &str, lang: Language) -> String {
+    match lang {
+        Language::Go => match GO_RE.captures(name) {
+            Some(caps) => {
+                let package = caps[1].replace('-', "_");
+                let ty = caps.get(2).or_else(|| caps.get(3)).map(|m| m.as_str());
+                match (ty, caps.get(4).map(|m| m.as_str())) {
+                    // closures are attributed to their functions like Rust's
+                    (Some(f), Some(c)) if c.starts_with("func") => {
+                        format!("{}.{}", package, snake_case(f))
+                    }
+                    (Some(ty), Some(method)) => {
+                        format!("{}.{}", snake_case(ty), snake_case(method))
+                    }
+                    (Some(f), None) => format!("{}.{}", package, snake_case(f)),
+                    _ => name.to_string(),
+                }
+            }
+            None => name.to_string(),
+        },
+        // py-spy names no class, so the module stands in for it
+        Language::Python => match PYTHON_RE.captures(name) {
+            Some(caps) => format!("{}.{}", &caps[2], &caps[1]),
+            None => name.to_string(),
+        },
+        Language::Rust => {
+            let name = strip_generics(&strip_hash(name));
+            if let Some(caps) = QUALIFIED_RE.captures(&name) {
+                return format!("{}.{}", snake_case(&caps[1]), &caps[2]);
+            }
+            let path: Vec<&str> = name
+                .split("::")
+                .filter(|p| !p.starts_with('{'))
+                .map(|p| p.trim_end_matches("<_>"))
+                .collect();
+            match path.as_slice() {
+                [.., ty, f] if ty.starts_with(char::is_uppercase) => {
+                    format!("{}.{}", snake_case(ty), f)
+                }
+                [.., module, f] => format!("{}.{}", module, f),
+                [.., f] => f.to_string(),
+                [] => name.clone(),
+            }
+        }
+    }
+}
+
+/// a copy of the profile with the function names in the canonical form
+pub fn normalize(profile: &pb::Profile) -> pb::Profile {
+    let lang = detect(profile);
+    let mut profile = profile.clone();
+    for i in 0..profile.function.len() {
+        let name = profile
+            .string_table
+            .get(profile.function[i].name as usize)
+            .cloned()
+            .unwrap_or_default();
+        profile.function[i].name = profile.string_table.len() as i64;
+        profile.string_table.push(canonical(&name, lang));
+    }
+    profile
+}
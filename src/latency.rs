@@ -0,0 +1,85 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+
+use crate::pprof::pb;
+
+/// A request served while recording.
+#[derive(Clone, Debug)]
+pub struct Request {
+    /// microseconds since the epoch, the clock of `perf record -k realtime`
+    pub start: u64,
+    pub end: u64,
+    /// milliseconds
+    pub latency: f64,
+}
+
+/// Reads the request spans of a log, `<start> <end> <latency>` per line,
+/// separated by spaces or commas. The start and the end are in microseconds
+/// since the epoch, the latency in milliseconds; the lines that don't parse,
+/// like a CSV header, are skipped.
+pub fn read_requests<R>(reader: R) -> io::Result<Vec<Request>>
+where
+    R: io::BufRead,
+{
+    let mut requests = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        if let [start, end, latency] = fields.as_slice() {
+            if let (Ok(start), Ok(end), Ok(latency)) = (start.parse(), end.parse(), latency.parse())
+            {
+                requests.push(Request {
+                    start,
+                    end,
+                    latency,
+                });
+            }
+        }
+    }
+    Ok(requests)
+}
+
+/// the latency at the percentile (0 to 100) of the requests
+pub fn percentile(requests: &[Request], p: f64) -> f64 {
+    let mut latencies: Vec<f64> = requests.iter().map(|r| r.latency).collect();
+    if latencies.is_empty() {
+        return 0.0;
+    }
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let i = ((latencies.len() - 1) as f64 * p / 100.0).round() as usize;
+    latencies[i.min(latencies.len() - 1)]
+}
+
+/// the samples of the profile with the `latency` label, `slow` or `fast`
+pub fn only(profile: &pb::Profile, latency: &str) -> pb::Profile {
+    let s = |i: i64| profile.string_table.get(i as usize).map(|s| s.as_str());
+    let mut profile = profile.clone();
+    let sample = std::mem::take(&mut profile.sample);
+    profile.sample = sample
+        .into_iter()
+        .filter(|sample| {
+            sample
+                .label
+                .iter()
+                .any(|l| s(l.key) == Some("latency") && s(l.str) == Some(latency))
+        })
+        .collect();
+    profile
+}
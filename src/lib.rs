@@ -13,4 +13,58 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod annotate;
+#[cfg(feature = "bpf")]
+pub mod bpf;
+pub mod budget;
+pub mod buildid;
+pub mod calibrate;
+pub mod callgraph;
+pub mod calltree;
+pub mod date;
+pub mod diff;
+pub mod dso;
+pub mod etw;
+pub mod exemplar;
+pub mod ffi;
+pub mod firefox;
+pub mod flamegraph;
+pub mod flat;
+pub mod fleet;
+pub mod grouping;
+pub mod html;
+pub mod inline;
+pub mod inspect;
+pub mod itm;
+pub mod jitdump;
+pub mod jsonl;
+pub mod lang;
+pub mod latency;
+pub mod marker;
+pub mod merge;
+pub mod parallel;
+pub mod pcdump;
+pub mod perfdata;
 pub mod pprof;
+pub mod primitives;
+pub mod profile;
+pub mod python;
+pub mod query;
+pub mod selftest;
+pub mod sidecar;
+pub mod simpleperf;
+pub mod snapshot;
+pub mod speedscope;
+pub mod subtract;
+pub mod symbolize;
+pub mod table;
+pub mod tenant;
+pub mod timeline;
+pub mod top;
+pub mod trace;
+pub mod trend;
+pub mod unit;
+pub mod wasm;
+pub mod xctrace;
+
+pub use marker::marker;
@@ -0,0 +1,63 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The environment variable naming the file that markers are appended to.
+pub const MARKERS_ENV: &str = "PERF_TOOLS_MARKERS";
+
+/// microseconds since the epoch, the clock of `perf record -k realtime`
+pub fn now_usec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Marks the start of a phase in the profiled program.
+///
+/// The samples after the marker are labeled with `name` in the profile. It's
+/// a no-op unless the program runs under `cargo perf --markers`.
+pub fn marker(name: &str) {
+    if let Some(path) = std::env::var_os(MARKERS_ENV) {
+        if let Ok(mut f) = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+        {
+            // a single write keeps lines from multiple threads intact
+            let _ = f.write_all(format!("{} {}\n", now_usec(), name.trim()).as_bytes());
+        }
+    }
+}
+
+/// reads markers written by [`marker`]
+pub fn read_markers<R>(reader: R) -> io::Result<Vec<(u64, String)>>
+where
+    R: io::BufRead,
+{
+    let mut markers = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((time, name)) = line.split_once(' ') {
+            if let Ok(time) = time.parse() {
+                markers.push((time, name.to_string()));
+            }
+        }
+    }
+    Ok(markers)
+}
@@ -0,0 +1,276 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use prost::Message;
+use std::collections::HashMap;
+use std::io;
+
+use crate::pprof::{self, pb, Compression};
+
+// the string table of the merged profile
+#[derive(Default)]
+struct Strings {
+    ids: HashMap<String, i64>,
+    table: Vec<String>,
+}
+
+impl Strings {
+    fn id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.ids.get(s) {
+            return *id;
+        }
+        let id = self.table.len() as i64;
+        self.ids.insert(s.to_string(), id);
+        self.table.push(s.to_string());
+        id
+    }
+}
+
+fn value_types(profile: &pb::Profile) -> Vec<(&str, &str)> {
+    let s = |i: i64| {
+        profile
+            .string_table
+            .get(i as usize)
+            .map_or("", |s| s.as_str())
+    };
+    profile
+        .sample_type
+        .iter()
+        .map(|t| (s(t.r#type), s(t.unit)))
+        .collect()
+}
+
+// location: mapping, and the lines or the address if there are none
+type LocationKey = (u64, Vec<(u64, i64)>, u64);
+// location ids, labels (key, str, num, num_unit)
+type SampleKey = (Vec<u64>, Vec<(i64, i64, i64, i64)>);
+
+/// merges the profiles, see [`merge_labeled`]
+pub fn merge(profiles: &[&pb::Profile]) -> io::Result<pb::Profile> {
+    let profiles: Vec<(&pb::Profile, &[(&str, &str)])> =
+        profiles.iter().map(|p| (*p, &[][..])).collect();
+    merge_labeled(&profiles)
+}
+
+/// Merges the profiles, setting the labels on the samples of each. The same
+/// functions, locations and stacks are shared across the profiles, so that a
+/// profile merged again and again grows with the distinct stacks; locations
+/// with lines are matched by the lines rather than by the addresses, which
+/// move with ASLR. The profiles must have the same sample types, and the
+/// merged one spans from the earliest recording to the end of the latest.
+pub fn merge_labeled(profiles: &[(&pb::Profile, &[(&str, &str)])]) -> io::Result<pb::Profile> {
+    let first = match profiles.first() {
+        Some((p, _)) => *p,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no profiles to merge",
+            ))
+        }
+    };
+    let types = value_types(first);
+    let mut strings = Strings::default();
+    strings.id("");
+    let mut merged = pb::Profile {
+        sample_type: types
+            .iter()
+            .map(|(t, u)| pb::ValueType {
+                r#type: strings.id(t),
+                unit: strings.id(u),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let mut mappings: HashMap<(i64, i64), u64> = HashMap::new();
+    let mut functions: HashMap<(i64, i64, i64), u64> = HashMap::new();
+    let mut locations: HashMap<LocationKey, u64> = HashMap::new();
+    let mut samples: HashMap<SampleKey, usize> = HashMap::new();
+    let mut comments = Vec::new();
+    let (mut start, mut end) = (i64::MAX, 0);
+
+    for (profile, labels) in profiles {
+        if value_types(profile) != types {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the profiles have different sample types",
+            ));
+        }
+        let labels: Vec<(i64, i64)> = labels
+            .iter()
+            .map(|(k, v)| (strings.id(k), strings.id(v)))
+            .collect();
+        let mut s = |i: i64| {
+            let name = profile
+                .string_table
+                .get(i as usize)
+                .map_or("", |s| s.as_str());
+            strings.id(name)
+        };
+
+        // the ids in the profile to the ones in the merged
+        let mut mapping_ids = HashMap::new();
+        for m in &profile.mapping {
+            let key = (s(m.filename), s(m.build_id));
+            let next_id = merged.mapping.len() as u64 + 1;
+            let id = *mappings.entry(key).or_insert_with(|| {
+                merged.mapping.push(pb::Mapping {
+                    id: next_id,
+                    filename: key.0,
+                    build_id: key.1,
+                    ..m.clone()
+                });
+                next_id
+            });
+            let mapping = &mut merged.mapping[id as usize - 1];
+            mapping.memory_start = mapping.memory_start.min(m.memory_start);
+            mapping.memory_limit = mapping.memory_limit.max(m.memory_limit);
+            mapping_ids.insert(m.id, id);
+        }
+        let mut function_ids = HashMap::new();
+        for f in &profile.function {
+            let key = (s(f.name), s(f.system_name), s(f.filename));
+            let next_id = merged.function.len() as u64 + 1;
+            let id = *functions.entry(key).or_insert_with(|| {
+                merged.function.push(pb::Function {
+                    id: next_id,
+                    name: key.0,
+                    system_name: key.1,
+                    filename: key.2,
+                    start_line: f.start_line,
+                });
+                next_id
+            });
+            function_ids.insert(f.id, id);
+        }
+        let mut location_ids = HashMap::new();
+        for l in &profile.location {
+            let mapping_id = mapping_ids.get(&l.mapping_id).copied().unwrap_or(0);
+            let line: Vec<pb::Line> = l
+                .line
+                .iter()
+                .map(|line| pb::Line {
+                    function_id: function_ids.get(&line.function_id).copied().unwrap_or(0),
+                    line: line.line,
+                })
+                .collect();
+            let key = (
+                mapping_id,
+                line.iter().map(|l| (l.function_id, l.line)).collect(),
+                if line.is_empty() { l.address } else { 0 },
+            );
+            let next_id = merged.location.len() as u64 + 1;
+            let id = *locations.entry(key).or_insert_with(|| {
+                merged.location.push(pb::Location {
+                    id: next_id,
+                    mapping_id,
+                    line,
+                    ..l.clone()
+                });
+                next_id
+            });
+            location_ids.insert(l.id, id);
+        }
+
+        for sample in &profile.sample {
+            let location_id: Vec<u64> = sample
+                .location_id
+                .iter()
+                .map(|id| location_ids.get(id).copied().unwrap_or(0))
+                .collect();
+            let mut label = Vec::new();
+            for l in &sample.label {
+                let key = s(l.key);
+                if labels.iter().any(|(k, _)| *k == key) {
+                    continue;
+                }
+                label.push((key, s(l.str), l.num, s(l.num_unit)));
+            }
+            label.extend(labels.iter().map(|(k, v)| (*k, *v, 0, 0)));
+            label.sort_unstable();
+            let i = *samples
+                .entry((location_id.clone(), label.clone()))
+                .or_insert_with(|| {
+                    merged.sample.push(pb::Sample {
+                        location_id,
+                        value: vec![0; types.len()],
+                        label: label
+                            .into_iter()
+                            .map(|(key, str, num, num_unit)| pb::Label {
+                                key,
+                                str,
+                                num,
+                                num_unit,
+                            })
+                            .collect(),
+                    });
+                    merged.sample.len() - 1
+                });
+            for (v, add) in merged.sample[i].value.iter_mut().zip(&sample.value) {
+                *v += add;
+            }
+        }
+
+        for c in &profile.comment {
+            let c = s(*c);
+            if !comments.contains(&c) {
+                comments.push(c);
+            }
+        }
+        if profile.time_nanos > 0 {
+            start = start.min(profile.time_nanos);
+            end = end.max(profile.time_nanos + profile.duration_nanos);
+        }
+    }
+
+    if start < end {
+        merged.time_nanos = start;
+        merged.duration_nanos = end - start;
+    }
+    let s = |i: i64| {
+        first
+            .string_table
+            .get(i as usize)
+            .map_or("", |s| s.as_str())
+    };
+    merged.period_type = first.period_type.as_ref().map(|t| pb::ValueType {
+        r#type: strings.id(s(t.r#type)),
+        unit: strings.id(s(t.unit)),
+    });
+    merged.period = first.period;
+    merged.drop_frames = strings.id(s(first.drop_frames));
+    merged.keep_frames = strings.id(s(first.keep_frames));
+    merged.default_sample_type = strings.id(s(first.default_sample_type));
+    merged.comment = comments;
+    merged.string_table = strings.table;
+    Ok(merged)
+}
+
+/// Merges the profile into the profile file at `path`, or creates it, to keep
+/// a rolling aggregate of recordings. The file is replaced by renaming, so
+/// that readers never see a partial one, and is written with `compression`.
+pub fn append_to(path: &str, profile: &pb::Profile, compression: Compression) -> io::Result<()> {
+    let merged = match std::fs::read(path) {
+        Ok(buf) => merge(&[&pprof::decode(&*buf)?, profile])?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => profile.clone(),
+        Err(e) => return Err(e),
+    };
+    let mut content = Vec::new();
+    merged
+        .encode(&mut content)
+        .map_err(|e| io::Error::other(format!("{}", e)))?;
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    std::fs::write(&tmp, pprof::compress(&content, compression)?)?;
+    std::fs::rename(&tmp, path)
+}
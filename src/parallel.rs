@@ -0,0 +1,158 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+
+use crate::pprof::PprofConverterBuilder;
+
+/// How evenly the samples of `perf script` output spread over threads.
+#[derive(Default)]
+pub struct Parallel {
+    total: u64,
+    /// samples per thread
+    threads: HashMap<u32, u64>,
+    /// root first stack, samples per thread
+    stacks: HashMap<Vec<String>, HashMap<u32, u64>>,
+}
+
+/// A stack and the threads running it.
+pub struct SharedStack<'a> {
+    /// root first
+    pub frames: &'a [String],
+    pub samples: u64,
+    pub threads: usize,
+    /// samples of the busiest thread running the stack
+    pub max: u64,
+}
+
+impl Parallel {
+    /// adds the samples in `perf script` output
+    pub fn update<R>(&mut self, reader: R) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        PprofConverterBuilder::default().read_samples(reader, |sample| {
+            let tid = sample
+                .labels
+                .iter()
+                .find(|(k, _)| k == "tid")
+                .and_then(|(_, v)| v.parse::<u32>().ok());
+            if let Some(tid) = tid {
+                let mut frames = sample.functions;
+                frames.reverse();
+                self.add(tid, frames);
+            }
+            Ok(())
+        })
+    }
+
+    fn add(&mut self, tid: u32, frames: Vec<String>) {
+        self.total += 1;
+        *self.threads.entry(tid).or_insert(0) += 1;
+        *self
+            .stacks
+            .entry(frames)
+            .or_default()
+            .entry(tid)
+            .or_insert(0) += 1;
+    }
+
+    /// the number of samples
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// the number of threads sampled
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Average over the busiest thread; 1.0 if the samples spread evenly
+    /// over all the threads.
+    pub fn efficiency(&self) -> f64 {
+        let max = self.threads.values().max().copied().unwrap_or(0);
+        self.total as f64 / (self.threads.len() as u64 * max).max(1) as f64
+    }
+
+    /// Samples in the stacks running one thread at a time, as a fraction.
+    ///
+    /// The stacks through one of `sections` are serial; without `sections`,
+    /// the stacks seen on only one thread are.
+    pub fn serial_fraction(&self, sections: &[String]) -> f64 {
+        let serial: u64 = self
+            .stacks
+            .iter()
+            .filter(|(frames, threads)| {
+                if sections.is_empty() {
+                    threads.len() == 1
+                } else {
+                    frames
+                        .iter()
+                        .any(|f| sections.iter().any(|s| f.starts_with(s.as_str())))
+                }
+            })
+            .map(|(_, threads)| threads.values().sum::<u64>())
+            .sum();
+        serial as f64 / self.total.max(1) as f64
+    }
+
+    /// the hottest stacks running on more than one thread
+    pub fn shared_stacks(&self, n: usize) -> Vec<SharedStack<'_>> {
+        let mut v: Vec<SharedStack> = self
+            .stacks
+            .iter()
+            .filter(|(_, threads)| threads.len() > 1)
+            .map(|(frames, threads)| SharedStack {
+                frames,
+                samples: threads.values().sum(),
+                threads: threads.len(),
+                max: threads.values().max().copied().unwrap_or(0),
+            })
+            .collect();
+        v.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.frames.cmp(b.frames)));
+        v.truncate(n);
+        v
+    }
+
+    /// renders the efficiency, the Amdahl bound and the hottest `n` shared stacks
+    pub fn render(&self, sections: &[String], n: usize) -> String {
+        let threads = self.threads();
+        let serial = self.serial_fraction(sections);
+        let speedup = 1.0 / (serial + (1.0 - serial) / threads.max(1) as f64);
+        let mut s = format!(
+            "{} samples on {} threads\nparallel efficiency: {:.1}%\nserial fraction: {:.1}% (at most {:.2}x on {} threads)\n\n",
+            self.total,
+            threads,
+            self.efficiency() * 100.0,
+            serial * 100.0,
+            speedup,
+            threads
+        );
+        let _ = writeln!(s, "{:>8} {:>8} {:>7}  Stack", "Samples", "Threads", "Even%");
+        for stack in self.shared_stacks(n) {
+            let _ = writeln!(
+                s,
+                "{:>8} {:>8} {:>6.1}%  {}",
+                stack.samples,
+                stack.threads,
+                stack.samples as f64 * 100.0 / (stack.threads as u64 * stack.max).max(1) as f64,
+                stack.frames.join(";")
+            );
+        }
+        s
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the program counters sampled from outside of a kernel, like with
+//! QEMU's gdbstub or a JTAG probe stopping the target periodically. A sample
+//! is a line of hex addresses, the pc then the return addresses if the stack
+//! was walked:
+//!
+//! ```text
+//! # pc ra...
+//! ffffffff80012a4c ffffffff80013100 ffffffff80010044
+//! 0x80200e10
+//! ```
+
+use std::io;
+
+use crate::pprof::{self, pb, SampleBuilder};
+use crate::symbolize::Symbolizer;
+
+fn address(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// Converts the PC dump into a profile of the sample counts, with the
+/// functions from the symbols of the ELF of the kernel.
+pub fn decode_pc_dump<R>(mut reader: R, elf: &str) -> io::Result<pb::Profile>
+where
+    R: io::BufRead,
+{
+    let mut symbolizer = Symbolizer::new(None);
+    let mut builder = SampleBuilder::default();
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            break;
+        }
+        let line = buf.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut frames = Vec::new();
+        for (i, s) in line.split_whitespace().enumerate() {
+            let addr = address(s).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("not an address: {}", s))
+            })?;
+            // the call instruction before the return address
+            let lookup = if i == 0 { addr } else { addr.saturating_sub(1) };
+            frames.push(match symbolizer.symbol(elf, lookup) {
+                Some(name) => pprof::demangle(&name),
+                None => format!("{:#x}", addr),
+            });
+        }
+        let frames: Vec<&str> = frames.iter().map(|f| f.as_str()).collect();
+        builder.add(&frames, &[], 1);
+    }
+    Ok(builder.build())
+}
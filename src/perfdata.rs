@@ -0,0 +1,839 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A reader of perf.data for hosts without the perf binary, like minimal
+//! containers. It reads the samples, the mmaps and the comms of a file
+//! recorded with frame pointers (`perf record -g`), and writes what
+//! `perf script --header` would, so that the converter takes it as is. The
+//! stacks of `--call-graph dwarf` need perf to unwind, and only their leaves
+//! and the kernel frames are read.
+
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use crate::pprof;
+use crate::symbolize::{self, Mmap, Symbolizer};
+
+const MAGIC: &[u8; 8] = b"PERFILE2";
+const FILE_HEADER_SIZE: usize = 104;
+
+// linux/perf_event.h
+const PERF_RECORD_MMAP: u32 = 1;
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_COMM: u32 = 3;
+const PERF_RECORD_FORK: u32 = 7;
+const PERF_RECORD_SAMPLE: u32 = 9;
+const PERF_RECORD_MMAP2: u32 = 10;
+const PERF_RECORD_LOST_SAMPLES: u32 = 13;
+
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+const PERF_SAMPLE_READ: u64 = 1 << 4;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const PERF_SAMPLE_ID: u64 = 1 << 6;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+const PERF_FORMAT_ID: u64 = 1 << 2;
+const PERF_FORMAT_GROUP: u64 = 1 << 3;
+const PERF_FORMAT_LOST: u64 = 1 << 4;
+
+const PERF_ATTR_FLAG_FREQ: u64 = 1 << 10;
+const PERF_ATTR_FLAG_SAMPLE_ID_ALL: u64 = 1 << 18;
+
+const PERF_CONTEXT_USER: u64 = -512i64 as u64;
+const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
+
+// the features of tools/perf/util/header.h
+const HEADER_HOSTNAME: usize = 3;
+const HEADER_OSRELEASE: usize = 4;
+const HEADER_VERSION: usize = 5;
+const HEADER_ARCH: usize = 6;
+const HEADER_CMDLINE: usize = 11;
+const HEADER_EVENT_DESC: usize = 12;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// reads the native-endian integers of a record
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8], pos: usize) -> Self {
+        Cursor { buf, pos }
+    }
+
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let b = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.buf.get(self.pos..end))
+            .ok_or_else(|| invalid("truncated perf.data"))?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    // a NUL-terminated string padded to 8 bytes, to the end of the buffer
+    fn c_str(&mut self) -> String {
+        let rest = self.buf.get(self.pos..).unwrap_or_default();
+        let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        self.pos = self.buf.len();
+        String::from_utf8_lossy(&rest[..end]).to_string()
+    }
+
+    // struct perf_header_string { u32 len; char str[len]; }
+    fn header_string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let b = self.bytes(len)?;
+        let end = b.iter().position(|b| *b == 0).unwrap_or(len);
+        Ok(String::from_utf8_lossy(&b[..end]).to_string())
+    }
+}
+
+struct Attr {
+    name: String,
+    r#type: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+}
+
+fn event_name(r#type: u32, config: u64) -> String {
+    let name = match (r#type, config) {
+        (0, 0) => "cycles",
+        (0, 1) => "instructions",
+        (0, 2) => "cache-references",
+        (0, 3) => "cache-misses",
+        (0, 4) => "branches",
+        (0, 5) => "branch-misses",
+        (1, 0) => "cpu-clock",
+        (1, 1) => "task-clock",
+        (1, 2) => "page-faults",
+        (1, 3) => "context-switches",
+        (1, 4) => "cpu-migrations",
+        _ => return format!("raw-{}-{:x}", r#type, config),
+    };
+    name.to_string()
+}
+
+fn parse_attr(buf: &[u8]) -> io::Result<Attr> {
+    let mut c = Cursor::new(buf, 0);
+    let r#type = c.u32()?;
+    let _size = c.u32()?;
+    let config = c.u64()?;
+    let sample_period = c.u64()?;
+    let sample_type = c.u64()?;
+    let read_format = c.u64()?;
+    let flags = c.u64()?;
+    Ok(Attr {
+        name: event_name(r#type, config),
+        r#type,
+        config,
+        sample_period,
+        sample_type,
+        read_format,
+        flags,
+    })
+}
+
+/// A perf.data file read in memory.
+pub struct PerfData {
+    buf: Vec<u8>,
+    attrs: Vec<Attr>,
+    // sample id to the index of the attr
+    ids: HashMap<u64, usize>,
+    data: (usize, usize),
+    // feature bit, (offset, size)
+    features: HashMap<usize, (usize, usize)>,
+    captured_time: DateTime<Local>,
+}
+
+impl PerfData {
+    pub fn open(path: &str) -> io::Result<PerfData> {
+        let buf = std::fs::read(path)?;
+        let captured_time = std::fs::metadata(path)?.modified()?.into();
+        let mut c = Cursor::new(&buf, 0);
+        if c.bytes(8)? != MAGIC {
+            return Err(invalid("not a perf.data file"));
+        }
+        if c.u64()? as usize != FILE_HEADER_SIZE {
+            return Err(invalid(
+                "perf.data in pipe mode isn't supported; record to a file",
+            ));
+        }
+        let attr_size = c.u64()? as usize;
+        let attrs_section = (c.u64()? as usize, c.u64()? as usize);
+        let data = (c.u64()? as usize, c.u64()? as usize);
+        let _event_types = (c.u64()?, c.u64()?);
+        let mut bitmap = [0u64; 4];
+        for b in bitmap.iter_mut() {
+            *b = c.u64()?;
+        }
+
+        let mut attrs = Vec::new();
+        let mut ids = HashMap::new();
+        if attr_size < 16 {
+            return Err(invalid("broken attributes"));
+        }
+        for i in 0..attrs_section.1 / attr_size {
+            let attr = i
+                .checked_mul(attr_size)
+                .and_then(|o| o.checked_add(attrs_section.0))
+                .and_then(|o| buf.get(o..o.checked_add(attr_size)?))
+                .ok_or_else(|| invalid("truncated perf.data"))?;
+            attrs.push(parse_attr(attr)?);
+            let mut c = Cursor::new(attr, attr_size - 16);
+            let (ids_offset, ids_size) = (c.u64()? as usize, c.u64()? as usize);
+            let mut c = Cursor::new(&buf, ids_offset);
+            for _ in 0..ids_size / 8 {
+                ids.insert(c.u64()?, i);
+            }
+        }
+        if attrs.is_empty() {
+            return Err(invalid("no events recorded"));
+        }
+
+        // the sections of the features follow the data in the order of the bits
+        let mut features = HashMap::new();
+        let data_end = data
+            .0
+            .checked_add(data.1)
+            .ok_or_else(|| invalid("broken data section"))?;
+        let mut c = Cursor::new(&buf, data_end);
+        for bit in 0..256 {
+            if bitmap[bit / 64] & (1 << (bit % 64)) != 0 {
+                features.insert(bit, (c.u64()? as usize, c.u64()? as usize));
+            }
+        }
+
+        let mut perf = PerfData {
+            buf,
+            attrs,
+            ids,
+            data,
+            features,
+            captured_time,
+        };
+        perf.read_event_names();
+        Ok(perf)
+    }
+
+    fn feature(&self, bit: usize) -> Option<Cursor<'_>> {
+        let (offset, size) = self.features.get(&bit)?;
+        Some(Cursor::new(
+            self.buf.get(..offset.checked_add(*size)?)?,
+            *offset,
+        ))
+    }
+
+    fn feature_string(&self, bit: usize) -> Option<String> {
+        self.feature(bit)?.header_string().ok()
+    }
+
+    // the names with the modifiers like `cycles:u`
+    fn read_event_names(&mut self) {
+        let mut names = Vec::new();
+        if let Some(mut c) = self.feature(HEADER_EVENT_DESC) {
+            let mut read = || -> io::Result<()> {
+                let nr = c.u32()?;
+                let attr_size = c.u32()? as usize;
+                for _ in 0..nr {
+                    c.bytes(attr_size)?;
+                    let nr_ids = c.u32()? as usize;
+                    let name = c.header_string()?;
+                    let mut ids = Vec::new();
+                    for _ in 0..nr_ids {
+                        ids.push(c.u64()?);
+                    }
+                    names.push((name, ids));
+                }
+                Ok(())
+            };
+            let _ = read();
+        }
+        for (name, ids) in names {
+            if let Some(i) = ids.iter().find_map(|id| self.ids.get(id)) {
+                self.attrs[*i].name = name;
+            }
+        }
+    }
+
+    fn write_header<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "# ========")?;
+        writeln!(
+            writer,
+            "# captured on    : {}",
+            self.captured_time.format("%c")
+        )?;
+        for (bit, key) in [
+            (HEADER_HOSTNAME, "hostname"),
+            (HEADER_OSRELEASE, "os release"),
+            (HEADER_VERSION, "perf version"),
+            (HEADER_ARCH, "arch"),
+        ] {
+            if let Some(value) = self.feature_string(bit) {
+                writeln!(writer, "# {} : {}", key, value)?;
+            }
+        }
+        if let Some(mut c) = self.feature(HEADER_CMDLINE) {
+            let mut args = Vec::new();
+            for _ in 0..c.u32().unwrap_or(0) {
+                match c.header_string() {
+                    Ok(a) => args.push(a),
+                    Err(_) => break,
+                }
+            }
+            writeln!(writer, "# cmdline : {}", args.join(" "))?;
+        }
+        for attr in &self.attrs {
+            let period = if attr.flags & PERF_ATTR_FLAG_FREQ != 0 {
+                "{ sample_period, sample_freq }"
+            } else {
+                "sample_period"
+            };
+            writeln!(
+                writer,
+                "# event : name = {}, type = {}, config = {:#x}, {} = {}",
+                attr.name, attr.r#type, attr.config, period, attr.sample_period
+            )?;
+        }
+        writeln!(writer, "# ========")?;
+        writeln!(writer, "#")?;
+        writeln!(writer)
+    }
+
+    // the attr of a sample; with more than one event, perf records the id
+    fn sample_attr(&self, record: &[u8]) -> io::Result<&Attr> {
+        let first = &self.attrs[0];
+        if self.attrs.len() == 1 {
+            return Ok(first);
+        }
+        let mut c = Cursor::new(record, 0);
+        let id = if first.sample_type & PERF_SAMPLE_IDENTIFIER != 0 {
+            c.u64()?
+        } else {
+            // the fields before the id
+            for bit in [
+                PERF_SAMPLE_IP,
+                PERF_SAMPLE_TID,
+                PERF_SAMPLE_TIME,
+                PERF_SAMPLE_ADDR,
+            ] {
+                if first.sample_type & bit != 0 {
+                    c.u64()?;
+                }
+            }
+            if first.sample_type & PERF_SAMPLE_ID == 0 {
+                return Ok(first);
+            }
+            c.u64()?
+        };
+        Ok(self.ids.get(&id).map_or(first, |i| &self.attrs[*i]))
+    }
+
+    // the time of a record: a sample's own, or the one in the sample_id
+    // appended to the other records with `sample_id_all`
+    fn record_time(&self, r#type: u32, record: &[u8]) -> Option<u64> {
+        let attr = if r#type == PERF_RECORD_SAMPLE {
+            self.sample_attr(record).ok()?
+        } else {
+            &self.attrs[0]
+        };
+        let t = attr.sample_type;
+        if t & PERF_SAMPLE_TIME == 0 {
+            return None;
+        }
+        let mut c = if r#type == PERF_RECORD_SAMPLE {
+            Cursor::new(record, 0)
+        } else {
+            if attr.flags & PERF_ATTR_FLAG_SAMPLE_ID_ALL == 0 {
+                return None;
+            }
+            let fields = [
+                PERF_SAMPLE_TID,
+                PERF_SAMPLE_TIME,
+                PERF_SAMPLE_ID,
+                PERF_SAMPLE_STREAM_ID,
+                PERF_SAMPLE_CPU,
+                PERF_SAMPLE_IDENTIFIER,
+            ];
+            let size = fields.iter().filter(|bit| t & *bit != 0).count() * 8;
+            Cursor::new(record, record.len().checked_sub(size)?)
+        };
+        let before = if r#type == PERF_RECORD_SAMPLE {
+            &[PERF_SAMPLE_IDENTIFIER, PERF_SAMPLE_IP, PERF_SAMPLE_TID][..]
+        } else {
+            &[PERF_SAMPLE_TID][..]
+        };
+        for bit in before {
+            if t & bit != 0 {
+                c.u64().ok()?;
+            }
+        }
+        c.u64().ok()
+    }
+
+    // the records of the data section with their types, sorted by time
+    // across the cpus like `perf script`; the ones without a time keep
+    // their place after the record before them
+    fn records(&self) -> io::Result<Vec<(u32, &[u8])>> {
+        let end = self.data.0.saturating_add(self.data.1).min(self.buf.len());
+        let mut pos = self.data.0;
+        let mut time = 0;
+        let mut records = Vec::new();
+        while pos + 8 <= end {
+            let mut c = Cursor::new(&self.buf, pos);
+            let r#type = c.u32()?;
+            let _misc = c.bytes(2)?;
+            let size = u16::from_ne_bytes(c.bytes(2)?.try_into().unwrap()) as usize;
+            if size < 8 || pos + size > end {
+                break;
+            }
+            let record = &self.buf[pos + 8..pos + size];
+            pos += size;
+            time = self.record_time(r#type, record).unwrap_or(time);
+            records.push((time, r#type, record));
+        }
+        records.sort_by_key(|(time, _, _)| *time);
+        Ok(records
+            .into_iter()
+            .map(|(_, r#type, record)| (r#type, record))
+            .collect())
+    }
+
+    /// writes the samples in the format of `perf script --header`, resolving
+    /// the symbols with the binaries (under `symfs` if any) and `kallsyms`;
+    /// the mmaps too with `mmaps`, like `--show-mmap-events`
+    pub fn script<W: Write>(
+        &self,
+        symfs: Option<&str>,
+        kallsyms: &str,
+        mmaps: bool,
+        mut writer: W,
+    ) -> io::Result<()> {
+        self.write_header(&mut writer)?;
+        let kernel = symbolize::kallsyms(kallsyms);
+        // the mmaps are per process
+        let mut symbolizers: HashMap<u32, Symbolizer> = HashMap::new();
+        let mut comms: HashMap<u32, String> = HashMap::new();
+
+        for (r#type, record) in self.records()? {
+            match r#type {
+                PERF_RECORD_COMM => {
+                    let mut c = Cursor::new(record, 0);
+                    let _pid = c.u32()?;
+                    let tid = c.u32()?;
+                    comms.insert(tid, c.c_str());
+                }
+                PERF_RECORD_FORK => {
+                    // the child runs the parent's code until it execs
+                    let mut c = Cursor::new(record, 0);
+                    let pid = c.u32()?;
+                    let ppid = c.u32()?;
+                    let tid = c.u32()?;
+                    let ptid = c.u32()?;
+                    if let Some(comm) = comms.get(&ptid).cloned() {
+                        comms.insert(tid, comm);
+                    }
+                    if pid != ppid {
+                        if let Some(parent) = symbolizers.get(&ppid) {
+                            let child = parent.fork();
+                            symbolizers.insert(pid, child);
+                        }
+                    }
+                }
+                PERF_RECORD_MMAP | PERF_RECORD_MMAP2 => {
+                    let mut c = Cursor::new(record, 0);
+                    let pid = c.u32()?;
+                    let tid = c.u32()?;
+                    let mmap = Mmap {
+                        start: c.u64()?,
+                        len: c.u64()?,
+                        pgoff: c.u64()?,
+                    };
+                    if r#type == PERF_RECORD_MMAP2 {
+                        // maj, min, ino, ino_generation (or the build id), prot, flags
+                        c.bytes(32)?;
+                    }
+                    // the kernel's
+                    if pid == u32::MAX {
+                        continue;
+                    }
+                    let file = c.c_str();
+                    if mmaps {
+                        writeln!(
+                            writer,
+                            "perf 0 0.000000: PERF_RECORD_MMAP2 {}/{}: [{:#x}({:#x}) @ {:#x} 00:00 0 0]: r-xp {}",
+                            pid, tid, mmap.start, mmap.len, mmap.pgoff, file
+                        )?;
+                    }
+                    symbolizers
+                        .entry(pid)
+                        .or_insert_with(|| Symbolizer::new(symfs))
+                        .add_mmap(file, mmap);
+                }
+                PERF_RECORD_LOST | PERF_RECORD_LOST_SAMPLES => {
+                    let mut c = Cursor::new(record, 0);
+                    if r#type == PERF_RECORD_LOST {
+                        // the id of the event
+                        c.u64()?;
+                    }
+                    let name = if r#type == PERF_RECORD_LOST {
+                        "LOST"
+                    } else {
+                        "LOST_SAMPLES"
+                    };
+                    writeln!(
+                        writer,
+                        "perf 0 0.000000: PERF_RECORD_{} lost {}",
+                        name,
+                        c.u64()?
+                    )?;
+                }
+                PERF_RECORD_SAMPLE => {
+                    let attr = self.sample_attr(record)?;
+                    let sample = match parse_sample(attr, record) {
+                        Ok(s) => s,
+                        Err(_) => continue,
+                    };
+                    let comm = comms.get(&sample.tid).map_or("[unknown]", |c| c.as_str());
+                    write!(writer, "{} {}/{} ", comm, sample.pid, sample.tid)?;
+                    if let Some(cpu) = sample.cpu {
+                        write!(writer, "[{:03}] ", cpu)?;
+                    }
+                    write!(
+                        writer,
+                        "{}.{:06}: ",
+                        sample.time / 1_000_000_000,
+                        sample.time % 1_000_000_000 / 1000
+                    )?;
+                    if let Some(period) = sample.period {
+                        write!(writer, "{} ", period)?;
+                    }
+                    writeln!(writer, "{}:", attr.name)?;
+
+                    let symbolizer = symbolizers
+                        .entry(sample.pid)
+                        .or_insert_with(|| Symbolizer::new(symfs));
+                    for (addr, kernel_frame) in sample.stack {
+                        let (name, module) = if kernel_frame {
+                            (
+                                symbolize::kernel_function(&kernel, addr).map(|n| n.to_string()),
+                                Some("[kernel.kallsyms]".to_string()),
+                            )
+                        } else {
+                            match symbolizer.function(addr) {
+                                Some((name, file)) => (Some(pprof::demangle(&name)), Some(file)),
+                                None => (None, None),
+                            }
+                        };
+                        writeln!(
+                            writer,
+                            "\t{:x} {} ({})",
+                            addr,
+                            name.as_deref().unwrap_or("[unknown]"),
+                            module.as_deref().unwrap_or("[unknown]")
+                        )?;
+                    }
+                    writeln!(writer)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Sample {
+    pid: u32,
+    tid: u32,
+    time: u64,
+    cpu: Option<u32>,
+    period: Option<u64>,
+    // leaf first, whether it's in the kernel
+    stack: Vec<(u64, bool)>,
+}
+
+fn parse_sample(attr: &Attr, record: &[u8]) -> io::Result<Sample> {
+    let t = attr.sample_type;
+    let mut c = Cursor::new(record, 0);
+    let mut sample = Sample {
+        pid: 0,
+        tid: 0,
+        time: 0,
+        cpu: None,
+        period: None,
+        stack: Vec::new(),
+    };
+    if t & PERF_SAMPLE_IDENTIFIER != 0 {
+        c.u64()?;
+    }
+    let mut ip = None;
+    if t & PERF_SAMPLE_IP != 0 {
+        ip = Some(c.u64()?);
+    }
+    if t & PERF_SAMPLE_TID != 0 {
+        sample.pid = c.u32()?;
+        sample.tid = c.u32()?;
+    }
+    if t & PERF_SAMPLE_TIME != 0 {
+        sample.time = c.u64()?;
+    }
+    for bit in [PERF_SAMPLE_ADDR, PERF_SAMPLE_ID, PERF_SAMPLE_STREAM_ID] {
+        if t & bit != 0 {
+            c.u64()?;
+        }
+    }
+    if t & PERF_SAMPLE_CPU != 0 {
+        sample.cpu = Some(c.u32()?);
+        c.u32()?;
+    }
+    if t & PERF_SAMPLE_PERIOD != 0 {
+        sample.period = Some(c.u64()?);
+    }
+    if t & PERF_SAMPLE_READ != 0 {
+        skip_read(attr.read_format, &mut c)?;
+    }
+    let kernel = |addr: u64| addr >= 1 << 63;
+    if t & PERF_SAMPLE_CALLCHAIN != 0 {
+        let nr = c.u64()?;
+        let mut in_kernel = matches!(ip, Some(ip) if kernel(ip));
+        for _ in 0..nr {
+            let addr = c.u64()?;
+            if addr >= PERF_CONTEXT_MAX {
+                // the kernel, the hypervisor or the guest, resolved with kallsyms
+                in_kernel = addr != PERF_CONTEXT_USER;
+                continue;
+            }
+            sample.stack.push((addr, in_kernel));
+        }
+    }
+    if sample.stack.is_empty() {
+        if let Some(ip) = ip {
+            sample.stack.push((ip, kernel(ip)));
+        }
+    }
+    Ok(sample)
+}
+
+// struct read_format
+fn skip_read(read_format: u64, c: &mut Cursor) -> io::Result<()> {
+    let per_value = 1
+        + (read_format & PERF_FORMAT_ID != 0) as usize
+        + (read_format & PERF_FORMAT_LOST != 0) as usize;
+    let times = (read_format & PERF_FORMAT_TOTAL_TIME_ENABLED != 0) as usize
+        + (read_format & PERF_FORMAT_TOTAL_TIME_RUNNING != 0) as usize;
+    let n = if read_format & PERF_FORMAT_GROUP != 0 {
+        let nr = c.u64()? as usize;
+        nr.checked_mul(per_value)
+            .and_then(|n| n.checked_add(times))
+            .ok_or_else(|| invalid("broken read_format"))?
+    } else {
+        times + per_value
+    };
+    c.bytes(
+        n.checked_mul(8)
+            .ok_or_else(|| invalid("broken read_format"))?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_TYPE: u64 =
+        PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_CPU | PERF_SAMPLE_PERIOD;
+
+    fn record(r#type: u32, body: &[u8]) -> Vec<u8> {
+        let mut r = r#type.to_ne_bytes().to_vec();
+        r.extend(0u16.to_ne_bytes());
+        r.extend(((body.len() + 8) as u16).to_ne_bytes());
+        r.extend(body);
+        r
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    fn u64s(values: &[u64]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    // the sample_id of the records other than the samples: tid, time, cpu
+    fn sample_id(pid: u32, time: u64, cpu: u32) -> Vec<u8> {
+        let mut b = u32s(&[pid, pid]);
+        b.extend(u64s(&[time]));
+        b.extend(u32s(&[cpu, 0]));
+        b
+    }
+
+    fn comm(pid: u32, name: &str, time: u64) -> Vec<u8> {
+        let mut b = u32s(&[pid, pid]);
+        let mut name = name.as_bytes().to_vec();
+        name.resize((name.len() / 8 + 1) * 8, 0);
+        b.extend(name);
+        b.extend(sample_id(pid, time, 0));
+        record(PERF_RECORD_COMM, &b)
+    }
+
+    fn fork(pid: u32, ppid: u32, time: u64) -> Vec<u8> {
+        let mut b = u32s(&[pid, ppid, pid, ppid]);
+        b.extend(u64s(&[time]));
+        b.extend(sample_id(pid, time, 0));
+        record(PERF_RECORD_FORK, &b)
+    }
+
+    fn sample(pid: u32, time: u64, cpu: u32) -> Vec<u8> {
+        let mut b = u64s(&[0x1000]);
+        b.extend(u32s(&[pid, pid]));
+        b.extend(u64s(&[time]));
+        b.extend(u32s(&[cpu, 0]));
+        b.extend(u64s(&[1]));
+        record(PERF_RECORD_SAMPLE, &b)
+    }
+
+    // a perf.data with a cpu-clock event and the records
+    fn perf_data(name: &str, records: &[Vec<u8>]) -> String {
+        let attr_size = 64;
+        let data: Vec<u8> = records.concat();
+        let mut buf = MAGIC.to_vec();
+        buf.extend(u64s(&[
+            FILE_HEADER_SIZE as u64,
+            attr_size,
+            FILE_HEADER_SIZE as u64,
+            attr_size,
+            FILE_HEADER_SIZE as u64 + attr_size,
+            data.len() as u64,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]));
+        // type, size, config, sample_period, sample_type, read_format, flags
+        buf.extend(u32s(&[1, 48]));
+        buf.extend(u64s(&[
+            0,
+            4000,
+            SAMPLE_TYPE,
+            0,
+            PERF_ATTR_FLAG_FREQ | PERF_ATTR_FLAG_SAMPLE_ID_ALL,
+        ]));
+        // the ids
+        buf.extend(u64s(&[0, 0]));
+        buf.extend(data);
+        let path =
+            std::env::temp_dir().join(format!("perf-tools-{}-{}.data", name, std::process::id()));
+        std::fs::write(&path, buf).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn script(path: &str) -> String {
+        let perf = PerfData::open(path).unwrap();
+        let mut out = Vec::new();
+        perf.script(None, "/nonexistent", false, &mut out).unwrap();
+        std::fs::remove_file(path).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn samples(script: &str) -> Vec<&str> {
+        script
+            .lines()
+            .filter(|l| l.ends_with("cpu-clock:"))
+            .collect()
+    }
+
+    #[test]
+    fn samples_sorted_by_time() {
+        let path = perf_data(
+            "sorted",
+            &[
+                comm(1, "app", 1),
+                sample(1, 3_000_000_000, 1),
+                sample(1, 1_000_000_000, 0),
+                sample(1, 2_000_000_000, 1),
+            ],
+        );
+        assert_eq!(
+            samples(&script(&path)),
+            [
+                "app 1/1 [000] 1.000000: 1 cpu-clock:",
+                "app 1/1 [001] 2.000000: 1 cpu-clock:",
+                "app 1/1 [001] 3.000000: 1 cpu-clock:",
+            ]
+        );
+    }
+
+    #[test]
+    fn forked_child() {
+        let path = perf_data(
+            "fork",
+            &[
+                comm(1, "server", 1),
+                sample(2, 3_000_000_000, 0),
+                fork(2, 1, 2_000_000_000),
+            ],
+        );
+        let script = script(&path);
+        assert_eq!(
+            samples(&script),
+            ["server 2/2 [000] 3.000000: 1 cpu-clock:"]
+        );
+        assert!(script.contains("# event : name = cpu-clock, type = 1, config = 0x0"));
+    }
+
+    #[test]
+    fn broken_sections() {
+        let path = perf_data("broken", &[sample(1, 1, 0)]);
+        let mut buf = std::fs::read(&path).unwrap();
+        // the size of the data section
+        buf[48..56].copy_from_slice(&u64::MAX.to_ne_bytes());
+        std::fs::write(&path, &buf).unwrap();
+        let err = PerfData::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // the offset of the attributes
+        buf[24..32].copy_from_slice(&u64::MAX.to_ne_bytes());
+        std::fs::write(&path, &buf).unwrap();
+        let err = PerfData::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
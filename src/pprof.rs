@@ -13,112 +13,1164 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use chrono::{offset::LocalResult, DateTime, Local, NaiveDateTime, TimeZone};
+use chrono::{DateTime, Local};
 use lazy_static::lazy_static;
 use prost::Message;
 use regex::Regex;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::io;
+use std::io::Read;
 use std::time::Duration;
 
+use crate::date;
+use crate::grouping::{self, Action, Rule};
+use crate::jitdump::JitDump;
+use crate::symbolize::{self, Mmap, Symbolizer};
+use crate::tenant::Tenants;
+
+pub use crate::profile::Profile;
+
 pub mod pb {
     include!(concat!(env!("OUT_DIR"), "/perftools.profiles.rs"));
 }
 
-#[derive(PartialEq, Hash, std::cmp::Eq)]
+#[derive(Clone, PartialEq, Hash, std::cmp::Eq)]
 struct Stack {
     pc: u64,
     func: String,
     module: String,
 }
 
-#[derive(PartialEq, Hash, std::cmp::Eq)]
+#[derive(Clone, PartialEq, Hash, std::cmp::Eq)]
 struct Sample {
     stacks: Vec<Stack>,
+    labels: Vec<(String, String)>,
+}
+
+// a fake address for the frames of a group
+fn group_pc(group: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    group.hash(&mut hasher);
+    hasher.finish() | 1 << 63
+}
+
+// applies the grouping rules to a stack, adding labels of highlighted groups
+fn group_frames(
+    stacks: Vec<Stack>,
+    rules: &[Rule],
+    labels: &mut Vec<(String, String)>,
+) -> Vec<Stack> {
+    if rules.is_empty() {
+        return stacks;
+    }
+    let mut v = Vec::with_capacity(stacks.len());
+    let mut last: Option<&str> = None;
+    for s in stacks {
+        let rule = grouping::rule_of(rules, &s.func);
+        let mut group = None;
+        match rule {
+            Some(r) if r.action == Action::Collapse => {
+                // runs of frames in the same group become one frame
+                if last != Some(r.group.as_str()) {
+                    v.push(Stack {
+                        pc: group_pc(&r.group),
+                        func: r.group.clone(),
+                        module: String::new(),
+                    });
+                }
+                group = Some(r.group.as_str());
+            }
+            Some(r) if r.action == Action::Hide => continue,
+            Some(r) => {
+                let label = ("group".to_string(), r.group.clone());
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+                v.push(s);
+            }
+            None => v.push(s),
+        }
+        last = group;
+    }
+    v
 }
 
 struct PerfReader {
-    sample: HashMap<Sample, u64>,
+    // the number of samples, and the sum of the periods per event
+    sample: HashMap<Sample, Vec<u64>>,
+    // in order of appearance
+    events: Vec<String>,
+    // perf script printed the periods, `-F period`
+    periods: bool,
+    retry_loops: HashSet<Sample>,
     captured_time: DateTime<Local>,
     duration: Duration,
     freq: u64,
+    // recorded with `-c` instead of `-F`
+    sample_period: u64,
+    mmaps: Vec<(String, Mmap)>,
+    header: Vec<String>,
+    // the lines skipped in the lenient mode
+    malformed_lines: u64,
+    lost: u64,
+    idle_samples: u64,
 }
 
-#[derive(Default)]
-pub struct PprofConverterBuilder {}
+#[derive(Clone, Default)]
+struct Options {
+    event: Option<String>,
+    syscall_errors: bool,
+    // sorted by time
+    markers: Vec<(u64, String)>,
+    phase: Option<String>,
+    // (start, end, slow) as given
+    requests: Vec<(u64, u64, bool)>,
+    // the latency from each start or end of a request on, sorted; built from
+    // `requests` once they're all given
+    request_spans: Vec<(u64, Option<&'static str>)>,
+    latency: Option<String>,
+    comments: Vec<String>,
+    grouping: Vec<Rule>,
+    // the pattern, and the pattern anchored to match full names
+    drop_frames: Option<(String, Regex)>,
+    keep_frames: Option<(String, Regex)>,
+    prune: bool,
+    focus: Option<Regex>,
+    ignore: Option<Regex>,
+    tenants: Option<Tenants>,
+    metadata: bool,
+    lines: bool,
+    symfs: Option<String>,
+    demangle: bool,
+    captured_time: Option<DateTime<Local>>,
+    // in place of the header's
+    captured_on: Option<DateTime<Local>>,
+    strict_header: bool,
+    strict: bool,
+    jitdumps: Vec<JitDump>,
+    freq: u64,
+    compression: Compression,
+    idle: Idle,
+    repair: bool,
+    default_sample_type: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct PprofConverterBuilder {
+    options: Options,
+}
 
 impl PprofConverterBuilder {
+    /// counts only the samples of the event (or the event group) instead of cpu time
+    pub fn event(&mut self, event: &str) -> &mut Self {
+        self.options.event = Some(event.to_string());
+        self
+    }
+
+    /// collapses runs of frames matching the rules into one frame per group
+    pub fn grouping(&mut self, rules: &[Rule]) -> &mut Self {
+        self.options.grouping.extend_from_slice(rules);
+        self
+    }
+
+    /// counts failed syscalls per call stack, labeled with the syscall number and errno;
+    /// expects `raw_syscalls:sys_exit` events
+    pub fn syscall_errors(&mut self, enable: bool) -> &mut Self {
+        self.options.syscall_errors = enable;
+        if enable && self.options.event.is_none() {
+            self.options.event = Some(SYSCALL_EXIT_EVENT.to_string());
+        }
+        self
+    }
+
+    /// labels the samples after `time_usec` (in the clock of the recording) with the marker
+    pub fn marker(&mut self, time_usec: u64, name: &str) -> &mut Self {
+        let i = self
+            .options
+            .markers
+            .partition_point(|(t, _)| *t <= time_usec);
+        self.options
+            .markers
+            .insert(i, (time_usec, name.to_string()));
+        self
+    }
+
+    /// converts only the samples between the marker with `name` and the next marker
+    pub fn phase(&mut self, name: &str) -> &mut Self {
+        self.options.phase = Some(name.to_string());
+        self
+    }
+
+    /// sets `drop_frames` of the profile; pprof drops the frames whose names fully
+    /// match, with the frames they call
+    pub fn drop_frames(&mut self, pattern: &Regex) -> &mut Self {
+        self.options.drop_frames = Some(anchored(pattern));
+        self
+    }
+
+    /// sets `keep_frames` of the profile, the frames kept even if they match `drop_frames`
+    pub fn keep_frames(&mut self, pattern: &Regex) -> &mut Self {
+        self.options.keep_frames = Some(anchored(pattern));
+        self
+    }
+
+    /// keeps only the samples with a frame matching the pattern, like `pprof
+    /// -focus`, to see the cost of one subsystem
+    pub fn focus(&mut self, pattern: &Regex) -> &mut Self {
+        self.options.focus = Some(pattern.clone());
+        self.options
+            .comments
+            .push(format!("focus: {}", pattern.as_str()));
+        self
+    }
+
+    /// drops the samples with a frame matching the pattern, like `pprof -ignore`
+    pub fn ignore(&mut self, pattern: &Regex) -> &mut Self {
+        self.options.ignore = Some(pattern.clone());
+        self.options
+            .comments
+            .push(format!("ignore: {}", pattern.as_str()));
+        self
+    }
+
+    /// labels the samples with the `tenant` of their process, to attribute a
+    /// system-wide recording on shared workers
+    pub fn tenants(&mut self, tenants: Tenants) -> &mut Self {
+        self.options.tenants = Some(tenants);
+        self
+    }
+
+    /// drops or collapses the samples of idle cpus, which take over the profiles of
+    /// system-wide recordings (`perf record -a`) on machines under light load
+    pub fn idle(&mut self, idle: Idle) -> &mut Self {
+        self.options.idle = idle;
+        self
+    }
+
+    /// reattaches the stacks that dwarf unwinding cut short to the callers most
+    /// often seen under their outermost frames in the complete stacks, labeling
+    /// them `repaired`
+    pub fn repair(&mut self, enable: bool) -> &mut Self {
+        self.options.repair = enable;
+        self
+    }
+
+    /// removes the frames matching `drop_frames` but not `keep_frames` while converting,
+    /// leaving the frames they call
+    pub fn prune(&mut self, enable: bool) -> &mut Self {
+        self.options.prune = enable;
+        self
+    }
+
+    /// labels the samples while the request was in flight (in the clock of the recording)
+    /// with `latency`, `slow` or `fast`; `slow` wins when requests overlap
+    pub fn request(&mut self, start_usec: u64, end_usec: u64, slow: bool) -> &mut Self {
+        self.options.requests.push((start_usec, end_usec, slow));
+        self
+    }
+
+    /// converts only the samples with the `latency` label, `slow` or `fast`
+    pub fn latency(&mut self, latency: &str) -> &mut Self {
+        self.options.latency = Some(latency.to_string());
+        self
+    }
+
+    /// fills in the file names and the line numbers from the DWARF info of the binaries;
+    /// expects `perf script --show-mmap-events` output
+    pub fn lines(&mut self, enable: bool) -> &mut Self {
+        self.options.lines = enable;
+        self
+    }
+
+    /// looks for the binaries to resolve the lines under the directory
+    pub fn symfs(&mut self, dir: &str) -> &mut Self {
+        self.options.symfs = Some(dir.to_string());
+        self
+    }
+
+    /// demangles the raw symbols that perf leaves, Rust's and C++'s
+    pub fn demangle(&mut self, enable: bool) -> &mut Self {
+        self.options.demangle = enable;
+        self
+    }
+
+    /// the sampling frequency of the recording, in case the header lacks it
+    pub fn freq(&mut self, freq: u64) -> &mut Self {
+        self.options.freq = freq;
+        self
+    }
+
+    /// the time of the recording when the header lacks it, like the text of
+    /// `perf script` without `--header`; now by default
+    pub fn captured_time(&mut self, captured_time: DateTime<Local>) -> &mut Self {
+        self.options.captured_time = Some(captured_time);
+        self
+    }
+
+    /// the time of the recording in place of the one in the header, for the
+    /// headers that perf printed in a format that can't be read
+    pub fn captured_on(&mut self, captured_on: DateTime<Local>) -> &mut Self {
+        self.options.captured_on = Some(captured_on);
+        self
+    }
+
+    /// fails when the header lacks the time of the recording instead of
+    /// falling back to `captured_time` or now
+    pub fn strict_header(&mut self, enable: bool) -> &mut Self {
+        self.options.strict_header = enable;
+        self
+    }
+
+    /// names the frames in the code a JIT compiler generated, which perf
+    /// leaves unknown without `perf inject --jit`
+    pub fn jitdump(&mut self, jitdump: JitDump) -> &mut Self {
+        self.options.jitdumps.push(jitdump);
+        self
+    }
+
+    /// the sample type pprof shows by default, like `samples` for the counts or
+    /// `cpu` for the time, instead of the last one
+    pub fn default_sample_type(&mut self, name: &str) -> &mut Self {
+        self.options.default_sample_type = Some(name.to_string());
+        self
+    }
+
+    /// compresses the profile written, raw protobuf by default
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// fails on a malformed line, naming it, instead of skipping it; the
+    /// number of the lines skipped is left in a comment
+    pub fn strict(&mut self, enable: bool) -> &mut Self {
+        self.options.strict = enable;
+        self
+    }
+
+    /// keeps the hostname, the perf version, the command line and the events
+    /// of the perf script header as comments
+    pub fn metadata(&mut self, enable: bool) -> &mut Self {
+        self.options.metadata = enable;
+        self
+    }
+
+    /// adds a free-form comment to the profile
+    pub fn comment(&mut self, comment: &str) -> &mut Self {
+        self.options.comments.push(comment.to_string());
+        self
+    }
+
     pub fn build(&mut self) -> PprofConverter {
-        PprofConverter::new()
+        PprofConverter::new(self.indexed_options())
+    }
+
+    fn indexed_options(&self) -> Options {
+        let mut options = self.options.clone();
+        options.request_spans = request_spans(&options.requests);
+        options
     }
+
+    /// calls `f` with the samples of `perf script` output in order, read with
+    /// the options like the profile, to stream them in other formats
+    pub fn read_samples<R, F>(&self, reader: R, mut f: F) -> io::Result<()>
+    where
+        R: io::BufRead,
+        F: FnMut(ScriptSample) -> io::Result<()>,
+    {
+        PerfReader::read(reader, &self.indexed_options(), Some(&mut f)).map(|_| ())
+    }
+}
+
+pub const SYSCALL_EXIT_EVENT: &str = "raw_syscalls:sys_exit";
+
+// the same failure from the same stack that many times in a row is a retry loop
+const RETRY_LOOP_THRESHOLD: u64 = 100;
+
+/// demangles Rust and C++ symbols; Rust's are tried first since legacy ones
+/// are valid C++ symbols too
+pub fn demangle(name: &str) -> String {
+    if name.starts_with("_R") || name.starts_with("_ZN") {
+        if let Ok(d) = rustc_demangle::try_demangle(name) {
+            return d.to_string();
+        }
+    }
+    if name.starts_with("_Z") {
+        if let Ok(d) = cpp_demangle::Symbol::new(name.as_bytes())
+            .map_err(|_| std::fmt::Error)
+            .and_then(|s| s.demangle(&cpp_demangle::DemangleOptions::default()))
+        {
+            return d;
+        }
+    }
+    name.to_string()
+}
+
+fn errno_name(errno: i64) -> String {
+    let name = match errno {
+        1 => "EPERM",
+        2 => "ENOENT",
+        4 => "EINTR",
+        9 => "EBADF",
+        11 => "EAGAIN",
+        12 => "ENOMEM",
+        13 => "EACCES",
+        17 => "EEXIST",
+        22 => "EINVAL",
+        32 => "EPIPE",
+        104 => "ECONNRESET",
+        110 => "ETIMEDOUT",
+        111 => "ECONNREFUSED",
+        115 => "EINPROGRESS",
+        _ => return errno.to_string(),
+    };
+    name.to_string()
+}
+
+// Splits the time at the starts and ends of the requests into spans, each
+// with the latency of the requests in flight, for a binary search per sample
+fn request_spans(requests: &[(u64, u64, bool)]) -> Vec<(u64, Option<&'static str>)> {
+    // (time, slow, in flight +1 or -1); the ends are inclusive
+    let mut edges: Vec<(u64, bool, i64)> = requests
+        .iter()
+        .flat_map(|&(start, end, slow)| [(start, slow, 1), (end.saturating_add(1), slow, -1)])
+        .collect();
+    edges.sort_unstable();
+    let (mut slow, mut fast) = (0, 0);
+    let mut spans = Vec::new();
+    for (i, &(time, is_slow, n)) in edges.iter().enumerate() {
+        if is_slow {
+            slow += n;
+        } else {
+            fast += n;
+        }
+        if matches!(edges.get(i + 1), Some(next) if next.0 == time) {
+            continue;
+        }
+        let latency = if slow > 0 {
+            Some("slow")
+        } else if fast > 0 {
+            Some("fast")
+        } else {
+            None
+        };
+        spans.push((time, latency));
+    }
+    spans
+}
+
+// the latency of the requests in flight at the time
+fn request_latency(options: &Options, time: u64) -> Option<&'static str> {
+    let i = options.request_spans.partition_point(|(t, _)| *t <= time);
+    options.request_spans[..i].last().and_then(|(_, l)| *l)
+}
+
+fn anchored(pattern: &Regex) -> (String, Regex) {
+    let s = pattern.as_str().to_string();
+    let re = Regex::new(&format!("^(?:{})$", s)).unwrap();
+    (s, re)
+}
+
+fn full_match(pattern: &Option<(String, Regex)>, name: &str) -> bool {
+    matches!(pattern, Some((_, re)) if re.is_match(name))
+}
+
+/// What to do with the samples of idle cpus.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Idle {
+    #[default]
+    Keep,
+    Drop,
+    /// into a single `[idle]` frame, to see the share of idle time only
+    Collapse,
 }
 
+impl std::str::FromStr for Idle {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "keep" => Ok(Idle::Keep),
+            "drop" => Ok(Idle::Drop),
+            "collapse" => Ok(Idle::Collapse),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown idle handling: {}", s),
+            )),
+        }
+    }
+}
+
+// the idle loop of the kernel and the cpuidle drivers
+const IDLE_FRAMES: [&str; 12] = [
+    "do_idle",
+    "cpu_idle_poll",
+    "cpuidle_enter",
+    "cpuidle_enter_state",
+    "default_idle",
+    "default_idle_call",
+    "arch_cpu_idle",
+    "intel_idle",
+    "acpi_idle_enter",
+    "acpi_idle_do_entry",
+    "poll_idle",
+    "mwait_idle",
+];
+
+// the entries of the interrupts and softirqs that idle cpus serve
+const INTERRUPT_PREFIXES: [&str; 6] = [
+    "asm_",
+    "sysvec_",
+    "__sysvec_",
+    "common_interrupt",
+    "__do_softirq",
+    "handle_softirqs",
+];
+
+// in the idle loop, not in an interrupt taken while idle; the swapper
+// threads (tid 0) are idle too
+fn idle(stack: &[Stack], tid: u64) -> bool {
+    // leaf first
+    for frame in stack {
+        if INTERRUPT_PREFIXES.iter().any(|p| frame.func.starts_with(p)) {
+            return false;
+        }
+        if IDLE_FRAMES.contains(&frame.func.as_str()) {
+            return true;
+        }
+    }
+    tid == 0
+}
+
+// the outermost frames of the threads, which complete stacks end with
+const ROOT_FRAMES: [&str; 12] = [
+    "_start",
+    "_start_c",
+    "__libc_start_main",
+    "__libc_start_call_main",
+    "start_thread",
+    "clone",
+    "clone3",
+    "__clone",
+    "__clone3",
+    "__start_thread",
+    "__pthread_start",
+    "ret_from_fork",
+];
+
+// the outermost frames of a truncated stack matched against the complete ones
+const REPAIR_SUFFIX: usize = 3;
+
+// Reattaches the stacks not ending with a root frame to the callers of the
+// complete stacks having the same outermost frames, the longest match of up
+// to REPAIR_SUFFIX frames and then the most samples winning, the callers'
+// names breaking ties. The `[unknown]` frames where the unwinding gave up are
+// stripped first, and no run with one matches, as they're alike everywhere.
+// Returns the number of the samples repaired.
+fn repair_stacks(sample: &mut HashMap<Sample, Vec<u64>>) -> u64 {
+    let complete =
+        |s: &Sample| matches!(s.stacks.last(), Some(f) if ROOT_FRAMES.contains(&f.func.as_str()));
+    let unknown = |f: &Stack| f.func == "[unknown]";
+    // the functions of a run of frames, to the callers of the run with the
+    // samples of each
+    let mut callers: HashMap<Vec<&str>, HashMap<&[Stack], u64>> = HashMap::new();
+    for (s, values) in sample.iter().filter(|(s, _)| complete(s)) {
+        // leaf first; a run ending at the root has no callers to add
+        for end in 0..s.stacks.len() - 1 {
+            for len in 1..=REPAIR_SUFFIX.min(end + 1) {
+                let run = &s.stacks[end + 1 - len..=end];
+                if run.iter().any(unknown) {
+                    break;
+                }
+                let run = run.iter().map(|f| f.func.as_str()).collect();
+                *callers
+                    .entry(run)
+                    .or_default()
+                    .entry(&s.stacks[end + 1..])
+                    .or_insert(0) += values[0];
+            }
+        }
+    }
+
+    let mut repaired = Vec::new();
+    for (s, values) in sample.iter().filter(|(s, _)| !complete(s)) {
+        let n = s.stacks.len() - s.stacks.iter().rev().take_while(|f| unknown(f)).count();
+        // the runs without an unknown frame
+        let known = s.stacks[..n]
+            .iter()
+            .rev()
+            .take_while(|f| !unknown(f))
+            .count();
+        let best = (1..=REPAIR_SUFFIX.min(known)).rev().find_map(|len| {
+            let run: Vec<&str> = s.stacks[n - len..n]
+                .iter()
+                .map(|f| f.func.as_str())
+                .collect();
+            callers.get(&run).and_then(|c| {
+                c.iter()
+                    .max_by(|a, b| {
+                        a.1.cmp(b.1).then(b.0.len().cmp(&a.0.len())).then_with(|| {
+                            b.0.iter().map(|f| &f.func).cmp(a.0.iter().map(|f| &f.func))
+                        })
+                    })
+                    .map(|(stacks, _)| *stacks)
+            })
+        });
+        if let Some(rest) = best {
+            let mut stacks = s.stacks[..n].to_vec();
+            stacks.extend_from_slice(rest);
+            let mut labels = s.labels.clone();
+            labels.push(("repaired".to_string(), "true".to_string()));
+            repaired.push((s.clone(), Sample { stacks, labels }, values.clone()));
+        }
+    }
+
+    let mut count = 0;
+    for (old, new, values) in repaired {
+        sample.remove(&old);
+        count += values[0];
+        let merged = sample.entry(new).or_default();
+        if merged.len() < values.len() {
+            merged.resize(values.len(), 0);
+        }
+        for (m, v) in merged.iter_mut().zip(&values) {
+            *m += v;
+        }
+    }
+    count
+}
+
+// a frame matches `focus` if any, and none matches `ignore`
+fn focused<'a, I: Clone + Iterator<Item = &'a str>>(
+    focus: Option<&Regex>,
+    ignore: Option<&Regex>,
+    names: I,
+) -> bool {
+    let matches = |re: &Regex| names.clone().any(|n| re.is_match(n));
+    focus.into_iter().all(matches) && !ignore.into_iter().any(matches)
+}
+
+// matches drop_frames but not keep_frames
+fn pruned(options: &Options, name: &str) -> bool {
+    full_match(&options.drop_frames, name) && !full_match(&options.keep_frames, name)
+}
+
+const METADATA: [&str; 6] = [
+    "hostname",
+    "os release",
+    "perf version",
+    "arch",
+    "cmdline",
+    "event",
+];
+
+// "# hostname : foo" to "hostname: foo"
+fn metadata(header: &[String]) -> Vec<String> {
+    header
+        .iter()
+        .filter_map(|h| {
+            let (key, value) = h.trim_start_matches('#').split_once(':')?;
+            let key = key.trim();
+            if METADATA.contains(&key) {
+                Some(format!("{}: {}", key, value.trim()))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// cpu-clock and task-clock, which count in nanoseconds, with the modifiers
+/// perf prints, like `cpu-clock:u` for the users without the privilege
+pub fn clock_event(event: &str) -> bool {
+    event.split(':').next().unwrap_or(event).ends_with("-clock")
+}
+
+fn match_event(filter: &str, event: &str) -> bool {
+    // "probe_foo" matches "probe_foo:bar", "cycles" matches "cycles:u"
+    event == filter || event.starts_with(&format!("{}:", filter))
+}
+
+// a frame in any of the layouts `perf script -F` prints, `ip [sym[+off]] [(dso)]`:
+//   55d0c0a01234 foo::bar+0x14 (/usr/bin/foo)
+//   55d0c0a01234 foo::bar
+//   55d0c0a01234 (/usr/bin/foo)
+//   55d0c0a01234
+// the position of the parenthesis opening the one closing the string, so that
+// `(/tmp/a (1)/libfoo.so)` is a module as a whole
+fn module_start(s: &str) -> Option<usize> {
+    if !s.ends_with(')') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, c) in s.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// the line with the bytes that aren't UTF-8 escaped as `\xff`, rather than
+// replaced, so that the symbols having them stay distinct
+pub(crate) fn escape_invalid(mut buf: &[u8]) -> Cow<'_, str> {
+    let mut s = String::new();
+    loop {
+        match std::str::from_utf8(buf) {
+            Ok(valid) if s.is_empty() => return Cow::Borrowed(valid),
+            Ok(valid) => {
+                s.push_str(valid);
+                return Cow::Owned(s);
+            }
+            Err(e) => {
+                let (valid, rest) = buf.split_at(e.valid_up_to());
+                s.push_str(std::str::from_utf8(valid).unwrap());
+                let n = e.error_len().unwrap_or(rest.len());
+                for b in &rest[..n] {
+                    s.push_str(&format!("\\x{:02x}", b));
+                }
+                buf = &rest[n..];
+            }
+        }
+    }
+}
+
+fn parse_frame(line: &str) -> Option<Stack> {
+    let (pc, rest) = match line.split_once(char::is_whitespace) {
+        Some((pc, rest)) => (pc, rest.trim()),
+        None => (line, ""),
+    };
+    let pc = u64::from_str_radix(pc, 16).ok()?;
+    // everything before the last parenthesized token is the symbol, which might
+    // have spaces and parentheses, like `operator()(int) const`
+    let (func, module) = match module_start(rest) {
+        Some(i) if i == 0 || rest[..i].ends_with(' ') => (rest[..i].trim_end(), &rest[i..]),
+        _ => (rest, "([unknown])"),
+    };
+    // symoff
+    let func = match func.rsplit_once("+0x") {
+        Some((f, off)) if u64::from_str_radix(off, 16).is_ok() => f,
+        _ => func,
+    };
+    Some(Stack {
+        pc,
+        func: if func.is_empty() { "[unknown]" } else { func }.to_string(),
+        module: module.to_string(),
+    })
+}
+
+/// a timestamp of perf script, `5678.123456` or `5678.123456789` with `--ns`,
+/// in nanoseconds
+pub fn timestamp_nanos(sec: &str, frac: &str) -> Option<u64> {
+    if frac.len() > 9 {
+        return None;
+    }
+    let scale = 10u64.pow(9 - frac.len() as u32);
+    let sec: u64 = sec.parse().ok()?;
+    let frac: u64 = frac.parse().ok()?;
+    sec.checked_mul(1_000_000_000)?.checked_add(frac * scale)
+}
+
+const VDSO_MODULE: &str = "([vdso])";
+
+// the trampolines returning from signal handlers, of glibc and of the
+// vDSOs of arm64 and x86
+const SIGNAL_TRAMPOLINES: [&str; 4] = [
+    "__restore_rt",
+    "__kernel_rt_sigreturn",
+    "__kernel_sigreturn",
+    "__sigreturn",
+];
+
+// Names the vDSO frames perf leaves unknown and cuts the unknown frames
+// rootward of them, which the unwinder made up without the vDSO's unwind
+// info. The frames of the code a signal handler interrupted go to the
+// `interrupted` label and the trampoline becomes `[signal]`, so that the
+// stacks of a handler aren't split by where the signals arrived.
+fn special_frames(stack: &mut Vec<Stack>, labels: &mut Vec<(String, String)>) {
+    for frame in stack.iter_mut().filter(|f| f.module == VDSO_MODULE) {
+        if frame.func == "[unknown]" {
+            frame.func = "[vdso]".to_string();
+        }
+    }
+    // leaf first
+    if let Some(i) = stack.iter().rposition(|f| f.module == VDSO_MODULE) {
+        if stack[i + 1..].iter().all(|f| f.func == "[unknown]") {
+            stack.truncate(i + 1);
+        }
+    }
+    if let Some(i) = stack
+        .iter()
+        .position(|f| SIGNAL_TRAMPOLINES.contains(&f.func.as_str()))
+    {
+        if let Some(interrupted) = stack.get(i + 1) {
+            labels.push(("interrupted".to_string(), interrupted.func.clone()));
+        }
+        stack.truncate(i + 1);
+        stack[i].func = "[signal]".to_string();
+    }
+}
+
+// names the unknown frame with the jitdump of the process if any, with the
+// code there at the time of the sample
+fn resolve_jit(options: &Options, pid: Option<u32>, nsec: u64, mut frame: Stack) -> Stack {
+    if frame.func != "[unknown]" {
+        return frame;
+    }
+    for dump in &options.jitdumps {
+        if pid.is_some() && pid != Some(dump.pid) {
+            continue;
+        }
+        if let Some(name) = dump.function(frame.pc, nsec) {
+            frame.func = name.to_string();
+            frame.module = format!("({})", dump.path);
+            break;
+        }
+    }
+    frame
+}
+
+// an error naming the line in the strict mode, otherwise counts it
+fn malformed(options: &Options, number: u64, line: &str, count: &mut u64) -> io::Result<()> {
+    if options.strict {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: can't parse {:?}", number, line),
+        ));
+    }
+    *count += 1;
+    Ok(())
+}
+
+// the callback of the samples streamed instead of aggregated
+type OnSample<'a> = &'a mut dyn FnMut(ScriptSample) -> io::Result<()>;
+
 impl PerfReader {
-    fn new<R>(mut reader: R) -> io::Result<Self>
+    fn new<R>(reader: R, options: &Options) -> io::Result<Self>
     where
         R: io::BufRead,
     {
+        PerfReader::read(reader, options, None)
+    }
+
+    // streams the samples to `on_sample` if any, which needs neither the
+    // header nor more than one sample
+    fn read<R>(
+        mut reader: R,
+        options: &Options,
+        mut on_sample: Option<OnSample>,
+    ) -> io::Result<Self>
+    where
+        R: io::BufRead,
+    {
+        let event = options.event.as_deref();
         let mut buf = Vec::new();
         let mut is_event_line = true;
+        let mut skip = false;
         let mut sample = HashMap::default();
         let mut header = Vec::new();
-        let mut stack = Vec::new();
-        let mut start_usec = 0;
-        let mut end_usec = 0;
+        let mut stack: Vec<Stack> = Vec::new();
+        let mut labels = Vec::new();
+        let mut tid = 0;
+        let mut start_nsec = 0;
+        let mut end_nsec = 0;
+        // the time of the sample
+        let mut sample_nsec = 0;
+        // tid, (last failure, how many times in a row)
+        let mut last_failure: HashMap<u64, (Sample, u64)> = HashMap::default();
+        let mut retry_loops = HashSet::default();
+        let mut mmaps = Vec::new();
+        let mut events: Vec<String> = Vec::new();
+        // the index of the event of the sample, and its period
+        let mut current_event = 0;
+        let mut period = 1;
+        let mut periods = false;
+        // the ip on the event line, printed without callchains
+        let mut ip: Option<Stack> = None;
+        // whether the samples have callchains, told by the line after the
+        // first event line: a frame, or the next event line of `-F` without
+        // `ip` or `-g`
+        let mut callchains: Option<bool> = None;
+        let mut eof = false;
+        let mut number = 0;
+        let mut malformed_lines = 0;
+        // the pid of the sample if perf script prints it
+        let mut pid = None;
+        // the samples perf dropped, `--show-lost-events`
+        let mut lost = 0;
+        let mut idle_samples = 0;
 
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"\S+\s+\d+\s+(\d+)\.(\d+)").unwrap();
+            // perf 1234 5678.123456:
+            // perf 1234/1234 [000] 5678.123456:
+            // tokio-runtime-w 1234/1235 [000] 5678.123456:
+            static ref RE: Regex =
+                Regex::new(r"^(.+?)\s+(\d+)(?:/(\d+))?\s+(?:\[\d+\]\s+)?(\d+)\.(\d+)").unwrap();
+            // 1234.567890:     250000 cpu-clock:u:
+            // 1234.567890: probe_foo:rust_begin_unwind: (55d0c0a0)
+            static ref EVENT_RE: Regex =
+                Regex::new(r"\d+\.\d+:\s+(?:(\d+)\s+)?(\S+):(?:\s|$)").unwrap();
+            // perf 0 [000] 0.000000: PERF_RECORD_LOST lost 123
+            static ref LOST_RE: Regex = Regex::new(r"PERF_RECORD_LOST\S*.*?(\d+)\s*$").unwrap();
+            // raw_syscalls:sys_exit: NR 0 = -11
+            static ref SYSCALL_RE: Regex = Regex::new(r"NR (\d+) = (-?\d+)").unwrap();
         }
 
         loop {
             buf.clear();
             if let Ok(n) = reader.read_until(b'\n', &mut buf) {
                 if n == 0 {
-                    break;
+                    // without callchains, no blank line ends the last sample
+                    if is_event_line || eof {
+                        break;
+                    }
+                    eof = true;
+                    buf.push(b'\n');
                 }
-                let line = String::from_utf8_lossy(&buf);
+                number += 1;
+                let line = escape_invalid(&buf);
                 if line.starts_with('#') {
                     header.push(line.trim().to_string());
                     continue;
                 }
                 let line = line.trim();
-                if line.is_empty() {
+                if callchains.is_none() && !is_event_line && !line.is_empty() {
+                    // a command named like `cafe` parses as a frame too
+                    callchains = Some(parse_frame(line).is_some() && !RE.is_match(line));
+                }
+                // without callchains, no blank line follows the sample, ending
+                // at the next event line, whether the sample was skipped or not
+                let next = callchains == Some(false)
+                    && !is_event_line
+                    && stack.is_empty()
+                    && RE.is_match(line);
+                if line.is_empty() || next {
                     // return one stack
                     is_event_line = true;
+                    if options.prune {
+                        stack.retain(|s| !pruned(options, &s.func));
+                    }
+                    if stack.is_empty() {
+                        stack.extend(ip.take());
+                    }
+                    special_frames(&mut stack, &mut labels);
+                    if !focused(
+                        options.focus.as_ref(),
+                        options.ignore.as_ref(),
+                        stack.iter().map(|s| s.func.as_str()),
+                    ) {
+                        stack.clear();
+                    }
+                    if options.idle != Idle::Keep && !stack.is_empty() && idle(&stack, tid) {
+                        if options.idle == Idle::Drop {
+                            idle_samples += 1;
+                            stack.clear();
+                        } else {
+                            stack = vec![Stack {
+                                pc: 0,
+                                func: "[idle]".to_string(),
+                                module: "[kernel.kallsyms]".to_string(),
+                            }];
+                        }
+                    }
                     if !stack.is_empty() {
-                        let count = sample
-                            .entry(Sample {
-                                stacks: stack.split_off(0),
-                            })
-                            .or_insert(0);
-                        *count += 1;
+                        let stacks =
+                            group_frames(stack.split_off(0), &options.grouping, &mut labels);
+                        let s = Sample {
+                            stacks,
+                            labels: labels.split_off(0),
+                        };
+                        if let Some(f) = on_sample.as_mut() {
+                            let (functions, modules) = s
+                                .stacks
+                                .into_iter()
+                                .map(|f| {
+                                    let module = match f.module.strip_prefix('(') {
+                                        Some(m) => m.strip_suffix(')').unwrap_or(m),
+                                        None => &f.module,
+                                    }
+                                    .to_string();
+                                    if options.demangle {
+                                        (demangle(&f.func), module)
+                                    } else {
+                                        (f.func, module)
+                                    }
+                                })
+                                .unzip();
+                            f(ScriptSample {
+                                nsec: sample_nsec,
+                                event: events.get(current_event).cloned().unwrap_or_default(),
+                                functions,
+                                modules,
+                                labels: s.labels,
+                                period,
+                            })?;
+                        } else {
+                            if options.syscall_errors {
+                                let last =
+                                    last_failure.entry(tid).or_insert_with(|| (s.clone(), 0));
+                                if last.0 == s {
+                                    last.1 += 1;
+                                    if last.1 == RETRY_LOOP_THRESHOLD {
+                                        retry_loops.insert(s.clone());
+                                    }
+                                } else {
+                                    *last = (s.clone(), 1);
+                                }
+                            }
+                            let values = sample.entry(s).or_insert_with(Vec::new);
+                            if values.len() < current_event + 2 {
+                                values.resize(current_event + 2, 0);
+                            }
+                            values[0] += 1;
+                            values[current_event + 1] += period;
+                        }
+                    }
+                    if line.is_empty() {
+                        continue;
+                    }
+                }
+                if line.contains("PERF_RECORD_LOST") {
+                    if let Some(caps) = LOST_RE.captures(line) {
+                        lost += caps[1].parse::<u64>().unwrap_or(0);
+                    }
+                    continue;
+                }
+                if line.contains("PERF_RECORD_MMAP") {
+                    if let Some(mmap) = symbolize::parse_mmap(line) {
+                        mmaps.push(mmap);
                     }
                     continue;
                 }
                 if is_event_line {
                     // event line
-                    if let Some(caps) = RE.captures(line) {
-                        let sec: u64 = caps.get(1).unwrap().as_str().parse().unwrap();
-                        let usec: u64 = caps.get(2).unwrap().as_str().parse().unwrap();
+                    labels.clear();
+                    pid = None;
+                    skip = false;
+                    let mut phase = None;
+                    let mut latency = None;
+                    let caps = RE.captures(line).and_then(|caps| {
+                        let first = caps.get(2).unwrap().as_str();
+                        let thread = caps.get(3).map_or(first, |t| t.as_str());
+                        let nsec = timestamp_nanos(&caps[4], &caps[5])?;
+                        Some((caps, thread.parse::<u64>().ok()?, nsec))
+                    });
+                    if caps.is_none() && RE.is_match(line) {
+                        // numbers too large
+                        malformed(options, number, line, &mut malformed_lines)?;
+                        skip = true;
+                    }
+                    if let Some((caps, thread_id, nsec)) = caps {
+                        // pid/tid if perf script prints both, otherwise tid
+                        let first = caps.get(2).unwrap().as_str();
+                        let thread = caps.get(3).map_or(first, |t| t.as_str());
+                        tid = thread_id;
+                        pid = caps.get(3).and_then(|_| first.parse().ok());
+                        if caps.get(3).is_some() {
+                            labels.push(("pid".to_string(), first.to_string()));
+                        }
+                        labels.push(("tid".to_string(), thread.to_string()));
+                        labels.push((
+                            "thread_name".to_string(),
+                            caps.get(1).unwrap().as_str().to_string(),
+                        ));
+                        if let Some(tenant) = options
+                            .tenants
+                            .as_ref()
+                            .and_then(|t| t.tenant(pid.map(u64::from), tid))
+                        {
+                            labels.push(("tenant".to_string(), tenant));
+                        }
+                        sample_nsec = nsec;
                         if sample.is_empty() {
-                            start_usec = sec * 1_000_000 + usec;
+                            start_nsec = nsec;
                         } else {
-                            end_usec = sec * 1_000_000 + usec;
+                            end_nsec = nsec;
+                        }
+                        // markers and requests are in microseconds
+                        let time = nsec / 1000;
+                        let i = options.markers.partition_point(|(t, _)| *t <= time);
+                        if i > 0 {
+                            let name = &options.markers[i - 1].1;
+                            labels.push(("marker".to_string(), name.clone()));
+                            phase = Some(name.as_str());
+                        }
+                        latency = request_latency(options, time);
+                        if let Some(l) = latency {
+                            labels.push(("latency".to_string(), l.to_string()));
+                        }
+                    }
+                    if options.phase.is_some() && options.phase.as_deref() != phase {
+                        skip = true;
+                    }
+                    if options.latency.is_some() && options.latency.as_deref() != latency {
+                        skip = true;
+                    }
+                    let caps = EVENT_RE.captures(line);
+                    if let Some(filter) = event {
+                        skip |= match caps.as_ref() {
+                            Some(caps) => !match_event(filter, caps.get(2).unwrap().as_str()),
+                            None => true,
+                        };
+                    }
+                    if let Some(caps) = caps.as_ref() {
+                        let name = caps.get(2).unwrap().as_str();
+                        current_event = match events.iter().position(|e| e == name) {
+                            Some(i) => i,
+                            None => {
+                                events.push(name.to_string());
+                                events.len() - 1
+                            }
+                        };
+                        period = match caps.get(1).and_then(|p| p.as_str().parse().ok()) {
+                            Some(p) => {
+                                periods = true;
+                                p
+                            }
+                            None => 1,
+                        };
+                    }
+                    if options.syscall_errors && !skip {
+                        match SYSCALL_RE.captures(line) {
+                            Some(caps) => {
+                                let ret: i64 = caps.get(2).unwrap().as_str().parse().unwrap();
+                                if ret < 0 {
+                                    labels.push((
+                                        "syscall".to_string(),
+                                        caps.get(1).unwrap().as_str().to_string(),
+                                    ));
+                                    labels.push(("errno".to_string(), errno_name(-ret)));
+                                } else {
+                                    skip = true;
+                                }
+                            }
+                            None => skip = true,
                         }
                     }
 
+                    ip = match caps.as_ref() {
+                        Some(caps) if !skip => {
+                            parse_frame(line[caps.get(0).unwrap().end()..].trim())
+                                .map(|f| resolve_jit(options, pid, sample_nsec, f))
+                        }
+                        _ => None,
+                    };
                     is_event_line = false;
                     continue;
-                } else {
+                } else if !skip {
                     // stack line
-                    let line = line.splitn(2, ' ').collect::<Vec<&str>>();
-                    if let Ok(pc) = u64::from_str_radix(line[0], 16) {
-                        let line = line[1].rsplitn(2, ' ').collect::<Vec<&str>>();
-                        stack.push(Stack {
-                            pc,
-                            func: line[1].to_string(),
-                            module: line[0].to_string(),
-                        });
+                    match parse_frame(line) {
+                        Some(frame) => stack.push(resolve_jit(options, pid, sample_nsec, frame)),
+                        None => malformed(options, number, line, &mut malformed_lines)?,
                     }
                 }
             } else {
@@ -126,65 +1178,117 @@ impl PerfReader {
             }
         }
 
-        if end_usec == 0 {
-            return Err(io::Error::new(io::ErrorKind::Other, "can't find duration"));
+        // probe events might be hit only once
+        if end_nsec == 0 && event.is_none() && on_sample.is_none() {
+            // the input ended before the second sample
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "can't find duration",
+            ));
         }
 
-        let (captured_time, freq) = PerfReader::verify_header(&header)?;
+        // the header of perf.data in pipe mode might lack them, and the text
+        // without `--header` has none
+        let (captured_time, freq, sample_period) = PerfReader::verify_header(&header)?;
+        let captured_time = match (options.captured_on, captured_time, options.captured_time) {
+            (Some(t), _, _) | (None, Ok(t), _) | (None, Err(_), Some(t)) => t,
+            (None, Err(e), None) if options.strict_header && on_sample.is_none() => {
+                return Err(io::Error::other(e))
+            }
+            (None, Err(_), None) => Local::now(),
+        };
+        let freq = if freq == 0 { options.freq } else { freq };
+        // the periods printed weight the samples without either
+        if freq == 0 && sample_period == 0 && !periods && event.is_none() && on_sample.is_none() {
+            return Err(io::Error::other(
+                "neither sample frequency nor period is found in the header",
+            ));
+        }
 
         Ok(PerfReader {
             sample,
+            events,
+            periods,
+            retry_loops,
             captured_time,
-            duration: Duration::from_micros(end_usec - start_usec),
+            duration: Duration::from_nanos(end_nsec.saturating_sub(start_nsec)),
             freq,
+            sample_period,
+            mmaps,
+            header,
+            malformed_lines,
+            lost,
+            idle_samples,
         })
     }
 
-    fn verify_header(header: &[String]) -> io::Result<(DateTime<Local>, u64)> {
-        let mut dt = None;
+    // the captured time or why it's missing, and the sampling frequency (`-F`)
+    // or the period (`-c`)
+    #[allow(clippy::type_complexity)]
+    fn verify_header(header: &[String]) -> io::Result<(Result<DateTime<Local>, String>, u64, u64)> {
+        let mut dt = Err("captured time isn't found in the header".to_string());
+        let mut reference = None;
         let mut freq = 0;
+        let mut period = 0;
 
-        for h in header {
+        lazy_static! {
             // sample_freq } = 997
-            let re = Regex::new(r"sample_freq\s+}\s+=\s+(\d+)").unwrap();
+            static ref FREQ_RE: Regex = Regex::new(r"sample_freq\s+}\s+=\s+(\d+)").unwrap();
+            // sample_period = 100000
+            static ref PERIOD_RE: Regex = Regex::new(r"sample_period\s+=\s+(\d+)").unwrap();
+        }
+        for h in header {
             // captured on    : Thu Mar 10 10:45:19 2022
             if h.contains("captured on") {
                 let line = h.splitn(2, ':').collect::<Vec<&str>>();
                 if line.len() == 2 {
-                    if let Ok(time) = NaiveDateTime::parse_from_str(line[1].trim(), "%c") {
-                        dt = if let LocalResult::Single(t) = Local.from_local_datetime(&time) {
-                            Some(t)
-                        } else {
-                            None
-                        };
-                    }
+                    dt = date::parse_captured_on(line[1]).ok_or_else(|| {
+                        format!("can't parse the captured time: {}", line[1].trim())
+                    });
                 }
-            } else if let Some(caps) = re.captures(h) {
+            } else if h.contains("reference time") {
+                reference = date::parse_reference_time(h);
+            } else if let Some(caps) = FREQ_RE.captures(h) {
                 if let Some(v) = caps.get(1) {
                     freq = v
                         .as_str()
                         .parse()
                         .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
                 }
+            } else if let Some(caps) = PERIOD_RE.captures(h) {
+                period = caps[1].parse().unwrap_or(0);
             }
         }
-        let captured_time = dt.ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "captured time isn't found in the header",
-            )
-        })?;
-        if freq == 0 {}
-
-        Ok((captured_time, freq))
+        Ok((reference.map_or(dt, Ok), freq, period))
     }
 }
 
+/// A sample of `perf script` output as the converter reads it: the frames
+/// grouped, pruned and named as in the profile, and the samples filtered
+/// out (`focus`, `ignore`, `phase`, the idle ones dropped, ...) skipped.
+#[derive(Debug)]
+pub struct ScriptSample {
+    /// nanoseconds, as perf prints
+    pub nsec: u64,
+    pub event: String,
+    /// leaf first
+    pub functions: Vec<String>,
+    /// the binary or library of each of `functions`, `[unknown]` if perf
+    /// can't tell
+    pub modules: Vec<String>,
+    /// the labels of the profile, `thread_name`, `tid`, `marker`, ...
+    pub labels: Vec<(String, String)>,
+    /// the period if perf script prints it, otherwise 1
+    pub period: u64,
+}
+
 pub struct PprofConverter {
     str_map: HashMap<String, u64>,
+    options: Options,
 
     location: LocationId,
     function: FunctionId,
+    mapping: MappingId,
 }
 
 struct FunctionId {
@@ -194,11 +1298,26 @@ struct FunctionId {
 
 struct LocationId {
     next_id: u64,
-    map: HashMap<u64, (u64, u64)>, // address, (id, funciton_id)
+    map: HashMap<u64, (u64, u64, u64)>, // address, (id, funciton_id, mapping_id)
+}
+
+struct MappingId {
+    next_id: u64,
+    map: HashMap<String, (u64, u64, u64)>, // file name, (id, memory_start, memory_limit)
+}
+
+// "(/usr/lib/libc.so.6)" to "/usr/lib/libc.so.6"; none for unknown modules
+fn module_name(module: &str) -> Option<&str> {
+    let name = module.trim_start_matches('(').trim_end_matches(')');
+    if name.is_empty() || name == "[unknown]" {
+        None
+    } else {
+        Some(name)
+    }
 }
 
 impl PprofConverter {
-    fn new() -> Self {
+    fn new(options: Options) -> Self {
         let mut str_map: HashMap<String, u64> = HashMap::default();
         for (i, s) in vec!["", "samples", "count", "cpu", "nanoseconds"]
             .iter()
@@ -206,9 +1325,14 @@ impl PprofConverter {
         {
             str_map.insert(s.to_string(), i as u64);
         }
+        if let Some(event) = options.event.as_ref() {
+            let s = str_map.len() as u64;
+            str_map.entry(event.clone()).or_insert(s);
+        }
 
         PprofConverter {
             str_map,
+            options,
             location: LocationId {
                 next_id: 0,
                 map: HashMap::default(),
@@ -217,25 +1341,62 @@ impl PprofConverter {
                 next_id: 0,
                 map: HashMap::default(),
             },
+            mapping: MappingId {
+                next_id: 0,
+                map: HashMap::default(),
+            },
         }
     }
 
-    fn location_id(&mut self, addr: u64, name: &str) -> u64 {
-        let loc_id = self.location.map.entry(addr).or_insert_with(|| {
-            self.location.next_id += 1;
-            let func_id = self
-                .function
-                .map
-                .entry(name.to_string())
-                .or_insert_with(|| {
-                    let s = self.str_map.len() as u64;
-                    let str_id = self.str_map.entry(name.to_string()).or_insert(s);
-                    self.function.next_id += 1;
-                    (self.function.next_id, *str_id)
-                });
-            (self.location.next_id, func_id.0)
+    fn string_id(&mut self, s: &str) -> i64 {
+        let id = self.str_map.len() as u64;
+        *self.str_map.entry(s.to_string()).or_insert(id) as i64
+    }
+
+    // the memory range of a mapping covers the addresses seen in the module
+    fn mapping_id(&mut self, addr: u64, module: &str) -> u64 {
+        let name = match module_name(module) {
+            Some(name) => name,
+            None => return 0,
+        };
+        let next_id = &mut self.mapping.next_id;
+        let m = self.mapping.map.entry(name.to_string()).or_insert_with(|| {
+            *next_id += 1;
+            (*next_id, addr, addr + 1)
         });
-        loc_id.0
+        m.1 = m.1.min(addr);
+        m.2 = m.2.max(addr + 1);
+        m.0
+    }
+
+    fn location_id(&mut self, addr: u64, name: &str, module: &str) -> u64 {
+        let mapping_id = self.mapping_id(addr, module);
+        if let Some(loc_id) = self.location.map.get(&addr) {
+            return loc_id.0;
+        }
+        let name = if self.options.demangle {
+            demangle(name)
+        } else {
+            name.to_string()
+        };
+        let func_id = self.function_id(&name);
+        self.location.next_id += 1;
+        self.location
+            .map
+            .insert(addr, (self.location.next_id, func_id, mapping_id));
+        self.location.next_id
+    }
+
+    fn function_id(&mut self, name: &str) -> u64 {
+        if let Some(func_id) = self.function.map.get(name) {
+            return func_id.0;
+        }
+        let str_id = self.string_id(name) as u64;
+        self.function.next_id += 1;
+        self.function
+            .map
+            .insert(name.to_string(), (self.function.next_id, str_id));
+        self.function.next_id
     }
 
     fn finish<R, W>(&mut self, reader: R, mut writer: W) -> io::Result<()>
@@ -243,24 +1404,146 @@ impl PprofConverter {
         R: io::BufRead,
         W: io::Write,
     {
-        let perf = PerfReader::new(reader)?;
+        let mut perf = PerfReader::new(reader, &self.options)?;
+        let repaired = if self.options.repair {
+            repair_stacks(&mut perf.sample)
+        } else {
+            0
+        };
+        let event_str = self.options.event.as_ref().map(|e| self.str_map[e] as i64);
+        // recorded with `-e cycles,instructions`, a value per event
+        let event_ids: Vec<i64> = if event_str.is_none() && perf.events.len() > 1 {
+            perf.events.iter().map(|e| self.string_id(e)).collect()
+        } else {
+            Vec::new()
+        };
+        // cpu-clock and task-clock count in nanoseconds; recorded with a
+        // fixed period (`-c`), the other events are counted in their units
+        let clock = perf.events.iter().all(|e| clock_event(e));
+        let event_count = perf.freq == 0 && !clock;
+        let event_count_id = if event_count {
+            let name = perf.events.first().map_or("events", |e| e.as_str());
+            self.string_id(name)
+        } else {
+            0
+        };
+        // the frequency-based sampling adjusts the period, so the samples
+        // are weighted by their periods if perf script printed them
+        let nanos_per_period = if !perf.periods {
+            None
+        } else if clock || event_count {
+            Some(1.0)
+        } else {
+            let (count, periods) = perf.sample.values().fold((0, 0), |(c, p), v| {
+                (c + v[0], p + v.get(1).copied().unwrap_or(0))
+            });
+            Some(count as f64 * 1_000_000_000.0 / perf.freq as f64 / periods.max(1) as f64)
+        };
         let sample: Vec<pb::Sample> = perf
             .sample
             .iter()
-            .map(|(s, count)| pb::Sample {
+            .map(|(s, values)| pb::Sample {
                 location_id: s
                     .stacks
                     .iter()
-                    .map(|s| self.location_id(s.pc, &s.func))
+                    .map(|s| self.location_id(s.pc, &s.func, &s.module))
                     .collect(),
-                value: vec![
-                    *count as i64,
-                    *count as i64 * 1_000_000_000 / perf.freq as i64,
-                ],
-                label: Vec::new(),
+                value: if event_str.is_some() {
+                    vec![values[0] as i64]
+                } else if !event_ids.is_empty() {
+                    std::iter::once(values[0] as i64)
+                        .chain(
+                            (0..event_ids.len())
+                                .map(|i| values.get(i + 1).copied().unwrap_or(0) as i64),
+                        )
+                        .collect()
+                } else {
+                    vec![
+                        values[0] as i64,
+                        match nanos_per_period {
+                            Some(n) => (values[1] as f64 * n).round() as i64,
+                            None if perf.freq > 0 => {
+                                values[0] as i64 * 1_000_000_000 / perf.freq as i64
+                            }
+                            None => (values[0] * perf.sample_period) as i64,
+                        },
+                    ]
+                },
+                label: {
+                    let mut label: Vec<pb::Label> = s
+                        .labels
+                        .iter()
+                        .map(|(k, v)| pb::Label {
+                            key: self.string_id(k),
+                            str: self.string_id(v),
+                            ..Default::default()
+                        })
+                        .collect();
+                    if perf.retry_loops.contains(s) {
+                        label.push(pb::Label {
+                            key: self.string_id("retry_loop"),
+                            str: self.string_id("true"),
+                            ..Default::default()
+                        });
+                    }
+                    label
+                },
             })
             .collect();
 
+        // address, the lines of the functions inlined there and the function
+        // they're inlined into, innermost first
+        let mut lines: HashMap<u64, Vec<pb::Line>> = HashMap::new();
+        // function id, file name
+        let mut filenames: HashMap<u64, i64> = HashMap::new();
+        if self.options.lines {
+            let mut symbolizer = Symbolizer::new(self.options.symfs.as_deref());
+            for (file, mmap) in perf.mmaps {
+                symbolizer.add_mmap(file, mmap);
+            }
+            let files: HashMap<u64, String> = self
+                .mapping
+                .map
+                .iter()
+                .map(|(k, v)| (v.0, k.clone()))
+                .collect();
+            let locations: Vec<(u64, u64, u64)> = self
+                .location
+                .map
+                .iter()
+                .map(|(addr, v)| (*addr, v.1, v.2))
+                .collect();
+            for (addr, function_id, mapping_id) in locations {
+                let frames = files
+                    .get(&mapping_id)
+                    .and_then(|f| symbolizer.frames(f, addr))
+                    .unwrap_or_default();
+                let mut v = Vec::new();
+                for (i, frame) in frames.iter().enumerate() {
+                    // the outermost is the function that perf found
+                    let id = if i + 1 == frames.len() {
+                        function_id
+                    } else {
+                        match &frame.function {
+                            Some(name) => self.function_id(&demangle(name)),
+                            None => continue,
+                        }
+                    };
+                    if let Some(file) = &frame.file {
+                        let file = self.string_id(file);
+                        filenames.entry(id).or_insert(file);
+                    }
+                    v.push(pb::Line {
+                        function_id: id,
+                        line: frame.line,
+                    });
+                }
+                if !v.is_empty() {
+                    lines.insert(addr, v);
+                }
+            }
+        }
+
         let mut function: Vec<pb::Function> = self
             .function
             .map
@@ -268,11 +1551,70 @@ impl PprofConverter {
             .map(|(_, v)| pb::Function {
                 id: v.0,
                 name: v.1 as i64,
+                filename: filenames.get(&v.0).copied().unwrap_or(0),
                 ..Default::default()
             })
             .collect();
         function.sort_by(|a, b| a.id.cmp(&b.id));
 
+        let mut comments = self.options.comments.clone();
+        if self.options.metadata {
+            comments.extend(metadata(&perf.header));
+        }
+        if perf.lost > 0 {
+            let total: u64 = perf.sample.values().map(|v| v[0]).sum();
+            comments.push(format!(
+                "{} {} samples ({:.1}%)",
+                LOST_PREFIX,
+                perf.lost,
+                perf.lost as f64 * 100.0 / (perf.lost + total) as f64
+            ));
+        }
+        if repaired > 0 {
+            comments.push(format!(
+                "{} samples of truncated stacks repaired by inferring the callers",
+                repaired
+            ));
+        }
+        if perf.idle_samples > 0 {
+            comments.push(format!("{} idle samples dropped", perf.idle_samples));
+        }
+        if perf.malformed_lines > 0 {
+            comments.push(format!(
+                "{} malformed lines of perf script skipped",
+                perf.malformed_lines
+            ));
+        }
+        let comment = comments.iter().map(|c| self.string_id(c)).collect();
+        let frames = [
+            self.options.drop_frames.clone(),
+            self.options.keep_frames.clone(),
+        ];
+        let [drop_frames, keep_frames] = frames.map(|p| p.map_or(0, |(s, _)| self.string_id(&s)));
+        let default_sample_type = match self.options.default_sample_type.clone() {
+            Some(name) => self.string_id(&name),
+            None => 0,
+        };
+
+        let mapping_files: Vec<(String, (u64, u64, u64))> = self
+            .mapping
+            .map
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        let mut mapping: Vec<pb::Mapping> = mapping_files
+            .into_iter()
+            .map(|(name, v)| pb::Mapping {
+                id: v.0,
+                memory_start: v.1,
+                memory_limit: v.2,
+                filename: self.string_id(&name),
+                has_functions: true,
+                ..Default::default()
+            })
+            .collect();
+        mapping.sort_by_key(|m| m.id);
+
         let mut string_table: Vec<(String, u64)> =
             self.str_map.iter().map(|(k, v)| (k.clone(), *v)).collect();
         string_table.sort_by(|a, b| a.1.cmp(&b.1));
@@ -283,35 +1625,108 @@ impl PprofConverter {
             .iter()
             .map(|(k, v)| pb::Location {
                 id: v.0,
+                mapping_id: v.2,
                 address: *k,
-                line: vec![pb::Line {
-                    function_id: v.1,
-                    line: 0,
-                }],
+                line: lines.get(k).cloned().unwrap_or_else(|| {
+                    vec![pb::Line {
+                        function_id: v.1,
+                        line: 0,
+                    }]
+                }),
                 ..Default::default()
             })
             .collect();
         location.sort_by(|a, b| a.id.cmp(&b.id));
 
+        let (sample_type, period, period_type) = match event_str {
+            Some(event) => (
+                vec![pb::ValueType {
+                    r#type: event,
+                    unit: 2,
+                }],
+                1,
+                Some(pb::ValueType {
+                    r#type: event,
+                    unit: 2,
+                }),
+            ),
+            None if !event_ids.is_empty() => (
+                std::iter::once(pb::ValueType { r#type: 1, unit: 2 })
+                    .chain(event_ids.iter().map(|e| pb::ValueType {
+                        r#type: *e,
+                        unit: 2,
+                    }))
+                    .collect(),
+                1,
+                Some(pb::ValueType { r#type: 1, unit: 2 }),
+            ),
+            None => {
+                let (value_type, period) = if perf.freq > 0 {
+                    (
+                        pb::ValueType { r#type: 3, unit: 4 },
+                        1_000_000_000 / perf.freq as i64,
+                    )
+                } else if event_count {
+                    (
+                        pb::ValueType {
+                            r#type: event_count_id,
+                            unit: 2,
+                        },
+                        perf.sample_period as i64,
+                    )
+                } else {
+                    (
+                        pb::ValueType { r#type: 3, unit: 4 },
+                        perf.sample_period as i64,
+                    )
+                };
+                (
+                    vec![pb::ValueType { r#type: 1, unit: 2 }, value_type.clone()],
+                    period,
+                    Some(value_type),
+                )
+            }
+        };
+
+        // pprof falls back to the last one silently
+        if let Some(name) = self.options.default_sample_type.as_ref() {
+            if !sample_type.iter().any(|t| t.r#type == default_sample_type) {
+                let names: Vec<&str> = sample_type
+                    .iter()
+                    .filter_map(|t| string_table.get(t.r#type as usize))
+                    .map(|(s, _)| s.as_str())
+                    .collect();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "no sample type {} in the profile, only {}",
+                        name,
+                        names.join(", ")
+                    ),
+                ));
+            }
+        }
+
         let mut content = Vec::new();
         pb::Profile {
-            sample_type: vec![
-                pb::ValueType { r#type: 1, unit: 2 },
-                pb::ValueType { r#type: 3, unit: 4 },
-            ],
+            sample_type,
             sample,
+            mapping,
             location,
             function,
             time_nanos: perf.captured_time.timestamp_nanos(),
             duration_nanos: perf.duration.as_nanos() as i64,
             string_table: string_table.into_iter().map(|(k, _)| k).collect(),
-            period: 1_000_000_000 / perf.freq as i64,
-            period_type: Some(pb::ValueType { r#type: 3, unit: 4 }),
-            ..pb::Profile::default()
+            period,
+            period_type,
+            comment,
+            drop_frames,
+            keep_frames,
+            default_sample_type,
         }
         .encode(&mut content)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
-        writer.write_all(&content)
+        writer.write_all(&compress(&content, self.options.compression)?)
     }
 
     pub fn from_reader<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
@@ -322,3 +1737,650 @@ impl PprofConverter {
         self.finish(reader, writer)
     }
 }
+
+/// How a profile is compressed on disk, with the level; pprof reads gzip and
+/// raw protobuf only, so zstd is for archiving the profiles read back with
+/// this crate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// 0 (none) to 9 (best)
+    Gzip(u32),
+    /// 1 to 22
+    Zstd(i32),
+}
+
+pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+impl std::str::FromStr for Compression {
+    type Err = io::Error;
+
+    /// `none`, `gzip` or `zstd`, optionally with the level like `gzip:9`
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown compression: {}", s),
+            )
+        };
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        match (name, level) {
+            ("none", None) => Ok(Compression::None),
+            ("gzip", None) => Ok(Compression::Gzip(DEFAULT_GZIP_LEVEL)),
+            ("gzip", Some(l)) => match l.parse() {
+                Ok(l) if l <= 9 => Ok(Compression::Gzip(l)),
+                _ => Err(invalid()),
+            },
+            ("zstd", None) => Ok(Compression::Zstd(DEFAULT_ZSTD_LEVEL)),
+            ("zstd", Some(l)) => match l.parse() {
+                Ok(l) if (1..=22).contains(&l) => Ok(Compression::Zstd(l)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// compresses an encoded profile
+pub fn compress(content: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(content.to_vec()),
+        Compression::Gzip(level) => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+            io::Write::write_all(&mut encoder, content)?;
+            encoder.finish()
+        }
+        Compression::Zstd(level) => zstd::stream::encode_all(content, level),
+    }
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// the compression of a profile file, at the default level since the files
+/// don't tell theirs
+pub fn compression(buf: &[u8]) -> Compression {
+    if buf.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip(DEFAULT_GZIP_LEVEL)
+    } else if buf.starts_with(&ZSTD_MAGIC) {
+        Compression::Zstd(DEFAULT_ZSTD_LEVEL)
+    } else {
+        Compression::None
+    }
+}
+
+/// reads a profile, gzipped, compressed with zstd or not
+pub fn decode<R>(mut reader: R) -> io::Result<pb::Profile>
+where
+    R: io::Read,
+{
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    match compression(&buf) {
+        Compression::Gzip(_) => {
+            let mut decoded = Vec::new();
+            flate2::read::MultiGzDecoder::new(&*buf).read_to_end(&mut decoded)?;
+            buf = decoded;
+        }
+        Compression::Zstd(_) => buf = zstd::stream::decode_all(&*buf)?,
+        Compression::None => {}
+    }
+    pb::Profile::decode(&*buf).map_err(|e| io::Error::other(format!("{}", e)))
+}
+
+/// Builds a profile of sample counts from the stacks of other profilers.
+pub struct SampleBuilder {
+    strings: HashMap<String, i64>,
+    string_table: Vec<String>,
+    // function name, the id of the function and the location
+    ids: HashMap<i64, u64>,
+    samples: HashMap<SampleKey, i64>,
+}
+
+// location ids, labels
+type SampleKey = (Vec<u64>, Vec<(i64, i64)>);
+
+impl Default for SampleBuilder {
+    fn default() -> Self {
+        SampleBuilder::new("samples", "count")
+    }
+}
+
+impl SampleBuilder {
+    /// the type and the unit of the sample value, like "time" and "microseconds"
+    pub fn new(r#type: &str, unit: &str) -> Self {
+        let mut b = SampleBuilder {
+            strings: HashMap::new(),
+            string_table: Vec::new(),
+            ids: HashMap::new(),
+            samples: HashMap::new(),
+        };
+        for s in ["", r#type, unit] {
+            b.string_id(s);
+        }
+        b
+    }
+
+    fn string_id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.strings.get(s) {
+            return *id;
+        }
+        let id = self.string_table.len() as i64;
+        self.strings.insert(s.to_string(), id);
+        self.string_table.push(s.to_string());
+        id
+    }
+
+    /// adds `count` samples of the stack, leaf first, with the labels; empty labels are dropped
+    pub fn add(&mut self, frames: &[&str], labels: &[(&str, &str)], count: i64) {
+        let location_id = frames
+            .iter()
+            .map(|f| {
+                let str_id = self.string_id(f);
+                let next_id = self.ids.len() as u64 + 1;
+                *self.ids.entry(str_id).or_insert(next_id)
+            })
+            .collect();
+        let labels = labels
+            .iter()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(k, v)| (self.string_id(k), self.string_id(v)))
+            .collect();
+        *self.samples.entry((location_id, labels)).or_insert(0) += count;
+    }
+
+    pub fn build(self) -> pb::Profile {
+        let mut ids: Vec<(i64, u64)> = self.ids.into_iter().collect();
+        ids.sort_by_key(|(_, id)| *id);
+        pb::Profile {
+            sample_type: vec![pb::ValueType { r#type: 1, unit: 2 }],
+            sample: self
+                .samples
+                .into_iter()
+                .map(|((location_id, labels), count)| pb::Sample {
+                    location_id,
+                    value: vec![count],
+                    label: labels
+                        .into_iter()
+                        .map(|(key, str)| pb::Label {
+                            key,
+                            str,
+                            ..Default::default()
+                        })
+                        .collect(),
+                })
+                .collect(),
+            location: ids
+                .iter()
+                .map(|(_, id)| pb::Location {
+                    id: *id,
+                    line: vec![pb::Line {
+                        function_id: *id,
+                        line: 0,
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+            function: ids
+                .iter()
+                .map(|(str_id, id)| pb::Function {
+                    id: *id,
+                    name: *str_id,
+                    ..Default::default()
+                })
+                .collect(),
+            string_table: self.string_table,
+            period: 1,
+            period_type: Some(pb::ValueType { r#type: 1, unit: 2 }),
+            ..pb::Profile::default()
+        }
+    }
+}
+
+/// Reads stacks in the folded format, `root;caller;leaf 123` per line, like
+/// async-profiler's collapsed output. The frame annotations of async-profiler,
+/// `_[j]` for JIT compiled and the like, are dropped.
+pub fn decode_folded<R>(mut reader: R) -> io::Result<pb::Profile>
+where
+    R: io::BufRead,
+{
+    lazy_static! {
+        static ref ANNOTATION_RE: Regex = Regex::new(r"_\[[a-z0-9]\]$").unwrap();
+    }
+    let mut builder = SampleBuilder::default();
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            break;
+        }
+        let line = buf.trim();
+        let (stack, count) = match line.rsplit_once(' ') {
+            Some((stack, count)) => match count.parse::<i64>() {
+                Ok(count) => (stack, count),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        // leaf first
+        let frames: Vec<std::borrow::Cow<str>> = stack
+            .split(';')
+            .rev()
+            .map(|f| ANNOTATION_RE.replace(f, ""))
+            .collect();
+        let frames: Vec<&str> = frames.iter().map(|f| f.as_ref()).collect();
+        builder.add(&frames, &[], count);
+    }
+    Ok(builder.build())
+}
+
+/// keeps the samples with a frame matching `focus` and drops the ones with a
+/// frame matching `ignore`, like `pprof -focus` and `-ignore`
+pub fn filter(profile: &pb::Profile, focus: Option<&Regex>, ignore: Option<&Regex>) -> pb::Profile {
+    let keep: Vec<bool> = stacks(profile)
+        .into_iter()
+        .map(|(names, _)| focused(focus, ignore, names.into_iter()))
+        .collect();
+    let mut result = profile.clone();
+    let mut keep = keep.into_iter();
+    result.sample.retain(|_| keep.next().unwrap_or(false));
+    for (name, re) in [("focus", focus), ("ignore", ignore)] {
+        if let Some(re) = re {
+            result.comment.push(result.string_table.len() as i64);
+            result
+                .string_table
+                .push(format!("{}: {}", name, re.as_str()));
+        }
+    }
+    result
+}
+
+/// function names of each sample, leaf first, with the sample
+pub fn stacks(profile: &pb::Profile) -> Vec<(Vec<&str>, &pb::Sample)> {
+    let function: HashMap<u64, &str> = profile
+        .function
+        .iter()
+        .map(|f| {
+            let name = profile
+                .string_table
+                .get(f.name as usize)
+                .map_or("", |s| s.as_str());
+            (f.id, name)
+        })
+        .collect();
+    // the innermost inlined function is the first
+    let location: HashMap<u64, Vec<&str>> = profile
+        .location
+        .iter()
+        .map(|l| {
+            let names = l
+                .line
+                .iter()
+                .filter_map(|line| function.get(&line.function_id).copied())
+                .collect();
+            (l.id, names)
+        })
+        .collect();
+
+    profile
+        .sample
+        .iter()
+        .map(|s| {
+            let names = s
+                .location_id
+                .iter()
+                .filter_map(|id| location.get(id))
+                .flatten()
+                .copied()
+                .collect();
+            (names, s)
+        })
+        .collect()
+}
+
+/// Splits a profile recorded with several events, a value per event after
+/// the number of samples, into a profile per event with the counts of the
+/// event alone; empty for a profile of one event.
+pub fn split_events(profile: &pb::Profile) -> Vec<(String, pb::Profile)> {
+    if profile.sample_type.len() <= 2 {
+        return Vec::new();
+    }
+    let mut profiles = Vec::new();
+    for (i, t) in profile.sample_type.iter().enumerate().skip(1) {
+        let mut p = profile.clone();
+        p.sample_type = vec![t.clone()];
+        p.period_type = Some(t.clone());
+        p.default_sample_type = 0;
+        p.sample = profile
+            .sample
+            .iter()
+            .filter(|s| s.value.get(i).copied().unwrap_or(0) != 0)
+            .map(|s| pb::Sample {
+                value: vec![s.value[i]],
+                ..s.clone()
+            })
+            .collect();
+        let name = profile
+            .string_table
+            .get(t.r#type as usize)
+            .cloned()
+            .unwrap_or_default();
+        profiles.push((name, p));
+    }
+    profiles
+}
+
+const LOST_PREFIX: &str = "perf lost";
+
+/// the comment on the samples perf lost under load, like "perf lost 123
+/// samples (4.5%)"
+pub fn lost_samples(profile: &pb::Profile) -> Option<&str> {
+    profile
+        .comment
+        .iter()
+        .filter_map(|i| profile.string_table.get(*i as usize))
+        .find(|c| c.starts_with(LOST_PREFIX))
+        .map(|c| c.as_str())
+}
+
+/// stacks of the profile in the folded format, root first, one line per sample
+pub fn folded(profile: &pb::Profile, index: usize) -> String {
+    let mut s = Vec::new();
+    write_folded(profile, index, &mut s).unwrap();
+    String::from_utf8(s).unwrap()
+}
+
+/// writes the stacks of the profile in the folded format of Brendan Gregg's
+/// flamegraph.pl, with the values of the sample type `index`
+pub fn write_folded<W: io::Write>(
+    profile: &pb::Profile,
+    index: usize,
+    mut writer: W,
+) -> io::Result<()> {
+    for (mut names, sample) in stacks(profile) {
+        let v = sample.value.get(index).copied().unwrap_or(0);
+        if names.is_empty() || v == 0 {
+            continue;
+        }
+        names.reverse();
+        writeln!(writer, "{} {}", names.join(";"), v)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the header of a recording with `-F 1000`, a sample every millisecond
+    const FREQ_HEADER: &str = "# ========\n\
+        # event : name = cpu-clock:u, type = 1, size = 128, { sample_period, sample_freq } = 1000\n\
+        # ========\n";
+
+    fn profile(builder: &mut PprofConverterBuilder, script: &[u8]) -> pb::Profile {
+        let mut content = Vec::new();
+        builder.build().from_reader(script, &mut content).unwrap();
+        decode(&*content).unwrap()
+    }
+
+    fn samples(builder: &PprofConverterBuilder, script: &[u8]) -> Vec<ScriptSample> {
+        let mut samples = Vec::new();
+        builder
+            .read_samples(script, |s| {
+                samples.push(s);
+                Ok(())
+            })
+            .unwrap();
+        samples
+    }
+
+    // the functions of each sample, leaf first, with the values, sorted
+    fn values(profile: &pb::Profile) -> Vec<(Vec<&str>, Vec<i64>)> {
+        let mut v: Vec<(Vec<&str>, Vec<i64>)> = stacks(profile)
+            .into_iter()
+            .map(|(names, s)| (names, s.value.clone()))
+            .collect();
+        v.sort();
+        v
+    }
+
+    fn label<'a>(profile: &'a pb::Profile, sample: &pb::Sample, key: &str) -> Option<&'a str> {
+        sample
+            .label
+            .iter()
+            .find(|l| profile.string_table[l.key as usize] == key)
+            .map(|l| profile.string_table[l.str as usize].as_str())
+    }
+
+    #[test]
+    fn callchains() {
+        let script = format!(
+            "{}\
+             foo 1234/1235 [001] 5678.000001: 1000000 cpu-clock:u:\n\
+             \t55d0c0a01234 foo::bar+0x14 (/usr/bin/foo)\n\
+             \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+             \n\
+             foo 1234/1235 [001] 5678.001001: 1000000 cpu-clock:u:\n\
+             \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+             \n",
+            FREQ_HEADER
+        );
+        let p = profile(&mut PprofConverterBuilder::default(), script.as_bytes());
+        assert_eq!(
+            values(&p),
+            vec![
+                (vec!["foo::bar", "main"], vec![1, 1_000_000]),
+                (vec!["main"], vec![1, 1_000_000]),
+            ]
+        );
+        assert_eq!(p.duration_nanos, 1_000_000);
+        let s = &p.sample[0];
+        assert_eq!(label(&p, s, "pid"), Some("1234"));
+        assert_eq!(label(&p, s, "tid"), Some("1235"));
+        assert_eq!(label(&p, s, "thread_name"), Some("foo"));
+    }
+
+    #[test]
+    fn without_callchains() {
+        // `-F comm,tid,time,event,ip,sym,dso`: the ip on the event line and
+        // no blank line between the samples
+        let script = format!(
+            "{}\
+             foo 1235 5678.000001: cpu-clock:u: 55d0c0a01234 foo::bar+0x14 (/usr/bin/foo)\n\
+             foo 1235 5678.001001: cpu-clock:u: 55d0c0a01234 foo::bar+0x14 (/usr/bin/foo)\n\
+             foo 1235 5678.002001: cpu-clock:u: 55d0c0a00100 main+0x10 (/usr/bin/foo)",
+            FREQ_HEADER
+        );
+        let p = profile(&mut PprofConverterBuilder::default(), script.as_bytes());
+        assert_eq!(
+            values(&p),
+            vec![
+                (vec!["foo::bar"], vec![2, 2_000_000]),
+                (vec!["main"], vec![1, 1_000_000]),
+            ]
+        );
+        assert_eq!(label(&p, &p.sample[0], "pid"), None);
+    }
+
+    #[test]
+    fn exotic_names() {
+        let mut script = b"foo 1235 5678.000001: cpu-clock:u:\n\
+            \t55d0c0a01234 operator()(int) const+0x10 (/tmp/a (1)/libfoo.so)\n\
+            \t55d0c0a01300 std::ostream& operator<< <char>(std::ostream&, char const*) (/usr/lib/libstdc++.so.6)\n\
+            \t55d0c0a01400 foo"
+            .to_vec();
+        script.extend(b"\xff\xfe");
+        script.extend(b" (/usr/bin/foo)\n\t55d0c0a01500 ([unknown])\n\n");
+        let s = samples(&PprofConverterBuilder::default(), &script);
+        assert_eq!(s.len(), 1);
+        assert_eq!(
+            s[0].functions,
+            vec![
+                "operator()(int) const",
+                "std::ostream& operator<< <char>(std::ostream&, char const*)",
+                "foo\\xff\\xfe",
+                "[unknown]",
+            ]
+        );
+        assert_eq!(
+            s[0].modules,
+            vec![
+                "/tmp/a (1)/libfoo.so",
+                "/usr/lib/libstdc++.so.6",
+                "/usr/bin/foo",
+                "[unknown]",
+            ]
+        );
+    }
+
+    #[test]
+    fn nanosecond_timestamps() {
+        let script = b"foo 1235 5678.000000001: cpu-clock:u:\n\
+            \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+            \n\
+            foo 1235 5678.000002: cpu-clock:u:\n\
+            \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+            \n";
+        let s = samples(&PprofConverterBuilder::default(), script);
+        let nsec: Vec<u64> = s.iter().map(|s| s.nsec).collect();
+        assert_eq!(nsec, vec![5_678_000_000_001, 5_678_000_002_000]);
+        assert_eq!(timestamp_nanos("5678", "1234567890"), None);
+    }
+
+    #[test]
+    fn periods() {
+        // recorded with `-F`, the periods of cycles vary, weighting the
+        // share of the cpu time of each sample
+        let script = format!(
+            "{}\
+             foo 1235 5678.000001: 100 cycles:u:\n\
+             \t55d0c0a01234 foo::bar+0x14 (/usr/bin/foo)\n\
+             \n\
+             foo 1235 5678.001001: 300 cycles:u:\n\
+             \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+             \n",
+            FREQ_HEADER
+        );
+        let p = profile(&mut PprofConverterBuilder::default(), script.as_bytes());
+        assert_eq!(
+            values(&p),
+            vec![
+                (vec!["foo::bar"], vec![1, 500_000]),
+                (vec!["main"], vec![1, 1_500_000]),
+            ]
+        );
+        let s = samples(&PprofConverterBuilder::default(), script.as_bytes());
+        let periods: Vec<(&str, u64)> = s.iter().map(|s| (s.event.as_str(), s.period)).collect();
+        assert_eq!(periods, vec![("cycles:u", 100), ("cycles:u", 300)]);
+    }
+
+    #[test]
+    fn sample_period_header() {
+        // recorded with `-c 100000`, no frequency in the header
+        let script = b"# ========\n\
+            # event : name = cycles, type = 0, size = 128, sample_period = 100000\n\
+            # ========\n\
+            foo 1235 5678.000001: cycles:\n\
+            \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+            \n\
+            foo 1235 5678.001001: cycles:\n\
+            \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+            \n";
+        let p = profile(&mut PprofConverterBuilder::default(), script);
+        assert_eq!(values(&p), vec![(vec!["main"], vec![2, 200_000])]);
+        assert_eq!(p.period, 100_000);
+        let t = p.period_type.as_ref().unwrap();
+        assert_eq!(p.string_table[t.r#type as usize], "cycles");
+        assert_eq!(p.string_table[t.unit as usize], "count");
+    }
+
+    #[test]
+    fn vdso_and_signal_frames() {
+        let script = format!(
+            "{}\
+             foo 1235 5678.000001: cpu-clock:u:\n\
+             \t7ffd1234a000 [unknown] ([vdso])\n\
+             \t7ffd00000010 [unknown] ([unknown])\n\
+             \t7ffd00000020 [unknown] ([unknown])\n\
+             \n\
+             foo 1235 5678.001001: cpu-clock:u:\n\
+             \t55d0c0a01234 on_signal+0x14 (/usr/bin/foo)\n\
+             \t7f0000001000 __restore_rt+0x0 (/usr/lib/libc.so.6)\n\
+             \t55d0c0a00200 compute+0x10 (/usr/bin/foo)\n\
+             \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+             \n",
+            FREQ_HEADER
+        );
+        let p = profile(&mut PprofConverterBuilder::default(), script.as_bytes());
+        assert_eq!(
+            values(&p),
+            vec![
+                (vec!["[vdso]"], vec![1, 1_000_000]),
+                (vec!["on_signal", "[signal]"], vec![1, 1_000_000]),
+            ]
+        );
+        let (_, s) = stacks(&p)
+            .into_iter()
+            .find(|(names, _)| names[0] == "on_signal")
+            .unwrap();
+        assert_eq!(label(&p, s, "interrupted"), Some("compute"));
+    }
+
+    #[test]
+    fn repair() {
+        // the second stack lost its callers below `run`, and the unwinder
+        // gave up with an unknown frame
+        let script = format!(
+            "{}\
+             foo 1235 5678.000001: cpu-clock:u:\n\
+             \t55d0c0a01234 work+0x14 (/usr/bin/foo)\n\
+             \t55d0c0a00300 run+0x10 (/usr/bin/foo)\n\
+             \t55d0c0a00100 main+0x10 (/usr/bin/foo)\n\
+             \t7f0000002000 __libc_start_main+0x80 (/usr/lib/libc.so.6)\n\
+             \n\
+             foo 1235 5678.001001: cpu-clock:u:\n\
+             \t55d0c0a01300 leaf+0x4 (/usr/bin/foo)\n\
+             \t55d0c0a00300 run+0x10 (/usr/bin/foo)\n\
+             \t7ffd00000010 [unknown] ([unknown])\n\
+             \n",
+            FREQ_HEADER
+        );
+        let p = profile(
+            PprofConverterBuilder::default().repair(true),
+            script.as_bytes(),
+        );
+        assert_eq!(
+            values(&p),
+            vec![
+                (
+                    vec!["leaf", "run", "main", "__libc_start_main"],
+                    vec![1, 1_000_000]
+                ),
+                (
+                    vec!["work", "run", "main", "__libc_start_main"],
+                    vec![1, 1_000_000]
+                ),
+            ]
+        );
+        let (_, s) = stacks(&p)
+            .into_iter()
+            .find(|(names, _)| names[0] == "leaf")
+            .unwrap();
+        assert_eq!(label(&p, s, "repaired"), Some("true"));
+        assert!(p
+            .comment
+            .iter()
+            .any(|c| p.string_table[*c as usize].starts_with("1 samples of truncated stacks")));
+
+        // left alone without the option
+        let p = profile(&mut PprofConverterBuilder::default(), script.as_bytes());
+        assert!(values(&p)
+            .iter()
+            .any(|(names, _)| names == &vec!["leaf", "run", "[unknown]"]));
+    }
+}
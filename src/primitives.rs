@@ -0,0 +1,164 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::pprof::{self, pb};
+
+lazy_static! {
+    // well-known primitives, including the internal functions of libc
+    static ref CLASSES: Vec<(&'static str, Regex)> = vec![
+        (
+            "memcpy",
+            Regex::new(r"^(__)?(memcpy|memmove|memset|bcopy|bzero)(_|$)|^__mem(cpy|move|set)_").unwrap(),
+        ),
+        (
+            "hash",
+            Regex::new(r"^<?(core::hash|std::collections::hash|hashbrown|ahash|siphasher|fxhash|rustc_hash)::|<core::hash::sip::").unwrap(),
+        ),
+        (
+            "alloc",
+            Regex::new(r"^(__rust_(alloc|dealloc|realloc|alloc_zeroed)|__rdl_\w+|__rg_\w+|malloc|free|realloc|calloc|cfree|_int_(malloc|free|realloc)|malloc_consolidate|tcache_\w+|je_\w+|mi_\w+|tc_\w+)$|^<?(alloc::alloc|std::alloc)::").unwrap(),
+        ),
+    ];
+    // the standard library between the caller and the primitive
+    static ref STD_RE: Regex = Regex::new(r"^<?(std|core|alloc)::").unwrap();
+}
+
+/// the class of a primitive function, if the function is one
+pub fn class_of(name: &str) -> Option<&'static str> {
+    CLASSES
+        .iter()
+        .find(|(_, re)| re.is_match(name))
+        .map(|(class, _)| *class)
+}
+
+/// Time spent in well-known primitives, per class and per caller.
+#[derive(Default)]
+pub struct Primitives {
+    pub total: i64,
+    /// class, (value, caller, value)
+    pub classes: BTreeMap<&'static str, (i64, HashMap<String, i64>)>,
+}
+
+impl Primitives {
+    /// Classifies the `index`-th sample value by the leaf function, and
+    /// attributes it to the nearest caller outside of the primitive and the
+    /// standard library.
+    pub fn new(profile: &pb::Profile, index: usize) -> Self {
+        let mut p = Primitives::default();
+        for (names, s) in pprof::stacks(profile) {
+            let v = s.value.get(index).copied().unwrap_or(0);
+            p.total += v;
+            let class = match names.first().and_then(|leaf| class_of(leaf)) {
+                Some(class) => class,
+                None => continue,
+            };
+            // leaf first
+            let callers = names.iter().skip_while(|n| class_of(n) == Some(class));
+            let caller = callers
+                .clone()
+                .find(|n| !STD_RE.is_match(n))
+                .or_else(|| callers.clone().next())
+                .unwrap_or(&"(root)");
+            let e = p.classes.entry(class).or_default();
+            e.0 += v;
+            *e.1.entry(caller.to_string()).or_insert(0) += v;
+        }
+        p
+    }
+
+    /// renders each class with its hottest `n` callers
+    pub fn render(&self, n: usize) -> String {
+        let total = self.total.max(1) as f64;
+        let mut s = String::new();
+        let mut classes: Vec<_> = self.classes.iter().collect();
+        classes.sort_by_key(|c| std::cmp::Reverse((c.1).0));
+        for (class, (value, callers)) in classes {
+            let _ = writeln!(s, "{:>7.2}%  {}", *value as f64 * 100.0 / total, class);
+            let mut callers: Vec<(&String, &i64)> = callers.iter().collect();
+            callers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (caller, v) in callers.into_iter().take(n) {
+                let _ = writeln!(s, "{:>7.2}%    from {}", *v as f64 * 100.0 / total, caller);
+            }
+        }
+        s
+    }
+}
+
+/// A call path into the allocator.
+pub struct AllocPath {
+    /// root first, the innermost `depth` callers of the allocator
+    pub frames: Vec<String>,
+    pub value: i64,
+}
+
+/// Ranks the call paths into the allocator by the `index`-th sample value;
+/// paths sharing their innermost `depth` callers are merged.
+pub fn alloc_paths(profile: &pb::Profile, index: usize, depth: usize) -> (i64, Vec<AllocPath>) {
+    let mut total = 0;
+    let mut paths: HashMap<Vec<String>, i64> = HashMap::new();
+    for (names, s) in pprof::stacks(profile) {
+        let v = s.value.get(index).copied().unwrap_or(0);
+        total += v;
+        if !matches!(names.first(), Some(leaf) if class_of(leaf) == Some("alloc")) {
+            continue;
+        }
+        // leaf first
+        let mut frames: Vec<String> = names
+            .iter()
+            .skip_while(|n| class_of(n) == Some("alloc"))
+            .take(depth)
+            .map(|n| n.to_string())
+            .collect();
+        frames.reverse();
+        *paths.entry(frames).or_insert(0) += v;
+    }
+    let mut v: Vec<AllocPath> = paths
+        .into_iter()
+        .map(|(frames, value)| AllocPath { frames, value })
+        .collect();
+    v.sort_by(|a, b| b.value.cmp(&a.value).then(a.frames.cmp(&b.frames)));
+    (total, v)
+}
+
+/// Renders the hottest `n` paths into the allocator, each with the pprof
+/// command focusing on it.
+pub fn render_alloc_paths(total: i64, paths: &[AllocPath], n: usize, profile: &str) -> String {
+    let total = total.max(1) as f64;
+    let all: i64 = paths.iter().map(|p| p.value).sum();
+    let mut s = format!(
+        "{:.2}% of the time is spent in the allocator\n",
+        all as f64 * 100.0 / total
+    );
+    for p in paths.iter().take(n) {
+        let _ = writeln!(s, "\n{:>7.2}%", p.value as f64 * 100.0 / total);
+        for f in &p.frames {
+            let _ = writeln!(s, "    {}", f);
+        }
+        if let Some(caller) = p.frames.last() {
+            let _ = writeln!(
+                s,
+                "    pprof -http=: -focus '^{}$' {}",
+                regex::escape(caller).replace('\'', "'\\''"),
+                profile
+            );
+        }
+    }
+    s
+}
@@ -0,0 +1,354 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pprof profile decoded for reading: the references to the string
+//! table are resolved, and the ids of the locations, the functions and the
+//! mappings are turned into positions in their vectors, so that walking the
+//! stacks of the samples is indexing rather than looking up maps. The ids
+//! that point nowhere are errors instead of empty frames.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::pprof::{self, pb};
+
+/// A type of the values of the samples, like `cpu`/`nanoseconds`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueType {
+    pub r#type: String,
+    pub unit: String,
+}
+
+/// A binary or a library mapped in the address space.
+#[derive(Clone, Debug, Default)]
+pub struct Mapping {
+    pub memory_start: u64,
+    pub memory_limit: u64,
+    pub file_offset: u64,
+    pub filename: String,
+    pub build_id: String,
+}
+
+/// A function, possibly inlined in others.
+#[derive(Clone, Debug, Default)]
+pub struct Function {
+    pub name: String,
+    pub system_name: String,
+    pub filename: String,
+    pub start_line: i64,
+}
+
+/// A function at a location, and the line in it.
+#[derive(Clone, Debug, Default)]
+pub struct Line {
+    /// the position in `Profile::functions`
+    pub function: usize,
+    pub line: i64,
+}
+
+/// An address in a stack with the functions there, the inlined ones first.
+#[derive(Clone, Debug, Default)]
+pub struct Location {
+    /// the position in `Profile::mappings`
+    pub mapping: Option<usize>,
+    pub address: u64,
+    pub lines: Vec<Line>,
+}
+
+/// The value of a label, a string or a number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LabelValue {
+    Str(String),
+    Num { value: i64, unit: String },
+}
+
+/// A label of a sample, like `thread_name` or `bytes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Label {
+    pub key: String,
+    pub value: LabelValue,
+}
+
+/// A stack and its values, one per sample type.
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    /// the positions in `Profile::locations`, leaf first
+    pub locations: Vec<usize>,
+    pub values: Vec<i64>,
+    pub labels: Vec<Label>,
+}
+
+impl Sample {
+    /// the string value of the label `key`
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.iter().find_map(|l| match &l.value {
+            LabelValue::Str(s) if l.key == key => Some(s.as_str()),
+            _ => None,
+        })
+    }
+
+    /// the numeric value of the label `key`
+    pub fn num_label(&self, key: &str) -> Option<i64> {
+        self.labels.iter().find_map(|l| match l.value {
+            LabelValue::Num { value, .. } if l.key == key => Some(value),
+            _ => None,
+        })
+    }
+}
+
+/// A decoded pprof profile.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub sample_types: Vec<ValueType>,
+    /// the sample type that pprof shows first, the last one if `None`
+    pub default_sample_type: Option<String>,
+    pub samples: Vec<Sample>,
+    pub locations: Vec<Location>,
+    pub functions: Vec<Function>,
+    pub mappings: Vec<Mapping>,
+    pub time_nanos: i64,
+    pub duration_nanos: i64,
+    pub period_type: Option<ValueType>,
+    pub period: i64,
+    pub comments: Vec<String>,
+    pub drop_frames: Option<String>,
+    pub keep_frames: Option<String>,
+}
+
+impl Profile {
+    /// reads a profile, gzipped, compressed with zstd or not
+    pub fn parse<R: io::Read>(reader: R) -> io::Result<Profile> {
+        Profile::from_pb(&pprof::decode(reader)?)
+    }
+
+    /// builds a profile of sample counts from stacks in the folded format,
+    /// root first, to write examples and tests of code using profiles
+    /// without recording any
+    ///
+    /// ```
+    /// use perf_tools::pprof::Profile;
+    ///
+    /// let profile = Profile::from_folded("main;run;parse 2\nmain;run 1\nmain;run;parse 1\n").unwrap();
+    /// assert_eq!(profile.total(0), 4);
+    /// assert_eq!(profile.to_folded(), "main;run 1\nmain;run;parse 3\n");
+    /// ```
+    pub fn from_folded(folded: &str) -> io::Result<Profile> {
+        Profile::from_pb(&pprof::decode_folded(folded.as_bytes())?)
+    }
+
+    /// resolves the strings and the ids of a decoded protobuf message
+    pub fn from_pb(profile: &pb::Profile) -> io::Result<Profile> {
+        let string = |i: i64| {
+            profile
+                .string_table
+                .get(i as usize)
+                .cloned()
+                .ok_or_else(|| invalid(format!("string {} out of the table", i)))
+        };
+        let optional = |i: i64| -> io::Result<Option<String>> {
+            Ok(if i == 0 { None } else { Some(string(i)?) })
+        };
+        let value_type = |t: &pb::ValueType| -> io::Result<ValueType> {
+            Ok(ValueType {
+                r#type: string(t.r#type)?,
+                unit: string(t.unit)?,
+            })
+        };
+        let positions = |ids: Vec<u64>, what: &str| -> io::Result<HashMap<u64, usize>> {
+            let mut map = HashMap::new();
+            for (i, id) in ids.into_iter().enumerate() {
+                if id == 0 || map.insert(id, i).is_some() {
+                    return Err(invalid(format!("{} id {} is zero or duplicated", what, id)));
+                }
+            }
+            Ok(map)
+        };
+        let mapping_pos = positions(profile.mapping.iter().map(|m| m.id).collect(), "mapping")?;
+        let function_pos = positions(profile.function.iter().map(|f| f.id).collect(), "function")?;
+        let location_pos = positions(profile.location.iter().map(|l| l.id).collect(), "location")?;
+        let find = |map: &HashMap<u64, usize>, id: u64, what: &str| {
+            map.get(&id)
+                .copied()
+                .ok_or_else(|| invalid(format!("{} {} isn't found", what, id)))
+        };
+
+        let mappings = profile
+            .mapping
+            .iter()
+            .map(|m| {
+                Ok(Mapping {
+                    memory_start: m.memory_start,
+                    memory_limit: m.memory_limit,
+                    file_offset: m.file_offset,
+                    filename: string(m.filename)?,
+                    build_id: string(m.build_id)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let functions = profile
+            .function
+            .iter()
+            .map(|f| {
+                Ok(Function {
+                    name: string(f.name)?,
+                    system_name: string(f.system_name)?,
+                    filename: string(f.filename)?,
+                    start_line: f.start_line,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let locations = profile
+            .location
+            .iter()
+            .map(|l| {
+                Ok(Location {
+                    mapping: match l.mapping_id {
+                        0 => None,
+                        id => Some(find(&mapping_pos, id, "mapping")?),
+                    },
+                    address: l.address,
+                    lines: l
+                        .line
+                        .iter()
+                        .map(|line| {
+                            Ok(Line {
+                                function: find(&function_pos, line.function_id, "function")?,
+                                line: line.line,
+                            })
+                        })
+                        .collect::<io::Result<_>>()?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let samples = profile
+            .sample
+            .iter()
+            .map(|s| {
+                if s.value.len() != profile.sample_type.len() {
+                    return Err(invalid(format!(
+                        "{} values for {} sample types",
+                        s.value.len(),
+                        profile.sample_type.len()
+                    )));
+                }
+                Ok(Sample {
+                    locations: s
+                        .location_id
+                        .iter()
+                        .map(|id| find(&location_pos, *id, "location"))
+                        .collect::<io::Result<_>>()?,
+                    values: s.value.clone(),
+                    labels: s
+                        .label
+                        .iter()
+                        .map(|l| {
+                            Ok(Label {
+                                key: string(l.key)?,
+                                value: if l.str != 0 {
+                                    LabelValue::Str(string(l.str)?)
+                                } else {
+                                    LabelValue::Num {
+                                        value: l.num,
+                                        unit: string(l.num_unit)?,
+                                    }
+                                },
+                            })
+                        })
+                        .collect::<io::Result<_>>()?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Profile {
+            sample_types: profile
+                .sample_type
+                .iter()
+                .map(value_type)
+                .collect::<io::Result<_>>()?,
+            default_sample_type: optional(profile.default_sample_type)?,
+            samples,
+            locations,
+            functions,
+            mappings,
+            time_nanos: profile.time_nanos,
+            duration_nanos: profile.duration_nanos,
+            period_type: profile.period_type.as_ref().map(value_type).transpose()?,
+            period: profile.period,
+            comments: profile
+                .comment
+                .iter()
+                .map(|c| string(*c))
+                .collect::<io::Result<_>>()?,
+            drop_frames: optional(profile.drop_frames)?,
+            keep_frames: optional(profile.keep_frames)?,
+        })
+    }
+
+    /// the position of the sample type named `name` in the values
+    pub fn sample_type(&self, name: &str) -> Option<usize> {
+        self.sample_types.iter().position(|t| t.r#type == name)
+    }
+
+    /// the position of the sample type that pprof shows by default
+    pub fn default_index(&self) -> usize {
+        self.default_sample_type
+            .as_ref()
+            .and_then(|t| self.sample_type(t))
+            .unwrap_or_else(|| self.sample_types.len().saturating_sub(1))
+    }
+
+    /// the sum of the values of the sample type at `index`
+    pub fn total(&self, index: usize) -> i64 {
+        self.samples
+            .iter()
+            .filter_map(|s| s.values.get(index))
+            .sum()
+    }
+
+    /// the functions of the stack of a sample, leaf first, the inlined ones
+    /// before their callers
+    pub fn functions<'a>(&'a self, sample: &'a Sample) -> impl Iterator<Item = &'a Function> {
+        sample
+            .locations
+            .iter()
+            .flat_map(move |l| self.locations[*l].lines.iter())
+            .map(move |line| &self.functions[line.function])
+    }
+
+    /// the stacks in the folded format, root first, with the values of the
+    /// default sample type; the same stacks are summed up and the lines are
+    /// sorted so that the text can be compared
+    pub fn to_folded(&self) -> String {
+        let index = self.default_index();
+        let mut stacks: BTreeMap<String, i64> = BTreeMap::new();
+        for sample in &self.samples {
+            let mut names: Vec<&str> = self.functions(sample).map(|f| f.name.as_str()).collect();
+            let v = sample.values.get(index).copied().unwrap_or(0);
+            if names.is_empty() || v == 0 {
+                continue;
+            }
+            names.reverse();
+            *stacks.entry(names.join(";")).or_default() += v;
+        }
+        stacks
+            .into_iter()
+            .map(|(stack, v)| format!("{} {}\n", stack, v))
+            .collect()
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
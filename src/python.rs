@@ -0,0 +1,146 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Deserialize;
+use std::io;
+
+use crate::pprof::{pb, SampleBuilder};
+
+#[derive(Deserialize)]
+struct Speedscope {
+    shared: Shared,
+    profiles: Vec<SpeedscopeProfile>,
+}
+
+#[derive(Deserialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+#[derive(Deserialize)]
+struct Frame {
+    name: String,
+    file: Option<String>,
+    line: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SpeedscopeProfile {
+    r#type: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    samples: Vec<Vec<usize>>,
+    #[serde(default)]
+    weights: Vec<f64>,
+}
+
+// as py-spy names frames in the raw output, `handle (server.py:42)`
+fn frame_name(function: &str, file: Option<&str>, line: Option<u64>) -> String {
+    match (file, line) {
+        (Some(file), Some(line)) if line > 0 => format!("{} ({}:{})", function, file, line),
+        (Some(file), _) => format!("{} ({})", function, file),
+        _ => function.to_string(),
+    }
+}
+
+/// Reads the sampled profiles of speedscope JSON, which py-spy writes with
+/// `--format speedscope`. Each profile is a thread, labeled `thread_name`.
+pub fn decode_speedscope<R>(reader: R) -> io::Result<pb::Profile>
+where
+    R: io::Read,
+{
+    let speedscope: Speedscope = serde_json::from_reader(reader)?;
+    let names: Vec<String> = speedscope
+        .shared
+        .frames
+        .iter()
+        .map(|f| frame_name(&f.name, f.file.as_deref(), f.line))
+        .collect();
+    let mut builder = SampleBuilder::default();
+    for p in speedscope.profiles.iter().filter(|p| p.r#type == "sampled") {
+        let labels = [("thread_name", p.name.as_str())];
+        for (i, stack) in p.samples.iter().enumerate() {
+            // root first
+            let frames: Vec<&str> = stack
+                .iter()
+                .rev()
+                .filter_map(|f| names.get(*f).map(|n| n.as_str()))
+                .collect();
+            let weight = p.weights.get(i).copied().unwrap_or(1.0).round() as i64;
+            builder.add(&frames, &labels, weight);
+        }
+    }
+    Ok(builder.build())
+}
+
+/// Reads austin's output, `P<pid>;T<tid>;<file>:<function>:<line>;... <metric>`
+/// root first per line; the metric is the time in microseconds. The process
+/// and the thread become the `pid` and `tid` labels.
+pub fn decode_austin<R>(mut reader: R) -> io::Result<pb::Profile>
+where
+    R: io::BufRead,
+{
+    let mut builder = SampleBuilder::new("time", "microseconds");
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            break;
+        }
+        let line = buf.trim();
+        // metadata
+        if line.starts_with('#') {
+            continue;
+        }
+        let (stack, metrics) = match line.rsplit_once(' ') {
+            Some(v) => v,
+            None => continue,
+        };
+        // full metrics: time,idle,memory
+        let value: i64 = match metrics.split(',').next().map(|m| m.parse()) {
+            Some(Ok(v)) => v,
+            _ => continue,
+        };
+        let mut pid = "";
+        let mut tid = "";
+        let mut frames = Vec::new();
+        for f in stack.split(';') {
+            if let Some(p) = f.strip_prefix('P') {
+                if pid.is_empty() && frames.is_empty() {
+                    pid = p;
+                    continue;
+                }
+            }
+            if let Some(t) = f.strip_prefix('T') {
+                if tid.is_empty() && frames.is_empty() {
+                    tid = t;
+                    continue;
+                }
+            }
+            let mut parts = f.rsplitn(3, ':');
+            let frame = match (parts.next(), parts.next(), parts.next()) {
+                (Some(line), Some(function), Some(file)) => {
+                    frame_name(function, Some(file), line.parse().ok())
+                }
+                _ => f.to_string(),
+            };
+            frames.push(frame);
+        }
+        let frames: Vec<&str> = frames.iter().rev().map(|f| f.as_str()).collect();
+        builder.add(&frames, &[("pid", pid), ("tid", tid)], value);
+    }
+    Ok(builder.build())
+}
@@ -0,0 +1,670 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small SQL subset over the tables of a profile:
+//!
+//! ```text
+//! SELECT item, ... FROM stacks|functions
+//!   [WHERE column op literal [AND ...]]
+//!   [GROUP BY column, ...]
+//!   [ORDER BY output [ASC|DESC]]
+//!   [LIMIT n]
+//! ```
+//!
+//! An item is a column or `sum|count|min|max|avg(column|*)`, optionally
+//! `AS alias`; `op` is one of `= != <> < <= > >= LIKE`.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+
+use crate::pprof::pb;
+use crate::table::Table;
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{:.2}", v),
+            Value::Str(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Value {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v),
+            Value::Str(_) => None,
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self.as_f64(), other.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => Some(self.to_string().cmp(&other.to_string())),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f64),
+    Str(String),
+    Symbol(String),
+}
+
+fn tokenize(s: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = s.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit()
+            || (c == '-' && matches!(chars.get(i + 1), Some(d) if d.is_ascii_digit()))
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let n: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(
+                n.parse()
+                    .map_err(|_| invalid(format!("invalid number: {}", n)))?,
+            ));
+        } else if c == '\'' {
+            let mut v = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    // '' is an escaped quote
+                    Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                        v.push('\'');
+                        i += 2;
+                    }
+                    Some('\'') => break,
+                    Some(c) => {
+                        v.push(*c);
+                        i += 1;
+                    }
+                    None => return Err(invalid("unterminated string".to_string())),
+                }
+            }
+            i += 1;
+            tokens.push(Token::Str(v));
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if ["<=", ">=", "!=", "<>"].contains(&two.as_str()) {
+                tokens.push(Token::Symbol(two));
+                i += 2;
+            } else if "(),*=<>".contains(c) {
+                tokens.push(Token::Symbol(c.to_string()));
+                i += 1;
+            } else {
+                return Err(invalid(format!("unexpected character: {}", c)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Column(String),
+    Aggregate(String, Option<String>),
+}
+
+#[derive(Debug)]
+struct Item {
+    expr: Expr,
+    name: String,
+}
+
+#[derive(Debug)]
+struct Condition {
+    column: String,
+    op: String,
+    value: Value,
+}
+
+/// A parsed query.
+#[derive(Debug)]
+pub struct Query {
+    items: Vec<Item>,
+    table: String,
+    conditions: Vec<Condition>,
+    group_by: Vec<String>,
+    order_by: Option<(String, bool)>,
+    limit: Option<usize>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> io::Result<Token> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| invalid("unexpected end of query".to_string()))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> io::Result<()> {
+        if self.is_keyword(keyword) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(invalid(format!("expected {}", keyword.to_uppercase())))
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol.to_string())) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn word(&mut self) -> io::Result<String> {
+        match self.next()? {
+            Token::Word(w) => Ok(w),
+            t => Err(invalid(format!("expected a name, found {:?}", t))),
+        }
+    }
+
+    fn item(&mut self) -> io::Result<Item> {
+        let word = self.word()?;
+        let expr = if self.symbol("(") {
+            let func = word.to_lowercase();
+            if !["sum", "count", "min", "max", "avg"].contains(&func.as_str()) {
+                return Err(invalid(format!("unknown function: {}", word)));
+            }
+            let arg = if self.symbol("*") {
+                None
+            } else {
+                Some(self.word()?)
+            };
+            if !self.symbol(")") {
+                return Err(invalid("expected )".to_string()));
+            }
+            Expr::Aggregate(func, arg)
+        } else {
+            Expr::Column(word)
+        };
+        let name = if self.is_keyword("as") {
+            self.pos += 1;
+            self.word()?
+        } else {
+            match &expr {
+                Expr::Column(c) => c.clone(),
+                Expr::Aggregate(f, a) => format!("{}({})", f, a.as_deref().unwrap_or("*")),
+            }
+        };
+        Ok(Item { expr, name })
+    }
+
+    fn condition(&mut self) -> io::Result<Condition> {
+        let column = self.word()?;
+        let op = match self.next()? {
+            Token::Symbol(s) if ["=", "!=", "<>", "<", "<=", ">", ">="].contains(&s.as_str()) => s,
+            Token::Word(w) if w.eq_ignore_ascii_case("like") => "like".to_string(),
+            t => return Err(invalid(format!("expected an operator, found {:?}", t))),
+        };
+        let value = match self.next()? {
+            Token::Number(n) if n.fract() == 0.0 => Value::Int(n as i64),
+            Token::Number(n) => Value::Float(n),
+            Token::Str(s) => Value::Str(s),
+            t => return Err(invalid(format!("expected a literal, found {:?}", t))),
+        };
+        Ok(Condition { column, op, value })
+    }
+}
+
+/// parses a query
+pub fn parse(s: &str) -> io::Result<Query> {
+    let mut p = Parser {
+        tokens: tokenize(s)?,
+        pos: 0,
+    };
+    p.keyword("select")?;
+    let mut items = vec![p.item()?];
+    while p.symbol(",") {
+        items.push(p.item()?);
+    }
+    p.keyword("from")?;
+    let table = p.word()?.to_lowercase();
+    let mut conditions = Vec::new();
+    if p.is_keyword("where") {
+        p.pos += 1;
+        conditions.push(p.condition()?);
+        while p.is_keyword("and") {
+            p.pos += 1;
+            conditions.push(p.condition()?);
+        }
+    }
+    let mut group_by = Vec::new();
+    if p.is_keyword("group") {
+        p.pos += 1;
+        p.keyword("by")?;
+        group_by.push(p.word()?);
+        while p.symbol(",") {
+            group_by.push(p.word()?);
+        }
+    }
+    let mut order_by = None;
+    if p.is_keyword("order") {
+        p.pos += 1;
+        p.keyword("by")?;
+        let item = p.item()?;
+        let desc = if p.is_keyword("desc") {
+            p.pos += 1;
+            true
+        } else {
+            if p.is_keyword("asc") {
+                p.pos += 1;
+            }
+            false
+        };
+        order_by = Some((item.name, desc));
+    }
+    let mut limit = None;
+    if p.is_keyword("limit") {
+        p.pos += 1;
+        match p.next()? {
+            Token::Number(n) => limit = Some(n as usize),
+            t => return Err(invalid(format!("expected a number, found {:?}", t))),
+        }
+    }
+    if let Some(t) = p.peek() {
+        return Err(invalid(format!("unexpected {:?}", t)));
+    }
+    Ok(Query {
+        items,
+        table,
+        conditions,
+        group_by,
+        order_by,
+        limit,
+    })
+}
+
+// `%` matches any string and `_` any character; on a mismatch, only the
+// last `%` takes one more character, which keeps it linear per `%`
+fn like(s: &str, pattern: &str) -> bool {
+    let s: Vec<char> = s.chars().collect();
+    let p: Vec<char> = pattern.chars().collect();
+    let (mut i, mut j) = (0, 0);
+    // the position after the last `%` and the start of the string it matches
+    let mut last = None;
+    while i < s.len() {
+        if j < p.len() && (p[j] == '_' || (p[j] != '%' && p[j] == s[i])) {
+            i += 1;
+            j += 1;
+        } else if j < p.len() && p[j] == '%' {
+            j += 1;
+            last = Some((j, i));
+        } else if let Some((after, start)) = last {
+            j = after;
+            i = start + 1;
+            last = Some((after, start + 1));
+        } else {
+            return false;
+        }
+    }
+    p[j..].iter().all(|c| *c == '%')
+}
+
+fn column_value(table: &Table, column: &str, row: usize) -> io::Result<Value> {
+    if let Some((_, v)) = table.string_columns.iter().find(|(n, _)| n == column) {
+        return Ok(Value::Str(v[row].clone()));
+    }
+    if let Some((_, v)) = table.int_columns.iter().find(|(n, _)| n == column) {
+        return Ok(Value::Int(v[row]));
+    }
+    Err(invalid(format!("unknown column: {}", column)))
+}
+
+fn aggregate(table: &Table, func: &str, arg: Option<&str>, rows: &[usize]) -> io::Result<Value> {
+    let arg = match arg {
+        Some(arg) => arg,
+        None if func == "count" => return Ok(Value::Int(rows.len() as i64)),
+        None => return Err(invalid(format!("{}(*) isn't supported", func))),
+    };
+    let values = rows
+        .iter()
+        .map(|r| column_value(table, arg, *r))
+        .collect::<io::Result<Vec<Value>>>()?;
+    if func == "count" {
+        return Ok(Value::Int(values.len() as i64));
+    }
+    if func == "min" || func == "max" {
+        let v = values.into_iter().reduce(|a, b| {
+            let less = a.compare(&b) == Some(Ordering::Less);
+            if less == (func == "min") {
+                a
+            } else {
+                b
+            }
+        });
+        return v.ok_or_else(|| invalid(format!("{} of no rows", func)));
+    }
+    let mut sum = 0;
+    for v in &values {
+        match v {
+            Value::Int(i) => sum += i,
+            _ => {
+                return Err(invalid(format!(
+                    "{} of a non-numeric column: {}",
+                    func, arg
+                )))
+            }
+        }
+    }
+    if func == "sum" {
+        Ok(Value::Int(sum))
+    } else {
+        Ok(Value::Float(sum as f64 / values.len().max(1) as f64))
+    }
+}
+
+impl Query {
+    /// runs the query on the profile, returning the header and the rows
+    pub fn run(&self, profile: &pb::Profile) -> io::Result<(Vec<String>, Vec<Vec<Value>>)> {
+        let table = match self.table.as_str() {
+            "stacks" => Table::stacks(profile),
+            "functions" => Table::functions(profile),
+            t => return Err(invalid(format!("unknown table: {}", t))),
+        };
+
+        let mut rows = Vec::new();
+        for r in 0..table.rows() {
+            let mut keep = true;
+            for c in &self.conditions {
+                let v = column_value(&table, &c.column, r)?;
+                let ord = v.compare(&c.value);
+                keep &= match c.op.as_str() {
+                    "=" => ord == Some(Ordering::Equal),
+                    "!=" | "<>" => ord != Some(Ordering::Equal),
+                    "<" => ord == Some(Ordering::Less),
+                    "<=" => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+                    ">" => ord == Some(Ordering::Greater),
+                    ">=" => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                    _ => like(&v.to_string(), &c.value.to_string()),
+                };
+            }
+            if keep {
+                rows.push(r);
+            }
+        }
+
+        let aggregated = !self.group_by.is_empty()
+            || self
+                .items
+                .iter()
+                .any(|i| matches!(i.expr, Expr::Aggregate(..)));
+        let groups: Vec<Vec<usize>> = if aggregated {
+            let mut order = Vec::new();
+            let mut groups: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+            for r in rows {
+                let key = self
+                    .group_by
+                    .iter()
+                    .map(|c| column_value(&table, c, r).map(|v| v.to_string()))
+                    .collect::<io::Result<Vec<String>>>()?;
+                if !groups.contains_key(&key) {
+                    order.push(key.clone());
+                }
+                groups.entry(key).or_default().push(r);
+            }
+            if order.is_empty() && self.group_by.is_empty() {
+                // aggregates over no rows
+                vec![Vec::new()]
+            } else {
+                order
+                    .into_iter()
+                    .map(|k| groups.remove(&k).unwrap())
+                    .collect()
+            }
+        } else {
+            rows.into_iter().map(|r| vec![r]).collect()
+        };
+
+        let mut output = Vec::new();
+        for g in groups {
+            let mut row = Vec::new();
+            for item in &self.items {
+                row.push(match &item.expr {
+                    Expr::Column(c) => {
+                        if aggregated && !self.group_by.contains(c) {
+                            return Err(invalid(format!("{} must be in GROUP BY", c)));
+                        }
+                        match g.first() {
+                            Some(r) => column_value(&table, c, *r)?,
+                            None => Value::Str(String::new()),
+                        }
+                    }
+                    Expr::Aggregate(f, a) => aggregate(&table, f, a.as_deref(), &g)?,
+                });
+            }
+            output.push(row);
+        }
+
+        let header: Vec<String> = self.items.iter().map(|i| i.name.clone()).collect();
+        if let Some((name, desc)) = &self.order_by {
+            let i = header
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| invalid(format!("ORDER BY must be one of the outputs: {}", name)))?;
+            output.sort_by(|a, b| {
+                let o = a[i].compare(&b[i]).unwrap_or(Ordering::Equal);
+                if *desc {
+                    o.reverse()
+                } else {
+                    o
+                }
+            });
+        }
+        if let Some(limit) = self.limit {
+            output.truncate(limit);
+        }
+        Ok((header, output))
+    }
+}
+
+/// renders the result as a text table
+pub fn render(header: &[String], rows: &[Vec<Value>]) -> String {
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|r| r.iter().map(|v| v.to_string()).collect())
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain(std::iter::once(header[i].chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut s = String::new();
+    for row in std::iter::once(header.to_vec()).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = *w))
+            .collect();
+        let _ = writeln!(s, "{}", cells.join("  ").trim_end());
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // main;a with 3, main;b with 1 and main;a;b with -2 nanoseconds of cpu
+    fn profile() -> pb::Profile {
+        let names = ["", "cpu", "nanoseconds", "main", "a", "b"];
+        let function = (3..6)
+            .map(|name| pb::Function {
+                id: name,
+                name: name as i64,
+                ..Default::default()
+            })
+            .collect();
+        let location = (3..6)
+            .map(|id| pb::Location {
+                id,
+                line: vec![pb::Line {
+                    function_id: id,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .collect();
+        let sample = [(vec![4, 3], 3), (vec![5, 3], 1), (vec![5, 4, 3], -2)]
+            .into_iter()
+            .map(|(location_id, v)| pb::Sample {
+                location_id,
+                value: vec![v],
+                ..Default::default()
+            })
+            .collect();
+        pb::Profile {
+            sample_type: vec![pb::ValueType { r#type: 1, unit: 2 }],
+            sample,
+            location,
+            function,
+            string_table: names.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn run(q: &str) -> Vec<Vec<Value>> {
+        parse(q).unwrap().run(&profile()).unwrap().1
+    }
+
+    fn s(v: &str) -> Value {
+        Value::Str(v.to_string())
+    }
+
+    #[test]
+    fn like_patterns() {
+        assert!(like("main;a;b", "main%"));
+        assert!(like("main;a;b", "%;a;%"));
+        assert!(like("main;a;b", "m_in%b"));
+        assert!(like("", "%%"));
+        assert!(!like("main;a", "%;b"));
+        assert!(!like("ab", "a_b"));
+        // backtracking on every % would take exponential time
+        let s = "a".repeat(64);
+        assert!(!like(&s, &format!("{}b", "%a".repeat(32))));
+    }
+
+    #[test]
+    fn negative_numbers() {
+        assert_eq!(
+            tokenize("cpu > -2.5").unwrap(),
+            vec![
+                Token::Word("cpu".to_string()),
+                Token::Symbol(">".to_string()),
+                Token::Number(-2.5),
+            ]
+        );
+        assert_eq!(
+            run("SELECT stack FROM stacks WHERE cpu < -1"),
+            vec![vec![s("main;a;b")]]
+        );
+        assert!(tokenize("cpu - 1").is_err());
+    }
+
+    #[test]
+    fn group_order_limit() {
+        assert_eq!(
+            run("SELECT leaf, sum(cpu) AS cpu, count(*) FROM stacks GROUP BY leaf ORDER BY cpu DESC"),
+            vec![
+                vec![s("a"), Value::Int(3), Value::Int(1)],
+                vec![s("b"), Value::Int(-1), Value::Int(2)],
+            ]
+        );
+        assert_eq!(
+            run("select stack from stacks where stack like 'main;_' and cpu >= 1 order by stack limit 1"),
+            vec![vec![s("main;a")]]
+        );
+        assert_eq!(
+            run("SELECT count(*), avg(cpu) FROM stacks WHERE leaf = 'c'"),
+            vec![vec![Value::Int(0), Value::Float(0.0)]]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for q in [
+            "SELECT leaf FROM",
+            "SELECT leaf FROM stacks WHERE leaf = 'a",
+            "SELECT median(cpu) FROM stacks",
+            "SELECT leaf FROM stacks LIMIT 1 2",
+        ] {
+            assert!(parse(q).is_err(), "{}", q);
+        }
+        for q in [
+            "SELECT leaf FROM samples",
+            "SELECT nope FROM stacks",
+            "SELECT leaf, sum(cpu) FROM stacks",
+            "SELECT leaf FROM stacks ORDER BY stack",
+            "SELECT max(cpu) FROM stacks WHERE leaf = 'c'",
+        ] {
+            assert!(parse(q).unwrap().run(&profile()).is_err(), "{}", q);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::diff::strip_hash;
+use crate::flat::FlatProfile;
+use crate::pprof::pb;
+
+/// The functions of the workload and their expected share of it.
+pub const HOTSPOTS: [(&str, f64); 3] = [
+    ("selftest::hot", 0.7),
+    ("selftest::warm", 0.2),
+    ("selftest::cold", 0.1),
+];
+
+#[inline(never)]
+fn spin(d: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < d {}
+}
+
+// each spins for a different time so that the linker doesn't fold them into one
+
+#[inline(never)]
+fn hot() {
+    spin(Duration::from_millis(7));
+}
+
+#[inline(never)]
+fn warm() {
+    spin(Duration::from_millis(2));
+}
+
+#[inline(never)]
+fn cold() {
+    spin(Duration::from_millis(1));
+}
+
+/// burns cpu in the hotspot functions for `duration`
+pub fn workload(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        hot();
+        warm();
+        cold();
+    }
+}
+
+/// The share of a hotspot function in the profiled workload.
+#[derive(Debug)]
+pub struct Hotspot {
+    pub function: &'static str,
+    pub expected: f64,
+    pub actual: f64,
+}
+
+impl Hotspot {
+    pub fn passed(&self, tolerance: f64) -> bool {
+        (self.actual - self.expected).abs() <= tolerance
+    }
+}
+
+/// Measures the share of each hotspot in the profile of the workload,
+/// relative to the hotspots, so that the startup of the process doesn't count.
+pub fn verify(profile: &pb::Profile) -> Vec<Hotspot> {
+    let flat = FlatProfile::new(profile, 0);
+    let totals: Vec<i64> = HOTSPOTS
+        .iter()
+        .map(|(function, _)| {
+            flat.functions
+                .iter()
+                .filter(|(name, _)| strip_hash(name).ends_with(function))
+                .map(|(_, (_, total))| *total)
+                .sum()
+        })
+        .collect();
+    let sum: i64 = totals.iter().sum();
+    HOTSPOTS
+        .iter()
+        .zip(totals)
+        .map(|((function, expected), total)| Hotspot {
+            function,
+            expected: *expected,
+            actual: total as f64 / sum.max(1) as f64,
+        })
+        .collect()
+}
+
+/// renders the results as a text table
+pub fn render(results: &[Hotspot], tolerance: f64) -> String {
+    let mut s = format!(
+        "{:>6} {:>9} {:>8}  {}\n",
+        "", "Expected%", "Actual%", "Function"
+    );
+    for r in results {
+        let _ = writeln!(
+            s,
+            "{:>6} {:>8.2}% {:>7.2}%  {}",
+            if r.passed(tolerance) { "ok" } else { "FAILED" },
+            r.expected * 100.0,
+            r.actual * 100.0,
+            r.function
+        );
+    }
+    s
+}
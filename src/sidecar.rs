@@ -0,0 +1,214 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use crate::pprof::pb;
+
+/// the kallsyms snapshot of the recording host
+pub fn kallsyms_path(perf_data: &str) -> String {
+    format!("{}.kallsyms", perf_data)
+}
+
+/// the module list snapshot of the recording host
+pub fn modules_path(perf_data: &str) -> String {
+    format!("{}.modules", perf_data)
+}
+
+/// Copies /proc/kallsyms and /proc/modules next to perf.data so that kernel
+/// frames can be resolved on another machine or after a reboot.
+pub fn snapshot_kallsyms(perf_data: &str) -> io::Result<()> {
+    let kallsyms = std::fs::read("/proc/kallsyms")?;
+    // addresses are hidden unless kernel.kptr_restrict is 0
+    if kallsyms.starts_with(b"0000000000000000 ") {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "kernel addresses are hidden; set kernel.kptr_restrict to 0",
+        ));
+    }
+    std::fs::write(kallsyms_path(perf_data), kallsyms)?;
+    std::fs::copy("/proc/modules", modules_path(perf_data))?;
+    Ok(())
+}
+
+/// the kallsyms snapshot if it was taken at record time
+pub fn find_kallsyms(perf_data: &str) -> Option<String> {
+    let path = kallsyms_path(perf_data);
+    if Path::new(&path).exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// the metadata of the recording
+pub fn meta_path(perf_data: &str) -> String {
+    format!("{}.perfmeta", perf_data)
+}
+
+/// the environment variable to pass the fixed seed to the binary
+pub const SEED_ENV: &str = "PERF_TOOLS_SEED";
+
+const STDOUT_HASH_COMMENT: &str = "stdout hash: ";
+
+/// FNV-1a, stable across builds unlike `DefaultHasher`
+pub fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// the initial value of `hash_bytes()`
+pub const HASH_INIT: u64 = 0xcbf29ce484222325;
+
+/// the hash of the binary's stdout recorded in the profile comments if any
+pub fn stdout_hash(profile: &pb::Profile) -> Option<&str> {
+    profile
+        .comment
+        .iter()
+        .filter_map(|i| profile.string_table.get(*i as usize))
+        .find_map(|c| c.strip_prefix(STDOUT_HASH_COMMENT))
+}
+
+// environment variables affecting the build, kept in the profiles that
+// get shared, so listed one by one rather than by prefix, which would take
+// `CARGO_REGISTRY_TOKEN` along
+const ENV_NAMES: [&str; 3] = ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS", "CARGO_INCREMENTAL"];
+const ENV_PREFIXES: [&str; 2] = ["CARGO_PROFILE_", "CARGO_BUILD_"];
+const SECRET_WORDS: [&str; 4] = ["TOKEN", "SECRET", "PASSWORD", "KEY"];
+
+// the variable is recorded
+fn recorded(name: &str) -> bool {
+    (ENV_NAMES.contains(&name) || ENV_PREFIXES.iter().any(|p| name.starts_with(p)))
+        && !SECRET_WORDS.iter().any(|w| name.to_uppercase().contains(w))
+}
+
+/// Metadata of a recording, written as JSON alongside perf.data.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Meta {
+    pub git_sha: Option<String>,
+    pub git_branch: Option<String>,
+    /// the checkout has uncommitted changes
+    pub git_dirty: Option<bool>,
+    pub cargo_args: Vec<String>,
+    pub rustc_version: Option<String>,
+    pub hostname: Option<String>,
+    pub cmdline: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// (microseconds since the epoch, name)
+    pub markers: Vec<(u64, String)>,
+    /// the value of `PERF_TOOLS_SEED`
+    pub seed: Option<u64>,
+    /// the hash of the binary's stdout, to tell if two runs did the same work
+    pub stdout_hash: Option<String>,
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Meta {
+    /// collects the metadata of the current environment
+    pub fn collect(cargo_args: Vec<String>) -> Self {
+        let git_sha = command_output("git", &["rev-parse", "HEAD"]);
+        let (git_branch, git_dirty) = if git_sha.is_some() {
+            (
+                // "HEAD" if detached
+                command_output("git", &["rev-parse", "--abbrev-ref", "HEAD"])
+                    .filter(|b| b != "HEAD"),
+                command_output("git", &["status", "--porcelain"]).map(|s| !s.is_empty()),
+            )
+        } else {
+            (None, None)
+        };
+        Meta {
+            git_sha,
+            git_branch,
+            git_dirty,
+            cargo_args,
+            rustc_version: command_output("rustc", &["--version"]),
+            hostname: std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()
+                .map(|h| h.trim().to_string()),
+            cmdline: std::env::args().collect(),
+            env: std::env::vars().filter(|(k, _)| recorded(k)).collect(),
+            markers: Vec::new(),
+            seed: None,
+            stdout_hash: None,
+        }
+    }
+
+    pub fn write(&self, perf_data: &str) -> io::Result<()> {
+        let f = std::fs::File::create(meta_path(perf_data))?;
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+
+    /// reads the metadata next to perf.data if any
+    pub fn read(perf_data: &str) -> io::Result<Option<Self>> {
+        let f = match std::fs::File::open(meta_path(perf_data)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(serde_json::from_reader(io::BufReader::new(f))?))
+    }
+
+    /// one line per item to put into profile comments
+    pub fn comments(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        if let Some(sha) = self.git_sha.as_ref() {
+            v.push(format!("git: {}", sha));
+        }
+        if let Some(branch) = self.git_branch.as_ref() {
+            v.push(format!("git branch: {}", branch));
+        }
+        if let Some(dirty) = self.git_dirty {
+            v.push(format!("git dirty: {}", dirty));
+        }
+        if !self.cargo_args.is_empty() {
+            v.push(format!("cargo: {}", self.cargo_args.join(" ")));
+        }
+        if let Some(rustc) = self.rustc_version.as_ref() {
+            v.push(format!("rustc: {}", rustc));
+        }
+        if let Some(hostname) = self.hostname.as_ref() {
+            v.push(format!("host: {}", hostname));
+        }
+        if !self.cmdline.is_empty() {
+            v.push(format!("cmdline: {}", self.cmdline.join(" ")));
+        }
+        if let Some(seed) = self.seed {
+            v.push(format!("seed: {}", seed));
+        }
+        if let Some(hash) = self.stdout_hash.as_ref() {
+            v.push(format!("{}{}", STDOUT_HASH_COMMENT, hash));
+        }
+        // the metadata written before the allowlist might have more
+        for (k, val) in self.env.iter().filter(|(k, _)| recorded(k)) {
+            v.push(format!("env: {}={}", k, val));
+        }
+        v
+    }
+}
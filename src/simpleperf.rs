@@ -0,0 +1,91 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+
+use crate::pprof::{self, pb, SampleBuilder};
+
+// sample:
+//   event_type: cpu-clock
+//   time: 1234567890
+//   event_count: 1000000
+//   thread_id: 1234
+//   thread_name: foo
+//   vaddr_in_file: 1a2b
+//   file: /data/local/tmp/foo
+//   symbol: foo::main
+//   callchain:
+//     vaddr_in_file: 3c4d
+//     file: /apex/com.android.runtime/lib64/bionic/libc.so
+//     symbol: __libc_init
+#[derive(Default)]
+struct Sample {
+    event: String,
+    count: i64,
+    tid: String,
+    thread_name: String,
+    frames: Vec<String>,
+}
+
+/// Reads the output of `simpleperf report-sample --show-callchain`, the
+/// symbols demangled, with the `tid` and `thread_name` labels. The values are
+/// cpu nanoseconds for the clock events, or the event counts.
+pub fn decode_report_sample<R>(mut reader: R) -> io::Result<pb::Profile>
+where
+    R: io::BufRead,
+{
+    let mut samples = Vec::new();
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            break;
+        }
+        let line = buf.trim();
+        if line == "sample:" {
+            samples.push(Sample::default());
+            continue;
+        }
+        let (s, (key, value)) = match (samples.last_mut(), line.split_once(": ")) {
+            (Some(s), Some(kv)) => (s, kv),
+            _ => continue,
+        };
+        match key {
+            "event_type" => s.event = value.to_string(),
+            "event_count" => s.count = value.parse().unwrap_or(0),
+            "thread_id" => s.tid = value.to_string(),
+            "thread_name" => s.thread_name = value.to_string(),
+            // leaf first
+            "symbol" => s.frames.push(value.to_string()),
+            _ => {}
+        }
+    }
+
+    let event = samples.first().map_or("", |s| s.event.as_str());
+    let mut builder = if pprof::clock_event(event) {
+        SampleBuilder::new("cpu", "nanoseconds")
+    } else {
+        SampleBuilder::new(event, "count")
+    };
+    for s in samples.iter().filter(|s| s.event == event) {
+        let frames: Vec<&str> = s.frames.iter().map(|f| f.as_str()).collect();
+        builder.add(
+            &frames,
+            &[("tid", &s.tid), ("thread_name", &s.thread_name)],
+            s.count,
+        );
+    }
+    Ok(builder.build())
+}
@@ -0,0 +1,212 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A snapshot of the stacks of all the threads of a process, like jstack,
+//! without stopping it. The kernel stacks come from `/proc/<pid>/task/<tid>/stack`,
+//! and the user stacks from the program counter and the stack pointer of the
+//! threads blocked in syscalls (`/proc/<pid>/task/<tid>/syscall`): the stack
+//! is scanned for return addresses, the words pointing into executable
+//! mappings, so that no frame pointers are needed, though stale return
+//! addresses left on the stack may show up. Reading the memory of another
+//! process needs the same permission as ptrace.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use crate::pprof::{self, pb, SampleBuilder};
+use crate::symbolize::{self, Symbolizer};
+
+// the frames scanned on a stack at most
+const MAX_FRAMES: usize = 64;
+const MAX_SCAN: u64 = 256 * 1024;
+
+/// A thread's stack at the snapshot.
+pub struct ThreadStack {
+    pub tid: u32,
+    pub name: String,
+    /// leaf first, the kernel frames on top of the user ones
+    pub frames: Vec<String>,
+    /// not blocked in a syscall, so the user stack isn't known
+    pub running: bool,
+}
+
+// (start, end, executable) of the mappings
+fn mappings(maps: &str) -> Vec<(u64, u64, bool)> {
+    maps.lines()
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let perms = fields.next()?;
+            Some((
+                u64::from_str_radix(start, 16).ok()?,
+                u64::from_str_radix(end, 16).ok()?,
+                perms.contains('x'),
+            ))
+        })
+        .collect()
+}
+
+// [<0>] ep_poll+0x2a1/0x2c0
+fn kernel_frames(stack: &str) -> Vec<String> {
+    stack
+        .lines()
+        .filter_map(|l| l.split_once("] ").map(|(_, f)| f))
+        .map(|f| f.split('+').next().unwrap_or(f).to_string())
+        .collect()
+}
+
+// the stack pointer and the program counter of a thread in a syscall,
+// `<nr> <args>... <sp> <pc>`
+fn user_registers(syscall: &str) -> Option<(u64, u64)> {
+    let fields: Vec<&str> = syscall.split_whitespace().collect();
+    if fields.len() < 3 {
+        return None;
+    }
+    let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    Some((
+        hex(fields[fields.len() - 2])?,
+        hex(fields[fields.len() - 1])?,
+    ))
+}
+
+// the return addresses on the stack from sp, the words in executable mappings
+fn scan(mem: &std::fs::File, maps: &[(u64, u64, bool)], sp: u64) -> Vec<u64> {
+    let end = match maps.iter().find(|(s, e, _)| *s <= sp && sp < *e) {
+        Some((_, e, _)) => (*e).min(sp + MAX_SCAN),
+        None => return Vec::new(),
+    };
+    let mut buf = vec![0; (end - sp) as usize];
+    let mut mem = mem;
+    if mem.seek(SeekFrom::Start(sp)).is_err() || mem.read_exact(&mut buf).is_err() {
+        return Vec::new();
+    }
+    buf.chunks_exact(8)
+        .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
+        .filter(|addr| maps.iter().any(|(s, e, x)| *x && *s <= *addr && *addr < *e))
+        .take(MAX_FRAMES)
+        .collect()
+}
+
+/// Captures the stacks of the threads of the process.
+pub fn capture(pid: u32) -> io::Result<Vec<ThreadStack>> {
+    let proc = format!("/proc/{}", pid);
+    let maps = std::fs::read_to_string(format!("{}/maps", proc))?;
+    let mut symbolizer = Symbolizer::new(None);
+    for (file, mmap) in maps.lines().filter_map(symbolize::parse_proc_maps) {
+        symbolizer.add_mmap(file, mmap);
+    }
+    let mappings = mappings(&maps);
+    let mem = std::fs::File::open(format!("{}/mem", proc)).ok();
+    let mut symbolize = |addr: u64| match symbolizer.function(addr) {
+        Some((name, _)) => pprof::demangle(&name),
+        None => format!("{:x}", addr),
+    };
+
+    let mut tids: Vec<u32> = std::fs::read_dir(format!("{}/task", proc))?
+        .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    tids.sort_unstable();
+    let mut threads = Vec::new();
+    for tid in tids {
+        let task = format!("{}/task/{}", proc, tid);
+        let read = |f: &str| std::fs::read_to_string(format!("{}/{}", task, f)).unwrap_or_default();
+        // root only
+        let mut frames = kernel_frames(&read("stack"));
+        let registers = user_registers(&read("syscall"));
+        if let Some((sp, pc)) = registers {
+            frames.push(symbolize(pc));
+            if let Some(mem) = mem.as_ref() {
+                for ra in scan(mem, &mappings, sp) {
+                    // the call before the return address
+                    frames.push(symbolize(ra - 1));
+                }
+            }
+        }
+        threads.push(ThreadStack {
+            tid,
+            name: read("comm").trim().to_string(),
+            frames,
+            running: registers.is_none(),
+        });
+    }
+    Ok(threads)
+}
+
+/// the snapshot as a profile of a sample per thread, labeled `tid` and
+/// `thread_name`
+pub fn to_profile(threads: &[ThreadStack]) -> pb::Profile {
+    let mut builder = SampleBuilder::new("threads", "count");
+    for t in threads {
+        let frames: Vec<&str> = t.frames.iter().map(|f| f.as_str()).collect();
+        builder.add(
+            &frames,
+            &[("tid", &t.tid.to_string()), ("thread_name", &t.name)],
+            1,
+        );
+    }
+    builder.build()
+}
+
+/// The threads of the last snapshot whose stacks were the same in all the
+/// snapshots, blocked in a syscall: stuck or deadlocked, or idle workers
+/// waiting for work, which the frames tell apart.
+pub fn unchanged(snapshots: &[Vec<ThreadStack>]) -> Vec<&ThreadStack> {
+    let last = match snapshots.last() {
+        Some(last) => last,
+        None => return Vec::new(),
+    };
+    last.iter()
+        .filter(|t| !t.running && !t.frames.is_empty())
+        .filter(|t| {
+            snapshots.iter().all(|s| {
+                s.iter()
+                    .any(|o| o.tid == t.tid && !o.running && o.frames == t.frames)
+            })
+        })
+        .collect()
+}
+
+/// the stuck threads as a profile of the time they were seen stuck, labeled
+/// `tid` and `thread_name`
+pub fn stuck_profile(threads: &[&ThreadStack], window: Duration) -> pb::Profile {
+    let mut builder = SampleBuilder::new("stuck", "nanoseconds");
+    for t in threads {
+        let frames: Vec<&str> = t.frames.iter().map(|f| f.as_str()).collect();
+        builder.add(
+            &frames,
+            &[("tid", &t.tid.to_string()), ("thread_name", &t.name)],
+            window.as_nanos() as i64,
+        );
+    }
+    builder.build()
+}
+
+/// the snapshot as text, a thread after another
+pub fn to_text<'a, I>(threads: I) -> String
+where
+    I: IntoIterator<Item = &'a ThreadStack>,
+{
+    let mut s = String::new();
+    for t in threads {
+        let state = if t.running { " (running)" } else { "" };
+        let _ = writeln!(s, "\"{}\" tid={}{}", t.name, t.tid, state);
+        for f in &t.frames {
+            let _ = writeln!(s, "    {}", f);
+        }
+        s.push('\n');
+    }
+    s
+}
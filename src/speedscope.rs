@@ -0,0 +1,118 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Writes speedscope's evented profiles, a flame chart per thread in time
+//! order, to see the phases of a program at www.speedscope.app rather than
+//! only where the time adds up.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::jsonl::{self, Event};
+use crate::pprof::PprofConverterBuilder;
+use crate::trace::{self, Span};
+
+#[derive(Serialize)]
+struct Frame {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize)]
+struct FrameEvent {
+    r#type: &'static str,
+    frame: usize,
+    /// microseconds from the first sample
+    at: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    r#type: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: f64,
+    end_value: f64,
+    events: Vec<FrameEvent>,
+}
+
+#[derive(Serialize)]
+struct File {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    shared: Shared,
+    profiles: Vec<Profile>,
+    exporter: &'static str,
+}
+
+/// Converts `perf script` output into speedscope's format, an evented
+/// profile per thread.
+pub fn convert<R, W>(builder: &PprofConverterBuilder, reader: R, mut writer: W) -> io::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut threads: BTreeMap<(u32, u32), Vec<Event>> = BTreeMap::new();
+    let mut start = f64::INFINITY;
+    jsonl::read_events(builder, reader, |e| {
+        start = start.min(e.timestamp);
+        threads.entry((e.pid, e.tid)).or_default().push(e);
+        Ok(())
+    })?;
+
+    let mut frames = Vec::new();
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    let mut profiles = Vec::new();
+    for ((_, tid), samples) in &threads {
+        let mut events = Vec::new();
+        trace::flame_chart(samples, start, |span| {
+            let (r#type, name, at) = match span {
+                Span::Open(name, at) => ("O", name, at),
+                Span::Close(name, _, at) => ("C", name, at),
+            };
+            let frame = *ids.entry(name.to_string()).or_insert_with(|| {
+                frames.push(Frame {
+                    name: name.to_string(),
+                });
+                frames.len() - 1
+            });
+            events.push(FrameEvent { r#type, frame, at });
+        });
+        profiles.push(Profile {
+            r#type: "evented",
+            name: format!("{} {}", samples[0].comm, tid),
+            unit: "microseconds",
+            start_value: events.first().map_or(0.0, |e| e.at),
+            end_value: events.last().map_or(0.0, |e| e.at),
+            events,
+        });
+    }
+    serde_json::to_writer(
+        &mut writer,
+        &File {
+            schema: "https://www.speedscope.app/file-format-schema.json",
+            shared: Shared { frames },
+            profiles,
+            exporter: "perf-tools",
+        },
+    )?;
+    writer.flush()
+}
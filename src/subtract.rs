@@ -0,0 +1,81 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::pprof::{self, pb};
+
+fn sample_types(profile: &pb::Profile) -> Vec<(&str, &str)> {
+    let s = |i: i64| {
+        profile
+            .string_table
+            .get(i as usize)
+            .map_or("", |s| s.as_str())
+    };
+    profile
+        .sample_type
+        .iter()
+        .map(|t| (s(t.r#type), s(t.unit)))
+        .collect()
+}
+
+/// Subtracts the baseline, an idle recording of the same binary, from the
+/// profile per stack, leaving the marginal work. The baseline is scaled by the
+/// ratio of the durations, and the values don't go below zero.
+pub fn subtract(profile: &pb::Profile, baseline: &pb::Profile) -> io::Result<pb::Profile> {
+    if sample_types(profile) != sample_types(baseline) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the profiles have different sample types",
+        ));
+    }
+    let scale = if profile.duration_nanos > 0 && baseline.duration_nanos > 0 {
+        profile.duration_nanos as f64 / baseline.duration_nanos as f64
+    } else {
+        1.0
+    };
+
+    // the stack, leaf first, to the values left to subtract
+    let mut remaining: HashMap<Vec<&str>, Vec<f64>> = HashMap::new();
+    for (names, s) in pprof::stacks(baseline) {
+        let v = remaining
+            .entry(names)
+            .or_insert_with(|| vec![0.0; s.value.len()]);
+        for (r, value) in v.iter_mut().zip(&s.value) {
+            *r += *value as f64 * scale;
+        }
+    }
+
+    let mut result = profile.clone();
+    let stacks: Vec<Vec<&str>> = pprof::stacks(profile)
+        .into_iter()
+        .map(|(names, _)| names)
+        .collect();
+    for (sample, names) in result.sample.iter_mut().zip(stacks) {
+        if let Some(r) = remaining.get_mut(&names) {
+            for (value, r) in sample.value.iter_mut().zip(r.iter_mut()) {
+                let sub = (*value as f64).min(*r);
+                *value -= sub.round() as i64;
+                *r -= sub;
+            }
+        }
+    }
+    result.sample.retain(|s| s.value.iter().any(|v| *v > 0));
+    let comment = format!("baseline subtracted, scaled by {:.2}", scale);
+    result.comment.push(result.string_table.len() as i64);
+    result.string_table.push(comment);
+    Ok(result)
+}
@@ -0,0 +1,259 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use addr2line::gimli::{EndianRcSlice, RunTimeEndian};
+use addr2line::object::{self, Object, ObjectSegment};
+use addr2line::Context;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+lazy_static! {
+    // perf 1234 [000] 0.000000: PERF_RECORD_MMAP2 1234/1234: [0x55d0c0a00000(0x5000) @ 0x2000 fd:01 1234 0]: r-xp /usr/bin/foo
+    static ref MMAP_RE: Regex = Regex::new(
+        r"PERF_RECORD_MMAP2? .*\[0x([0-9a-f]+)\(0x([0-9a-f]+)\) @ (?:0x)?([0-9a-f]+)[^\]]*\]: \S+ (.+)$"
+    )
+    .unwrap();
+    // 55d0c0a00000-55d0c0a05000 r-xp 00002000 fd:01 1234 /usr/bin/foo
+    static ref MAPS_RE: Regex =
+        Regex::new(r"^([0-9a-f]+)-([0-9a-f]+) \S+ ([0-9a-f]+) \S+ \d+\s+(/.+)$").unwrap();
+}
+
+/// Where a file is mapped in memory.
+#[derive(Clone, Debug)]
+pub struct Mmap {
+    pub start: u64,
+    pub len: u64,
+    pub pgoff: u64,
+}
+
+/// parses a `perf script --show-mmap-events` line into the file name and the mapping
+pub fn parse_mmap(line: &str) -> Option<(String, Mmap)> {
+    let caps = MMAP_RE.captures(line)?;
+    let hex = |i| u64::from_str_radix(&caps[i], 16).ok();
+    Some((
+        caps[4].trim().to_string(),
+        Mmap {
+            start: hex(1)?,
+            len: hex(2)?,
+            pgoff: hex(3)?,
+        },
+    ))
+}
+
+/// parses a line of `/proc/<pid>/maps` into the file name and the mapping
+pub fn parse_proc_maps(line: &str) -> Option<(String, Mmap)> {
+    let caps = MAPS_RE.captures(line)?;
+    let hex = |i| u64::from_str_radix(&caps[i], 16).ok();
+    let start = hex(1)?;
+    Some((
+        caps[4].trim().to_string(),
+        Mmap {
+            start,
+            len: hex(2)?.checked_sub(start)?,
+            pgoff: hex(3)?,
+        },
+    ))
+}
+
+/// reads the kernel symbols, (address, name) sorted by address; empty if the file can't be read
+pub fn kallsyms(path: &str) -> Vec<(u64, String)> {
+    let mut symbols: Vec<(u64, String)> = std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| {
+            // ffffffff81000000 T _stext
+            let mut fields = l.split_whitespace();
+            let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let name = fields.nth(1)?;
+            Some((addr, name.to_string()))
+        })
+        .collect();
+    symbols.sort_by_key(|(addr, _)| *addr);
+    symbols
+}
+
+/// the kernel function at the address
+pub fn kernel_function(symbols: &[(u64, String)], addr: u64) -> Option<&str> {
+    let i = symbols.partition_point(|(a, _)| *a <= addr);
+    symbols
+        .get(i.checked_sub(1)?)
+        .map(|(_, name)| name.as_str())
+}
+
+struct Binary {
+    context: Option<Context<EndianRcSlice<RunTimeEndian>>>,
+    // (file offset, file size, address)
+    segments: Vec<(u64, u64, u64)>,
+    // (address, name) sorted by address
+    symbols: Vec<(u64, String)>,
+}
+
+impl Binary {
+    fn open(path: &Path) -> Option<Binary> {
+        let data = std::fs::read(path).ok()?;
+        let file = object::File::parse(data.as_slice()).ok()?;
+        let segments = file
+            .segments()
+            .map(|s| {
+                let (offset, size) = s.file_range();
+                (offset, size, s.address())
+            })
+            .collect();
+        let mut symbols: Vec<(u64, String)> = file
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|s| (s.address(), s.name().to_string()))
+            .collect();
+        symbols.sort_by_key(|(address, _)| *address);
+        // stripped binaries still have the symbols
+        let context = Context::new(&file).ok();
+        Some(Binary {
+            context,
+            segments,
+            symbols,
+        })
+    }
+
+    // a file offset to the address in the DWARF info
+    fn address(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(o, size, _)| *o <= offset && offset < o + size)
+            .map(|(o, _, address)| offset - o + address)
+    }
+}
+
+/// A function at an address, inlined or not.
+pub struct Frame {
+    /// the mangled name
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: i64,
+}
+
+/// Resolves runtime addresses to source lines with the DWARF info of the
+/// binaries, and to functions with the symbol tables.
+#[derive(Default)]
+pub struct Symbolizer {
+    symfs: Option<String>,
+    mmaps: HashMap<String, Vec<Mmap>>,
+    binaries: HashMap<String, Option<Binary>>,
+}
+
+impl Symbolizer {
+    /// looks for the binaries under `symfs` if any, like `perf script --symfs`
+    pub fn new(symfs: Option<&str>) -> Self {
+        Symbolizer {
+            symfs: symfs.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// the symbolizer of a process forked from this one, with the same mmaps
+    pub fn fork(&self) -> Self {
+        Symbolizer {
+            symfs: self.symfs.clone(),
+            mmaps: self.mmaps.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_mmap(&mut self, file: String, mmap: Mmap) {
+        self.mmaps.entry(file).or_default().push(mmap);
+    }
+
+    // the binary mapped at the address, and the address in the binary
+    fn binary(&mut self, file: &str, addr: u64) -> Option<(&Binary, u64)> {
+        let mmap = self
+            .mmaps
+            .get(file)?
+            .iter()
+            .find(|m| m.start <= addr && addr < m.start + m.len)?;
+        let offset = addr - mmap.start + mmap.pgoff;
+        let binary = self.open(file)?;
+        let address = binary.address(offset)?;
+        Some((binary, address))
+    }
+
+    // the binary of the file, opened once
+    fn open(&mut self, file: &str) -> Option<&Binary> {
+        let symfs = self.symfs.as_deref();
+        self.binaries
+            .entry(file.to_string())
+            .or_insert_with(|| match symfs {
+                Some(root) => Binary::open(&Path::new(root).join(file.trim_start_matches('/'))),
+                None => Binary::open(Path::new(file)),
+            })
+            .as_ref()
+    }
+
+    /// the mangled name of the function at the address the binary is linked
+    /// at, like firmware running where it's loaded; the Thumb bit of the
+    /// symbols is ignored
+    pub fn symbol(&mut self, file: &str, address: u64) -> Option<String> {
+        let binary = self.open(file)?;
+        let i = binary.symbols.partition_point(|(a, _)| *a & !1 <= address);
+        Some(binary.symbols.get(i.checked_sub(1)?)?.1.clone())
+    }
+
+    /// the file name and the line of the address in the file mapped
+    pub fn locate(&mut self, file: &str, addr: u64) -> Option<(String, i64)> {
+        let (binary, address) = self.binary(file, addr)?;
+        let location = binary.context.as_ref()?.find_location(address).ok()??;
+        Some((location.file?.to_string(), location.line? as i64))
+    }
+
+    /// the functions inlined at the address in the file mapped and the
+    /// function they're inlined into, innermost first
+    pub fn frames(&mut self, file: &str, addr: u64) -> Option<Vec<Frame>> {
+        let (binary, address) = self.binary(file, addr)?;
+        let mut iter = binary.context.as_ref()?.find_frames(address).ok()?;
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = iter.next() {
+            let function = frame
+                .function
+                .as_ref()
+                .and_then(|f| f.raw_name().ok())
+                .map(|n| n.to_string());
+            let (file, line) = match frame.location {
+                Some(l) => (l.file.map(|f| f.to_string()), l.line.unwrap_or(0) as i64),
+                None => (None, 0),
+            };
+            frames.push(Frame {
+                function,
+                file,
+                line,
+            });
+        }
+        Some(frames)
+    }
+
+    /// the mangled name of the function at the address, and the file mapped there
+    pub fn function(&mut self, addr: u64) -> Option<(String, String)> {
+        let file = self
+            .mmaps
+            .iter()
+            .find(|(_, v)| v.iter().any(|m| m.start <= addr && addr < m.start + m.len))?
+            .0
+            .clone();
+        let (binary, address) = self.binary(&file, addr)?;
+        let i = binary.symbols.partition_point(|(a, _)| *a <= address);
+        let name = binary.symbols.get(i.checked_sub(1)?)?.1.clone();
+        Some((name, file))
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+
+use crate::flat::FlatProfile;
+use crate::pprof::{self, pb};
+use crate::unit::Unit;
+
+/// Rows of a profile to load into DuckDB, pandas and the like.
+#[derive(Default)]
+pub struct Table {
+    pub string_columns: Vec<(String, Vec<String>)>,
+    pub int_columns: Vec<(String, Vec<i64>)>,
+}
+
+fn string(profile: &pb::Profile, i: i64) -> &str {
+    profile
+        .string_table
+        .get(i as usize)
+        .map_or("", |s| s.as_str())
+}
+
+fn sample_types(profile: &pb::Profile) -> Vec<String> {
+    profile
+        .sample_type
+        .iter()
+        .map(|t| string(profile, t.r#type).to_string())
+        .collect()
+}
+
+impl Table {
+    /// one row per sample: the leaf, the stack root first, the labels, the
+    /// values, and a `label_<key>` column per label key
+    pub fn stacks(profile: &pb::Profile) -> Self {
+        let types = sample_types(profile);
+        let mut leaf = Vec::new();
+        let mut stack = Vec::new();
+        let mut labels = Vec::new();
+        let mut values = vec![Vec::new(); types.len()];
+        // label key, value per sample
+        let mut label_columns: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for s in &profile.sample {
+            for l in &s.label {
+                label_columns.entry(string(profile, l.key)).or_default();
+            }
+        }
+        for (names, s) in pprof::stacks(profile) {
+            leaf.push(names.first().copied().unwrap_or("").to_string());
+            stack.push(names.iter().rev().copied().collect::<Vec<&str>>().join(";"));
+            labels.push(
+                s.label
+                    .iter()
+                    .map(|l| {
+                        if l.str != 0 {
+                            format!("{}={}", string(profile, l.key), string(profile, l.str))
+                        } else {
+                            format!("{}={}", string(profile, l.key), l.num)
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(";"),
+            );
+            for (i, v) in values.iter_mut().enumerate() {
+                v.push(s.value.get(i).copied().unwrap_or(0));
+            }
+            for (key, column) in label_columns.iter_mut() {
+                let value = s
+                    .label
+                    .iter()
+                    .find(|l| string(profile, l.key) == *key)
+                    .map(|l| {
+                        if l.str != 0 {
+                            string(profile, l.str).to_string()
+                        } else {
+                            l.num.to_string()
+                        }
+                    });
+                column.push(value.unwrap_or_default());
+            }
+        }
+        let mut string_columns = vec![
+            ("leaf".to_string(), leaf),
+            ("stack".to_string(), stack),
+            ("labels".to_string(), labels),
+        ];
+        string_columns.extend(
+            label_columns
+                .into_iter()
+                .map(|(key, column)| (format!("label_{}", key), column)),
+        );
+        Table {
+            string_columns,
+            int_columns: types.into_iter().zip(values).collect(),
+        }
+    }
+
+    /// Converts the columns of the sample types in nanoseconds to the time
+    /// `unit`, rounded, and suffixes their names with it, like `cpu_ms`.
+    pub fn time_unit(&mut self, profile: &pb::Profile, unit: Unit) {
+        let (nanos, suffix) = match unit {
+            Unit::Nanoseconds => (1, "ns"),
+            Unit::Microseconds => (1_000, "us"),
+            Unit::Milliseconds => (1_000_000, "ms"),
+            _ => return,
+        };
+        let times: Vec<&str> = profile
+            .sample_type
+            .iter()
+            .filter(|t| string(profile, t.unit) == "nanoseconds")
+            .map(|t| string(profile, t.r#type))
+            .collect();
+        for (name, column) in self.int_columns.iter_mut() {
+            let time = times.iter().any(|t| {
+                name == t || *name == format!("self_{}", t) || *name == format!("total_{}", t)
+            });
+            if time {
+                for v in column.iter_mut() {
+                    *v = (*v + nanos / 2) / nanos;
+                }
+                *name = format!("{}_{}", name, suffix);
+            }
+        }
+    }
+
+    /// one row per function with the self and total of each value
+    pub fn functions(profile: &pb::Profile) -> Self {
+        let types = sample_types(profile);
+        let flats: Vec<FlatProfile> = (0..types.len())
+            .map(|i| FlatProfile::new(profile, i))
+            .collect();
+        let mut names: Vec<String> = flats
+            .first()
+            .map(|f| f.functions.keys().cloned().collect())
+            .unwrap_or_default();
+        names.sort();
+        let mut int_columns = Vec::new();
+        for (t, flat) in types.iter().zip(&flats) {
+            let values = |total: bool| {
+                names
+                    .iter()
+                    .map(|n| {
+                        flat.functions
+                            .get(n)
+                            .map_or(0, |(s, t)| if total { *t } else { *s })
+                    })
+                    .collect()
+            };
+            int_columns.push((format!("self_{}", t), values(false)));
+            int_columns.push((format!("total_{}", t), values(true)));
+        }
+        Table {
+            string_columns: vec![("function".to_string(), names)],
+            int_columns,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.string_columns
+            .first()
+            .map(|(_, v)| v.len())
+            .or_else(|| self.int_columns.first().map(|(_, v)| v.len()))
+            .unwrap_or(0)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+        let header: Vec<String> = self
+            .string_columns
+            .iter()
+            .map(|(name, _)| quote(name))
+            .chain(self.int_columns.iter().map(|(name, _)| quote(name)))
+            .collect();
+        let mut s = header.join(",");
+        s.push('\n');
+        for i in 0..self.rows() {
+            let row: Vec<String> = self
+                .string_columns
+                .iter()
+                .map(|(_, v)| quote(&v[i]))
+                .chain(self.int_columns.iter().map(|(_, v)| v[i].to_string()))
+                .collect();
+            let _ = writeln!(s, "{}", row.join(","));
+        }
+        s
+    }
+
+    /// writes the table in Parquet
+    #[cfg(feature = "parquet")]
+    pub fn write_parquet(&self, file: std::fs::File) -> io::Result<()> {
+        use arrow::array::{ArrayRef, Int64Array, StringArray};
+        use arrow::record_batch::RecordBatch;
+        use std::sync::Arc;
+
+        let other =
+            |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::Other, format!("{}", e));
+        let columns: Vec<(String, ArrayRef)> = self
+            .string_columns
+            .iter()
+            .map(|(name, v)| {
+                let array: ArrayRef = Arc::new(StringArray::from_iter_values(v.iter()));
+                (name.clone(), array)
+            })
+            .chain(self.int_columns.iter().map(|(name, v)| {
+                let array: ArrayRef = Arc::new(Int64Array::from(v.clone()));
+                (name.clone(), array)
+            }))
+            .collect();
+        let batch = RecordBatch::try_from_iter(columns).map_err(|e| other(&e))?;
+        let mut writer = parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None)
+            .map_err(|e| other(&e))?;
+        writer.write(&batch).map_err(|e| other(&e))?;
+        writer.close().map_err(|e| other(&e))?;
+        Ok(())
+    }
+
+    /// writes the table in Parquet
+    #[cfg(not(feature = "parquet"))]
+    pub fn write_parquet(&self, _file: std::fs::File) -> io::Result<()> {
+        Err(io::Error::other("built without the parquet feature"))
+    }
+}
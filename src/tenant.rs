@@ -0,0 +1,145 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Attributes the samples of a system-wide recording on shared workers to
+//! tenants, by the pid of the process or by the cgroup it runs in, from a
+//! mapping file like:
+//!
+//! ```toml
+//! [pids]
+//! 1234 = "acme"
+//!
+//! [cgroups]
+//! "/system.slice/acme.service" = "acme"
+//! "/kubepods.slice/kubepods-pod0123.slice" = "globex"
+//! ```
+//!
+//! The cgroups of the processes are read from `/proc` while converting, so
+//! processes that exited by then are attributed by pid only.
+
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    pids: HashMap<String, String>,
+    #[serde(default)]
+    cgroups: HashMap<String, String>,
+}
+
+// the pid if perf script printed it, and the tid
+type Thread = (Option<u64>, u64);
+
+/// The tenants of the processes and of the cgroups.
+#[derive(Clone, Debug, Default)]
+pub struct Tenants {
+    pids: HashMap<u64, String>,
+    // the paths without the trailing slash, the longest first
+    cgroups: Vec<(String, String)>,
+    // a tid reused by another process has another pid
+    cache: RefCell<HashMap<Thread, Option<String>>>,
+}
+
+impl Tenants {
+    /// reads the mapping of pids and cgroups to tenants
+    pub fn parse(s: &str) -> io::Result<Tenants> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let config: Config = toml::from_str(s).map_err(|e| invalid(format!("{}", e)))?;
+        let pids = config
+            .pids
+            .into_iter()
+            .map(|(pid, tenant)| {
+                let pid = pid
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid pid: {}", pid)))?;
+                Ok((pid, tenant))
+            })
+            .collect::<io::Result<_>>()?;
+        let mut cgroups: Vec<(String, String)> = config
+            .cgroups
+            .into_iter()
+            .map(|(path, tenant)| (path.trim_end_matches('/').to_string(), tenant))
+            .collect();
+        cgroups.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Ok(Tenants {
+            pids,
+            cgroups,
+            cache: RefCell::default(),
+        })
+    }
+
+    /// reads the mapping from a file
+    pub fn load(path: &str) -> io::Result<Tenants> {
+        Tenants::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// the tenant of the thread by the pid of its process, or else by the
+    /// cgroup of the process, the most specific one mapped; perf script
+    /// prints the pid with `-F +pid` only, so it's read from `/proc`
+    /// without it
+    pub fn tenant(&self, pid: Option<u64>, tid: u64) -> Option<String> {
+        self.cache
+            .borrow_mut()
+            .entry((pid, tid))
+            .or_insert_with(|| {
+                let pid = pid.or_else(|| tgid(tid)).unwrap_or(tid);
+                if let Some(t) = self.pids.get(&pid) {
+                    return Some(t.clone());
+                }
+                let cgroup = cgroup(pid)?;
+                self.cgroups
+                    .iter()
+                    .find(|(path, _)| {
+                        cgroup == *path
+                            || (cgroup.starts_with(path.as_str())
+                                && cgroup[path.len()..].starts_with('/'))
+                    })
+                    .map(|(_, t)| t.clone())
+            })
+            .clone()
+    }
+}
+
+// the process of the thread
+fn tgid(tid: u64) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("Tgid:"))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+// the cgroup v2 path of the process, `0::/system.slice/foo.service`, or the
+// first v1 one
+fn cgroup(pid: u64) -> Option<String> {
+    let s = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let paths: Vec<(&str, &str)> = s
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.splitn(3, ':');
+            let id = fields.next()?;
+            fields.next()?;
+            Some((id, fields.next()?))
+        })
+        .collect();
+    paths
+        .iter()
+        .find(|(id, _)| *id == "0")
+        .or_else(|| paths.first())
+        .map(|(_, path)| path.trim_end_matches('/').to_string())
+}
@@ -0,0 +1,228 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::pprof;
+
+lazy_static! {
+    // tokio-runtime-w 1234/1235 [001] 5678.123456: 250000 cpu-clock:u:
+    static ref EVENT_RE: Regex =
+        Regex::new(r"^(.+?)\s+(\d+)(?:/(\d+))?\s+(?:\[\d+\]\s+)?(\d+)\.(\d+):").unwrap();
+    // sched:sched_switch: prev_comm=foo prev_pid=123 prev_prio=120 prev_state=S ==> next_comm=bar next_pid=456 next_prio=120
+    static ref SWITCH_RE: Regex =
+        Regex::new(r"sched:sched_switch:.*prev_pid=(\d+).*==> next_comm=(.+?) next_pid=(\d+)")
+            .unwrap();
+    // sample_freq } = 997
+    static ref FREQ_RE: Regex = Regex::new(r"sample_freq\s+}\s+=\s+(\d+)").unwrap();
+}
+
+/// Busy time of each thread over time, from `perf script --header` output.
+///
+/// With `sched:sched_switch` events in the recording, a thread is busy while
+/// it's on a CPU; otherwise busy time is estimated from the cpu samples.
+pub struct Timeline {
+    /// bucket width in microseconds
+    interval: u64,
+    freq: u64,
+    start: Option<u64>,
+    /// tid, thread name
+    names: HashMap<u32, String>,
+    /// tid, bucket, busy microseconds
+    busy: HashMap<u32, BTreeMap<u64, u64>>,
+    /// tid, time switched in
+    on_cpu: HashMap<u32, u64>,
+    sched: bool,
+}
+
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    ph: &'static str,
+    /// microseconds
+    ts: u64,
+    pid: u32,
+    tid: u32,
+    args: BTreeMap<&'static str, f64>,
+}
+
+/// the name of the pool a thread belongs to, `rayon-worker-3` to `rayon-worker`
+pub fn pool_name(thread: &str) -> &str {
+    thread.trim_end_matches(|c: char| c.is_ascii_digit() || c == '-' || c == '#' || c == '_')
+}
+
+// the values of the buckets, with a 0 after each run of busy buckets; a
+// counter holds its value until the next event, so without it an idle
+// thread would stay busy until it ran again
+fn with_idle(buckets: impl Iterator<Item = (u64, f64)>) -> Vec<(u64, f64)> {
+    let mut values: Vec<(u64, f64)> = Vec::new();
+    for (bucket, value) in buckets {
+        if let Some((last, _)) = values.last() {
+            if *last + 1 < bucket {
+                values.push((last + 1, 0.0));
+            }
+        }
+        values.push((bucket, value));
+    }
+    if let Some((last, _)) = values.last() {
+        values.push((last + 1, 0.0));
+    }
+    values
+}
+
+impl Timeline {
+    pub fn new(interval_usec: u64) -> Self {
+        Timeline {
+            interval: interval_usec.max(1),
+            freq: 0,
+            start: None,
+            names: HashMap::new(),
+            busy: HashMap::new(),
+            on_cpu: HashMap::new(),
+            sched: false,
+        }
+    }
+
+    // adds the busy time from `from` to `to` into the buckets
+    fn add_busy(&mut self, tid: u32, from: u64, to: u64) {
+        let interval = self.interval;
+        let buckets = self.busy.entry(tid).or_default();
+        let mut t = from;
+        while t < to {
+            let bucket = t / interval;
+            let end = ((bucket + 1) * interval).min(to);
+            *buckets.entry(bucket).or_insert(0) += end - t;
+            t = end;
+        }
+    }
+
+    /// adds the events in `perf script --header` output
+    pub fn update<R>(&mut self, mut reader: R) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        let mut buf = Vec::new();
+        // samples before the frequency is known
+        let mut samples: Vec<(u32, u64)> = Vec::new();
+
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+            let line = pprof::escape_invalid(&buf);
+            if line.starts_with('#') {
+                if let Some(caps) = FREQ_RE.captures(&line) {
+                    self.freq = caps[1].parse().unwrap_or(0);
+                }
+                continue;
+            }
+            // every event line starts an event, since the samples have
+            // neither frames nor a blank line after them without `-g`
+            let line = line.trim();
+            let caps = match EVENT_RE.captures(line) {
+                Some(caps) => caps,
+                None => continue,
+            };
+            let tid: u32 = caps
+                .get(3)
+                .unwrap_or_else(|| caps.get(2).unwrap())
+                .as_str()
+                .parse()
+                .unwrap_or(0);
+            let time = pprof::timestamp_nanos(&caps[4], &caps[5]).unwrap_or(0) / 1000;
+            self.start.get_or_insert(time);
+
+            if let Some(switch) = SWITCH_RE.captures(line) {
+                self.sched = true;
+                let prev: u32 = switch[1].parse().unwrap_or(0);
+                let next: u32 = switch[3].parse().unwrap_or(0);
+                if let Some(since) = self.on_cpu.remove(&prev) {
+                    self.add_busy(prev, since, time);
+                }
+                // the idle task
+                if next != 0 {
+                    self.names
+                        .entry(next)
+                        .or_insert_with(|| switch[2].to_string());
+                    self.on_cpu.insert(next, time);
+                }
+                continue;
+            }
+            self.names
+                .entry(tid)
+                .or_insert_with(|| caps[1].trim().to_string());
+            samples.push((tid, time));
+        }
+
+        // switch events are exact; samples are only an estimate
+        if !self.sched {
+            let period = 1_000_000 / self.freq.max(1);
+            for (tid, time) in samples {
+                self.add_busy(tid, time, time + period);
+            }
+        }
+        Ok(())
+    }
+
+    /// Chrome trace counters of the busy fraction of each thread and of the
+    /// busy threads of each pool, to open in chrome://tracing or Perfetto.
+    pub fn chrome_trace(&self) -> io::Result<String> {
+        let start = self.start.unwrap_or(0) / self.interval;
+        let mut events = Vec::new();
+        let mut pools: BTreeMap<&str, BTreeMap<u64, f64>> = BTreeMap::new();
+        let mut tids: Vec<&u32> = self.busy.keys().collect();
+        tids.sort();
+        for tid in tids {
+            let name = self.names.get(tid).map_or("", |n| n.as_str());
+            let pool = pools.entry(pool_name(name)).or_default();
+            let buckets: Vec<(u64, f64)> = self.busy[tid]
+                .iter()
+                .map(|(bucket, usec)| (*bucket, (*usec as f64 / self.interval as f64).min(1.0)))
+                .collect();
+            for (bucket, busy) in &buckets {
+                *pool.entry(*bucket).or_insert(0.0) += busy;
+            }
+            for (bucket, busy) in with_idle(buckets.into_iter()) {
+                events.push(TraceEvent {
+                    name: format!("{} {}", name, tid),
+                    ph: "C",
+                    ts: (bucket - start.min(bucket)) * self.interval,
+                    pid: 0,
+                    tid: *tid,
+                    args: std::iter::once(("busy", busy)).collect(),
+                });
+            }
+        }
+        for (pool, buckets) in pools {
+            for (bucket, busy) in with_idle(buckets.into_iter()) {
+                events.push(TraceEvent {
+                    name: format!("{} busy threads", pool),
+                    ph: "C",
+                    ts: (bucket - start.min(bucket)) * self.interval,
+                    pid: 0,
+                    tid: 0,
+                    args: std::iter::once(("threads", busy)).collect(),
+                });
+            }
+        }
+        events.sort_by_key(|e| e.ts);
+        Ok(serde_json::to_string(&events)?)
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+
+use crate::diff::strip_hash;
+use crate::pprof::PprofConverterBuilder;
+
+/// Hottest functions in `perf script` output, demangled.
+#[derive(Default)]
+pub struct Top {
+    total: u64,
+    self_count: HashMap<String, u64>,
+    total_count: HashMap<String, u64>,
+}
+
+impl Top {
+    /// adds the samples in `perf script` output
+    pub fn update<R>(&mut self, reader: R) -> io::Result<()>
+    where
+        R: io::BufRead,
+    {
+        PprofConverterBuilder::default()
+            .demangle(true)
+            .read_samples(reader, |sample| {
+                self.total += 1;
+                // without the hashes of legacy symbols, `app::main::h0123456789abcdef`
+                let functions: Vec<String> =
+                    sample.functions.iter().map(|f| strip_hash(f)).collect();
+                if let Some(leaf) = functions.first() {
+                    *self.self_count.entry(leaf.clone()).or_insert(0) += 1;
+                }
+                // count recursive functions once per stack
+                let seen: HashSet<&String> = functions.iter().collect();
+                for func in seen {
+                    *self.total_count.entry(func.clone()).or_insert(0) += 1;
+                }
+                Ok(())
+            })
+    }
+
+    /// the number of samples
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// functions sorted by self samples with their self and total samples
+    pub fn hottest(&self, n: usize) -> Vec<(&str, u64, u64)> {
+        let mut v: Vec<(&str, u64, u64)> = self
+            .self_count
+            .iter()
+            .map(|(k, v)| (k.as_str(), *v, self.total_count[k]))
+            .collect();
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(b.0)));
+        v.truncate(n);
+        v
+    }
+
+    /// renders the hottest `n` functions as a text table
+    pub fn render(&self, n: usize) -> String {
+        let mut s = format!("{:>8} {:>8}  {}\n", "Self%", "Total%", "Function");
+        let total = self.total.max(1) as f64;
+        for (func, self_count, total_count) in self.hottest(n) {
+            let _ = writeln!(
+                s,
+                "{:>7.2}% {:>7.2}%  {}",
+                self_count as f64 * 100.0 / total,
+                total_count as f64 * 100.0 / total,
+                func
+            );
+        }
+        s
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::jsonl::{self, Event};
+use crate::pprof::PprofConverterBuilder;
+
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    ph: &'static str,
+    /// microseconds from the first sample
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: u32,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    args: BTreeMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct Trace {
+    #[serde(rename = "traceEvents")]
+    trace_events: Vec<TraceEvent>,
+}
+
+/// A frame of a flame chart entered or left, in microseconds.
+pub(crate) enum Span<'a> {
+    Open(&'a str, f64),
+    /// with the time it was entered
+    Close(&'a str, f64, f64),
+}
+
+// the flame chart of a thread in time order: a frame spans the samples in a
+// row having it at the same depth, and a gap longer than two sampling
+// intervals ends them; the frames left are closed innermost first
+pub(crate) fn flame_chart<'a, F>(samples: &'a [Event], start: f64, mut f: F)
+where
+    F: FnMut(Span<'a>),
+{
+    let usec = |t: f64| (t - start) * 1_000_000.0;
+    // the shortest gap between the samples, the sampling interval
+    let interval = samples
+        .windows(2)
+        .map(|w| usec(w[1].timestamp) - usec(w[0].timestamp))
+        .filter(|d| *d > 0.0)
+        .fold(f64::INFINITY, f64::min);
+    let interval = if interval.is_finite() {
+        interval
+    } else {
+        1000.0
+    };
+
+    // root first, and when they began
+    let mut open: Vec<(&str, f64)> = Vec::new();
+    let close = |open: &mut Vec<(&'a str, f64)>, depth: usize, end: f64, f: &mut F| {
+        while open.len() > depth {
+            let (name, begin) = open.pop().unwrap();
+            f(Span::Close(name, begin, end));
+        }
+    };
+    let mut last = 0.0;
+    for s in samples {
+        let ts = usec(s.timestamp);
+        if !open.is_empty() && ts - last > interval * 2.0 {
+            close(&mut open, 0, last + interval, &mut f);
+        }
+        let frames: Vec<&str> = s.stack.iter().rev().map(|f| f.as_str()).collect();
+        let common = open
+            .iter()
+            .zip(&frames)
+            .take_while(|((o, _), f)| o == *f)
+            .count();
+        close(&mut open, common, ts, &mut f);
+        for name in &frames[common..] {
+            f(Span::Open(name, ts));
+            open.push((name, ts));
+        }
+        last = ts;
+    }
+    close(&mut open, 0, last + interval, &mut f);
+}
+
+/// Converts `perf script` output into a Chrome trace of a flame chart per
+/// thread at the times of the samples, to open in chrome://tracing or
+/// ui.perfetto.dev.
+pub fn convert<R, W>(builder: &PprofConverterBuilder, reader: R, mut writer: W) -> io::Result<()>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut threads: BTreeMap<(u32, u32), Vec<Event>> = BTreeMap::new();
+    let mut start = f64::INFINITY;
+    jsonl::read_events(builder, reader, |e| {
+        start = start.min(e.timestamp);
+        threads.entry((e.pid, e.tid)).or_default().push(e);
+        Ok(())
+    })?;
+
+    let mut events = Vec::new();
+    for ((pid, tid), samples) in &threads {
+        events.push(TraceEvent {
+            name: "thread_name".to_string(),
+            ph: "M",
+            ts: 0.0,
+            dur: None,
+            pid: *pid,
+            tid: *tid,
+            args: std::iter::once(("name", samples[0].comm.clone())).collect(),
+        });
+        flame_chart(samples, start, |span| {
+            if let Span::Close(name, begin, end) = span {
+                events.push(TraceEvent {
+                    name: name.to_string(),
+                    ph: "X",
+                    ts: begin,
+                    dur: Some(end - begin),
+                    pid: *pid,
+                    tid: *tid,
+                    args: BTreeMap::new(),
+                });
+            }
+        });
+    }
+    serde_json::to_writer(
+        &mut writer,
+        &Trace {
+            trace_events: events,
+        },
+    )?;
+    writer.flush()
+}
@@ -0,0 +1,182 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use crate::flat::FlatProfile;
+use crate::html::escape;
+use crate::pprof;
+
+const COLORS: [&str; 8] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+];
+
+/// One profile in the series.
+#[derive(Debug, Serialize)]
+pub struct Point {
+    pub file: String,
+    pub time_nanos: i64,
+    /// function name, self share in percent
+    pub shares: HashMap<String, f64>,
+}
+
+/// Per-function share over a series of profiles.
+#[derive(Debug, Serialize)]
+pub struct Trend {
+    /// the hottest functions over the series
+    pub functions: Vec<String>,
+    /// sorted by time
+    pub points: Vec<Point>,
+}
+
+impl Trend {
+    /// loads the profiles in the directory and tracks the hottest `n` functions
+    pub fn load<P: AsRef<Path>>(dir: P, n: usize) -> io::Result<Self> {
+        let mut points = Vec::new();
+        let mut peak: HashMap<String, f64> = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.to_string_lossy().to_string();
+            if !(name.ends_with(".pprof") || name.ends_with(".pb.gz")) {
+                continue;
+            }
+            let profile = pprof::decode(io::BufReader::new(std::fs::File::open(&path)?))?;
+            let flat = FlatProfile::new(&profile, 0);
+            let shares: HashMap<String, f64> = flat
+                .functions
+                .keys()
+                .map(|f| (f.clone(), flat.share(f).0 * 100.0))
+                .collect();
+            for (f, share) in &shares {
+                let p = peak.entry(f.clone()).or_insert(0.0);
+                *p = p.max(*share);
+            }
+            points.push(Point {
+                file: name,
+                time_nanos: profile.time_nanos,
+                shares,
+            });
+        }
+        points.sort_by_key(|p| p.time_nanos);
+
+        let mut functions: Vec<(String, f64)> = peak.into_iter().collect();
+        functions.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.0.cmp(&b.0))
+        });
+        functions.truncate(n);
+        let functions: Vec<String> = functions.into_iter().map(|(f, _)| f).collect();
+        // keep only the tracked functions
+        for p in points.iter_mut() {
+            p.shares.retain(|f, _| functions.contains(f));
+        }
+        Ok(Trend { functions, points })
+    }
+
+    fn share(&self, point: &Point, function: &str) -> f64 {
+        point.shares.get(function).copied().unwrap_or(0.0)
+    }
+
+    /// one row per profile, one column per function
+    pub fn to_csv(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+        let mut s = String::from("time_nanos,file");
+        for f in &self.functions {
+            s.push(',');
+            s.push_str(&quote(f));
+        }
+        s.push('\n');
+        for p in &self.points {
+            let _ = write!(s, "{},{}", p.time_nanos, quote(&p.file));
+            for f in &self.functions {
+                let _ = write!(s, ",{:.4}", self.share(p, f));
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    pub fn to_json(&self) -> io::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// renders a line chart of the shares
+    pub fn to_svg(&self) -> String {
+        const WIDTH: f64 = 800.0;
+        const HEIGHT: f64 = 400.0;
+        const MARGIN: f64 = 40.0;
+        const LEGEND: f64 = 16.0;
+
+        let max = self
+            .points
+            .iter()
+            .flat_map(|p| p.shares.values())
+            .fold(1.0_f64, |a, b| a.max(*b));
+        let step = (WIDTH - 2.0 * MARGIN) / (self.points.len().max(2) - 1) as f64;
+        let height = HEIGHT + LEGEND * self.functions.len() as f64;
+
+        let mut s = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\" font-size=\"12\">\n",
+            WIDTH, height
+        );
+        let _ = writeln!(
+            s,
+            "<line x1=\"{m}\" y1=\"{b}\" x2=\"{r}\" y2=\"{b}\" stroke=\"black\"/>\n<line x1=\"{m}\" y1=\"{m}\" x2=\"{m}\" y2=\"{b}\" stroke=\"black\"/>",
+            m = MARGIN,
+            b = HEIGHT - MARGIN,
+            r = WIDTH - MARGIN
+        );
+        let _ = writeln!(
+            s,
+            "<text x=\"2\" y=\"{}\">{:.1}%</text>\n<text x=\"2\" y=\"{}\">0%</text>",
+            MARGIN,
+            max,
+            HEIGHT - MARGIN
+        );
+        for (i, f) in self.functions.iter().enumerate() {
+            let color = COLORS[i % COLORS.len()];
+            let points: Vec<String> = self
+                .points
+                .iter()
+                .enumerate()
+                .map(|(x, p)| {
+                    let y = HEIGHT - MARGIN - self.share(p, f) / max * (HEIGHT - 2.0 * MARGIN);
+                    format!("{:.1},{:.1}", MARGIN + x as f64 * step, y)
+                })
+                .collect();
+            let _ = writeln!(
+                s,
+                "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"2\" points=\"{}\"/>",
+                color,
+                points.join(" ")
+            );
+            let _ = writeln!(
+                s,
+                "<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>",
+                MARGIN,
+                HEIGHT + LEGEND * i as f64,
+                color,
+                escape(f)
+            );
+        }
+        s.push_str("</svg>\n");
+        s
+    }
+}
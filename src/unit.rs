@@ -0,0 +1,87 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+
+use crate::pprof::pb;
+
+/// How the reports show the values of a profile.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Unit {
+    /// of the total
+    #[default]
+    Percent,
+    Samples,
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+}
+
+impl std::str::FromStr for Unit {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "percent" | "%" => Ok(Unit::Percent),
+            "samples" => Ok(Unit::Samples),
+            "nanoseconds" | "ns" => Ok(Unit::Nanoseconds),
+            "microseconds" | "us" => Ok(Unit::Microseconds),
+            "milliseconds" | "ms" => Ok(Unit::Milliseconds),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown unit: {}", s),
+            )),
+        }
+    }
+}
+
+impl Unit {
+    /// the nanoseconds in the unit, none for the others
+    pub fn nanos(&self) -> Option<i64> {
+        match self {
+            Unit::Nanoseconds => Some(1),
+            Unit::Microseconds => Some(1_000),
+            Unit::Milliseconds => Some(1_000_000),
+            _ => None,
+        }
+    }
+
+    /// the sample type to show in the unit: the first in nanoseconds for
+    /// times, the first counting samples otherwise, or the first of all
+    pub fn index(&self, profile: &pb::Profile) -> usize {
+        let unit = |u: &str| {
+            profile.sample_type.iter().position(
+                |t| matches!(profile.string_table.get(t.unit as usize), Some(s) if s == u),
+            )
+        };
+        let index = if self.nanos().is_some() {
+            unit("nanoseconds")
+        } else {
+            unit("count")
+        };
+        index.unwrap_or(0)
+    }
+
+    /// a value in the unit, out of `total` for percentages
+    pub fn format(&self, value: i64, total: i64) -> String {
+        match self {
+            Unit::Percent => format!("{:.2}%", value as f64 * 100.0 / total.max(1) as f64),
+            Unit::Samples => value.to_string(),
+            Unit::Nanoseconds => format!("{}ns", value),
+            Unit::Microseconds => format!("{:.1}us", value as f64 / 1e3),
+            Unit::Milliseconds => format!("{:.2}ms", value as f64 / 1e6),
+        }
+    }
+}
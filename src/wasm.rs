@@ -0,0 +1,120 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the profiles of WebAssembly guests that wasmtime writes with
+//! `--profile=guest`, in the processed profile format of the Firefox
+//! Profiler. The tables are columns; a stack is a frame and the stack of its
+//! caller, `prefix`.
+
+use serde::Deserialize;
+use std::io;
+
+use crate::pprof::{self, pb, SampleBuilder};
+
+#[derive(Deserialize)]
+struct Profile {
+    threads: Vec<Thread>,
+    // the strings shared by the threads in the recent versions
+    #[serde(default)]
+    shared: Option<Shared>,
+}
+
+#[derive(Deserialize)]
+struct Shared {
+    #[serde(rename = "stringArray", default)]
+    string_array: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Thread {
+    #[serde(default)]
+    name: String,
+    samples: Samples,
+    #[serde(rename = "stackTable")]
+    stack_table: StackTable,
+    #[serde(rename = "frameTable")]
+    frame_table: FrameTable,
+    #[serde(rename = "funcTable")]
+    func_table: FuncTable,
+    #[serde(rename = "stringArray", default)]
+    string_array: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct Samples {
+    stack: Vec<Option<usize>>,
+    #[serde(default)]
+    weight: Option<Vec<f64>>,
+}
+
+#[derive(Deserialize)]
+struct StackTable {
+    frame: Vec<usize>,
+    prefix: Vec<Option<usize>>,
+}
+
+#[derive(Deserialize)]
+struct FrameTable {
+    func: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct FuncTable {
+    name: Vec<usize>,
+}
+
+/// Reads a guest profile of wasmtime, the samples per thread labeled
+/// `thread_name`, with the wasm function names demangled, like those of
+/// Rust guests.
+pub fn decode_guest_profile<R>(reader: R) -> io::Result<pb::Profile>
+where
+    R: io::Read,
+{
+    let profile: Profile = serde_json::from_reader(reader)?;
+    let shared = profile.shared.map(|s| s.string_array).unwrap_or_default();
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "broken stack table");
+    let mut builder = SampleBuilder::default();
+    for thread in &profile.threads {
+        let strings = thread.string_array.as_ref().unwrap_or(&shared);
+        let func_name = |frame: usize| -> Option<String> {
+            let func = *thread.frame_table.func.get(frame)?;
+            let name = strings.get(*thread.func_table.name.get(func)?)?;
+            Some(pprof::demangle(name))
+        };
+        for (i, stack) in thread.samples.stack.iter().enumerate() {
+            // leaf first
+            let mut frames = Vec::new();
+            let mut next = *stack;
+            while let Some(s) = next {
+                let frame = *thread.stack_table.frame.get(s).ok_or_else(invalid)?;
+                frames.push(func_name(frame).unwrap_or_else(|| "[unknown]".to_string()));
+                next = *thread.stack_table.prefix.get(s).ok_or_else(invalid)?;
+                if frames.len() > thread.stack_table.frame.len() {
+                    return Err(invalid());
+                }
+            }
+            if frames.is_empty() {
+                continue;
+            }
+            let weight = match thread.samples.weight.as_ref() {
+                Some(w) => w.get(i).copied().unwrap_or(1.0).round() as i64,
+                None => 1,
+            };
+            let frames: Vec<&str> = frames.iter().map(|f| f.as_str()).collect();
+            builder.add(&frames, &[("thread_name", &thread.name)], weight);
+        }
+    }
+    Ok(builder.build())
+}
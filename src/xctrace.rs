@@ -0,0 +1,197 @@
+// Copyright (C) 2022 The Perf-tools Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the samples of the Time Profiler of Instruments, as `xctrace export
+//! --xpath '/trace-toc/run[@number="1"]/data/table[@schema="time-profile"]'`
+//! prints them. Elements printed once have an `id`, and later ones refer to
+//! them with `ref`:
+//!
+//! ```text
+//! <row><sample-time id="1" fmt="00:00.001.234">1234000</sample-time>
+//! <thread id="2" fmt="Main Thread 0x1b3 (foo, pid: 123)"><tid id="3" fmt="0x1b3">435</tid>...</thread>
+//! <weight id="6" fmt="1.00 ms">1000000</weight>
+//! <backtrace id="7"><frame id="8" name="foo::bar" addr="0x100a4"><binary .../></frame><frame ref="9"/></backtrace></row>
+//! ```
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::io;
+
+use crate::pprof::{pb, SampleBuilder};
+
+lazy_static! {
+    static ref TAG_RE: Regex = Regex::new(r"<(/?)([\w\-]+)([^>]*?)(/?)>").unwrap();
+    static ref ATTR_RE: Regex = Regex::new(r#"([\w\-]+)="([^"]*)""#).unwrap();
+}
+
+// the sampling interval of the Time Profiler
+const DEFAULT_WEIGHT: i64 = 1_000_000;
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// "Main Thread 0x1b3 (foo, pid: 123)" to "Main Thread"
+fn thread_name(fmt: &str) -> &str {
+    let name = fmt.rsplit_once(" (").map_or(fmt, |(n, _)| n);
+    match name.rsplit_once(" 0x") {
+        Some((n, tid)) if u64::from_str_radix(tid, 16).is_ok() => n,
+        _ => name,
+    }
+}
+
+#[derive(Default)]
+struct Row {
+    thread: Option<String>,
+    weight: Option<i64>,
+    frames: Vec<String>,
+}
+
+/// Reads the exported time profile into a profile of cpu nanoseconds with
+/// the `thread_name` labels.
+pub fn decode_time_profile<R>(mut reader: R) -> io::Result<pb::Profile>
+where
+    R: io::Read,
+{
+    let mut xml = String::new();
+    reader.read_to_string(&mut xml)?;
+
+    let mut builder = SampleBuilder::new("cpu", "nanoseconds");
+    // the elements by id
+    let mut frames: HashMap<String, String> = HashMap::new();
+    let mut backtraces: HashMap<String, Vec<String>> = HashMap::new();
+    let mut threads: HashMap<String, String> = HashMap::new();
+    let mut weights: HashMap<String, i64> = HashMap::new();
+
+    let mut row: Option<Row> = None;
+    // the id of the backtrace and the weight being read
+    let mut backtrace: Option<String> = None;
+    let mut weight: Option<(String, usize)> = None;
+    // the depth of the elements in a frame, like <binary>
+    let mut in_frame = 0;
+    for caps in TAG_RE.captures_iter(&xml) {
+        let (closing, name, self_closing) = (&caps[1] == "/", &caps[2], &caps[4] == "/");
+        let attrs: HashMap<&str, String> = ATTR_RE
+            .captures_iter(caps.get(3).unwrap().as_str())
+            .map(|a| (a.get(1).unwrap().as_str(), unescape(&a[2])))
+            .collect();
+        let id = attrs.get("id").cloned();
+        let r = attrs.get("ref");
+        if closing {
+            match name {
+                "row" => {
+                    if let Some(row) = row.take() {
+                        let frames: Vec<&str> = row.frames.iter().map(|f| f.as_str()).collect();
+                        let thread = row.thread.as_deref().map_or("", thread_name);
+                        builder.add(
+                            &frames,
+                            &[("thread_name", thread)],
+                            row.weight.unwrap_or(DEFAULT_WEIGHT),
+                        );
+                    }
+                }
+                "backtrace" => backtrace = None,
+                "frame" => in_frame -= 1,
+                "weight" => {
+                    if let Some((id, start)) = weight.take() {
+                        let text = &xml[start..caps.get(0).unwrap().start()];
+                        if let (Some(row), Ok(v)) = (row.as_mut(), text.trim().parse()) {
+                            row.weight = Some(v);
+                            weights.insert(id, v);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match name {
+            "row" => row = Some(Row::default()),
+            "thread" if in_frame == 0 => {
+                let fmt = match r {
+                    Some(r) => threads.get(r).cloned(),
+                    None => attrs.get("fmt").cloned(),
+                };
+                if let (Some(id), Some(fmt)) = (id, fmt.as_ref()) {
+                    threads.insert(id, fmt.clone());
+                }
+                if let Some(row) = row.as_mut() {
+                    row.thread = fmt;
+                }
+            }
+            "weight" => match r {
+                Some(r) => {
+                    if let Some(row) = row.as_mut() {
+                        row.weight = weights.get(r).copied();
+                    }
+                }
+                None if !self_closing => {
+                    weight = Some((id.unwrap_or_default(), caps.get(0).unwrap().end()));
+                }
+                None => {}
+            },
+            "backtrace" => match r {
+                Some(r) => {
+                    if let (Some(row), Some(b)) = (row.as_mut(), backtraces.get(r)) {
+                        row.frames = b.clone();
+                    }
+                }
+                None => {
+                    if let Some(id) = id.as_ref() {
+                        backtraces.insert(id.clone(), Vec::new());
+                    }
+                    if !self_closing {
+                        backtrace = id;
+                    }
+                }
+            },
+            "frame" => {
+                let frame = match r {
+                    Some(r) => frames.get(r).cloned(),
+                    None => {
+                        let name = attrs
+                            .get("name")
+                            .cloned()
+                            .or_else(|| attrs.get("addr").cloned())
+                            .unwrap_or_else(|| "[unknown]".to_string());
+                        if let Some(id) = id {
+                            frames.insert(id, name.clone());
+                        }
+                        Some(name)
+                    }
+                };
+                if !self_closing {
+                    in_frame += 1;
+                }
+                if let Some(frame) = frame {
+                    // leaf first
+                    if let Some(b) = backtrace.as_ref().and_then(|b| backtraces.get_mut(b)) {
+                        b.push(frame.clone());
+                    }
+                    if let Some(row) = row.as_mut() {
+                        row.frames.push(frame);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(builder.build())
+}